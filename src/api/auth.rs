@@ -4,6 +4,6 @@ use serde_json::{json, Value};
 
 #[get("/jwt")]
 pub fn get_jwt() -> Result<Value, Status> {
-    let token = jwt::jwt_sign("demo");
+    let token = jwt::jwt_sign("demo", None);
     Ok(json!({ "token": token }))
 }
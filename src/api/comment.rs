@@ -0,0 +1,219 @@
+use crate::api::share::{authorize_document, get_principal};
+use crate::helpers::jwt;
+use crate::helpers::mongo_id::MongoId;
+use crate::models::{comment::{Comment, CommentThread, TextAnchor}, document::Document, share::{Permission, Principal}};
+use crate::repository::mongodb_repo::MongoRepo;
+use mongodb::bson::{doc, oid::ObjectId, to_bson};
+use rocket::{http::Status, serde::json::Json};
+use serde::{Serialize, Deserialize};
+use chrono::Utc;
+
+const MAX_BODY_LENGTH: usize = 10_000;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewComment {
+    body: String,
+    // only the first comment of a thread can be anchored
+    anchor: Option<TextAnchor>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CommentEdit {
+    body: String,
+}
+
+fn check_body(body: &str) -> Result<String, Status> {
+    let body = body.trim();
+    if body.is_empty() || body.chars().count() > MAX_BODY_LENGTH {
+        return Err(Status::UnprocessableEntity);
+    }
+    Ok(body.to_string())
+}
+
+// the anchor as stored, with the quote taken from the current content
+fn check_anchor(document: &Document, anchor: TextAnchor) -> Result<TextAnchor, Status> {
+    match anchor.quote_from(document.content.as_deref().unwrap_or("")) {
+        Some(quote) => Ok(TextAnchor { quote: Some(quote), ..anchor }),
+        None => Err(Status::UnprocessableEntity),
+    }
+}
+
+// the document, checked for `needed`, with its id
+async fn commented_document(db: &MongoRepo, principal: &Principal, id: &MongoId, needed: Permission) -> Result<(ObjectId, Document), Status> {
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let document = authorize_document(db, principal, &obj_id, needed).await?;
+    Ok((obj_id, document))
+}
+
+async fn find_comment(db: &MongoRepo, document_id: &ObjectId, comment_id: &MongoId) -> Result<Comment, Status> {
+    let comment_id = ObjectId::parse_str(comment_id.to_string()).map_err(|_| Status::NotFound)?;
+    match db.find_comment(doc! {"_id": comment_id, "document_id": document_id}).await {
+        Ok(Some(comment)) => Ok(comment),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+async fn reload(db: &MongoRepo, comment: &Comment) -> Result<Json<Comment>, Status> {
+    match db.find_comment(doc! {"_id": comment.id}).await {
+        Ok(Some(comment)) => Ok(Json(comment)),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+// threads are opened and resolved as a whole, replies can not be
+fn require_thread(comment: &Comment) -> Result<ObjectId, Status> {
+    match (comment.is_reply(), comment.id) {
+        (false, Some(id)) => Ok(id),
+        _ => Err(Status::UnprocessableEntity),
+    }
+}
+
+// every thread on the document with its replies, `resolved` picks resolved or open threads
+#[get("/<id>/comments?<resolved>")]
+pub async fn list_comments(
+    db: &MongoRepo,
+    id: MongoId,
+    resolved: Option<bool>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Vec<CommentThread>>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let (obj_id, _) = commented_document(db, &principal, &id, Permission::Viewer).await?;
+
+    let comments = db.find_comments(doc! {"document_id": obj_id}).await.map_err(|_| Status::InternalServerError)?;
+    let threads = CommentThread::group(comments)
+        .into_iter()
+        .filter(|thread| resolved.is_none_or(|resolved| thread.comment.is_resolved() == resolved))
+        .collect();
+    Ok(Json(threads))
+}
+
+/**
+ * Starts a thread, optionally anchored to a range of the content. Commenters,
+ * editors and the owner can comment; the range has to lie within the content.
+ */
+#[post("/<id>/comments", data = "<new_comment>")]
+pub async fn create_comment(
+    db: &MongoRepo,
+    id: MongoId,
+    new_comment: Json<NewComment>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Comment>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let (obj_id, document) = commented_document(db, &principal, &id, Permission::Commenter).await?;
+    let data = new_comment.into_inner();
+    let body = check_body(&data.body)?;
+    let anchor = data.anchor.map(|anchor| check_anchor(&document, anchor)).transpose()?;
+
+    let comment = Comment {
+        document_id: obj_id,
+        author_id: principal.user.id,
+        body,
+        anchor,
+        date_created: Some(Utc::now()),
+        ..Default::default()
+    };
+    db.create_comment(comment).await.map(Json).map_err(|_| Status::InternalServerError)
+}
+
+// replying to a reply answers in the same thread
+#[post("/<id>/comments/<comment_id>/replies", data = "<reply>")]
+pub async fn reply_to_comment(
+    db: &MongoRepo,
+    id: MongoId,
+    comment_id: MongoId,
+    reply: Json<CommentEdit>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Comment>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let (obj_id, _) = commented_document(db, &principal, &id, Permission::Commenter).await?;
+    let parent = find_comment(db, &obj_id, &comment_id).await?;
+    let body = check_body(&reply.body)?;
+
+    let comment = Comment {
+        document_id: obj_id,
+        author_id: principal.user.id,
+        parent_id: parent.parent_id.or(parent.id),
+        body,
+        date_created: Some(Utc::now()),
+        ..Default::default()
+    };
+    db.create_comment(comment).await.map(Json).map_err(|_| Status::InternalServerError)
+}
+
+// only the author can edit a comment, as long as they can still comment on the document
+#[put("/<id>/comments/<comment_id>", data = "<edit>")]
+pub async fn edit_comment(
+    db: &MongoRepo,
+    id: MongoId,
+    comment_id: MongoId,
+    edit: Json<CommentEdit>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Comment>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let (obj_id, _) = commented_document(db, &principal, &id, Permission::Commenter).await?;
+    let comment = find_comment(db, &obj_id, &comment_id).await?;
+    if comment.author_id.is_none() || comment.author_id != principal.user.id {
+        return Err(Status::Forbidden);
+    }
+    let body = check_body(&edit.body)?;
+
+    let comment_id = comment.id.ok_or(Status::NotFound)?;
+    let now = to_bson(&Utc::now()).map_err(|_| Status::InternalServerError)?;
+    if db.update_comment(&comment_id, doc! {"$set": {"body": body, "last_modified": now}}).await.is_err() {
+        return Err(Status::InternalServerError);
+    }
+    reload(db, &comment).await
+}
+
+// authors can delete their comments and the owner can delete any, a thread goes with its first comment
+#[delete("/<id>/comments/<comment_id>")]
+pub async fn delete_comment(
+    db: &MongoRepo,
+    id: MongoId,
+    comment_id: MongoId,
+    _auth: jwt::AuthObject,
+) -> Result<Json<&'static str>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let (obj_id, document) = commented_document(db, &principal, &id, Permission::Viewer).await?;
+    let comment = find_comment(db, &obj_id, &comment_id).await?;
+    let is_author = comment.author_id.is_some() && comment.author_id == principal.user.id;
+    if !is_author && document.permission_for(&principal) != Some(Permission::Owner) {
+        return Err(Status::Forbidden);
+    }
+
+    let comment_id = comment.id.ok_or(Status::NotFound)?;
+    match db.delete_comment(&comment_id).await {
+        Ok(_) => Ok(Json("Comment successfully deleted!")),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[post("/<id>/comments/<comment_id>/resolve")]
+pub async fn resolve_comment(db: &MongoRepo, id: MongoId, comment_id: MongoId, _auth: jwt::AuthObject) -> Result<Json<Comment>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let (obj_id, _) = commented_document(db, &principal, &id, Permission::Commenter).await?;
+    let comment = find_comment(db, &obj_id, &comment_id).await?;
+    let thread_id = require_thread(&comment)?;
+
+    let now = to_bson(&Utc::now()).map_err(|_| Status::InternalServerError)?;
+    let update = doc! {"$set": {"resolved_at": now, "resolved_by": principal.user.id}};
+    if db.update_comment(&thread_id, update).await.is_err() {
+        return Err(Status::InternalServerError);
+    }
+    reload(db, &comment).await
+}
+
+#[post("/<id>/comments/<comment_id>/reopen")]
+pub async fn reopen_comment(db: &MongoRepo, id: MongoId, comment_id: MongoId, _auth: jwt::AuthObject) -> Result<Json<Comment>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let (obj_id, _) = commented_document(db, &principal, &id, Permission::Commenter).await?;
+    let comment = find_comment(db, &obj_id, &comment_id).await?;
+    let thread_id = require_thread(&comment)?;
+
+    if db.update_comment(&thread_id, doc! {"$unset": {"resolved_at": "", "resolved_by": ""}}).await.is_err() {
+        return Err(Status::InternalServerError);
+    }
+    reload(db, &comment).await
+}
@@ -1,105 +1,335 @@
+use crate::api::folder::target_folder;
+use crate::api::share::{authorize_document, get_principal, shared_filter};
+use crate::api::tag::normalize_tags;
+use crate::api::user::get_auth_user;
+use crate::helpers::etag::{IfMatch, RequiredIfMatch, Tagged};
 use crate::helpers::jwt;
 use crate::helpers::mongo_id::MongoId;
-use crate::{models::document::Document, repository::mongodb_repo::MongoRepo};
-use mongodb::bson::oid::ObjectId;
+use crate::helpers::patch::{apply_json_patch, apply_merge_patch, field_changes, PatchError};
+use crate::search::analyzer::Language;
+use crate::{models::{document::Document, share::{Permission, Principal}, user::{RoleEnum, User}}, repository::mongodb_repo::MongoRepo};
+use mongodb::bson::{doc, oid::ObjectId, to_bson, Document as BsonDocument};
 use mongodb::{results::InsertOneResult};
-use rocket::{http::Status, serde::json::Json, State};
+use rocket::{http::Status, serde::json::Json};
 use struct_helpers::rocket::guard::HelpersGuard;
 use chrono::prelude::*;
 use serde::{Serialize, Deserialize};
+use serde_json::Value;
 use struct_helpers::{Helpers};
 
-async fn get_owner_id(db: &State<MongoRepo>, email: String) -> Option<ObjectId> {
-    let owner_details = db.get_user_by_email(email).await;
-    let owner = owner_details.unwrap();
+// id of the user behind the token, if they have an account
+pub async fn get_author_id(db: &MongoRepo, auth: &jwt::AuthObject) -> Option<ObjectId> {
+    match db.find_user(auth.user_filter()).await {
+        Ok(Some(user)) => user.id,
+        _ => None,
+    }
+}
 
-    return owner.id;
+// limits a document query to what `user` owns, administrators own everything
+pub fn owned_filter(user: &User) -> BsonDocument {
+    match user.role {
+        Some(RoleEnum::Administrator) => doc! {},
+        _ => doc! {"owner_id": user.id},
+    }
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, Helpers)]
-pub struct NewDocument {
-    title: Option<String>,
-    content: Option<String>
+// limits a document query to what `principal` may read, their own documents and those shared with them
+pub fn readable_filter(principal: &Principal) -> BsonDocument {
+    if principal.is_admin() {
+        return doc! {};
+    }
+    doc! {"$or": [{"owner_id": principal.user.id}, shared_filter(principal)]}
 }
 
+const DEFAULT_LIST_LIMIT: i64 = 50;
+const MAX_LIST_LIMIT: i64 = 200;
 
+// the fields a PATCH may change, matching NewDocument
+const PATCHABLE_FIELDS: [&str; 4] = ["title", "content", "language", "tags"];
+
+// the fields clients may write, ownership, timestamps and version are managed by the server
 #[derive(Debug, Default, Serialize, Deserialize, Helpers)]
-pub struct DocumentCreateObject {
+pub struct NewDocument {
+    #[helper(to_lower_case)]
     title: Option<String>,
     content: Option<String>,
-    date_created: DateTime<Utc>,
-    last_modified: DateTime<Utc>,
+    language: Option<String>,
+    tags: Option<Vec<String>>,
 }
 
-#[get("/<id>")]
-pub async fn get_document(db: &State<MongoRepo>, id: MongoId) -> Result<Json<Document>, Status> {
-    let doc_detail = db.get_document(&id.to_string()).await;
-    match doc_detail {
-        Ok(document) => Ok(Json(document)),
+// documents can only name a language search knows how to stem
+fn check_language(language: &Option<String>) -> Result<(), Status> {
+    match language {
+        Some(code) if Language::from_code(code).is_none() => Err(Status::UnprocessableEntity),
+        _ => Ok(()),
+    }
+}
+
+#[derive(Responder)]
+pub enum DocumentError {
+    // the If-Match version is stale, the body holds the current document
+    #[response(status = 412)]
+    Stale(Tagged<Json<Document>>),
+    Status(Status),
+}
+
+impl From<Status> for DocumentError {
+    fn from(status: Status) -> Self {
+        DocumentError::Status(status)
+    }
+}
+
+// tells a stale precondition apart from a missing document after a write matched nothing
+pub async fn precondition_failed(db: &MongoRepo, id: &ObjectId) -> DocumentError {
+    match db.find_document(doc! {"_id": id}).await {
+        Ok(Some(document)) => {
+            let version = document.version();
+            DocumentError::Stale(Tagged(Json(document), version))
+        },
+        Ok(None) => DocumentError::Status(Status::NotFound),
+        Err(_) => DocumentError::Status(Status::InternalServerError),
+    }
+}
+
+/**
+ * Lists the documents the caller can read, most recently modified first.
+ * Documents can be filtered by tag: `any` needs one of the given tags, `all`
+ * every one of them and `none` excludes documents carrying any of them.
+ */
+#[get("/?<any>&<all>&<none>&<skip>&<limit>")]
+pub async fn list_documents(
+    db: &MongoRepo,
+    any: Vec<String>,
+    all: Vec<String>,
+    none: Vec<String>,
+    skip: Option<u64>,
+    limit: Option<i64>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Vec<Document>>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let mut clauses = vec![readable_filter(&principal)];
+    if !any.is_empty() {
+        clauses.push(doc! {"tags": {"$in": normalize_tags(any)?}});
+    }
+    if !all.is_empty() {
+        clauses.push(doc! {"tags": {"$all": normalize_tags(all)?}});
+    }
+    if !none.is_empty() {
+        clauses.push(doc! {"tags": {"$nin": normalize_tags(none)?}});
+    }
+
+    let limit = limit.unwrap_or(DEFAULT_LIST_LIMIT).clamp(1, MAX_LIST_LIMIT);
+    match db.find_documents(doc! {"$and": clauses}, skip.unwrap_or(0), limit).await {
+        Ok(documents) => Ok(Json(documents)),
         Err(_) => Err(Status::InternalServerError),
     }
 }
 
-#[post("/", data = "<new_document>")]
+#[get("/<id>")]
+pub async fn get_document(db: &MongoRepo, id: MongoId, _auth: jwt::AuthObject) -> Result<Tagged<Json<Document>>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let document = authorize_document(db, &principal, &obj_id, Permission::Viewer).await?;
+    let version = document.version();
+    Ok(Tagged(Json(document), version))
+}
+
+// `folder` files the new document in one of the caller's folders
+#[post("/?<folder>", data = "<new_document>")]
 pub async fn create_document(
-    db: &State<MongoRepo>,
-    new_document: HelpersGuard<Json<Document>>,
+    db: &MongoRepo,
+    folder: Option<&str>,
+    new_document: HelpersGuard<Json<NewDocument>>,
     _auth: jwt::AuthObject,
 ) -> Result<Json<InsertOneResult>, Status> {
+    let folder_id = match folder {
+        Some(folder) => {
+            let user = get_auth_user(db, &_auth).await?;
+            target_folder(db, &user, Some(folder)).await?.and_then(|f| f.id)
+        },
+        None => None,
+    };
     // get owner_id from auth user
-    let owner_id = get_owner_id(db, _auth.user).await;
+    let owner_id = get_author_id(db, &_auth).await;
     let data = new_document.into_deep_inner();
+    check_language(&data.language)?;
+    let tags = data.tags.map(normalize_tags).transpose()?;
+    let now = Utc::now();
     let new_doc = Document {
-        id: data.id,
+        id: None,
         owner_id: owner_id,
+        folder_id,
         title: data.title,
         content: data.content,
-        date_created: Utc::now().with_second(1),
-        last_modified: Utc::now().with_second(1),
+        language: data.language,
+        tags,
+        shares: None,
+        team_shares: None,
+        date_created: Some(now),
+        last_modified: Some(now),
+        last_modified_by: owner_id,
+        version: Some(1),
+        deleted_at: None,
+        deleted_by: None,
+    };
+    let inserted = match db.create_document(Document::from(new_doc)).await {
+        Ok(document) => document,
+        Err(_) => return Err(Status::InternalServerError),
     };
-    let doc_detail = db.create_document(Document::from(new_doc)).await;
 
-    return match doc_detail {
-        Ok(document) => Ok(Json(document)),
-        Err(_) => Err(Status::InternalServerError),
+    // the first revision holds the document as created
+    if let Some(doc_id) = inserted.inserted_id.as_object_id() {
+        let document = match db.find_document(doc! {"_id": doc_id}).await {
+            Ok(Some(document)) => document,
+            _ => return Err(Status::InternalServerError),
+        };
+        if db.record_revision(&document, owner_id, None).await.is_err() {
+            return Err(Status::InternalServerError);
+        }
     }
+
+    Ok(Json(inserted))
 }
 
 #[put("/<id>", data = "<new_document>")]
 pub async fn update_document(
-    db: &State<MongoRepo>,
+    db: &MongoRepo,
     id: MongoId,
-    new_document: HelpersGuard<Json<Document>>,
-) -> Result<Json<Document>, Status> {
-    let mut data = new_document.into_deep_inner();
-    data.remove_id();
-
-    let update_result = match db.update_document(&id.to_string(), data).await {
-        Ok(update) => update,
-        Err(_) => return Err(Status::InternalServerError)
+    new_document: HelpersGuard<Json<NewDocument>>,
+    if_match: RequiredIfMatch,
+    _auth: jwt::AuthObject,
+) -> Result<Tagged<Json<Document>>, DocumentError> {
+    let data = new_document.into_deep_inner();
+    check_language(&data.language)?;
+    let tags = data.tags.map(normalize_tags).transpose()?;
+
+    let principal = get_principal(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let current = authorize_document(db, &principal, &obj_id, Permission::Editor).await?;
+    // keep the pre-update text of documents that have no history yet
+    if db.ensure_initial_revision(&current).await.is_err() {
+        return Err(Status::InternalServerError.into());
+    }
+
+    let author_id = principal.user.id;
+    let changes = Document {
+        title: data.title,
+        content: data.content,
+        language: data.language,
+        tags,
+        last_modified: Some(Utc::now()),
+        last_modified_by: author_id,
+        ..Default::default()
+    };
+
+    let updated = match db.update_document(&id.to_string(), changes, if_match.0).await {
+        Ok(updated) => updated,
+        Err(_) => return Err(Status::InternalServerError.into())
     };
 
-    if update_result.matched_count == 1 {
-        match db.get_document(&id.to_string()).await {
-            Ok(document) => return Ok(Json(document)),
-            Err(_) => return Err(Status::InternalServerError),
+    if let Some(document) = updated {
+        if db.record_revision(&document, author_id, None).await.is_err() {
+            return Err(Status::InternalServerError.into());
         }
+        let version = document.version();
+        return Ok(Tagged(Json(document), version));
+    }
+
+    return Err(precondition_failed(db, &obj_id).await);
+}
+
+/**
+ * Applies a patch to the stored document and writes only the fields it
+ * changed. Without If-Match the patch is still checked against the version it
+ * was applied to, so a concurrent write is never overwritten.
+ */
+async fn patch_document<F>(
+    db: &MongoRepo,
+    id: MongoId,
+    if_match: IfMatch,
+    auth: &jwt::AuthObject,
+    apply: F,
+) -> Result<Tagged<Json<Document>>, DocumentError>
+where
+    F: FnOnce(&Document) -> Result<Document, PatchError>,
+{
+    let principal = get_principal(db, auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let current = authorize_document(db, &principal, &obj_id, Permission::Editor).await?;
+    if if_match.0.map_or(false, |v| v != current.version()) {
+        return Err(precondition_failed(db, &obj_id).await);
+    }
+
+    let mut patched = apply(&current).map_err(Status::from)?;
+    patched.title = patched.title.map(|title| title.to_lowercase());
+    check_language(&patched.language)?;
+    patched.tags = patched.tags.map(normalize_tags).transpose()?;
+    let mut changes = field_changes(&current, &patched, &PATCHABLE_FIELDS).map_err(Status::from)?;
+    if changes.is_empty() {
+        let version = current.version();
+        return Ok(Tagged(Json(current), version));
+    }
+
+    if db.ensure_initial_revision(&current).await.is_err() {
+        return Err(Status::InternalServerError.into());
+    }
+    let author_id = principal.user.id;
+    let now = to_bson(&Utc::now()).map_err(|_| Status::InternalServerError)?;
+    changes.set.insert("last_modified", now);
+    if let Some(author_id) = author_id {
+        changes.set.insert("last_modified_by", author_id);
     }
 
-    return Err(Status::NotFound);
+    let updated = match db.update_document_fields(&id.to_string(), changes.set, changes.unset, Some(current.version())).await {
+        Ok(updated) => updated,
+        Err(_) => return Err(Status::InternalServerError.into()),
+    };
+    let document = match updated {
+        Some(document) => document,
+        None => return Err(precondition_failed(db, &obj_id).await),
+    };
+    if db.record_revision(&document, author_id, None).await.is_err() {
+        return Err(Status::InternalServerError.into());
+    }
+    let version = document.version();
+    Ok(Tagged(Json(document), version))
+}
+
+#[patch("/<id>", format = "application/merge-patch+json", data = "<patch>")]
+pub async fn merge_patch_document(
+    db: &MongoRepo,
+    id: MongoId,
+    patch: Json<Value>,
+    if_match: IfMatch,
+    _auth: jwt::AuthObject,
+) -> Result<Tagged<Json<Document>>, DocumentError> {
+    patch_document(db, id, if_match, &_auth, |current| apply_merge_patch(current, &patch)).await
 }
 
+#[patch("/<id>", format = "application/json-patch+json", data = "<patch>")]
+pub async fn json_patch_document(
+    db: &MongoRepo,
+    id: MongoId,
+    patch: Json<json_patch::Patch>,
+    if_match: IfMatch,
+    _auth: jwt::AuthObject,
+) -> Result<Tagged<Json<Document>>, DocumentError> {
+    patch_document(db, id, if_match, &_auth, |current| apply_json_patch(current, &patch)).await
+}
+
+// only the owner can delete a document, it goes to their trash, see api::trash
 #[delete("/<id>")]
-pub async fn delete_document(db: &State<MongoRepo>, id: MongoId) -> Result<Json<&str>, Status> {
-    let result = db.delete_document(&id.to_string()).await;
-    match result {
-        Ok(res) => {
-            if res.deleted_count == 1 {
-                return Ok(Json("Document successfully deleted!"));
-            } else {
-                return Err(Status::NotFound);
-            }
-        }
-        Err(_) => Err(Status::InternalServerError),
+pub async fn delete_document(db: &MongoRepo, id: MongoId, if_match: RequiredIfMatch, _auth: jwt::AuthObject) -> Result<Json<&'static str>, DocumentError> {
+    let principal = get_principal(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    authorize_document(db, &principal, &obj_id, Permission::Owner).await?;
+    let deleted = match db.trash_document(&obj_id, if_match.0, principal.user.id).await {
+        Ok(trashed) => trashed == 1,
+        Err(_) => return Err(Status::InternalServerError.into()),
+    };
+
+    if deleted {
+        return Ok(Json("Document moved to the trash!"));
     }
+    Err(precondition_failed(db, &obj_id).await)
 }
@@ -0,0 +1,121 @@
+use std::{sync::Arc, time::Duration};
+use crate::api::share::get_principal;
+use crate::events::bus::Published;
+use crate::helpers::jwt;
+use crate::models::{event::EventKind, share::Principal};
+use crate::repository::mongodb_repo::MongoRepo;
+use mongodb::bson::oid::ObjectId;
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome},
+    response::stream::{Event, EventStream},
+    tokio::{select, sync::broadcast::error::RecvError, time::{interval_at, Instant}},
+    Request, Shutdown,
+};
+
+// how often a stream looks up the caller's teams and folder grants again
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+// the id of the last event a client saw, sent by EventSource when it reconnects
+pub struct LastEventId(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for LastEventId {
+    type Error = &'r str;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        Outcome::Success(LastEventId(req.headers().get_one("last-event-id").map(str::to_string)))
+    }
+}
+
+// events of the caller's organization about documents they can read, as the document was at the time
+fn is_visible(published: &Published, org_id: Option<ObjectId>, principal: &Principal) -> bool {
+    published.org_id == org_id && published.document.as_ref().is_some_and(|document| document.permission_for(principal).is_some())
+}
+
+// events after which what the caller can read may have changed
+pub fn changes_access(published: &Published, org_id: Option<ObjectId>, principal: &Principal) -> bool {
+    published.org_id == org_id && match published.event.kind {
+        EventKind::DocumentShared => true,
+        EventKind::UserUpdated | EventKind::UserDeleted => published.event.user_id == principal.user.id,
+        _ => false,
+    }
+}
+
+// looks the caller up again, false once they are deactivated or gone
+async fn refresh(db: &MongoRepo, auth: &jwt::AuthObject, principal: &mut Principal) -> bool {
+    match get_principal(db, auth).await {
+        Ok(refreshed) => {
+            *principal = refreshed;
+            true
+        },
+        Err(status) => status != Status::Unauthorized,
+    }
+}
+
+fn to_sse(published: &Published) -> Event {
+    Event::json(&published.event).id(published.event.id.clone()).event(published.event.kind.name())
+}
+
+// tells a client it missed events, it has to fetch what it shows again
+fn reset() -> Event {
+    Event::data("{}").event("reset")
+}
+
+/**
+ * Streams changes to the documents the caller can read as Server-Sent Events.
+ * A client reconnecting with Last-Event-ID (or `last_event_id`) gets the
+ * events it missed first, or a `reset` event when those are gone. The caller's
+ * access is looked up again after share and user changes and every minute,
+ * and the stream ends once they are deactivated.
+ */
+#[get("/events?<last_event_id>")]
+pub async fn document_events(
+    db: &MongoRepo,
+    last_event_id: Option<String>,
+    header: LastEventId,
+    _auth: jwt::AuthObject,
+    mut shutdown: Shutdown,
+) -> Result<EventStream![], Status> {
+    let mut principal = get_principal(db, &_auth).await?;
+    let org_id = db.org_id();
+    let (missed, mut subscription) = db.events().subscribe(header.0.or(last_event_id).as_deref());
+    let db = db.clone();
+    let mut refreshes = interval_at(Instant::now() + REFRESH_INTERVAL, REFRESH_INTERVAL);
+
+    Ok(EventStream! {
+        match missed {
+            Some(missed) => {
+                for published in missed.iter().filter(|published| is_visible(published, org_id, &principal)) {
+                    yield to_sse(published);
+                }
+            },
+            None => yield reset(),
+        }
+        loop {
+            let published: Arc<Published> = select! {
+                received = subscription.recv() => match received {
+                    Ok(published) => published,
+                    Err(RecvError::Lagged(_)) => {
+                        yield reset();
+                        continue;
+                    },
+                    Err(RecvError::Closed) => break,
+                },
+                _ = refreshes.tick() => {
+                    if !refresh(&db, &_auth, &mut principal).await {
+                        break;
+                    }
+                    continue;
+                },
+                _ = &mut shutdown => break,
+            };
+            if changes_access(&published, org_id, &principal) && !refresh(&db, &_auth, &mut principal).await {
+                break;
+            }
+            if is_visible(&published, org_id, &principal) {
+                yield to_sse(&published);
+            }
+        }
+    })
+}
@@ -0,0 +1,431 @@
+use crate::api::share::get_principal;
+use crate::api::user::get_auth_user;
+use crate::helpers::etag::Tagged;
+use crate::helpers::jwt;
+use crate::helpers::mongo_id::MongoId;
+use crate::models::{document::Document, folder::{Breadcrumb, Folder, FolderListing}, revision::Revision, share::Principal, user::User};
+use crate::repository::mongodb_repo::{MongoRepo, MAX_FOLDER_DEPTH};
+use mongodb::bson::{doc, oid::ObjectId, to_bson, Document as BsonDocument};
+use rocket::{http::Status, serde::json::Json};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use chrono::{DateTime, Utc};
+
+const MAX_NAME_LENGTH: usize = 255;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewFolder {
+    name: String,
+    parent_id: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FolderRename {
+    name: String,
+}
+
+// where a move or copy puts a folder or document, None for the root
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Destination {
+    folder_id: Option<String>,
+}
+
+fn check_name(name: &str) -> Result<String, Status> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(Status::UnprocessableEntity);
+    }
+    Ok(name.to_string())
+}
+
+// a folder the caller owns, or any folder for administrators
+pub async fn find_folder(db: &MongoRepo, user: &User, id: &ObjectId) -> Result<Folder, Status> {
+    let folder = match db.find_folder(doc! {"_id": id}).await {
+        Ok(Some(folder)) => folder,
+        Ok(None) => return Err(Status::NotFound),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    if !folder.owner_id.map_or(user.is_admin(), |owner| user.can_manage(&owner)) {
+        return Err(Status::Forbidden);
+    }
+    Ok(folder)
+}
+
+// the folder a client named as parent or destination, it has to belong to the caller
+pub async fn target_folder(db: &MongoRepo, user: &User, folder_id: Option<&str>) -> Result<Option<Folder>, Status> {
+    let folder_id = match folder_id {
+        Some(folder_id) => ObjectId::parse_str(folder_id).map_err(|_| Status::UnprocessableEntity)?,
+        None => return Ok(None),
+    };
+    match find_folder(db, user, &folder_id).await {
+        Ok(folder) => Ok(Some(folder)),
+        Err(status) if status == Status::NotFound => Err(Status::UnprocessableEntity),
+        Err(status) => Err(status),
+    }
+}
+
+// how many levels a folder tree spans, `subtree` lists parents before their children
+pub fn subtree_height(subtree: &[Folder]) -> usize {
+    let mut depths: HashMap<ObjectId, usize> = HashMap::new();
+    let mut height = 0;
+    for folder in subtree {
+        let depth = folder.parent_id.and_then(|p| depths.get(&p)).map_or(1, |d| d + 1);
+        if let Some(id) = folder.id {
+            depths.insert(id, depth);
+        }
+        height = height.max(depth);
+    }
+    height
+}
+
+// a tree of `height` levels placed below a folder `parent_depth` levels deep, 0 for the root
+pub fn check_depth(parent_depth: usize, height: usize) -> Result<(), Status> {
+    if parent_depth + height > MAX_FOLDER_DEPTH {
+        return Err(Status::UnprocessableEntity);
+    }
+    Ok(())
+}
+
+// a folder can not move below itself
+pub fn check_move(folder_id: &ObjectId, destination_path: &[Folder], height: usize) -> Result<(), Status> {
+    if destination_path.iter().any(|f| f.id.as_ref() == Some(folder_id)) {
+        return Err(Status::Conflict);
+    }
+    check_depth(destination_path.len(), height)
+}
+
+/**
+ * Copies of the folders in `subtree` (parents before their children) for
+ * `owner_id`, with new ids and the top one placed in `parent_id`. Returns
+ * them in the same order, with a map from each original id to its copy.
+ */
+pub fn plan_folder_copy(
+    subtree: &[Folder],
+    parent_id: Option<ObjectId>,
+    owner_id: Option<ObjectId>,
+    now: DateTime<Utc>,
+) -> (Vec<Folder>, HashMap<ObjectId, ObjectId>) {
+    let mut copies: HashMap<ObjectId, ObjectId> = HashMap::new();
+    let mut folders = Vec::new();
+    for (i, original) in subtree.iter().enumerate() {
+        let copy_id = ObjectId::new();
+        if let Some(original_id) = original.id {
+            copies.insert(original_id, copy_id);
+        }
+        folders.push(Folder {
+            id: Some(copy_id),
+            owner_id,
+            name: original.name.clone(),
+            parent_id: match i {
+                0 => parent_id,
+                _ => original.parent_id.and_then(|p| copies.get(&p).copied()),
+            },
+            // team grants stay with the original
+            team_shares: None,
+            date_created: Some(now),
+            last_modified: Some(now),
+        });
+    }
+    (folders, copies)
+}
+
+// a new document with the same text and tags, its history starts over
+pub fn copy_of(document: &Document, owner_id: Option<ObjectId>, folder_id: Option<ObjectId>, now: DateTime<Utc>) -> Document {
+    Document {
+        id: Some(ObjectId::new()),
+        owner_id,
+        folder_id,
+        title: document.title.clone(),
+        content: document.content.clone(),
+        language: document.language.clone(),
+        tags: document.tags.clone(),
+        date_created: Some(now),
+        last_modified: Some(now),
+        last_modified_by: owner_id,
+        version: Some(1),
+        ..Default::default()
+    }
+}
+
+async fn folder_path(db: &MongoRepo, folder: Folder) -> Result<Vec<Folder>, Status> {
+    match db.folder_path(folder).await {
+        Ok(path) => Ok(path),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+async fn find_document(db: &MongoRepo, user: &User, id: &MongoId) -> Result<Document, Status> {
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let document = match db.find_document(doc! {"_id": obj_id}).await {
+        Ok(Some(document)) => document,
+        Ok(None) => return Err(Status::NotFound),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    if !document.owner_id.map_or(user.is_admin(), |owner| user.can_manage(&owner)) {
+        return Err(Status::Forbidden);
+    }
+    Ok(document)
+}
+
+// a copy of the document with its own history starting at revision 1
+async fn copy_document_into(db: &MongoRepo, user: &User, document: &Document, folder_id: Option<ObjectId>) -> Result<Document, Status> {
+    let copy = copy_of(document, user.id, folder_id, Utc::now());
+    let copy_id = match db.create_document(copy).await {
+        Ok(inserted) => inserted.inserted_id.as_object_id().ok_or(Status::InternalServerError)?,
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    let copy = match db.find_document(doc! {"_id": copy_id}).await {
+        Ok(Some(copy)) => copy,
+        _ => return Err(Status::InternalServerError),
+    };
+    if db.record_revision(&copy, user.id, None).await.is_err() {
+        return Err(Status::InternalServerError);
+    }
+    Ok(copy)
+}
+
+async fn listing(db: &MongoRepo, principal: &Principal, folder: Option<Folder>) -> Result<Json<FolderListing>, Status> {
+    let user = &principal.user;
+    let (folders, documents) = match folder.as_ref().and_then(|f| f.id) {
+        Some(folder_id) => (doc! {"parent_id": folder_id}, doc! {"folder_id": folder_id}),
+        None => (
+            doc! {"owner_id": user.id, "parent_id": null},
+            doc! {"owner_id": user.id, "folder_id": null},
+        ),
+    };
+    let folders = db.find_folders(folders).await.map_err(|_| Status::InternalServerError)?;
+    let documents = db.find_documents(documents, 0, 0).await.map_err(|_| Status::InternalServerError)?;
+
+    let breadcrumbs = match &folder {
+        Some(folder) => folder_path(db, folder.clone()).await?
+            .into_iter()
+            // team members only see the part of the path their grant covers
+            .filter(|f| f.owner_id.map_or(user.is_admin(), |owner| user.can_manage(&owner)) || f.id.is_some_and(|id| principal.folder_grants.contains_key(&id)))
+            .filter_map(|f| Some(Breadcrumb { id: f.id?.to_hex(), name: f.name }))
+            .collect(),
+        None => Vec::new(),
+    };
+
+    Ok(Json(FolderListing { folder, breadcrumbs, folders, documents }))
+}
+
+// the caller's top-level folders and the documents not filed in any folder
+#[get("/")]
+pub async fn get_root_folder(db: &MongoRepo, _auth: jwt::AuthObject) -> Result<Json<FolderListing>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    listing(db, &principal, None).await
+}
+
+// the owner and members of teams granted the folder (or one above it) can list it
+#[get("/<id>")]
+pub async fn get_folder(db: &MongoRepo, id: MongoId, _auth: jwt::AuthObject) -> Result<Json<FolderListing>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let folder = match db.find_folder(doc! {"_id": obj_id}).await {
+        Ok(Some(folder)) => folder,
+        Ok(None) => return Err(Status::NotFound),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    if !folder.owner_id.map_or(principal.is_admin(), |owner| principal.user.can_manage(&owner)) && !principal.folder_grants.contains_key(&obj_id) {
+        return Err(Status::Forbidden);
+    }
+    listing(db, &principal, Some(folder)).await
+}
+
+#[post("/", data = "<new_folder>")]
+pub async fn create_folder(db: &MongoRepo, new_folder: Json<NewFolder>, _auth: jwt::AuthObject) -> Result<Json<Folder>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    let name = check_name(&new_folder.name)?;
+    let parent = target_folder(db, &user, new_folder.parent_id.as_deref()).await?;
+    if let Some(parent) = &parent {
+        check_depth(folder_path(db, parent.clone()).await?.len(), 1)?;
+    }
+
+    let now = Utc::now();
+    let folder = Folder {
+        id: None,
+        owner_id: user.id,
+        name,
+        parent_id: parent.and_then(|p| p.id),
+        team_shares: None,
+        date_created: Some(now),
+        last_modified: Some(now),
+    };
+    let folder_id = match db.create_folder(folder.clone()).await {
+        Ok(inserted) => inserted.inserted_id.as_object_id(),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    Ok(Json(Folder { id: folder_id, ..folder }))
+}
+
+#[put("/<id>", data = "<rename>")]
+pub async fn rename_folder(
+    db: &MongoRepo,
+    id: MongoId,
+    rename: Json<FolderRename>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Folder>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let folder = find_folder(db, &user, &obj_id).await?;
+    let name = check_name(&rename.name)?;
+
+    let now = Utc::now();
+    let set = doc! {"name": &name, "last_modified": to_bson(&now).map_err(|_| Status::InternalServerError)?};
+    if db.update_folder_fields(&obj_id, set, BsonDocument::new()).await.is_err() {
+        return Err(Status::InternalServerError);
+    }
+    Ok(Json(Folder { name, last_modified: Some(now), ..folder }))
+}
+
+// moves a folder with everything in it, a folder can not move into itself or its own subfolders
+#[post("/<id>/move", data = "<destination>")]
+pub async fn move_folder(
+    db: &MongoRepo,
+    id: MongoId,
+    destination: Json<Destination>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Folder>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let folder = find_folder(db, &user, &obj_id).await?;
+    let parent = target_folder(db, &user, destination.folder_id.as_deref()).await?;
+
+    if let Some(parent) = &parent {
+        let path = folder_path(db, parent.clone()).await?;
+        let subtree = db.folder_subtree(folder.clone()).await.map_err(|_| Status::InternalServerError)?;
+        check_move(&obj_id, &path, subtree_height(&subtree))?;
+    }
+
+    let parent_id = parent.and_then(|p| p.id);
+    let now = Utc::now();
+    let mut set = doc! {"last_modified": to_bson(&now).map_err(|_| Status::InternalServerError)?};
+    let mut unset = BsonDocument::new();
+    match parent_id {
+        Some(parent_id) => set.insert("parent_id", parent_id),
+        None => unset.insert("parent_id", ""),
+    };
+    if db.update_folder_fields(&obj_id, set, unset).await.is_err() {
+        return Err(Status::InternalServerError);
+    }
+    Ok(Json(Folder { parent_id, last_modified: Some(now), ..folder }))
+}
+
+// copies a folder, its subfolders and their documents, returns the top of the copy
+#[post("/<id>/copy", data = "<destination>")]
+pub async fn copy_folder(
+    db: &MongoRepo,
+    id: MongoId,
+    destination: Json<Destination>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Folder>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let folder = find_folder(db, &user, &obj_id).await?;
+    let parent = target_folder(db, &user, destination.folder_id.as_deref()).await?;
+    let subtree = match db.folder_subtree(folder).await {
+        Ok(subtree) => subtree,
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    // copying into the folder's own subtree would keep finding the copies
+    if parent.as_ref().is_some_and(|p| subtree.iter().any(|f| f.id == p.id)) {
+        return Err(Status::Conflict);
+    }
+    let parent_depth = match &parent {
+        Some(parent) => folder_path(db, parent.clone()).await?.len(),
+        None => 0,
+    };
+    check_depth(parent_depth, subtree_height(&subtree))?;
+
+    let now = Utc::now();
+    let (folders, copies) = plan_folder_copy(&subtree, parent.and_then(|p| p.id), user.id, now);
+    let originals: Vec<ObjectId> = copies.keys().copied().collect();
+    let documents = db.find_documents(doc! {"folder_id": {"$in": originals}}, 0, 0).await.map_err(|_| Status::InternalServerError)?;
+    let documents: Vec<Document> = documents
+        .iter()
+        .map(|document| copy_of(document, user.id, document.folder_id.and_then(|f| copies.get(&f).copied()), now))
+        .collect();
+    let revisions = documents
+        .iter()
+        .map(|document| Revision::snapshot(document, 1, user.id))
+        .collect::<Option<Vec<Revision>>>()
+        .ok_or(Status::InternalServerError)?;
+
+    let top = folders.first().cloned().ok_or(Status::InternalServerError)?;
+    if db.insert_folder_copies(folders, documents, revisions).await.is_err() {
+        return Err(Status::InternalServerError);
+    }
+    Ok(Json(top))
+}
+
+/**
+ * Deletes a folder. A folder that still holds folders or documents is only
+ * deleted with `recursive=true`, which deletes its subfolders too and moves
+ * its documents to the trash.
+ */
+#[delete("/<id>?<recursive>")]
+pub async fn delete_folder(
+    db: &MongoRepo,
+    id: MongoId,
+    recursive: Option<bool>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<&'static str>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let folder = find_folder(db, &user, &obj_id).await?;
+
+    match db.delete_folder_tree(folder, recursive.unwrap_or(false), user.id).await {
+        Ok(Some(_)) => Ok(Json("Folder successfully deleted!")),
+        Ok(None) => Err(Status::Conflict),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[post("/<id>/move", data = "<destination>")]
+pub async fn move_document(
+    db: &MongoRepo,
+    id: MongoId,
+    destination: Json<Destination>,
+    _auth: jwt::AuthObject,
+) -> Result<Tagged<Json<Document>>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    let document = find_document(db, &user, &id).await?;
+    let folder = target_folder(db, &user, destination.folder_id.as_deref()).await?;
+
+    let mut set = BsonDocument::new();
+    let mut unset = BsonDocument::new();
+    match folder.and_then(|f| f.id) {
+        Some(folder_id) => set.insert("folder_id", folder_id),
+        None => unset.insert("folder_id", ""),
+    };
+    // a move is an edit like any other, it shows up in the history
+    if db.ensure_initial_revision(&document).await.is_err() {
+        return Err(Status::InternalServerError);
+    }
+    let moved = match db.update_document_fields(&id.to_string(), set, unset, Some(document.version())).await {
+        Ok(Some(moved)) => moved,
+        Ok(None) => return Err(Status::Conflict),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    if db.record_revision(&moved, user.id, None).await.is_err() {
+        return Err(Status::InternalServerError);
+    }
+    let version = moved.version();
+    Ok(Tagged(Json(moved), version))
+}
+
+#[post("/<id>/copy", data = "<destination>")]
+pub async fn copy_document(
+    db: &MongoRepo,
+    id: MongoId,
+    destination: Json<Destination>,
+    _auth: jwt::AuthObject,
+) -> Result<Tagged<Json<Document>>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    let document = find_document(db, &user, &id).await?;
+    let folder = target_folder(db, &user, destination.folder_id.as_deref()).await?;
+
+    let copy = copy_document_into(db, &user, &document, folder.and_then(|f| f.id)).await?;
+    let version = copy.version();
+    Ok(Tagged(Json(copy), version))
+}
@@ -0,0 +1,170 @@
+use crate::api::share::{authorize_document, get_principal};
+use crate::helpers::jwt;
+use crate::helpers::mongo_id::MongoId;
+use crate::helpers::password::{hash_password, verify_password, Verification};
+use crate::models::{link::{ShareLink, ShareLinkInfo, SharedDocument}, share::Permission};
+use crate::repository::mongodb_repo::MongoRepo;
+use mongodb::bson::{doc, oid::ObjectId};
+use rand_core::{OsRng, RngCore};
+use rocket::{http::Status, serde::json::Json};
+use serde::{Serialize, Deserialize};
+use chrono::{DateTime, Utc};
+
+// 256 bits, rendered as 64 hex characters
+const TOKEN_BYTES: usize = 32;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewShareLink {
+    password: Option<String>,
+    expires_at: Option<DateTime<Utc>>,
+    max_views: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LinkPassword {
+    password: String,
+}
+
+pub fn new_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/**
+ * Serves the document behind a link token. Unknown and revoked links are
+ * 404, expired and used up ones 410, and a missing or wrong password 401.
+ * Wrong passwords are counted on the link, which locks with 429 once there are
+ * too many. Every successful visit counts against the link's view limit.
+ */
+async fn open_link(db: &MongoRepo, token: &str, password: Option<&str>) -> Result<Json<SharedDocument>, Status> {
+    let link = match db.find_share_link_by_token(token).await {
+        Ok(Some(link)) => link,
+        Ok(None) => return Err(Status::NotFound),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    let now = Utc::now();
+    if link.is_expired(now) || link.is_used_up() {
+        return Err(Status::Gone);
+    }
+    let link_id = link.id.ok_or(Status::NotFound)?;
+    if let Some(password_hash) = &link.password_hash {
+        // a locked link does not even check the password, guessing gets nowhere
+        if link.is_locked(now) {
+            return Err(Status::TooManyRequests);
+        }
+        match password.map(|password| verify_password(password, password_hash)) {
+            Some(Verification::Valid) | Some(Verification::ValidNeedsRehash) => {},
+            Some(Verification::Invalid) => {
+                let recorded = db.record_link_failure(&link_id, now).await.is_ok();
+                return Err(if recorded { Status::Unauthorized } else { Status::InternalServerError });
+            },
+            None => return Err(Status::Unauthorized),
+        }
+    }
+
+    // the visitor carries no organization, the link knows which one the document is in
+    let organization = match link.org_id {
+        Some(org_id) => match db.find_organization(doc! {"_id": org_id}).await {
+            Ok(Some(organization)) => Some(organization),
+            Ok(None) => return Err(Status::NotFound),
+            Err(_) => return Err(Status::InternalServerError),
+        },
+        None => None,
+    };
+    let repo = db.for_organization(organization).await.map_err(|_| Status::InternalServerError)?;
+    let document = match repo.find_document(doc! {"_id": link.document_id}).await {
+        Ok(Some(document)) => document,
+        Ok(None) => return Err(Status::NotFound),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+
+    match db.record_link_view(&link_id).await {
+        Ok(true) => Ok(Json(SharedDocument::from(document))),
+        Ok(false) => Err(Status::Gone),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+// public, no account needed
+#[get("/<token>")]
+pub async fn view_link(db: &MongoRepo, token: &str) -> Result<Json<SharedDocument>, Status> {
+    open_link(db, token, None).await
+}
+
+// links with a password take it in the body so it stays out of URLs and logs
+#[post("/<token>", data = "<password>")]
+pub async fn unlock_link(db: &MongoRepo, token: &str, password: Json<LinkPassword>) -> Result<Json<SharedDocument>, Status> {
+    open_link(db, token, Some(&password.password)).await
+}
+
+#[get("/<id>/links")]
+pub async fn list_links(db: &MongoRepo, id: MongoId, _auth: jwt::AuthObject) -> Result<Json<Vec<ShareLinkInfo>>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    authorize_document(db, &principal, &obj_id, Permission::Owner).await?;
+
+    match db.find_share_links(&obj_id).await {
+        Ok(links) => Ok(Json(links.into_iter().map(ShareLinkInfo::from).collect())),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/**
+ * Creates a public link to a document, only the owner can. The expiry has
+ * to lie in the future and a view limit has to allow at least one view.
+ */
+#[post("/<id>/links", data = "<new_link>")]
+pub async fn create_link(
+    db: &MongoRepo,
+    id: MongoId,
+    new_link: Json<NewShareLink>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<ShareLinkInfo>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    authorize_document(db, &principal, &obj_id, Permission::Owner).await?;
+
+    let data = new_link.into_inner();
+    let now = Utc::now();
+    if data.expires_at.is_some_and(|expires_at| expires_at <= now)
+        || data.max_views.is_some_and(|max_views| max_views < 1)
+        || data.password.as_deref().is_some_and(str::is_empty)
+    {
+        return Err(Status::UnprocessableEntity);
+    }
+
+    let link = ShareLink {
+        id: None,
+        token: new_token(),
+        document_id: obj_id,
+        org_id: None,
+        created_by: principal.user.id,
+        password_hash: data.password.as_deref().map(hash_password),
+        expires_at: data.expires_at,
+        max_views: data.max_views,
+        views: 0,
+        failed_attempts: 0,
+        locked_until: None,
+        date_created: Some(now),
+    };
+    match db.create_share_link(link).await {
+        Ok(link) => Ok(Json(ShareLinkInfo::from(link))),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+// revoked links stop working at once
+#[delete("/<id>/links/<link_id>")]
+pub async fn revoke_link(db: &MongoRepo, id: MongoId, link_id: MongoId, _auth: jwt::AuthObject) -> Result<Json<&'static str>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let link_id = ObjectId::parse_str(link_id.to_string()).map_err(|_| Status::NotFound)?;
+    authorize_document(db, &principal, &obj_id, Permission::Owner).await?;
+
+    match db.delete_share_link(&obj_id, &link_id).await {
+        Ok(deleted) if deleted.deleted_count == 1 => Ok(Json("Link successfully revoked!")),
+        Ok(_) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
@@ -1,3 +1,17 @@
 pub mod auth;
+pub mod comment;
 pub mod document;
-pub mod user;
\ No newline at end of file
+pub mod event;
+pub mod folder;
+pub mod link;
+pub mod organization;
+pub mod revision;
+pub mod scim;
+pub mod search;
+pub mod share;
+pub mod suggestion;
+pub mod tag;
+pub mod team;
+pub mod trash;
+pub mod user;
+pub mod webhook;
\ No newline at end of file
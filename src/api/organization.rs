@@ -0,0 +1,122 @@
+use crate::api::link::new_token;
+use crate::api::user::require_admin;
+use crate::helpers::jwt;
+use crate::helpers::mongo_id::MongoId;
+use crate::helpers::scim::scim_token_hash;
+use crate::models::{organization::Organization, user::User};
+use crate::repository::mongodb_repo::{is_duplicate_key, MongoRepo};
+use mongodb::bson::{doc, oid::ObjectId};
+use rocket::{http::Status, serde::json::Json};
+use serde::{Serialize, Deserialize};
+use chrono::Utc;
+
+// keeps dedicated database names well inside MongoDB's 64 character limit
+const MAX_SLUG_LENGTH: usize = 40;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewOrganization {
+    name: String,
+    slug: String,
+    // keep the organization's data in a database of its own
+    dedicated_database: Option<bool>,
+}
+
+// a freshly issued SCIM token, the only time it is shown
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScimToken {
+    token: String,
+}
+
+fn check_slug(slug: &str) -> Result<String, Status> {
+    let slug = slug.trim().to_lowercase();
+    let valid = !slug.is_empty()
+        && slug.len() <= MAX_SLUG_LENGTH
+        && slug.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+        && !slug.starts_with('-')
+        && !slug.ends_with('-');
+    if !valid {
+        return Err(Status::UnprocessableEntity);
+    }
+    Ok(slug)
+}
+
+// organizations are run by the administrators of the default organization
+async fn require_operator(db: &MongoRepo, auth: &jwt::AuthObject) -> Result<User, Status> {
+    let user = require_admin(db, auth).await?;
+    if db.organization().is_some() {
+        return Err(Status::Forbidden);
+    }
+    Ok(user)
+}
+
+#[get("/")]
+pub async fn list_organizations(db: &MongoRepo, _auth: jwt::AuthObject) -> Result<Json<Vec<Organization>>, Status> {
+    require_operator(db, &_auth).await?;
+
+    match db.find_organizations(doc! {}).await {
+        Ok(organizations) => Ok(Json(organizations.into_iter().map(Organization::without_secrets).collect())),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[get("/<id>")]
+pub async fn get_organization(db: &MongoRepo, id: MongoId, _auth: jwt::AuthObject) -> Result<Json<Organization>, Status> {
+    require_operator(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+
+    match db.find_organization(doc! {"_id": obj_id}).await {
+        Ok(Some(organization)) => Ok(Json(organization.without_secrets())),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/**
+ * Registers a new organization. Its users sign up and log in with the slug
+ * in the X-Organization header; tokens issued to them stay in the organization.
+ */
+#[post("/", data = "<new_organization>")]
+pub async fn create_organization(
+    db: &MongoRepo,
+    new_organization: Json<NewOrganization>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Organization>, Status> {
+    require_operator(db, &_auth).await?;
+    let data = new_organization.into_inner();
+    let name = data.name.trim().to_string();
+    if name.is_empty() {
+        return Err(Status::UnprocessableEntity);
+    }
+    let slug = check_slug(&data.slug)?;
+
+    match db.find_organization(doc! {"slug": &slug}).await {
+        Ok(None) => {},
+        Ok(Some(_)) => return Err(Status::Conflict),
+        Err(_) => return Err(Status::InternalServerError),
+    }
+
+    let organization = Organization { id: None, name, slug, database: None, scim_token_hash: None, date_created: Some(Utc::now()) };
+    match db.create_organization(organization, data.dedicated_database.unwrap_or(false)).await {
+        Ok(organization) => Ok(Json(organization)),
+        // the slug was taken in the meantime, the unique index caught it
+        Err(e) if e.downcast_ref::<mongodb::error::Error>().is_some_and(is_duplicate_key) => Err(Status::Conflict),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/**
+ * Issues the organization's SCIM token for its identity provider, replacing
+ * the previous one. Only a hash is stored, so the token is shown just once.
+ */
+#[post("/<id>/scim-token")]
+pub async fn rotate_scim_token(db: &MongoRepo, id: MongoId, _auth: jwt::AuthObject) -> Result<Json<ScimToken>, Status> {
+    require_operator(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+
+    let token = new_token();
+    match db.set_scim_token_hash(&obj_id, scim_token_hash(&token)).await {
+        Ok(true) => Ok(Json(ScimToken { token })),
+        Ok(false) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
@@ -0,0 +1,149 @@
+use crate::api::document::{get_author_id, precondition_failed, DocumentError};
+use crate::api::share::{authorize_document, get_principal};
+use crate::helpers::diff::{diff_hunks, unified_diff};
+use crate::helpers::etag::{RequiredIfMatch, Tagged};
+use crate::helpers::jwt;
+use crate::helpers::mongo_id::MongoId;
+use crate::models::{diff::{DocumentDiff, Granularity}, document::Document, revision::Revision, share::Permission};
+use crate::repository::mongodb_repo::MongoRepo;
+use mongodb::bson::{doc, oid::ObjectId};
+use rocket::{http::Status, serde::json::Json};
+
+const DEFAULT_DIFF_CONTEXT: usize = 3;
+
+#[derive(Responder)]
+pub enum DiffResponse {
+    Json(Json<DocumentDiff>),
+    Text(String),
+}
+
+// loads the document if the caller holds at least `needed` on it
+async fn find_document(db: &MongoRepo, id: &MongoId, auth: &jwt::AuthObject, needed: Permission) -> Result<Document, Status> {
+    let principal = get_principal(db, auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    authorize_document(db, &principal, &obj_id, needed).await
+}
+
+pub async fn find_revision(db: &MongoRepo, document_id: &ObjectId, number: i64) -> Result<Revision, Status> {
+    match db.get_revision(document_id, number).await {
+        Ok(Some(revision)) => Ok(revision),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[get("/<id>/revisions")]
+pub async fn get_revisions(db: &MongoRepo, id: MongoId, _auth: jwt::AuthObject) -> Result<Json<Vec<Revision>>, Status> {
+    let document = find_document(db, &id, &_auth, Permission::Viewer).await?;
+    let document_id = document.id.ok_or(Status::NotFound)?;
+
+    match db.get_revisions(&document_id).await {
+        Ok(revisions) => Ok(Json(revisions)),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[get("/<id>/revisions/<rev>")]
+pub async fn get_revision(db: &MongoRepo, id: MongoId, rev: i64, _auth: jwt::AuthObject) -> Result<Json<Revision>, Status> {
+    let document = find_document(db, &id, &_auth, Permission::Viewer).await?;
+    let document_id = document.id.ok_or(Status::NotFound)?;
+
+    find_revision(db, &document_id, rev).await.map(Json)
+}
+
+#[post("/<id>/revisions/<rev>/restore")]
+pub async fn restore_revision(
+    db: &MongoRepo,
+    id: MongoId,
+    rev: i64,
+    if_match: RequiredIfMatch,
+    _auth: jwt::AuthObject,
+) -> Result<Tagged<Json<Document>>, DocumentError> {
+    let current = find_document(db, &id, &_auth, Permission::Editor).await?;
+    let document_id = current.id.ok_or(Status::NotFound)?;
+    let revision = find_revision(db, &document_id, rev).await?;
+
+    let author_id = get_author_id(db, &_auth).await;
+    let restored = revision.restore(author_id);
+    let updated = match db.update_document(&id.to_string(), restored, if_match.0).await {
+        Ok(updated) => updated,
+        Err(_) => return Err(Status::InternalServerError.into()),
+    };
+    let document = match updated {
+        Some(document) => document,
+        None => return Err(precondition_failed(db, &document_id).await),
+    };
+
+    // restoring never rewrites history, it adds a revision on top
+    if db.record_revision(&document, author_id, Some(rev)).await.is_err() {
+        return Err(Status::InternalServerError.into());
+    }
+
+    let version = document.version();
+    Ok(Tagged(Json(document), version))
+}
+
+// compares revision `from` with revision `to`, or with the current document when `to` is left out
+#[get("/<id>/diff?<from>&<to>&<granularity>&<format>&<context>")]
+#[allow(clippy::too_many_arguments)]
+pub async fn diff_revisions(
+    db: &MongoRepo,
+    id: MongoId,
+    from: i64,
+    to: Option<i64>,
+    granularity: Option<&str>,
+    format: Option<&str>,
+    context: Option<usize>,
+    _auth: jwt::AuthObject,
+) -> Result<DiffResponse, Status> {
+    let document = find_document(db, &id, &_auth, Permission::Viewer).await?;
+    let document_id = document.id.ok_or(Status::NotFound)?;
+    let old = find_revision(db, &document_id, from).await?;
+    let new_content = match to {
+        Some(number) => find_revision(db, &document_id, number).await?.content,
+        None => document.content,
+    };
+
+    let old_content = old.content.unwrap_or_default();
+    let new_content = new_content.unwrap_or_default();
+    revision_diff(document_id.to_hex(), from, to, &old_content, &new_content, granularity, format, context)
+}
+
+// renders the diff of two revision contents as asked for, `to` None stands for the current document
+#[allow(clippy::too_many_arguments)]
+pub fn revision_diff(
+    document_id: String,
+    from: i64,
+    to: Option<i64>,
+    old_content: &str,
+    new_content: &str,
+    granularity: Option<&str>,
+    format: Option<&str>,
+    context: Option<usize>,
+) -> Result<DiffResponse, Status> {
+    let granularity = match granularity.unwrap_or("line") {
+        "line" => Granularity::Line,
+        "word" => Granularity::Word,
+        _ => return Err(Status::BadRequest),
+    };
+    let context = context.unwrap_or(DEFAULT_DIFF_CONTEXT);
+
+    match format.unwrap_or("json") {
+        "json" => Ok(DiffResponse::Json(Json(DocumentDiff {
+            document_id,
+            from,
+            to,
+            granularity,
+            hunks: diff_hunks(old_content, new_content, granularity, context),
+        }))),
+        "unified" => {
+            let from_label = format!("revision {}", from);
+            let to_label = match to {
+                Some(number) => format!("revision {}", number),
+                None => "current".to_string(),
+            };
+            Ok(DiffResponse::Text(unified_diff(old_content, new_content, granularity, context, &from_label, &to_label)))
+        },
+        _ => Err(Status::BadRequest),
+    }
+}
@@ -0,0 +1,417 @@
+use crate::helpers::mongo_id::MongoId;
+use crate::api::user::{find_user_by_id, password_fields};
+use crate::helpers::password::{hash_password, unusable_password};
+use crate::helpers::password_policy::PasswordPolicy;
+use crate::helpers::scim::{filter_to_document, group_filter_to_document, patch_group, patch_to_changes, ScimAuth};
+use crate::models::scim::{ScimError, ScimGroup, ScimListResponse, ScimPatchRequest, ScimUser, LIST_RESPONSE_SCHEMA, PATCH_OP_SCHEMA};
+use crate::models::team::{Team, TeamMember, TeamRole};
+use crate::{models::user::{DeletionStrategy, User}, repository::mongodb_repo::MongoRepo};
+use mongodb::bson::{doc, oid::ObjectId, to_bson, Document};
+use rocket::{http::Status, response::status::Created, serde::json::Json, Request};
+use chrono::Utc;
+
+const DEFAULT_PAGE_SIZE: i64 = 100;
+
+#[derive(Debug, FromForm)]
+pub struct ScimListQuery {
+    filter: Option<String>,
+    #[field(name = "startIndex")]
+    start_index: Option<u64>,
+    count: Option<i64>,
+}
+
+fn internal_error() -> ScimError {
+    ScimError::from(Status::InternalServerError)
+}
+
+fn bad_request(scim_type: &str, detail: impl Into<String>) -> ScimError {
+    ScimError::new(Status::BadRequest.code, Some(scim_type), detail)
+}
+
+fn not_found(resource: &str, id: &MongoId) -> ScimError {
+    ScimError::new(Status::NotFound.code, None, format!("{} {} not found", resource, id.to_string()))
+}
+
+fn taken(detail: &str) -> ScimError {
+    ScimError::new(Status::Conflict.code, Some("uniqueness"), detail)
+}
+
+// answers guard and body failures under /scim/v2 with error bodies too
+#[catch(default)]
+pub fn scim_error(status: Status, _req: &Request) -> ScimError {
+    ScimError::from(status)
+}
+
+async fn find_scim_user(db: &MongoRepo, id: &MongoId) -> Result<ScimUser, ScimError> {
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| not_found("User", id))?;
+    match db.find_user(doc! {"_id": obj_id}).await {
+        Ok(Some(user)) => Ok(ScimUser::from(user)),
+        Ok(None) => Err(not_found("User", id)),
+        Err(_) => Err(internal_error()),
+    }
+}
+
+async fn username_taken(db: &MongoRepo, username: &str, except: Option<&MongoId>) -> Result<bool, ScimError> {
+    let mut filter = doc! {"username": username};
+    if let Some(id) = except {
+        let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| not_found("User", id))?;
+        filter.insert("_id", doc! {"$ne": obj_id});
+    }
+    match db.count_users(filter).await {
+        Ok(count) => Ok(count > 0),
+        Err(_) => Err(internal_error()),
+    }
+}
+
+// SCIM pages are 1-based
+fn page(query: &ScimListQuery) -> (u64, i64) {
+    (query.start_index.unwrap_or(1).max(1), query.count.unwrap_or(DEFAULT_PAGE_SIZE).max(0))
+}
+
+fn list_response<T>(total_results: u64, start_index: u64, resources: Vec<T>) -> Json<ScimListResponse<T>> {
+    Json(ScimListResponse {
+        schemas: vec![LIST_RESPONSE_SCHEMA.to_string()],
+        total_results,
+        start_index,
+        items_per_page: resources.len() as u64,
+        resources,
+    })
+}
+
+#[get("/Users?<query..>")]
+pub async fn scim_list_users(
+    query: ScimListQuery,
+    scim: ScimAuth<'_>,
+) -> Result<Json<ScimListResponse<ScimUser>>, ScimError> {
+    let db = scim.db;
+    let filter = match &query.filter {
+        Some(f) if !f.trim().is_empty() => filter_to_document(f).map_err(|e| bad_request("invalidFilter", e))?,
+        _ => Document::new(),
+    };
+    let (start_index, count) = page(&query);
+
+    let total_results = db.count_users(filter.clone()).await.map_err(|_| internal_error())?;
+    let users = if count == 0 {
+        Vec::new()
+    } else {
+        db.find_users(filter, start_index - 1, count).await.map_err(|_| internal_error())?
+    };
+
+    Ok(list_response(total_results, start_index, users.into_iter().map(ScimUser::from).collect()))
+}
+
+#[get("/Users/<id>")]
+pub async fn scim_get_user(id: MongoId, scim: ScimAuth<'_>) -> Result<Json<ScimUser>, ScimError> {
+    let db = scim.db;
+    find_scim_user(db, &id).await.map(Json)
+}
+
+#[post("/Users", data = "<new_user>")]
+pub async fn scim_create_user(
+    new_user: Json<ScimUser>,
+    scim: ScimAuth<'_>,
+) -> Result<Created<Json<ScimUser>>, ScimError> {
+    let db = scim.db;
+    let data = new_user.into_inner();
+    let username = match &data.user_name {
+        Some(u) if !u.is_empty() => u.clone(),
+        _ => return Err(bad_request("invalidValue", "userName is required")),
+    };
+    if username_taken(db, &username, None).await? {
+        return Err(taken("userName is already taken"));
+    }
+
+    let email = data.primary_email();
+    let role = data.role();
+    let (firstname, lastname) = match data.name {
+        Some(name) => (name.given_name, name.family_name),
+        None => (None, None),
+    };
+    let password = match &data.password {
+        Some(p) if PasswordPolicy::get().validate(p, &[]).is_err() => return Err(bad_request("invalidValue", "password does not meet the password policy")),
+        Some(p) => hash_password(p),
+        None => unusable_password(),
+    };
+
+    let usr = User {
+        id: None,
+        firstname,
+        lastname,
+        username: Some(username),
+        email,
+        password,
+        role,
+        active: data.active,
+        password_history: None,
+        password_changed_at: Some(Utc::now()),
+        deleted_at: None,
+        deletion_strategy: None,
+    };
+
+    let inserted = db.create_user(usr).await.map_err(|_| internal_error())?;
+    let obj_id = inserted.inserted_id.as_object_id().ok_or_else(internal_error)?;
+    match db.find_user(doc! {"_id": obj_id}).await {
+        Ok(Some(user)) => {
+            let location = format!("/scim/v2/Users/{}", obj_id.to_hex());
+            Ok(Created::new(location).body(Json(ScimUser::from(user))))
+        },
+        _ => Err(internal_error()),
+    }
+}
+
+// writes every change of a replace or patch, the password included, in one update
+async fn write_user(db: &MongoRepo, id: &MongoId, set: Document, unset: Document, password: Option<&str>) -> Result<Json<ScimUser>, ScimError> {
+    let mut set = set;
+    if let Some(password) = password {
+        let user = find_user_by_id(db, id).await?;
+        let fields = password_fields(&user, password).map_err(|_| bad_request("invalidValue", "password does not meet the password policy"))?;
+        set.extend(fields);
+    }
+
+    let matched = db.update_user_fields(&id.to_string(), set, unset).await.map_err(|_| internal_error())?;
+    if matched == 0 {
+        return Err(not_found("User", id));
+    }
+
+    find_scim_user(db, id).await.map(Json)
+}
+
+#[put("/Users/<id>", data = "<new_user>")]
+pub async fn scim_replace_user(
+    id: MongoId,
+    new_user: Json<ScimUser>,
+    scim: ScimAuth<'_>,
+) -> Result<Json<ScimUser>, ScimError> {
+    let db = scim.db;
+    let data = new_user.into_inner();
+    let username = match &data.user_name {
+        Some(u) if !u.is_empty() => u.clone(),
+        _ => return Err(bad_request("invalidValue", "userName is required")),
+    };
+    if username_taken(db, &username, Some(&id)).await? {
+        return Err(taken("userName is already taken"));
+    }
+
+    // a replace clears every attribute the provider left out
+    let mut set = doc! {"username": username};
+    let mut unset = Document::new();
+    let name = data.name.as_ref();
+    let attributes = [
+        ("firstname", name.and_then(|n| n.given_name.clone())),
+        ("lastname", name.and_then(|n| n.family_name.clone())),
+        ("email", data.primary_email()),
+    ];
+    for (field, value) in attributes {
+        match value {
+            Some(v) => { set.insert(field, v); },
+            None => { unset.insert(field, ""); },
+        }
+    }
+    match data.role() {
+        Some(role) => { set.insert("role", to_bson(&role).unwrap()); },
+        None => { unset.insert("role", ""); },
+    }
+    set.insert("active", data.active.unwrap_or(true));
+
+    write_user(db, &id, set, unset, data.password.as_deref()).await
+}
+
+#[patch("/Users/<id>", data = "<patch>")]
+pub async fn scim_patch_user(
+    id: MongoId,
+    patch: Json<ScimPatchRequest>,
+    scim: ScimAuth<'_>,
+) -> Result<Json<ScimUser>, ScimError> {
+    let db = scim.db;
+    if !patch.schemas.iter().any(|s| s == PATCH_OP_SCHEMA) {
+        return Err(bad_request("invalidSyntax", "A PatchOp request is expected"));
+    }
+    let changes = patch_to_changes(&patch.operations).map_err(|e| bad_request("invalidValue", e))?;
+    if let Ok(username) = changes.set.get_str("username") {
+        if username_taken(db, username, Some(&id)).await? {
+            return Err(taken("userName is already taken"));
+        }
+    }
+
+    write_user(db, &id, changes.set, changes.unset, changes.password.as_deref()).await
+}
+
+#[delete("/Users/<id>")]
+pub async fn scim_delete_user(id: MongoId, scim: ScimAuth<'_>) -> Result<Status, ScimError> {
+    let db = scim.db;
+    // the provider can not pick a strategy, so documents of deprovisioned users are archived
+    match db.soft_delete_user(&id.to_string(), &DeletionStrategy::Archive).await {
+        Ok(res) if res.modified_count == 1 => Ok(Status::NoContent),
+        Ok(_) => Err(not_found("User", &id)),
+        Err(_) => Err(internal_error()),
+    }
+}
+
+
+/**
+ * Groups
+*/
+
+async fn find_team(db: &MongoRepo, id: &MongoId) -> Result<Team, ScimError> {
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| not_found("Group", id))?;
+    match db.find_team(doc! {"_id": obj_id}).await {
+        Ok(Some(team)) => Ok(team),
+        Ok(None) => Err(not_found("Group", id)),
+        Err(_) => Err(internal_error()),
+    }
+}
+
+fn display_name(group: &ScimGroup) -> Result<String, ScimError> {
+    match group.display_name.as_deref().map(str::trim) {
+        Some(name) if !name.is_empty() => Ok(name.to_string()),
+        _ => Err(bad_request("invalidValue", "displayName is required")),
+    }
+}
+
+fn group_member_ids(group: &ScimGroup) -> Result<Vec<ObjectId>, ScimError> {
+    let mut ids: Vec<ObjectId> = Vec::new();
+    for member in &group.members {
+        let id = ObjectId::parse_str(&member.value).map_err(|_| bad_request("invalidValue", "Members need a valid value"))?;
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    Ok(ids)
+}
+
+// members have to be users of the organization
+async fn check_members(db: &MongoRepo, team: &Team) -> Result<(), ScimError> {
+    let ids: Vec<ObjectId> = team.members.iter().map(|m| m.user_id).collect();
+    if ids.is_empty() {
+        return Ok(());
+    }
+    let found = db.count_users(doc! {"_id": {"$in": &ids}}).await.map_err(|_| internal_error())?;
+    if found != ids.len() as u64 {
+        return Err(bad_request("invalidValue", "Members have to be users of the organization"));
+    }
+    Ok(())
+}
+
+// replaces the team's name and members in one update
+async fn write_team(db: &MongoRepo, team: Team) -> Result<Json<ScimGroup>, ScimError> {
+    let id = team.id.ok_or_else(internal_error)?;
+    check_members(db, &team).await?;
+    let members = to_bson(&team.members).map_err(|_| internal_error())?;
+    let update = doc! {"$set": {
+        "name": &team.name,
+        "members": members,
+        "last_modified": to_bson(&Utc::now()).map_err(|_| internal_error())?,
+    }};
+    match db.update_team(&id, update).await {
+        Ok(result) if result.matched_count == 1 => {},
+        Ok(_) => return Err(ScimError::new(Status::NotFound.code, None, "Group not found")),
+        Err(_) => return Err(internal_error()),
+    }
+    match db.find_team(doc! {"_id": id}).await {
+        Ok(Some(team)) => Ok(Json(ScimGroup::from(team))),
+        _ => Err(internal_error()),
+    }
+}
+
+#[get("/Groups?<query..>")]
+pub async fn scim_list_groups(
+    query: ScimListQuery,
+    scim: ScimAuth<'_>,
+) -> Result<Json<ScimListResponse<ScimGroup>>, ScimError> {
+    let db = scim.db;
+    let filter = match &query.filter {
+        Some(f) if !f.trim().is_empty() => group_filter_to_document(f).map_err(|e| bad_request("invalidFilter", e))?,
+        _ => Document::new(),
+    };
+    let (start_index, count) = page(&query);
+
+    let total_results = db.count_teams(filter.clone()).await.map_err(|_| internal_error())?;
+    let teams = if count == 0 {
+        Vec::new()
+    } else {
+        db.find_teams_page(filter, start_index - 1, count).await.map_err(|_| internal_error())?
+    };
+
+    Ok(list_response(total_results, start_index, teams.into_iter().map(ScimGroup::from).collect()))
+}
+
+#[get("/Groups/<id>")]
+pub async fn scim_get_group(id: MongoId, scim: ScimAuth<'_>) -> Result<Json<ScimGroup>, ScimError> {
+    let db = scim.db;
+    find_team(db, &id).await.map(|team| Json(ScimGroup::from(team)))
+}
+
+// groups the provider creates start out without team admins, administrators manage them
+#[post("/Groups", data = "<new_group>")]
+pub async fn scim_create_group(
+    new_group: Json<ScimGroup>,
+    scim: ScimAuth<'_>,
+) -> Result<Created<Json<ScimGroup>>, ScimError> {
+    let db = scim.db;
+    let data = new_group.into_inner();
+    let now = Utc::now();
+    let team = Team {
+        id: None,
+        name: display_name(&data)?,
+        members: group_member_ids(&data)?.into_iter().map(|user_id| TeamMember { user_id, role: TeamRole::Member }).collect(),
+        date_created: Some(now),
+        last_modified: Some(now),
+    };
+    check_members(db, &team).await?;
+
+    let inserted = db.create_team(team).await.map_err(|_| internal_error())?;
+    let obj_id = inserted.inserted_id.as_object_id().ok_or_else(internal_error)?;
+    match db.find_team(doc! {"_id": obj_id}).await {
+        Ok(Some(team)) => {
+            let location = format!("/scim/v2/Groups/{}", obj_id.to_hex());
+            Ok(Created::new(location).body(Json(ScimGroup::from(team))))
+        },
+        _ => Err(internal_error()),
+    }
+}
+
+#[put("/Groups/<id>", data = "<new_group>")]
+pub async fn scim_replace_group(
+    id: MongoId,
+    new_group: Json<ScimGroup>,
+    scim: ScimAuth<'_>,
+) -> Result<Json<ScimGroup>, ScimError> {
+    let db = scim.db;
+    let data = new_group.into_inner();
+    let mut team = find_team(db, &id).await?;
+    team.name = display_name(&data)?;
+    let previous = std::mem::take(&mut team.members);
+    for user_id in group_member_ids(&data)? {
+        let role = previous.iter().find(|m| m.user_id == user_id).map_or(TeamRole::Member, |m| m.role);
+        team.members.push(TeamMember { user_id, role });
+    }
+
+    write_team(db, team).await
+}
+
+#[patch("/Groups/<id>", data = "<patch>")]
+pub async fn scim_patch_group(
+    id: MongoId,
+    patch: Json<ScimPatchRequest>,
+    scim: ScimAuth<'_>,
+) -> Result<Json<ScimGroup>, ScimError> {
+    let db = scim.db;
+    if !patch.schemas.iter().any(|s| s == PATCH_OP_SCHEMA) {
+        return Err(bad_request("invalidSyntax", "A PatchOp request is expected"));
+    }
+    let team = find_team(db, &id).await?;
+    let patched = patch_group(&team, &patch.operations).map_err(|e| bad_request("invalidValue", e))?;
+
+    write_team(db, patched).await
+}
+
+#[delete("/Groups/<id>")]
+pub async fn scim_delete_group(id: MongoId, scim: ScimAuth<'_>) -> Result<Status, ScimError> {
+    let db = scim.db;
+    let team = find_team(db, &id).await?;
+    let team_id = team.id.ok_or_else(internal_error)?;
+    match db.delete_team(&team_id).await {
+        Ok(_) => Ok(Status::NoContent),
+        Err(_) => Err(internal_error()),
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+
+use crate::api::document::readable_filter;
+use crate::api::share::get_principal;
+use crate::api::tag::normalize_tags;
+use crate::api::user::require_admin;
+use crate::helpers::jwt;
+use crate::helpers::search::{parse_query, snippet, SearchQuery};
+use crate::models::{document::Document, search::{Facets, SearchHit}, share::Principal};
+use crate::repository::mongodb_repo::MongoRepo;
+use crate::search::index::{IndexQuery, IndexResults, SearchIndex};
+use mongodb::bson::{doc, oid::ObjectId, Document as BsonDocument};
+use rocket::{http::Status, serde::json::Json};
+
+const DEFAULT_LIMIT: i64 = 20;
+const MAX_LIMIT: i64 = 100;
+const SNIPPET_RADIUS: usize = 12;
+
+#[derive(Debug, FromForm)]
+pub struct SearchParams {
+    q: String,
+    skip: Option<u64>,
+    limit: Option<i64>,
+    // tolerate typos, only the embedded engine supports it
+    fuzzy: Option<bool>,
+    owner: Option<String>,
+    // repeatable, documents need every listed tag
+    tag: Vec<String>,
+}
+
+impl SearchParams {
+    fn owner_id(&self) -> Result<Option<ObjectId>, Status> {
+        match &self.owner {
+            Some(owner) => ObjectId::parse_str(owner).map(Some).map_err(|_| Status::BadRequest),
+            None => Ok(None),
+        }
+    }
+
+    // tags are stored normalized, so they are looked for that way
+    fn tags(&self) -> Result<Vec<String>, Status> {
+        normalize_tags(self.tag.clone())
+    }
+}
+
+// the MongoDB filter for a search, limited to what `principal` can read
+fn search_filter(query: &SearchQuery, params: &SearchParams, principal: &Principal) -> Result<BsonDocument, Status> {
+    let mut clauses = vec![query.to_filter(), readable_filter(principal)];
+    if let Some(owner_id) = params.owner_id()? {
+        clauses.push(doc! {"owner_id": owner_id});
+    }
+    let tags = params.tags()?;
+    if !tags.is_empty() {
+        clauses.push(doc! {"tags": {"$all": tags}});
+    }
+    Ok(doc! {"$and": clauses})
+}
+
+fn search_index(index: &SearchIndex, params: &SearchParams, principal: &Principal) -> Result<IndexResults, Status> {
+    let readable_by = if principal.is_admin() { None } else { Some(principal.user.id.ok_or(Status::Unauthorized)?) };
+    Ok(index.search(&IndexQuery {
+        text: &params.q,
+        fuzzy: params.fuzzy.unwrap_or(false),
+        readable_by,
+        readable_teams: principal.team_ids.clone(),
+        readable_folders: principal.folder_grants.keys().copied().collect(),
+        owner: params.owner_id()?,
+        tags: params.tags()?,
+    }))
+}
+
+fn search_hit(document: Document, score: Option<f64>, query: &SearchQuery) -> Option<SearchHit> {
+    Some(SearchHit {
+        id: document.id?.to_hex(),
+        snippet: snippet(document.content.as_deref().unwrap_or(""), query, SNIPPET_RADIUS),
+        title: document.title,
+        score,
+        last_modified: document.last_modified,
+    })
+}
+
+/**
+ * Searches the title and content of the documents the caller can read.
+ * `q` takes plain words, "quoted phrases" and prefix* terms; `owner` and
+ * `tag` narrow the results down.
+ */
+#[get("/search?<params..>")]
+pub async fn search_documents(
+    db: &MongoRepo,
+    params: SearchParams,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Vec<SearchHit>>, Status> {
+    let query = parse_query(&params.q);
+    if query.is_empty() {
+        return Err(Status::BadRequest);
+    }
+    let skip = params.skip.unwrap_or(0);
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let principal = get_principal(db, &_auth).await?;
+
+    let index = match db.search_index() {
+        Some(index) => index,
+        None => {
+            let filter = search_filter(&query, &params, &principal)?;
+            let found = match db.search_documents(filter, query.uses_text_index(), skip, limit).await {
+                Ok(found) => found,
+                Err(_) => return Err(Status::InternalServerError),
+            };
+            return Ok(Json(found.into_iter().filter_map(|(document, score)| search_hit(document, score, &query)).collect()));
+        },
+    };
+
+    // the index only knows ids and scores, the page itself is read from the database
+    let hits: Vec<(ObjectId, f64)> = search_index(index, &params, &principal)?
+        .hits
+        .into_iter()
+        .skip(skip as usize)
+        .take(limit as usize)
+        .collect();
+    let ids: Vec<ObjectId> = hits.iter().map(|(id, _)| *id).collect();
+    let found = match db.search_documents(doc! {"_id": {"$in": ids}}, false, 0, limit).await {
+        Ok(found) => found,
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    let mut documents: HashMap<ObjectId, Document> = found
+        .into_iter()
+        .filter_map(|(document, _)| Some((document.id?, document)))
+        .collect();
+
+    Ok(Json(hits
+        .into_iter()
+        .filter_map(|(id, score)| search_hit(documents.remove(&id)?, Some(score), &query))
+        .collect()))
+}
+
+// how the results of a search spread over owners and tags
+#[get("/search/facets?<params..>")]
+pub async fn search_facets(
+    db: &MongoRepo,
+    params: SearchParams,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Facets>, Status> {
+    let query = parse_query(&params.q);
+    if query.is_empty() {
+        return Err(Status::BadRequest);
+    }
+    let principal = get_principal(db, &_auth).await?;
+
+    if let Some(index) = db.search_index() {
+        return search_index(index, &params, &principal).map(|results| Json(results.facets));
+    }
+    let filter = search_filter(&query, &params, &principal)?;
+    match db.document_facets(filter).await {
+        Ok(facets) => Ok(Json(facets)),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[post("/search/rebuild")]
+pub async fn rebuild_search_index(db: &MongoRepo, _auth: jwt::AuthObject) -> Result<Json<String>, Status> {
+    require_admin(db, &_auth).await?;
+    // there is nothing to rebuild with the MongoDB text index
+    if db.search_index().is_none() {
+        return Err(Status::NotFound);
+    }
+
+    match db.rebuild_search_index().await {
+        Ok(count) => Ok(Json(format!("Search index rebuilt with {} documents", count))),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
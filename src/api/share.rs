@@ -0,0 +1,245 @@
+use crate::api::folder::find_folder;
+use crate::api::user::get_auth_user;
+use crate::helpers::jwt;
+use crate::helpers::mongo_id::MongoId;
+use crate::models::{document::Document, folder::Folder, share::{Permission, Principal, Share, ShareGrant, TeamShare}};
+use crate::repository::mongodb_repo::MongoRepo;
+use mongodb::bson::{doc, oid::ObjectId, Document as BsonDocument};
+use rocket::{http::Status, serde::json::Json};
+
+const DEFAULT_LIST_LIMIT: i64 = 50;
+const MAX_LIST_LIMIT: i64 = 200;
+
+// the caller with the teams they are in and the folders granted to those teams
+pub async fn get_principal(db: &MongoRepo, auth: &jwt::AuthObject) -> Result<Principal, Status> {
+    let user = get_auth_user(db, auth).await?;
+    let user_id = user.id.ok_or(Status::Unauthorized)?;
+    let team_ids = db.team_ids_of(&user_id).await.map_err(|_| Status::InternalServerError)?;
+    let folder_grants = db.team_folder_grants(&team_ids).await.map_err(|_| Status::InternalServerError)?;
+    Ok(Principal { user, team_ids, folder_grants })
+}
+
+// documents shared with the principal directly, through one of their teams or through a folder
+pub fn shared_filter(principal: &Principal) -> BsonDocument {
+    let folders: Vec<ObjectId> = principal.folder_grants.keys().copied().collect();
+    doc! {"$or": [
+        {"shares.user_id": principal.user.id},
+        {"team_shares.team_id": {"$in": &principal.team_ids}},
+        {"folder_id": {"$in": folders}},
+    ]}
+}
+
+/**
+ * Loads a document for `principal` if they hold at least `needed` on it. Users
+ * who can not read the document at all get a 404 so they do not learn it exists.
+ */
+pub async fn authorize_document(db: &MongoRepo, principal: &Principal, id: &ObjectId, needed: Permission) -> Result<Document, Status> {
+    let document = match db.find_document(doc! {"_id": id}).await {
+        Ok(Some(document)) => document,
+        Ok(None) => return Err(Status::NotFound),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    match document.permission_for(principal) {
+        Some(permission) if permission >= needed => Ok(document),
+        Some(_) => Err(Status::Forbidden),
+        None => Err(Status::NotFound),
+    }
+}
+
+fn shares_of(document: Document) -> Json<Vec<Share>> {
+    Json(document.shares.unwrap_or_default())
+}
+
+// a grant can name any permission short of ownership
+fn check_grant(grant: &ShareGrant) -> Result<(), Status> {
+    match grant.permission {
+        Permission::Owner => Err(Status::UnprocessableEntity),
+        _ => Ok(()),
+    }
+}
+
+async fn find_team_id(db: &MongoRepo, team_id: &MongoId) -> Result<ObjectId, Status> {
+    let team_id = ObjectId::parse_str(team_id.to_string()).map_err(|_| Status::UnprocessableEntity)?;
+    match db.find_team(doc! {"_id": team_id}).await {
+        Ok(Some(_)) => Ok(team_id),
+        Ok(None) => Err(Status::UnprocessableEntity),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+// documents other users shared with the caller or their teams, most recently modified first
+#[get("/shared?<skip>&<limit>")]
+pub async fn shared_with_me(
+    db: &MongoRepo,
+    skip: Option<u64>,
+    limit: Option<i64>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Vec<Document>>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let limit = limit.unwrap_or(DEFAULT_LIST_LIMIT).clamp(1, MAX_LIST_LIMIT);
+    let filter = doc! {"$and": [{"owner_id": {"$ne": principal.user.id}}, shared_filter(&principal)]};
+
+    match db.find_documents(filter, skip.unwrap_or(0), limit).await {
+        Ok(documents) => Ok(Json(documents)),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[get("/<id>/shares")]
+pub async fn list_shares(db: &MongoRepo, id: MongoId, _auth: jwt::AuthObject) -> Result<Json<Vec<Share>>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+
+    authorize_document(db, &principal, &obj_id, Permission::Viewer).await.map(shares_of)
+}
+
+/**
+ * Shares a document with another active user as viewer, commenter or editor.
+ * Sharing again with the same user changes their permission. Only the owner
+ * (or an administrator) can share.
+ */
+#[put("/<id>/shares/<user_id>", data = "<grant>")]
+pub async fn share_document(
+    db: &MongoRepo,
+    id: MongoId,
+    user_id: MongoId,
+    grant: Json<ShareGrant>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Vec<Share>>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let document = authorize_document(db, &principal, &obj_id, Permission::Owner).await?;
+    check_grant(&grant)?;
+    let grantee = ObjectId::parse_str(user_id.to_string()).map_err(|_| Status::UnprocessableEntity)?;
+    if document.owner_id == Some(grantee) {
+        return Err(Status::UnprocessableEntity);
+    }
+    match db.find_user(doc! {"_id": grantee}).await {
+        Ok(Some(grantee)) if grantee.is_active() => {},
+        Ok(_) => return Err(Status::UnprocessableEntity),
+        Err(_) => return Err(Status::InternalServerError),
+    }
+
+    if db.share_document(&obj_id, &grantee, grant.permission).await.is_err() {
+        return Err(Status::InternalServerError);
+    }
+    match db.find_document(doc! {"_id": obj_id}).await {
+        Ok(Some(document)) => Ok(shares_of(document)),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+// the owner can take a share back and users can drop documents shared with them
+#[delete("/<id>/shares/<user_id>")]
+pub async fn unshare_document(
+    db: &MongoRepo,
+    id: MongoId,
+    user_id: MongoId,
+    _auth: jwt::AuthObject,
+) -> Result<Json<&'static str>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let grantee = ObjectId::parse_str(user_id.to_string()).map_err(|_| Status::NotFound)?;
+    let needed = if principal.user.id == Some(grantee) { Permission::Viewer } else { Permission::Owner };
+    let document = authorize_document(db, &principal, &obj_id, needed).await?;
+
+    if !document.shares.iter().flatten().any(|share| share.user_id == grantee) {
+        return Err(Status::NotFound);
+    }
+    match db.unshare_document(&obj_id, &grantee).await {
+        Ok(_) => Ok(Json("Share successfully removed!")),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+// grants every member of a team a permission on a document, only the owner can do so
+#[put("/<id>/teams/<team_id>", data = "<grant>")]
+pub async fn share_document_with_team(
+    db: &MongoRepo,
+    id: MongoId,
+    team_id: MongoId,
+    grant: Json<ShareGrant>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Vec<TeamShare>>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    authorize_document(db, &principal, &obj_id, Permission::Owner).await?;
+    check_grant(&grant)?;
+    let team_id = find_team_id(db, &team_id).await?;
+
+    if db.share_document_with_team(&obj_id, &team_id, grant.permission).await.is_err() {
+        return Err(Status::InternalServerError);
+    }
+    match db.find_document(doc! {"_id": obj_id}).await {
+        Ok(Some(document)) => Ok(Json(document.team_shares.unwrap_or_default())),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[delete("/<id>/teams/<team_id>")]
+pub async fn unshare_document_with_team(
+    db: &MongoRepo,
+    id: MongoId,
+    team_id: MongoId,
+    _auth: jwt::AuthObject,
+) -> Result<Json<&'static str>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let team_id = ObjectId::parse_str(team_id.to_string()).map_err(|_| Status::NotFound)?;
+    let document = authorize_document(db, &principal, &obj_id, Permission::Owner).await?;
+
+    if !document.team_shares.iter().flatten().any(|share| share.team_id == team_id) {
+        return Err(Status::NotFound);
+    }
+    match db.unshare_document_with_team(&obj_id, &team_id).await {
+        Ok(_) => Ok(Json("Share successfully removed!")),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/**
+ * Grants every member of a team a permission on all documents in a folder
+ * and its subfolders, including ones filed there later.
+ */
+#[put("/<id>/teams/<team_id>", data = "<grant>")]
+pub async fn share_folder_with_team(
+    db: &MongoRepo,
+    id: MongoId,
+    team_id: MongoId,
+    grant: Json<ShareGrant>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Folder>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    find_folder(db, &principal.user, &obj_id).await?;
+    check_grant(&grant)?;
+    let team_id = find_team_id(db, &team_id).await?;
+
+    if db.share_folder_with_team(&obj_id, &team_id, grant.permission).await.is_err() {
+        return Err(Status::InternalServerError);
+    }
+    find_folder(db, &principal.user, &obj_id).await.map(Json)
+}
+
+#[delete("/<id>/teams/<team_id>")]
+pub async fn unshare_folder_with_team(
+    db: &MongoRepo,
+    id: MongoId,
+    team_id: MongoId,
+    _auth: jwt::AuthObject,
+) -> Result<Json<&'static str>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let team_id = ObjectId::parse_str(team_id.to_string()).map_err(|_| Status::NotFound)?;
+    let folder = find_folder(db, &principal.user, &obj_id).await?;
+
+    if !folder.team_shares.iter().flatten().any(|share| share.team_id == team_id) {
+        return Err(Status::NotFound);
+    }
+    match db.unshare_folder_with_team(&obj_id, &team_id).await {
+        Ok(_) => Ok(Json("Share successfully removed!")),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
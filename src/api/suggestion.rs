@@ -0,0 +1,285 @@
+use std::collections::{hash_map::Entry, HashMap};
+use crate::api::revision::find_revision;
+use crate::api::share::{authorize_document, get_principal};
+use crate::helpers::etag::Tagged;
+use crate::helpers::jwt;
+use crate::helpers::mongo_id::MongoId;
+use crate::models::{document::Document, share::{Permission, Principal}, suggestion::{Suggestion, SuggestionStatus}};
+use crate::repository::mongodb_repo::MongoRepo;
+use mongodb::bson::{doc, oid::ObjectId, to_bson};
+use rocket::{http::Status, serde::json::Json};
+use serde::{Serialize, Deserialize};
+use chrono::Utc;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewSuggestion {
+    start: usize,
+    end: usize,
+    #[serde(default)]
+    text: String,
+}
+
+// the document, checked for `needed`, with its id
+async fn suggested_document(db: &MongoRepo, principal: &Principal, id: &MongoId, needed: Permission) -> Result<(ObjectId, Document), Status> {
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let document = authorize_document(db, principal, &obj_id, needed).await?;
+    Ok((obj_id, document))
+}
+
+async fn find_suggestion(db: &MongoRepo, document_id: &ObjectId, suggestion_id: &MongoId) -> Result<Suggestion, Status> {
+    let suggestion_id = ObjectId::parse_str(suggestion_id.to_string()).map_err(|_| Status::NotFound)?;
+    match db.find_suggestion(doc! {"_id": suggestion_id, "document_id": document_id}).await {
+        Ok(Some(suggestion)) => Ok(suggestion),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+async fn reload(db: &MongoRepo, id: &ObjectId) -> Result<Json<Suggestion>, Status> {
+    match db.find_suggestion(doc! {"_id": id}).await {
+        Ok(Some(suggestion)) => Ok(Json(suggestion)),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+// the latest revision, recording the current state first for documents from before revisions
+async fn latest_revision(db: &MongoRepo, document: &Document) -> Result<i64, Status> {
+    let document_id = document.id.ok_or(Status::NotFound)?;
+    db.ensure_initial_revision(document).await.map_err(|_| Status::InternalServerError)?;
+    match db.latest_revision(&document_id).await {
+        Ok(Some(revision)) => Ok(revision.number),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/**
+ * Moves `suggestion` onto `content`, the document at `version` recorded as
+ * `revision`. Returns None when a change since the content the suggestion was
+ * made on touched its range. Older suggestions without a stored base fall back
+ * to their base revision, kept in `bases` since they often share one.
+ */
+async fn rebase(
+    db: &MongoRepo,
+    suggestion: &Suggestion,
+    version: i64,
+    revision: i64,
+    content: &str,
+    bases: &mut HashMap<i64, String>,
+) -> Result<Option<Suggestion>, Status> {
+    if suggestion.base_version == Some(version) {
+        return Ok(Some(suggestion.clone()));
+    }
+    let base = match &suggestion.base_content {
+        Some(base) => base,
+        None => {
+            if let Entry::Vacant(entry) = bases.entry(suggestion.base_revision) {
+                let base = find_revision(db, &suggestion.document_id, suggestion.base_revision).await?;
+                entry.insert(base.content.unwrap_or_default());
+            }
+            &bases[&suggestion.base_revision]
+        },
+    };
+    Ok(suggestion.rebased(base, content, version, revision))
+}
+
+// the stored fields a rebase changes
+fn rebased_fields(suggestion: &Suggestion) -> mongodb::bson::Document {
+    doc! {
+        "base_revision": suggestion.base_revision,
+        "base_version": suggestion.base_version,
+        "base_content": suggestion.base_content.as_deref(),
+        "start": suggestion.start as i64,
+        "end": suggestion.end as i64,
+    }
+}
+
+async fn set_status(db: &MongoRepo, principal: &Principal, id: &ObjectId, status: SuggestionStatus) -> Result<(), Status> {
+    let now = to_bson(&Utc::now()).map_err(|_| Status::InternalServerError)?;
+    let status = to_bson(&status).map_err(|_| Status::InternalServerError)?;
+    let update = doc! {"$set": {"status": status, "decided_by": principal.user.id, "decided_at": now}};
+    db.update_suggestion(id, update).await.map(|_| ()).map_err(|_| Status::InternalServerError)
+}
+
+#[get("/<id>/suggestions?<status>")]
+pub async fn list_suggestions(
+    db: &MongoRepo,
+    id: MongoId,
+    status: Option<SuggestionStatus>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Vec<Suggestion>>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let (obj_id, _) = suggested_document(db, &principal, &id, Permission::Viewer).await?;
+    let mut filter = doc! {"document_id": obj_id};
+    if let Some(status) = status {
+        filter.insert("status", to_bson(&status).map_err(|_| Status::InternalServerError)?);
+    }
+
+    match db.find_suggestions(filter).await {
+        Ok(suggestions) => Ok(Json(suggestions)),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/**
+ * Proposes replacing the characters `start..end` of the current content with
+ * `text`, without touching the content itself. Commenters and editors can
+ * suggest; a suggestion has to change something.
+ */
+#[post("/<id>/suggestions", data = "<new_suggestion>")]
+pub async fn create_suggestion(
+    db: &MongoRepo,
+    id: MongoId,
+    new_suggestion: Json<NewSuggestion>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Suggestion>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let (obj_id, document) = suggested_document(db, &principal, &id, Permission::Commenter).await?;
+    let data = new_suggestion.into_inner();
+    let content = document.content.clone().unwrap_or_default();
+    if data.start > data.end || data.end > content.chars().count() {
+        return Err(Status::UnprocessableEntity);
+    }
+    let quote: String = content.chars().skip(data.start).take(data.end - data.start).collect();
+    if quote == data.text {
+        return Err(Status::UnprocessableEntity);
+    }
+
+    let suggestion = Suggestion {
+        id: None,
+        document_id: obj_id,
+        author_id: principal.user.id,
+        base_revision: latest_revision(db, &document).await?,
+        base_version: Some(document.version()),
+        base_content: Some(content),
+        start: data.start,
+        end: data.end,
+        text: data.text,
+        quote,
+        status: SuggestionStatus::Pending,
+        date_created: Some(Utc::now()),
+        decided_by: None,
+        decided_at: None,
+        revision: None,
+    };
+    db.create_suggestion(suggestion).await.map(Json).map_err(|_| Status::InternalServerError)
+}
+
+/**
+ * Applies a pending suggestion as a new revision credited to its author, then
+ * rebases the other pending suggestions onto it. Suggestions whose text was
+ * changed in the meantime become conflicted instead, and accepting one of
+ * those answers with 409.
+ */
+#[post("/<id>/suggestions/<suggestion_id>/accept")]
+pub async fn accept_suggestion(
+    db: &MongoRepo,
+    id: MongoId,
+    suggestion_id: MongoId,
+    _auth: jwt::AuthObject,
+) -> Result<Tagged<Json<Document>>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let (obj_id, document) = suggested_document(db, &principal, &id, Permission::Owner).await?;
+    let suggestion = find_suggestion(db, &obj_id, &suggestion_id).await?;
+    let suggestion_id = suggestion.id.ok_or(Status::NotFound)?;
+    if suggestion.status != SuggestionStatus::Pending {
+        return Err(Status::Conflict);
+    }
+
+    let current = latest_revision(db, &document).await?;
+    let content = document.content.clone().unwrap_or_default();
+    let mut bases = HashMap::new();
+    let suggestion = match rebase(db, &suggestion, document.version(), current, &content, &mut bases).await? {
+        Some(suggestion) => suggestion,
+        None => {
+            // nobody decided anything, so the suggestion only changes its status
+            db.update_suggestion(&suggestion_id, doc! {"$set": {"status": "conflicted"}}).await.map_err(|_| Status::InternalServerError)?;
+            return Err(Status::Conflict);
+        }
+    };
+    let accepted = suggestion.apply(&content).ok_or(Status::Conflict)?;
+
+    let changes = Document {
+        content: Some(accepted.clone()),
+        last_modified: Some(Utc::now()),
+        last_modified_by: suggestion.author_id,
+        ..Default::default()
+    };
+    let document = match db.update_document(&obj_id.to_string(), changes, Some(document.version())).await {
+        Ok(Some(document)) => document,
+        // the content changed while the suggestion was being applied
+        Ok(None) => return Err(Status::Conflict),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    let revision = match db.record_revision(&document, suggestion.author_id, None).await {
+        Ok(revision) => revision.number,
+        Err(_) => return Err(Status::InternalServerError),
+    };
+
+    set_status(db, &principal, &suggestion_id, SuggestionStatus::Accepted).await?;
+    let mut fields = rebased_fields(&suggestion);
+    fields.insert("revision", revision);
+    db.update_suggestion(&suggestion_id, doc! {"$set": fields}).await.map_err(|_| Status::InternalServerError)?;
+
+    let pending = db
+        .find_suggestions(doc! {"document_id": obj_id, "status": "pending"})
+        .await
+        .map_err(|_| Status::InternalServerError)?;
+    for other in pending {
+        let other_id = other.id.ok_or(Status::InternalServerError)?;
+        let update = match rebase(db, &other, document.version(), revision, &accepted, &mut bases).await? {
+            Some(rebased) => doc! {"$set": rebased_fields(&rebased)},
+            None => doc! {"$set": {"status": "conflicted"}},
+        };
+        db.update_suggestion(&other_id, update).await.map_err(|_| Status::InternalServerError)?;
+    }
+
+    let version = document.version();
+    Ok(Tagged(Json(document), version))
+}
+
+// pending and conflicted suggestions can be rejected, the content stays as it is
+#[post("/<id>/suggestions/<suggestion_id>/reject")]
+pub async fn reject_suggestion(
+    db: &MongoRepo,
+    id: MongoId,
+    suggestion_id: MongoId,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Suggestion>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let (obj_id, _) = suggested_document(db, &principal, &id, Permission::Owner).await?;
+    let suggestion = find_suggestion(db, &obj_id, &suggestion_id).await?;
+    let suggestion_id = suggestion.id.ok_or(Status::NotFound)?;
+    if !matches!(suggestion.status, SuggestionStatus::Pending | SuggestionStatus::Conflicted) {
+        return Err(Status::Conflict);
+    }
+
+    set_status(db, &principal, &suggestion_id, SuggestionStatus::Rejected).await?;
+    reload(db, &suggestion_id).await
+}
+
+// authors can withdraw a suggestion until it is accepted or rejected
+#[delete("/<id>/suggestions/<suggestion_id>")]
+pub async fn withdraw_suggestion(
+    db: &MongoRepo,
+    id: MongoId,
+    suggestion_id: MongoId,
+    _auth: jwt::AuthObject,
+) -> Result<Json<&'static str>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+    let (obj_id, _) = suggested_document(db, &principal, &id, Permission::Viewer).await?;
+    let suggestion = find_suggestion(db, &obj_id, &suggestion_id).await?;
+    if suggestion.author_id.is_none() || suggestion.author_id != principal.user.id {
+        return Err(Status::Forbidden);
+    }
+    if !matches!(suggestion.status, SuggestionStatus::Pending | SuggestionStatus::Conflicted) {
+        return Err(Status::Conflict);
+    }
+
+    let suggestion_id = suggestion.id.ok_or(Status::NotFound)?;
+    match db.delete_suggestion(&suggestion_id).await {
+        Ok(_) => Ok(Json("Suggestion successfully withdrawn!")),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
@@ -0,0 +1,139 @@
+use crate::api::document::{owned_filter, precondition_failed, readable_filter, DocumentError};
+use crate::api::share::{authorize_document, get_principal};
+use crate::api::user::get_auth_user;
+use crate::helpers::etag::{IfMatch, Tagged};
+use crate::helpers::jwt;
+use crate::helpers::mongo_id::MongoId;
+use crate::models::{document::Document, search::FacetCount, share::Permission};
+use crate::repository::mongodb_repo::MongoRepo;
+use mongodb::bson::{doc, oid::ObjectId};
+use rocket::{http::Status, serde::json::Json};
+use serde::{Serialize, Deserialize};
+
+const MAX_TAG_LENGTH: usize = 64;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TagList {
+    tags: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TagRename {
+    name: String,
+}
+
+fn normalize_tag(tag: &str) -> Result<String, Status> {
+    let tag = tag.trim().to_lowercase();
+    if tag.is_empty() || tag.chars().count() > MAX_TAG_LENGTH {
+        return Err(Status::UnprocessableEntity);
+    }
+    Ok(tag)
+}
+
+// trims and lowercases tags and drops duplicates, empty or overlong tags are rejected
+pub fn normalize_tags(tags: Vec<String>) -> Result<Vec<String>, Status> {
+    let mut normalized: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = normalize_tag(&tag)?;
+        if !normalized.contains(&tag) {
+            normalized.push(tag);
+        }
+    }
+    Ok(normalized)
+}
+
+async fn tagged_document(db: &MongoRepo, id: &ObjectId) -> Result<Tagged<Json<Document>>, Status> {
+    match db.find_document(doc! {"_id": id}).await {
+        Ok(Some(document)) => {
+            let version = document.version();
+            Ok(Tagged(Json(document), version))
+        },
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[post("/<id>/tags", data = "<tags>")]
+pub async fn add_tags(
+    db: &MongoRepo,
+    id: MongoId,
+    tags: Json<TagList>,
+    if_match: IfMatch,
+    _auth: jwt::AuthObject,
+) -> Result<Tagged<Json<Document>>, DocumentError> {
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let tags = normalize_tags(tags.into_inner().tags)?;
+    if tags.is_empty() {
+        return Err(Status::UnprocessableEntity.into());
+    }
+    let principal = get_principal(db, &_auth).await?;
+    authorize_document(db, &principal, &obj_id, Permission::Editor).await?;
+
+    let matched = match db.add_document_tags(&obj_id, tags, if_match.0).await {
+        Ok(res) => res.matched_count,
+        Err(_) => return Err(Status::InternalServerError.into()),
+    };
+    if matched == 0 {
+        return Err(precondition_failed(db, &obj_id).await);
+    }
+    Ok(tagged_document(db, &obj_id).await?)
+}
+
+#[delete("/<id>/tags/<tag>")]
+pub async fn remove_tag(
+    db: &MongoRepo,
+    id: MongoId,
+    tag: &str,
+    if_match: IfMatch,
+    _auth: jwt::AuthObject,
+) -> Result<Tagged<Json<Document>>, DocumentError> {
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let tag = normalize_tag(tag)?;
+    let principal = get_principal(db, &_auth).await?;
+    authorize_document(db, &principal, &obj_id, Permission::Editor).await?;
+
+    let matched = match db.remove_document_tag(&obj_id, &tag, if_match.0).await {
+        Ok(res) => res.matched_count,
+        Err(_) => return Err(Status::InternalServerError.into()),
+    };
+    if matched == 0 {
+        return Err(precondition_failed(db, &obj_id).await);
+    }
+    Ok(tagged_document(db, &obj_id).await?)
+}
+
+// every tag on the documents the caller can read, with how many documents carry it
+#[get("/tags")]
+pub async fn list_tags(db: &MongoRepo, _auth: jwt::AuthObject) -> Result<Json<Vec<FacetCount>>, Status> {
+    let principal = get_principal(db, &_auth).await?;
+
+    match db.document_facets(readable_filter(&principal)).await {
+        Ok(facets) => Ok(Json(facets.tags)),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/**
+ * Renames a tag on the documents the caller owns, or on every document of the
+ * organization for administrators. Documents shared with the caller keep their
+ * tags. Renaming to a tag that is already in use merges the two.
+ */
+#[put("/tags/<tag>", data = "<rename>")]
+pub async fn rename_tag(
+    db: &MongoRepo,
+    tag: &str,
+    rename: Json<TagRename>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<String>, Status> {
+    let from = normalize_tag(tag)?;
+    let to = normalize_tag(&rename.name)?;
+    if from == to {
+        return Err(Status::UnprocessableEntity);
+    }
+    let user = get_auth_user(db, &_auth).await?;
+
+    match db.rename_document_tag(owned_filter(&user), &from, &to).await {
+        Ok(count) => Ok(Json(format!("Tag renamed on {} documents", count))),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
@@ -0,0 +1,186 @@
+use crate::api::user::get_auth_user;
+use crate::helpers::jwt;
+use crate::helpers::mongo_id::MongoId;
+use crate::models::{team::{Team, TeamMember, TeamRole}, user::User};
+use crate::repository::mongodb_repo::MongoRepo;
+use mongodb::bson::{doc, oid::ObjectId};
+use rocket::{http::Status, serde::json::Json};
+use serde::{Serialize, Deserialize};
+use chrono::Utc;
+
+const MAX_NAME_LENGTH: usize = 255;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewTeam {
+    name: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Membership {
+    role: TeamRole,
+}
+
+fn check_name(name: &str) -> Result<String, Status> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH {
+        return Err(Status::UnprocessableEntity);
+    }
+    Ok(name.to_string())
+}
+
+// a team the caller belongs to, administrators see every team
+async fn find_team(db: &MongoRepo, user: &User, id: &MongoId) -> Result<Team, Status> {
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    let team = match db.find_team(doc! {"_id": obj_id}).await {
+        Ok(Some(team)) => team,
+        Ok(None) => return Err(Status::NotFound),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    match user.id {
+        Some(user_id) if team.role_of(&user_id).is_some() || user.is_admin() => Ok(team),
+        _ => Err(Status::NotFound),
+    }
+}
+
+fn require_team_admin(user: &User, team: &Team) -> Result<(), Status> {
+    match user.id {
+        Some(user_id) if team.role_of(&user_id) == Some(TeamRole::Admin) || user.is_admin() => Ok(()),
+        _ => Err(Status::Forbidden),
+    }
+}
+
+async fn reload(db: &MongoRepo, id: &ObjectId) -> Result<Json<Team>, Status> {
+    match db.find_team(doc! {"_id": id}).await {
+        Ok(Some(team)) => Ok(Json(team)),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+// the teams the caller belongs to, administrators get all of them
+#[get("/")]
+pub async fn list_teams(db: &MongoRepo, _auth: jwt::AuthObject) -> Result<Json<Vec<Team>>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    let filter = if user.is_admin() { doc! {} } else { doc! {"members.user_id": user.id} };
+
+    match db.find_teams(filter).await {
+        Ok(teams) => Ok(Json(teams)),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+// the caller becomes the first admin of the new team
+#[post("/", data = "<new_team>")]
+pub async fn create_team(db: &MongoRepo, new_team: Json<NewTeam>, _auth: jwt::AuthObject) -> Result<Json<Team>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    let user_id = user.id.ok_or(Status::Unauthorized)?;
+    let name = check_name(&new_team.name)?;
+
+    let now = Utc::now();
+    let team = Team {
+        id: None,
+        name,
+        members: vec![TeamMember { user_id, role: TeamRole::Admin }],
+        date_created: Some(now),
+        last_modified: Some(now),
+    };
+    let team_id = match db.create_team(team.clone()).await {
+        Ok(inserted) => inserted.inserted_id.as_object_id(),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    Ok(Json(Team { id: team_id, ..team }))
+}
+
+#[get("/<id>")]
+pub async fn get_team(db: &MongoRepo, id: MongoId, _auth: jwt::AuthObject) -> Result<Json<Team>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    find_team(db, &user, &id).await.map(Json)
+}
+
+#[put("/<id>", data = "<rename>")]
+pub async fn rename_team(db: &MongoRepo, id: MongoId, rename: Json<NewTeam>, _auth: jwt::AuthObject) -> Result<Json<Team>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    let team = find_team(db, &user, &id).await?;
+    require_team_admin(&user, &team)?;
+    let name = check_name(&rename.name)?;
+    let team_id = team.id.ok_or(Status::NotFound)?;
+
+    if db.rename_team(&team_id, &name).await.is_err() {
+        return Err(Status::InternalServerError);
+    }
+    reload(db, &team_id).await
+}
+
+// deleting a team takes back every document and folder grant made to it
+#[delete("/<id>")]
+pub async fn delete_team(db: &MongoRepo, id: MongoId, _auth: jwt::AuthObject) -> Result<Json<&'static str>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    let team = find_team(db, &user, &id).await?;
+    require_team_admin(&user, &team)?;
+    let team_id = team.id.ok_or(Status::NotFound)?;
+
+    match db.delete_team(&team_id).await {
+        Ok(_) => Ok(Json("Team successfully deleted!")),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/**
+ * Adds an active user to the team or changes their role. Only team admins can
+ * manage members, and the last admin can not step down to member.
+ */
+#[put("/<id>/members/<user_id>", data = "<membership>")]
+pub async fn set_team_member(
+    db: &MongoRepo,
+    id: MongoId,
+    user_id: MongoId,
+    membership: Json<Membership>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Team>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    let team = find_team(db, &user, &id).await?;
+    require_team_admin(&user, &team)?;
+    let team_id = team.id.ok_or(Status::NotFound)?;
+    let member_id = ObjectId::parse_str(user_id.to_string()).map_err(|_| Status::UnprocessableEntity)?;
+
+    match db.find_user(doc! {"_id": member_id}).await {
+        Ok(Some(member)) if member.is_active() => {},
+        Ok(_) => return Err(Status::UnprocessableEntity),
+        Err(_) => return Err(Status::InternalServerError),
+    }
+    if team.role_of(&member_id) == Some(TeamRole::Admin) && membership.role != TeamRole::Admin && team.admin_count() == 1 {
+        return Err(Status::Conflict);
+    }
+
+    if db.set_team_member(&team_id, &member_id, membership.role).await.is_err() {
+        return Err(Status::InternalServerError);
+    }
+    reload(db, &team_id).await
+}
+
+// team admins can remove anyone and members can leave, as long as an admin is left
+#[delete("/<id>/members/<user_id>")]
+pub async fn remove_team_member(
+    db: &MongoRepo,
+    id: MongoId,
+    user_id: MongoId,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Team>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    let team = find_team(db, &user, &id).await?;
+    let team_id = team.id.ok_or(Status::NotFound)?;
+    let member_id = ObjectId::parse_str(user_id.to_string()).map_err(|_| Status::NotFound)?;
+    if user.id != Some(member_id) {
+        require_team_admin(&user, &team)?;
+    }
+
+    match team.role_of(&member_id) {
+        None => return Err(Status::NotFound),
+        Some(TeamRole::Admin) if team.admin_count() == 1 => return Err(Status::Conflict),
+        Some(_) => {},
+    }
+    if db.remove_team_member(&team_id, &member_id).await.is_err() {
+        return Err(Status::InternalServerError);
+    }
+    reload(db, &team_id).await
+}
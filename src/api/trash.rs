@@ -0,0 +1,78 @@
+use crate::api::document::owned_filter;
+use crate::api::user::get_auth_user;
+use crate::helpers::etag::Tagged;
+use crate::helpers::jwt;
+use crate::helpers::mongo_id::MongoId;
+use crate::models::{document::Document, user::User};
+use crate::repository::mongodb_repo::MongoRepo;
+use mongodb::bson::{doc, oid::ObjectId, Document as BsonDocument};
+use rocket::{http::Status, serde::json::Json};
+
+const DEFAULT_LIST_LIMIT: i64 = 50;
+const MAX_LIST_LIMIT: i64 = 200;
+
+// a trashed document of the caller, administrators reach every trash
+fn trashed_filter(user: &User, id: &MongoId) -> Result<BsonDocument, Status> {
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    Ok(doc! {"$and": [{"_id": obj_id}, owned_filter(user)]})
+}
+
+// the caller's deleted documents, most recently deleted first
+#[get("/trash?<skip>&<limit>")]
+pub async fn list_trash(
+    db: &MongoRepo,
+    skip: Option<u64>,
+    limit: Option<i64>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Vec<Document>>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    let limit = limit.unwrap_or(DEFAULT_LIST_LIMIT).clamp(1, MAX_LIST_LIMIT);
+
+    match db.find_trashed_documents(owned_filter(&user), skip.unwrap_or(0), limit).await {
+        Ok(documents) => Ok(Json(documents)),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+// puts a document back where it was, or at the root when its folder is gone
+#[post("/trash/<id>/restore")]
+pub async fn restore_document(db: &MongoRepo, id: MongoId, _auth: jwt::AuthObject) -> Result<Tagged<Json<Document>>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    let document = match db.find_trashed_document(trashed_filter(&user, &id)?).await {
+        Ok(Some(document)) => document,
+        Ok(None) => return Err(Status::NotFound),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    let obj_id = document.id.ok_or(Status::NotFound)?;
+
+    match db.restore_document(&obj_id).await {
+        Ok(Some(document)) => {
+            let version = document.version();
+            Ok(Tagged(Json(document), version))
+        },
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[delete("/trash/<id>")]
+pub async fn delete_trashed_document(db: &MongoRepo, id: MongoId, _auth: jwt::AuthObject) -> Result<Json<&'static str>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+
+    match db.delete_trashed_documents(trashed_filter(&user, &id)?).await {
+        Ok(1) => Ok(Json("Document permanently deleted!")),
+        Ok(_) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+// deletes everything in the caller's own trash for good, also for administrators
+#[delete("/trash")]
+pub async fn empty_trash(db: &MongoRepo, _auth: jwt::AuthObject) -> Result<Json<String>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+
+    match db.delete_trashed_documents(doc! {"owner_id": user.id}).await {
+        Ok(count) => Ok(Json(format!("Permanently deleted {} documents", count))),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
@@ -1,50 +1,237 @@
 use crate::helpers::jwt;
 use crate::helpers::mongo_id::MongoId;
-use crate::repository::mongodb_repo::{LoginObject, AuthResponse};
-use crate::{models::user::User, repository::mongodb_repo::MongoRepo};
-use mongodb::{results::InsertOneResult};
-use rocket::{http::Status, serde::json::Json, State};
+use crate::helpers::patch::{apply_json_patch, apply_merge_patch, field_changes, PatchError};
+use crate::repository::mongodb_repo::{AuthResponse, LoginError, LoginObject};
+use crate::{models::user::{DeletionStrategy, ProfileUpdate, RoleEnum, User, UserProfile}, repository::mongodb_repo::MongoRepo};
+use mongodb::{bson::{doc, oid::ObjectId, to_bson, to_document, Document}, results::InsertOneResult};
+use rocket::{http::Status, serde::json::Json};
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
 use struct_helpers::rocket::guard::HelpersGuard;
-use argon2::{
-    password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
-    Argon2
-};
+use crate::helpers::password::{hash_password, verify_password, Verification};
+use crate::helpers::password_policy::PasswordPolicy;
+use chrono::Utc;
 
+// the fields a PATCH may change, the role only by administrators
+const PATCHABLE_FIELDS: [&str; 5] = ["firstname", "lastname", "username", "email", "role"];
 
-#[get("/<id>")]
-pub async fn get_user(db: &State<MongoRepo>, id: MongoId) -> Result<Json<User>, Status> {
-    let user_detail = db.get_user(&id.to_string()).await;
-    match user_detail {
-        Ok(user) => Ok(Json(user)),
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordChange {
+    current_password: String,
+    new_password: String,
+}
+
+// changes a password without a token, see renew_password
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordRenewal {
+    username: String,
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordReset {
+    new_password: String,
+}
+
+pub async fn find_user_by_id(db: &MongoRepo, id: &MongoId) -> Result<User, Status> {
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    match db.find_user(doc! {"_id": obj_id}).await {
+        Ok(Some(user)) => Ok(user),
+        Ok(None) => Err(Status::NotFound),
         Err(_) => Err(Status::InternalServerError),
     }
 }
 
-#[post("/", data = "<new_user>")]
-pub async fn create_user(
-    db: &State<MongoRepo>,
-    new_user: HelpersGuard<Json<User>>,
-) -> Result<Json<InsertOneResult>, Status> {
-    let data = new_user.into_deep_inner();
-    // hash password before saving
-    let password = data.password.as_bytes();
-    let salt = SaltString::generate(&mut OsRng);
-    // Argon2 with default params (Argon2id v19)
-    let argon2 = Argon2::default();
-    // Hash password to PHC string ($argon2id$v=19$...)
-    let password_hash = argon2.hash_password(password, &salt).unwrap();
-
-    let usr = User {
+// the user behind a validated token, tokens are signed with the user's id
+pub async fn get_auth_user(db: &MongoRepo, auth: &jwt::AuthObject) -> Result<User, Status> {
+    match db.find_user(auth.user_filter()).await {
+        Ok(Some(user)) if user.is_active() => Ok(user),
+        Ok(_) => Err(Status::Unauthorized),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+pub async fn require_admin(db: &MongoRepo, auth: &jwt::AuthObject) -> Result<User, Status> {
+    let user = get_auth_user(db, auth).await?;
+    match user.is_admin() {
+        true => Ok(user),
+        false => Err(Status::Forbidden),
+    }
+}
+
+/**
+ * Reads the `strategy` (delete, transfer or archive) and `transfer_to` query
+ * params of a user deletion. Users can not transfer documents to themselves.
+ */
+pub fn parse_deletion_strategy(user_id: &ObjectId, strategy: Option<&str>, transfer_to: Option<&str>) -> Result<DeletionStrategy, Status> {
+    match (strategy, transfer_to) {
+        (Some("delete"), None) => Ok(DeletionStrategy::Delete),
+        (Some("archive"), None) => Ok(DeletionStrategy::Archive),
+        (Some("transfer"), Some(to)) => {
+            let to = ObjectId::parse_str(to).map_err(|_| Status::BadRequest)?;
+            if &to == user_id {
+                return Err(Status::BadRequest);
+            }
+            Ok(DeletionStrategy::Transfer { to })
+        },
+        _ => Err(Status::BadRequest),
+    }
+}
+
+/**
+ * Resolves the deletion strategy, see parse_deletion_strategy. Documents can
+ * only be handed to another active user, and since the lookup is scoped to
+ * the organization, only to one of the same organization.
+ */
+pub async fn deletion_strategy(
+    db: &MongoRepo,
+    user_id: &ObjectId,
+    strategy: Option<&str>,
+    transfer_to: Option<&str>,
+) -> Result<DeletionStrategy, Status> {
+    let strategy = parse_deletion_strategy(user_id, strategy, transfer_to)?;
+    if let DeletionStrategy::Transfer { to } = &strategy {
+        match db.find_user(doc! {"_id": to}).await {
+            Ok(Some(recipient)) if recipient.is_active() => {},
+            Ok(_) => return Err(Status::BadRequest),
+            Err(_) => return Err(Status::InternalServerError),
+        }
+    }
+    Ok(strategy)
+}
+
+/**
+ * Checks `new_password` against the password policy and returns the fields
+ * that store it: the new hash, the history with the replaced hash moved in,
+ * and the change date. Callers write them along with their other changes.
+ */
+pub fn password_fields(user: &User, new_password: &str) -> Result<Document, Status> {
+    let policy = PasswordPolicy::get();
+    let history = user.password_history.clone().unwrap_or_default();
+    let mut previous = vec![user.password.clone()];
+    previous.extend(history.iter().cloned());
+
+    if policy.validate(new_password, &previous).is_err() {
+        return Err(Status::UnprocessableEntity);
+    }
+
+    let history = policy.remember(user.password.clone(), history);
+    Ok(doc! {
+        "password": hash_password(new_password),
+        "password_history": history,
+        "password_changed_at": to_bson(&Utc::now()).map_err(|_| Status::InternalServerError)?,
+    })
+}
+
+// stores a new password for the user, see password_fields; returns the new hash
+pub async fn set_user_password(db: &MongoRepo, user: &User, new_password: &str) -> Result<String, Status> {
+    let id = user.id.ok_or(Status::NotFound)?;
+    let fields = password_fields(user, new_password)?;
+    let password_hash = fields.get_str("password").map_err(|_| Status::InternalServerError)?.to_string();
+    let updated = db.set_password(&id, fields).await.is_ok();
+    if !updated {
+        return Err(Status::InternalServerError);
+    }
+
+    Ok(password_hash)
+}
+
+
+#[get("/me")]
+pub async fn get_me(db: &MongoRepo, _auth: jwt::AuthObject) -> Result<Json<UserProfile>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    Ok(Json(UserProfile::from(user)))
+}
+
+#[put("/me", data = "<profile>")]
+pub async fn update_me(
+    db: &MongoRepo,
+    profile: HelpersGuard<Json<ProfileUpdate>>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<UserProfile>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    let id = user.id.ok_or(Status::NotFound)?;
+    let data = profile.into_deep_inner();
+
+    if let Some(username) = &data.username {
+        let taken = db.count_users(doc! {"username": username, "_id": {"$ne": id}}).await.map_err(|_| Status::InternalServerError)?;
+        if taken > 0 {
+            return Err(Status::Conflict);
+        }
+    }
+
+    let set = to_document(&data).map_err(|_| Status::BadRequest)?;
+    let updated = db.update_user_fields(&id.to_hex(), set, Document::new()).await.is_ok();
+    if !updated {
+        return Err(Status::InternalServerError);
+    }
+
+    match db.find_user(doc! {"_id": id}).await {
+        Ok(Some(user)) => Ok(Json(UserProfile::from(user))),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[delete("/me?<strategy>&<transfer_to>")]
+pub async fn delete_me(
+    db: &MongoRepo,
+    strategy: Option<&str>,
+    transfer_to: Option<&str>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<&'static str>, Status> {
+    let user = get_auth_user(db, &_auth).await?;
+    let id = user.id.ok_or(Status::NotFound)?;
+    let strategy = deletion_strategy(db, &id, strategy, transfer_to).await?;
+    let result = db.soft_delete_user(&id.to_hex(), &strategy).await;
+    match result {
+        Ok(res) => {
+            if res.modified_count == 1 {
+                return Ok(Json("User successfully deleted!"));
+            } else {
+                return Err(Status::NotFound);
+            }
+        }
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[get("/<id>")]
+pub async fn get_user(db: &MongoRepo, id: MongoId) -> Result<Json<UserProfile>, Status> {
+    find_user_by_id(db, &id).await.map(|user| Json(UserProfile::from(user)))
+}
+
+// the user a signup creates, with the password hashed and without any role asked for
+pub fn new_signup(data: User) -> User {
+    User {
         id: None,
         firstname: data.firstname,
         lastname: data.lastname,
         username: data.username,
         email: data.email,
-        password: password_hash.to_string(),
-        role: data.role
-    };
-   
-    println!("{:?}", usr);
+        password: hash_password(&data.password),
+        // signing up never grants a role, administrators hand those out
+        role: None,
+        active: None,
+        password_history: None,
+        password_changed_at: Some(Utc::now()),
+        deleted_at: None,
+        deletion_strategy: None,
+    }
+}
+
+#[post("/", data = "<new_user>")]
+pub async fn create_user(
+    db: &MongoRepo,
+    new_user: HelpersGuard<Json<User>>,
+) -> Result<Json<InsertOneResult>, Status> {
+    let data = new_user.into_deep_inner();
+    if PasswordPolicy::get().validate(&data.password, &[]).is_err() {
+        return Err(Status::UnprocessableEntity);
+    }
+    let usr = new_signup(data);
+
     let user_detail = db.create_user(User::from(usr)).await;
     match user_detail {
         Ok(user) => Ok(Json(user)),
@@ -52,34 +239,67 @@ pub async fn create_user(
     }
 }
 
+// a refused login as clients see it, expired passwords are told apart so clients can offer a renewal
+pub fn login_failure(error: LoginError) -> (Status, &'static str) {
+    match error {
+        LoginError::Invalid => (Status::Unauthorized, "Invalid username or password"),
+        LoginError::Deactivated => (Status::Forbidden, "User is deactivated"),
+        LoginError::Expired => (Status::Forbidden, "Password expired"),
+        LoginError::Database => (Status::InternalServerError, "Login could not be checked"),
+    }
+}
+
 #[post("/login", data = "<new_user>")]
 pub async fn login(
-    db: &State<MongoRepo>,
+    db: &MongoRepo,
     new_user: HelpersGuard<Json<User>>,
-) -> Result<Json<AuthResponse>, Status> {
+) -> Result<Json<AuthResponse>, (Status, &'static str)> {
     let data = new_user.into_deep_inner();
-    println!("{:?}", data);
-
     let login_object = LoginObject {
-        username: data.username.unwrap(),
+        username: data.username.ok_or(login_failure(LoginError::Invalid))?,
         password: data.password
     };
-    
-    let user_detail = db.login(LoginObject::from(login_object)).await;
-    match user_detail {
-        Ok(auth_response) => Ok(Json(auth_response)),
-        Err(_) => Err(Status::InternalServerError),
+
+    db.login(login_object).await.map(Json).map_err(login_failure)
+}
+
+// only administrators hand out roles, the role of anyone else's update is dropped
+pub fn permitted_role(caller: &User, requested: Option<RoleEnum>) -> Option<RoleEnum> {
+    match caller.is_admin() {
+        true => requested,
+        false => None,
     }
 }
 
+// replaces a user, the user themself or an administrator
 #[put("/<id>", data = "<new_user>")]
 pub async fn update_user(
-    db: &State<MongoRepo>,
+    db: &MongoRepo,
     id: MongoId,
     new_user: HelpersGuard<Json<User>>,
-) -> Result<Json<User>, Status> {
+    _auth: jwt::AuthObject,
+) -> Result<Json<UserProfile>, Status> {
+    let caller = get_auth_user(db, &_auth).await?;
+    let existing = find_user_by_id(db, &id).await?;
+    let user_id = existing.id.ok_or(Status::NotFound)?;
+    if !caller.can_manage(&user_id) {
+        return Err(Status::Forbidden);
+    }
+
     let mut data = new_user.into_deep_inner();
     data.remove_id();
+    data.role = permitted_role(&caller, data.role);
+    data.password_history = None;
+    data.password_changed_at = None;
+    // account status only changes through the deactivate/reactivate endpoints
+    data.active = None;
+    data.deleted_at = None;
+
+    // the body carries the plain password, only a different one counts as a change
+    data.password = match verify_password(&data.password, &existing.password) {
+        Verification::Invalid => set_user_password(db, &existing, &data.password).await?,
+        _ => existing.password,
+    };
 
     let update_result = match db.update_user(&id.to_string(), data).await {
         Ok(update) => update,
@@ -87,21 +307,169 @@ pub async fn update_user(
     };
 
     if update_result.matched_count == 1 {
-        match db.get_user(&id.to_string()).await {
-            Ok(user) => return Ok(Json(user)),
-            Err(_) => return Err(Status::InternalServerError),
-        }
+        return find_user_by_id(db, &id).await.map(|user| Json(UserProfile::from(user)));
     }
 
     return Err(Status::NotFound);
 }
 
-#[delete("/<id>")]
-pub async fn delete_user(db: &State<MongoRepo>, id: MongoId, _auth: jwt::AuthObject) -> Result<Json<&str>, Status> {
-    let result = db.delete_user(&id.to_string()).await;
+/**
+ * Applies a patch to a user and writes only the fields it changed. Passwords
+ * and account status have their own endpoints and can not be patched.
+ */
+async fn patch_user<F>(db: &MongoRepo, id: MongoId, auth: &jwt::AuthObject, apply: F) -> Result<Json<UserProfile>, Status>
+where
+    F: FnOnce(&User) -> Result<User, PatchError>,
+{
+    let caller = get_auth_user(db, auth).await?;
+    let user = find_user_by_id(db, &id).await?;
+    let is_admin = caller.is_admin();
+    if !user.id.is_some_and(|user_id| caller.can_manage(&user_id)) {
+        return Err(Status::Forbidden);
+    }
+
+    let mut patched = apply(&user)?;
+    patched.firstname = patched.firstname.map(|firstname| firstname.to_lowercase());
+    let writable = if is_admin { &PATCHABLE_FIELDS[..] } else { &PATCHABLE_FIELDS[..4] };
+    let changes = field_changes(&user, &patched, writable)?;
+
+    if let Ok(username) = changes.set.get_str("username") {
+        let taken = db.count_users(doc! {"username": username, "_id": {"$ne": user.id}}).await.map_err(|_| Status::InternalServerError)?;
+        if taken > 0 {
+            return Err(Status::Conflict);
+        }
+    }
+
+    let updated = db.update_user_fields(&id.to_string(), changes.set, changes.unset).await.is_ok();
+    if !updated {
+        return Err(Status::InternalServerError);
+    }
+
+    find_user_by_id(db, &id).await.map(|user| Json(UserProfile::from(user)))
+}
+
+#[patch("/<id>", format = "application/merge-patch+json", data = "<patch>")]
+pub async fn merge_patch_user(
+    db: &MongoRepo,
+    id: MongoId,
+    patch: Json<Value>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<UserProfile>, Status> {
+    patch_user(db, id, &_auth, |user| apply_merge_patch(user, &patch)).await
+}
+
+#[patch("/<id>", format = "application/json-patch+json", data = "<patch>")]
+pub async fn json_patch_user(
+    db: &MongoRepo,
+    id: MongoId,
+    patch: Json<json_patch::Patch>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<UserProfile>, Status> {
+    patch_user(db, id, &_auth, |user| apply_json_patch(user, &patch)).await
+}
+
+#[put("/<id>/password", data = "<change>")]
+pub async fn change_password(
+    db: &MongoRepo,
+    id: MongoId,
+    change: Json<PasswordChange>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<&str>, Status> {
+    let user = find_user_by_id(db, &id).await?;
+    if user.id.map(|id| id.to_hex()) != Some(_auth.user.clone()) {
+        return Err(Status::Forbidden);
+    }
+    if verify_password(&change.current_password, &user.password) == Verification::Invalid {
+        return Err(Status::Forbidden);
+    }
+
+    set_user_password(db, &user, &change.new_password).await?;
+    Ok(Json("Password successfully changed!"))
+}
+
+/**
+ * Changes a password with the current one instead of a token, so users whose
+ * password has expired, and who can not log in for that reason, can choose a
+ * new one. Answers like a login with the new password.
+ */
+#[post("/login/password", data = "<renewal>")]
+pub async fn renew_password(db: &MongoRepo, renewal: Json<PasswordRenewal>) -> Result<Json<AuthResponse>, Status> {
+    let user = match db.find_user(doc! {"username": &renewal.username}).await {
+        Ok(Some(user)) => user,
+        Ok(None) => return Err(Status::Unauthorized),
+        Err(_) => return Err(Status::InternalServerError),
+    };
+    if verify_password(&renewal.current_password, &user.password) == Verification::Invalid {
+        return Err(Status::Unauthorized);
+    }
+    if !user.is_active() {
+        return Err(Status::Forbidden);
+    }
+
+    set_user_password(db, &user, &renewal.new_password).await?;
+    let credentials = LoginObject { username: renewal.username.clone(), password: renewal.new_password.clone() };
+    db.login(credentials).await.map(Json).map_err(|e| login_failure(e).0)
+}
+
+#[put("/<id>/password/reset", data = "<reset>")]
+pub async fn reset_password(
+    db: &MongoRepo,
+    id: MongoId,
+    reset: Json<PasswordReset>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<&str>, Status> {
+    require_admin(db, &_auth).await?;
+    let user = find_user_by_id(db, &id).await?;
+
+    set_user_password(db, &user, &reset.new_password).await?;
+    Ok(Json("Password successfully reset!"))
+}
+
+#[put("/<id>/deactivate")]
+pub async fn deactivate_user(db: &MongoRepo, id: MongoId, _auth: jwt::AuthObject) -> Result<Json<&str>, Status> {
+    let caller = get_auth_user(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    if !caller.can_manage(&obj_id) {
+        return Err(Status::Forbidden);
+    }
+
+    match db.set_user_active(&id.to_string(), false).await {
+        Ok(res) if res.matched_count == 1 => Ok(Json("User successfully deactivated!")),
+        Ok(_) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[put("/<id>/reactivate")]
+pub async fn reactivate_user(db: &MongoRepo, id: MongoId, _auth: jwt::AuthObject) -> Result<Json<&str>, Status> {
+    require_admin(db, &_auth).await?;
+
+    match db.set_user_active(&id.to_string(), true).await {
+        Ok(res) if res.matched_count == 1 => Ok(Json("User successfully reactivated!")),
+        Ok(_) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[delete("/<id>?<strategy>&<transfer_to>")]
+pub async fn delete_user(
+    db: &MongoRepo,
+    id: MongoId,
+    strategy: Option<&str>,
+    transfer_to: Option<&str>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<&'static str>, Status> {
+    let caller = get_auth_user(db, &_auth).await?;
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    // users delete themselves, administrators anyone
+    if !caller.can_manage(&obj_id) {
+        return Err(Status::Forbidden);
+    }
+    let strategy = deletion_strategy(db, &obj_id, strategy, transfer_to).await?;
+    let result = db.soft_delete_user(&id.to_string(), &strategy).await;
     match result {
         Ok(res) => {
-            if res.deleted_count == 1 {
+            if res.modified_count == 1 {
                 return Ok(Json("User successfully deleted!"));
             } else {
                 return Err(Status::NotFound);
@@ -112,10 +480,10 @@ pub async fn delete_user(db: &State<MongoRepo>, id: MongoId, _auth: jwt::AuthObj
 }
 
 #[get("/")]
-pub async fn get_all_users(db: &State<MongoRepo>, _auth: jwt::AuthObject) -> Result<Json<Vec<User>>, Status> {
+pub async fn get_all_users(db: &MongoRepo, _auth: jwt::AuthObject) -> Result<Json<Vec<UserProfile>>, Status> {
     let users = db.get_all_users().await;
     match users {
-        Ok(users) => Ok(Json(users)),
+        Ok(users) => Ok(Json(users.into_iter().map(UserProfile::from).collect())),
         Err(_) => Err(Status::InternalServerError),
     }
 }
\ No newline at end of file
@@ -0,0 +1,223 @@
+use crate::api::link::new_token;
+use crate::api::user::require_admin;
+use crate::helpers::jwt;
+use crate::jobs::webhooks::{allow_private_targets, is_private_address};
+use crate::helpers::mongo_id::MongoId;
+use crate::models::{event::{Event, EventKind}, webhook::{Webhook, WebhookDelivery, WebhookInfo}};
+use crate::repository::mongodb_repo::MongoRepo;
+use mongodb::bson::{doc, oid::ObjectId, to_bson, Document as BsonDocument};
+use rocket::{http::Status, serde::json::Json, tokio::net::lookup_host};
+use std::net::IpAddr;
+use serde::{Serialize, Deserialize};
+use chrono::Utc;
+
+const DEFAULT_LIST_LIMIT: i64 = 50;
+const MAX_LIST_LIMIT: i64 = 200;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NewWebhook {
+    url: String,
+    // every event type when left out
+    #[serde(default)]
+    events: Vec<EventKind>,
+    // generated when left out
+    secret: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WebhookUpdate {
+    url: Option<String>,
+    events: Option<Vec<EventKind>>,
+    secret: Option<String>,
+    active: Option<bool>,
+}
+
+/**
+ * Deliveries go to plain http(s) URLs. Hosts are resolved, and ones with a
+ * loopback, private or link-local address are refused unless
+ * WEBHOOK_ALLOW_PRIVATE is set, so webhooks can not reach into our network.
+ */
+pub async fn check_url(url: &str) -> Result<String, Status> {
+    let url = url.trim();
+    let parsed = match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") => parsed,
+        _ => return Err(Status::UnprocessableEntity),
+    };
+    let host = parsed.host_str().ok_or(Status::UnprocessableEntity)?;
+    let port = parsed.port_or_known_default().ok_or(Status::UnprocessableEntity)?;
+    // IPv6 hosts come in brackets
+    let addresses: Vec<IpAddr> = match host.trim_start_matches('[').trim_end_matches(']').parse() {
+        Ok(ip) => vec![ip],
+        Err(_) if allow_private_targets() => return Ok(url.to_string()),
+        Err(_) => match lookup_host((host, port)).await {
+            Ok(addresses) => addresses.map(|address| address.ip()).collect(),
+            Err(_) => return Err(Status::UnprocessableEntity),
+        },
+    };
+    let private = addresses.iter().any(|ip| is_private_address(*ip)) && !allow_private_targets();
+    match addresses.is_empty() || private {
+        true => Err(Status::UnprocessableEntity),
+        false => Ok(url.to_string()),
+    }
+}
+
+fn check_secret(secret: &str) -> Result<String, Status> {
+    match secret.is_empty() {
+        true => Err(Status::UnprocessableEntity),
+        false => Ok(secret.to_string()),
+    }
+}
+
+async fn find_webhook(db: &MongoRepo, id: &MongoId) -> Result<Webhook, Status> {
+    let obj_id = ObjectId::parse_str(id.to_string()).map_err(|_| Status::NotFound)?;
+    match db.find_webhook(doc! {"_id": obj_id}).await {
+        Ok(Some(webhook)) => Ok(webhook),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+async fn find_delivery(db: &MongoRepo, webhook_id: &ObjectId, delivery_id: &MongoId) -> Result<WebhookDelivery, Status> {
+    let delivery_id = ObjectId::parse_str(delivery_id.to_string()).map_err(|_| Status::NotFound)?;
+    match db.find_delivery(doc! {"_id": delivery_id, "webhook_id": webhook_id}).await {
+        Ok(Some(delivery)) => Ok(delivery),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+async fn queue(db: &MongoRepo, delivery: WebhookDelivery) -> Result<Json<WebhookDelivery>, Status> {
+    db.create_delivery(delivery).await.map(Json).map_err(|_| Status::InternalServerError)
+}
+
+#[get("/")]
+pub async fn list_webhooks(db: &MongoRepo, _auth: jwt::AuthObject) -> Result<Json<Vec<WebhookInfo>>, Status> {
+    require_admin(db, &_auth).await?;
+
+    match db.find_webhooks(doc! {}).await {
+        Ok(webhooks) => Ok(Json(webhooks.into_iter().map(WebhookInfo::from).collect())),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+/**
+ * Subscribes a URL to events of the caller's organization; administrators
+ * only. The answer is the only time the secret is shown.
+ */
+#[post("/", data = "<new_webhook>")]
+pub async fn create_webhook(db: &MongoRepo, new_webhook: Json<NewWebhook>, _auth: jwt::AuthObject) -> Result<Json<Webhook>, Status> {
+    let admin = require_admin(db, &_auth).await?;
+    let data = new_webhook.into_inner();
+
+    let webhook = Webhook {
+        id: None,
+        url: check_url(&data.url).await?,
+        events: data.events,
+        secret: match data.secret {
+            Some(secret) => check_secret(&secret)?,
+            None => new_token(),
+        },
+        active: true,
+        created_by: admin.id,
+        date_created: Some(Utc::now()),
+    };
+    db.create_webhook(webhook).await.map(Json).map_err(|_| Status::InternalServerError)
+}
+
+#[get("/<id>")]
+pub async fn get_webhook(db: &MongoRepo, id: MongoId, _auth: jwt::AuthObject) -> Result<Json<WebhookInfo>, Status> {
+    require_admin(db, &_auth).await?;
+    find_webhook(db, &id).await.map(|webhook| Json(WebhookInfo::from(webhook)))
+}
+
+// changes the given fields, deliveries already queued go out with the new settings
+#[put("/<id>", data = "<update>")]
+pub async fn update_webhook(db: &MongoRepo, id: MongoId, update: Json<WebhookUpdate>, _auth: jwt::AuthObject) -> Result<Json<WebhookInfo>, Status> {
+    require_admin(db, &_auth).await?;
+    let webhook = find_webhook(db, &id).await?;
+    let webhook_id = webhook.id.ok_or(Status::NotFound)?;
+    let data = update.into_inner();
+
+    let mut set = BsonDocument::new();
+    if let Some(url) = data.url {
+        set.insert("url", check_url(&url).await?);
+    }
+    if let Some(events) = data.events {
+        set.insert("events", to_bson(&events).map_err(|_| Status::InternalServerError)?);
+    }
+    if let Some(secret) = data.secret {
+        set.insert("secret", check_secret(&secret)?);
+    }
+    if let Some(active) = data.active {
+        set.insert("active", active);
+    }
+    if !set.is_empty() && db.update_webhook(&webhook_id, doc! {"$set": set}).await.is_err() {
+        return Err(Status::InternalServerError);
+    }
+    find_webhook(db, &id).await.map(|webhook| Json(WebhookInfo::from(webhook)))
+}
+
+#[delete("/<id>")]
+pub async fn delete_webhook(db: &MongoRepo, id: MongoId, _auth: jwt::AuthObject) -> Result<Json<&'static str>, Status> {
+    require_admin(db, &_auth).await?;
+    let webhook = find_webhook(db, &id).await?;
+    let webhook_id = webhook.id.ok_or(Status::NotFound)?;
+
+    match db.delete_webhook(&webhook_id).await {
+        Ok(_) => Ok(Json("Webhook successfully deleted!")),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+// queues a `ping` event to try the webhook out, the delivery log shows how it went
+#[post("/<id>/ping")]
+pub async fn ping_webhook(db: &MongoRepo, id: MongoId, _auth: jwt::AuthObject) -> Result<Json<WebhookDelivery>, Status> {
+    require_admin(db, &_auth).await?;
+    let webhook = find_webhook(db, &id).await?;
+    let webhook_id = webhook.id.ok_or(Status::NotFound)?;
+
+    let event = Event::ping(format!("ping-{}", ObjectId::new().to_hex()));
+    queue(db, WebhookDelivery::new(webhook_id, event)).await
+}
+
+// the webhook's deliveries, newest first
+#[get("/<id>/deliveries?<skip>&<limit>")]
+pub async fn list_deliveries(
+    db: &MongoRepo,
+    id: MongoId,
+    skip: Option<u64>,
+    limit: Option<i64>,
+    _auth: jwt::AuthObject,
+) -> Result<Json<Vec<WebhookDelivery>>, Status> {
+    require_admin(db, &_auth).await?;
+    let webhook = find_webhook(db, &id).await?;
+    let limit = limit.unwrap_or(DEFAULT_LIST_LIMIT).clamp(1, MAX_LIST_LIMIT);
+
+    match db.find_deliveries(doc! {"webhook_id": webhook.id}, skip.unwrap_or(0), limit).await {
+        Ok(deliveries) => Ok(Json(deliveries)),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+#[get("/<id>/deliveries/<delivery_id>")]
+pub async fn get_delivery(db: &MongoRepo, id: MongoId, delivery_id: MongoId, _auth: jwt::AuthObject) -> Result<Json<WebhookDelivery>, Status> {
+    require_admin(db, &_auth).await?;
+    let webhook = find_webhook(db, &id).await?;
+    let webhook_id = webhook.id.ok_or(Status::NotFound)?;
+    find_delivery(db, &webhook_id, &delivery_id).await.map(Json)
+}
+
+// sends a delivery's event again as a new delivery, whatever became of the first one
+#[post("/<id>/deliveries/<delivery_id>/redeliver")]
+pub async fn redeliver(db: &MongoRepo, id: MongoId, delivery_id: MongoId, _auth: jwt::AuthObject) -> Result<Json<WebhookDelivery>, Status> {
+    require_admin(db, &_auth).await?;
+    let webhook = find_webhook(db, &id).await?;
+    let webhook_id = webhook.id.ok_or(Status::NotFound)?;
+    let original = find_delivery(db, &webhook_id, &delivery_id).await?;
+
+    let delivery = WebhookDelivery {
+        redelivery_of: original.id,
+        ..WebhookDelivery::new(webhook_id, original.event)
+    };
+    queue(db, delivery).await
+}
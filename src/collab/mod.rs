@@ -0,0 +1,3 @@
+pub mod ot;
+pub mod room;
+pub mod server;
\ No newline at end of file
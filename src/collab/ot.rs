@@ -0,0 +1,194 @@
+use serde::{Serialize, Deserialize};
+use similar::{DiffTag, TextDiff};
+
+/**
+ * A step of an operation: keep, insert or delete text. Counts are in
+ * characters, not bytes, so clients in any language agree on them.
+ */
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Component {
+    Retain(usize),
+    Insert(String),
+    Delete(usize),
+}
+
+/**
+ * An edit of the whole content, the same model ot.js uses: the components
+ * walk the content from start to end, so an operation only applies to text
+ * of exactly its base length. Sent as `[{"retain": 5}, {"insert": "x"}, {"delete": 2}]`.
+ */
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TextOperation(Vec<Component>);
+
+impl TextOperation {
+    pub fn new() -> Self {
+        TextOperation(Vec::new())
+    }
+
+    // the builders merge with the previous component of the same kind
+    pub fn retain(mut self, n: usize) -> Self {
+        if n > 0 {
+            match self.0.last_mut() {
+                Some(Component::Retain(last)) => *last += n,
+                _ => self.0.push(Component::Retain(n)),
+            }
+        }
+        self
+    }
+
+    pub fn insert(mut self, text: &str) -> Self {
+        if !text.is_empty() {
+            match self.0.last_mut() {
+                Some(Component::Insert(last)) => last.push_str(text),
+                _ => self.0.push(Component::Insert(text.to_string())),
+            }
+        }
+        self
+    }
+
+    pub fn delete(mut self, n: usize) -> Self {
+        if n > 0 {
+            match self.0.last_mut() {
+                Some(Component::Delete(last)) => *last += n,
+                _ => self.0.push(Component::Delete(n)),
+            }
+        }
+        self
+    }
+
+    // the operation that turns `old` into `new`, character by character
+    pub fn between(old: &str, new: &str) -> Self {
+        let inserted: Vec<char> = new.chars().collect();
+        let mut operation = TextOperation::new();
+        for op in TextDiff::from_chars(old, new).ops() {
+            let (old_range, new_range) = (op.old_range(), op.new_range());
+            operation = match op.tag() {
+                DiffTag::Equal => operation.retain(old_range.len()),
+                _ => {
+                    let text: String = inserted[new_range].iter().collect();
+                    operation.delete(old_range.len()).insert(&text)
+                },
+            };
+        }
+        operation
+    }
+
+    // the length of the text the operation applies to
+    pub fn base_len(&self) -> usize {
+        self.0.iter().map(|c| match c {
+            Component::Retain(n) | Component::Delete(n) => *n,
+            Component::Insert(_) => 0,
+        }).sum()
+    }
+
+    // None when `content` is not as long as the operation expects
+    pub fn apply(&self, content: &str) -> Option<String> {
+        let mut chars = content.chars();
+        let mut applied = String::with_capacity(content.len());
+        if content.chars().count() != self.base_len() {
+            return None;
+        }
+        for component in &self.0 {
+            match component {
+                Component::Retain(n) => applied.extend(chars.by_ref().take(*n)),
+                Component::Insert(text) => applied.push_str(text),
+                Component::Delete(n) => {
+                    chars.by_ref().take(*n).for_each(drop);
+                },
+            }
+        }
+        Some(applied)
+    }
+}
+
+/**
+ * Transforms two operations made on the same text so each applies after the
+ * other: `a` then `b'` and `b` then `a'` end in the same text. Where both
+ * insert at the same spot the text of `a` comes first. None when the two
+ * were not made on text of the same length.
+ */
+pub fn transform(a: &TextOperation, b: &TextOperation) -> Option<(TextOperation, TextOperation)> {
+    if a.base_len() != b.base_len() {
+        return None;
+    }
+    let (mut a_prime, mut b_prime) = (TextOperation::new(), TextOperation::new());
+    let mut a_components = a.0.iter().cloned();
+    let mut b_components = b.0.iter().cloned();
+    let (mut op_a, mut op_b) = (a_components.next(), b_components.next());
+
+    loop {
+        match (&op_a, &op_b) {
+            (None, None) => break,
+            (Some(Component::Insert(text)), _) => {
+                a_prime = a_prime.insert(text);
+                b_prime = b_prime.retain(text.chars().count());
+                op_a = a_components.next();
+            },
+            (_, Some(Component::Insert(text))) => {
+                a_prime = a_prime.retain(text.chars().count());
+                b_prime = b_prime.insert(text);
+                op_b = b_components.next();
+            },
+            (Some(first), Some(second)) => {
+                let (n, m) = (count(first), count(second));
+                let step = n.min(m);
+                match (first, second) {
+                    (Component::Retain(_), Component::Retain(_)) => {
+                        a_prime = a_prime.retain(step);
+                        b_prime = b_prime.retain(step);
+                    },
+                    (Component::Delete(_), Component::Retain(_)) => a_prime = a_prime.delete(step),
+                    (Component::Retain(_), Component::Delete(_)) => b_prime = b_prime.delete(step),
+                    // both deleted the same text, nothing is left to do
+                    _ => {},
+                }
+                op_a = if n > step { Some(shorten(first, n - step)) } else { a_components.next() };
+                op_b = if m > step { Some(shorten(second, m - step)) } else { b_components.next() };
+            },
+            // the base lengths match, so both run out together
+            _ => return None,
+        }
+    }
+    Some((a_prime, b_prime))
+}
+
+fn count(component: &Component) -> usize {
+    match component {
+        Component::Retain(n) | Component::Delete(n) => *n,
+        Component::Insert(text) => text.chars().count(),
+    }
+}
+
+fn shorten(component: &Component, n: usize) -> Component {
+    match component {
+        Component::Retain(_) => Component::Retain(n),
+        Component::Delete(_) => Component::Delete(n),
+        Component::Insert(text) => Component::Insert(text.clone()),
+    }
+}
+
+// where a cursor ends up after `operation`, text inserted at the cursor pushes it along
+pub fn transform_cursor(cursor: usize, operation: &TextOperation) -> usize {
+    let (mut old, mut new) = (0, 0);
+    for component in &operation.0 {
+        match component {
+            Component::Retain(n) => {
+                if cursor < old + n {
+                    return new + cursor - old;
+                }
+                old += n;
+                new += n;
+            },
+            Component::Insert(text) => new += text.chars().count(),
+            Component::Delete(n) => {
+                if cursor < old + n {
+                    return new;
+                }
+                old += n;
+            },
+        }
+    }
+    new + cursor.saturating_sub(old)
+}
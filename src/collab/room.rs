@@ -0,0 +1,421 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+use chrono::Utc;
+use mongodb::bson::{doc, oid::ObjectId};
+use rocket::tokio::sync::{broadcast, Mutex as AsyncMutex};
+use serde::{Serialize, Deserialize};
+use serde_with::skip_serializing_none;
+
+use crate::collab::ot::{transform, transform_cursor, TextOperation};
+use crate::models::document::Document;
+use crate::repository::mongodb_repo::MongoRepo;
+
+// operations kept to transform late ones against, clients further behind have to rejoin
+const MAX_HISTORY: usize = 1000;
+// messages a slow connection can fall behind by before it misses some
+const CHANNEL_CAPACITY: usize = 256;
+// saves that can run into a change made elsewhere before the room gives up until the next one
+const SAVE_ATTEMPTS: usize = 3;
+// the session changes made outside the room are applied as
+const SERVER_SESSION: u64 = 0;
+
+// a caret, with `anchor` at the other end when text is selected
+#[skip_serializing_none]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Cursor {
+    pub pos: usize,
+    pub anchor: Option<usize>,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Peer {
+    pub session: u64,
+    pub user_id: Option<ObjectId>,
+    pub name: Option<String>,
+    pub can_edit: bool,
+    pub cursor: Option<Cursor>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ClientMessage {
+    // `operation` was made on the content as of revision `rev`
+    Op { rev: u64, operation: TextOperation },
+    Cursor { cursor: Option<Cursor> },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ServerMessage {
+    Init { session: u64, rev: u64, content: String, peers: Vec<Peer> },
+    // an operation from another session, to apply on top of revision `rev - 1`
+    Op { rev: u64, operation: TextOperation, session: u64 },
+    // the sender's own operation was applied as revision `rev`
+    Ack { rev: u64 },
+    Presence { peers: Vec<Peer> },
+    Error { message: String },
+}
+
+// what the sessions of a room hear, messages for their client and instructions for themselves
+#[derive(Debug, Clone)]
+pub enum RoomEvent {
+    Message(ServerMessage),
+    // access to the document may have changed, every session checks its own again
+    Recheck,
+    // the room is gone, the sessions end with the reason
+    Closed(String),
+}
+
+/**
+ * The live state of a document while anyone has it open. Operations are
+ * transformed against everything applied since the revision they were made
+ * on, applied in arrival order and handed to every session. Revisions here
+ * count operations since the room opened, they are not document revisions.
+ * `base` is the stored content as of `version`, everything past it is unsaved.
+ */
+pub struct Room {
+    repo: MongoRepo,
+    document_id: ObjectId,
+    content: String,
+    rev: u64,
+    history: VecDeque<TextOperation>,
+    base: String,
+    version: i64,
+    closed: bool,
+    last_editor: Option<ObjectId>,
+    peers: HashMap<u64, Peer>,
+    events: broadcast::Sender<RoomEvent>,
+}
+
+impl Room {
+    fn new(repo: MongoRepo, document: &Document) -> Option<Room> {
+        let (events, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let content = document.content.clone().unwrap_or_default();
+        Some(Room {
+            repo,
+            document_id: document.id?,
+            content: content.clone(),
+            rev: 0,
+            history: VecDeque::new(),
+            base: content,
+            version: document.version(),
+            closed: false,
+            last_editor: None,
+            peers: HashMap::new(),
+            events,
+        })
+    }
+
+    fn peers(&self) -> Vec<Peer> {
+        let mut peers: Vec<Peer> = self.peers.values().cloned().collect();
+        peers.sort_by_key(|peer| peer.session);
+        peers
+    }
+
+    fn broadcast(&self, message: ServerMessage) {
+        self.notify(RoomEvent::Message(message));
+    }
+
+    fn notify(&self, event: RoomEvent) {
+        // nobody listening is fine, the last session may just have left
+        let _ = self.events.send(event);
+    }
+
+    pub fn join(&mut self, peer: Peer) -> (ServerMessage, broadcast::Receiver<RoomEvent>) {
+        let session = peer.session;
+        let events = self.events.subscribe();
+        self.peers.insert(session, peer);
+        self.broadcast(ServerMessage::Presence { peers: self.peers() });
+        (ServerMessage::Init { session, rev: self.rev, content: self.content.clone(), peers: self.peers() }, events)
+    }
+
+    // returns whether the room is empty now
+    fn leave(&mut self, session: u64) -> bool {
+        self.peers.remove(&session);
+        self.broadcast(ServerMessage::Presence { peers: self.peers() });
+        self.peers.is_empty()
+    }
+
+    pub fn move_cursor(&mut self, session: u64, cursor: Option<Cursor>) {
+        if let Some(peer) = self.peers.get_mut(&session) {
+            peer.cursor = cursor;
+            self.broadcast(ServerMessage::Presence { peers: self.peers() });
+        }
+    }
+
+    // after a session checked its access again, editing may have been granted or taken away
+    pub fn set_can_edit(&mut self, session: u64, can_edit: bool) {
+        if let Some(peer) = self.peers.get_mut(&session) {
+            if peer.can_edit != can_edit {
+                peer.can_edit = can_edit;
+                self.broadcast(ServerMessage::Presence { peers: self.peers() });
+            }
+        }
+    }
+
+    /**
+     * Brings an operation made on revision `rev` up to date and applies it.
+     * The error is meant for the client, which should rejoin to resync.
+     */
+    pub fn submit(&mut self, session: u64, rev: u64, operation: TextOperation) -> Result<u64, String> {
+        let peer = self.peers.get(&session).ok_or("Not in this document")?;
+        if !peer.can_edit {
+            return Err("You can not edit this document".to_string());
+        }
+        let user_id = peer.user_id;
+        if rev > self.rev {
+            return Err(format!("Revision {} is ahead of the document at {}", rev, self.rev));
+        }
+        let behind = (self.rev - rev) as usize;
+        if behind > self.history.len() {
+            return Err(format!("Revision {} is too old, rejoin to catch up", rev));
+        }
+
+        let mut operation = operation;
+        for applied in self.history.iter().skip(self.history.len() - behind) {
+            // operations already applied win ties, so take the second half
+            operation = transform(applied, &operation).ok_or("The operation does not fit the document")?.1;
+        }
+        self.apply(session, operation).ok_or("The operation does not fit the document")?;
+        self.last_editor = user_id;
+        Ok(self.rev)
+    }
+
+    // applies an up to date operation as the next revision and hands it to every session
+    fn apply(&mut self, session: u64, operation: TextOperation) -> Option<u64> {
+        self.content = operation.apply(&self.content)?;
+
+        for peer in self.peers.values_mut() {
+            if let Some(cursor) = peer.cursor.as_mut() {
+                cursor.pos = transform_cursor(cursor.pos, &operation);
+                cursor.anchor = cursor.anchor.map(|anchor| transform_cursor(anchor, &operation));
+            }
+        }
+        self.rev += 1;
+        self.history.push_back(operation.clone());
+        if self.history.len() > MAX_HISTORY {
+            self.history.pop_front();
+        }
+        self.broadcast(ServerMessage::Op { rev: self.rev, operation, session });
+        Some(self.rev)
+    }
+
+    /**
+     * Takes in a change made to the stored document outside the room. The
+     * change from the base to `document` is transformed against everything
+     * unsaved and applied like an operation of its own, so the sessions keep
+     * their edits. False when the two can not be combined.
+     */
+    pub fn merge(&mut self, document: &Document) -> bool {
+        let stored = document.content.clone().unwrap_or_default();
+        let unsaved = TextOperation::between(&self.base, &self.content);
+        let external = TextOperation::between(&self.base, &stored);
+        let external = match transform(&unsaved, &external) {
+            Some((_, external)) => external,
+            None => return false,
+        };
+        if self.base != stored && self.apply(SERVER_SESSION, external).is_none() {
+            return false;
+        }
+        self.base = stored;
+        self.version = document.version();
+        true
+    }
+
+    // what has to be written back, None while nothing changed since the last save
+    fn unsaved(&self) -> Option<Snapshot> {
+        if self.closed || self.content == self.base {
+            return None;
+        }
+        Some(Snapshot {
+            repo: self.repo.clone(),
+            document_id: self.document_id,
+            content: self.content.clone(),
+            version: self.version,
+            author_id: self.last_editor,
+        })
+    }
+
+    // the room's content was stored as `document`
+    fn saved(&mut self, snapshot: Snapshot, document: &Document) {
+        self.base = snapshot.content;
+        self.version = document.version();
+    }
+
+    fn close(&mut self, reason: &str) {
+        self.closed = true;
+        self.notify(RoomEvent::Closed(reason.to_string()));
+    }
+}
+
+struct Snapshot {
+    repo: MongoRepo,
+    document_id: ObjectId,
+    content: String,
+    // the version the content was made on, the save only goes through while the document is still at it
+    version: i64,
+    author_id: Option<ObjectId>,
+}
+
+enum Saved {
+    Written(Document),
+    // someone changed the document outside the room since, this is the document now
+    Changed(Document),
+    // deleted or moved to the trash
+    Gone,
+}
+
+impl Snapshot {
+    // writes the content like an update would, as a new document revision
+    async fn save(&self) -> Result<Saved, Box<dyn std::error::Error>> {
+        let document = match self.repo.find_document(doc! {"_id": self.document_id}).await? {
+            Some(document) if document.version() != self.version => return Ok(Saved::Changed(document)),
+            Some(document) => document,
+            None => return Ok(Saved::Gone),
+        };
+        self.repo.ensure_initial_revision(&document).await?;
+        let changes = Document {
+            content: Some(self.content.clone()),
+            last_modified: Some(Utc::now()),
+            last_modified_by: self.author_id,
+            ..Default::default()
+        };
+        let updated = self.repo.update_document(&self.document_id.to_string(), changes, Some(self.version)).await?;
+        let document = match updated {
+            Some(document) => document,
+            // changed between reading and writing, the next attempt picks that up
+            None => return match self.repo.find_document(doc! {"_id": self.document_id}).await? {
+                Some(document) => Ok(Saved::Changed(document)),
+                None => Ok(Saved::Gone),
+            },
+        };
+        self.repo.record_revision(&document, self.author_id, None).await?;
+        Ok(Saved::Written(document))
+    }
+}
+
+// rooms are per organization as well as per document, ids are only unique within a database
+type RoomKey = (Option<ObjectId>, ObjectId);
+
+// every open room, shared by all connections
+#[derive(Clone, Default)]
+pub struct Rooms {
+    rooms: Arc<Mutex<HashMap<RoomKey, Arc<Mutex<Room>>>>>,
+    // one save at a time, so the same changes are not recorded twice
+    saving: Arc<AsyncMutex<()>>,
+}
+
+impl Rooms {
+    pub fn find(&self, org_id: Option<ObjectId>, document_id: ObjectId) -> Option<Arc<Mutex<Room>>> {
+        self.rooms.lock().unwrap().get(&(org_id, document_id)).cloned()
+    }
+
+    // the open rooms of an organization
+    pub fn of_organization(&self, org_id: Option<ObjectId>) -> Vec<Arc<Mutex<Room>>> {
+        let rooms = self.rooms.lock().unwrap();
+        rooms.iter().filter(|((org, _), _)| *org == org_id).map(|(_, room)| room.clone()).collect()
+    }
+
+    // the open room of `document`, or a new one starting from the stored content
+    pub fn open(&self, repo: &MongoRepo, document: &Document) -> Option<Arc<Mutex<Room>>> {
+        let key = (repo.org_id(), document.id?);
+        let mut rooms = self.rooms.lock().unwrap();
+        if let Some(room) = rooms.get(&key) {
+            return Some(room.clone());
+        }
+        let room = Arc::new(Mutex::new(Room::new(repo.clone(), document)?));
+        rooms.insert(key, room.clone());
+        Some(room)
+    }
+
+    /**
+     * The last session to leave saves the room and closes it. The room stays
+     * open while saving, so someone joining meanwhile does not start over from
+     * the stored content; it is only closed if still empty afterwards.
+     */
+    pub async fn leave(&self, room: &Arc<Mutex<Room>>, session: u64) {
+        let empty = room.lock().unwrap().leave(session);
+        if !empty {
+            return;
+        }
+        self.save(room).await;
+
+        let mut rooms = self.rooms.lock().unwrap();
+        if room.lock().unwrap().peers.is_empty() {
+            rooms.retain(|_, other| !Arc::ptr_eq(other, room));
+        }
+    }
+
+    // writes every room with changes back to its document
+    pub async fn save_all(&self) {
+        let rooms: Vec<Arc<Mutex<Room>>> = self.rooms.lock().unwrap().values().cloned().collect();
+        for room in rooms {
+            self.save(&room).await;
+        }
+    }
+
+    // sessions end and nobody can join the room anymore, what it did not save is lost
+    pub fn close(&self, room: &Arc<Mutex<Room>>, reason: &str) {
+        self.rooms.lock().unwrap().retain(|_, other| !Arc::ptr_eq(other, room));
+        room.lock().unwrap().close(reason);
+    }
+
+    /**
+     * Saves with the version the room last read, so a change made elsewhere
+     * in the meantime is not overwritten. Such a change is merged into the
+     * room and the save tried again; a room whose document was deleted closes.
+     */
+    async fn save(&self, room: &Arc<Mutex<Room>>) {
+        let _saving = self.saving.lock().await;
+        for _ in 0..SAVE_ATTEMPTS {
+            let unsaved = room.lock().unwrap().unsaved();
+            let snapshot = match unsaved {
+                Some(snapshot) => snapshot,
+                None => return,
+            };
+            let document_id = snapshot.document_id;
+            match snapshot.save().await {
+                Ok(Saved::Written(document)) => {
+                    room.lock().unwrap().saved(snapshot, &document);
+                    return;
+                },
+                Ok(Saved::Changed(document)) => {
+                    if !room.lock().unwrap().merge(&document) {
+                        self.close(room, "The document changed elsewhere, rejoin to catch up");
+                        return;
+                    }
+                },
+                Ok(Saved::Gone) => {
+                    self.close(room, "The document was deleted");
+                    return;
+                },
+                Err(e) => {
+                    println!("Error saving document {}: {}", document_id, e);
+                    return;
+                },
+            }
+        }
+    }
+
+    // brings the room up to date with a document changed elsewhere, unless the room saved that itself
+    pub async fn refresh(&self, room: &Arc<Mutex<Room>>, document: &Document) {
+        // a save in progress updates the version before the lock is free
+        let _saving = self.saving.lock().await;
+        let mut locked = room.lock().unwrap();
+        if locked.closed || document.version() <= locked.version {
+            return;
+        }
+        if !locked.merge(document) {
+            drop(locked);
+            self.close(room, "The document changed elsewhere, rejoin to catch up");
+        }
+    }
+
+    // every session of the room checks it can still read the document
+    pub fn recheck(&self, room: &Mutex<Room>) {
+        room.lock().unwrap().notify(RoomEvent::Recheck);
+    }
+}
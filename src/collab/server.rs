@@ -0,0 +1,292 @@
+use std::{
+    env,
+    sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex},
+    time::Duration,
+};
+use mongodb::bson::{doc, oid::ObjectId};
+use rocket::futures::{SinkExt, StreamExt};
+use rocket::http::Status;
+use rocket::tokio::{self, net::{TcpListener, TcpStream}, sync::broadcast::error::RecvError, time::{self, Instant}};
+use tokio_tungstenite::{
+    accept_hdr_async,
+    tungstenite::{handshake::server::{ErrorResponse, Request, Response}, http, Message},
+    WebSocketStream,
+};
+
+use crate::api::share::{authorize_document, get_principal};
+use crate::collab::room::{ClientMessage, Peer, Room, RoomEvent, Rooms, ServerMessage};
+use crate::helpers::jwt::{check_token, jwt_validate, AuthObject};
+use crate::models::{document::Document, event::EventKind, share::{Permission, Principal}};
+use crate::repository::mongodb_repo::MongoRepo;
+
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(1);
+// how often a session checks its access again, on top of the share and user changes it hears about
+const RECHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// the port the editing socket listens on, COLLAB_PORT (default 8001)
+pub fn collab_port() -> u16 {
+    match env::var("COLLAB_PORT") {
+        Ok(v) => v.parse().unwrap_or(8001),
+        Err(_) => 8001,
+    }
+}
+
+// how often open documents are written back, COLLAB_SAVE_SECONDS (default 10)
+pub fn save_interval() -> Duration {
+    let seconds = match env::var("COLLAB_SAVE_SECONDS") {
+        Ok(v) => v.parse().unwrap_or(10),
+        Err(_) => 10,
+    };
+    Duration::from_secs(seconds.max(1))
+}
+
+/**
+ * Serves live editing at ws://host:COLLAB_PORT/documents/<id>. Rocket can not
+ * upgrade connections, so the sockets get a listener of their own next to it.
+ */
+pub async fn serve(db: MongoRepo) {
+    let listener = match TcpListener::bind(("0.0.0.0", collab_port())).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Error starting collaborative editing: {}", e);
+            return;
+        },
+    };
+    let rooms = Rooms::default();
+    tokio::spawn(save_open_documents(rooms.clone()));
+    tokio::spawn(follow_events(db.clone(), rooms.clone()));
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                tokio::spawn(connect(db.clone(), rooms.clone(), stream));
+            },
+            Err(e) => println!("Error accepting an editing connection: {}", e),
+        }
+    }
+}
+
+async fn save_open_documents(rooms: Rooms) {
+    let mut interval = time::interval(save_interval());
+
+    loop {
+        interval.tick().await;
+        rooms.save_all().await;
+    }
+}
+
+/**
+ * Follows changes made outside the rooms: deleting a document closes its
+ * room, updates are merged into it, and shares or users changing make the
+ * sessions check their access again.
+ */
+async fn follow_events(db: MongoRepo, rooms: Rooms) {
+    let (_, mut subscription) = db.events().subscribe(None);
+
+    loop {
+        let published = match subscription.recv().await {
+            Ok(published) => published,
+            // saves still notice changes and deletions, and sessions recheck on their own
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        let room = published.event.document_id.and_then(|id| rooms.find(published.org_id, id));
+        match (&published.event.kind, room) {
+            (EventKind::DocumentDeleted, Some(room)) => rooms.close(&room, "The document was deleted"),
+            (EventKind::DocumentUpdated, Some(room)) => {
+                if let Some(document) = &published.document {
+                    rooms.refresh(&room, document).await;
+                }
+            },
+            (EventKind::DocumentShared, Some(room)) => rooms.recheck(&room),
+            (EventKind::UserUpdated | EventKind::UserDeleted, _) => {
+                for room in rooms.of_organization(published.org_id) {
+                    rooms.recheck(&room);
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+fn reject(status: Status) -> ErrorResponse {
+    let mut response = ErrorResponse::new(Some(status.reason_lossy().to_string()));
+    *response.status_mut() = http::StatusCode::from_u16(status.code).unwrap_or(http::StatusCode::BAD_REQUEST);
+    response
+}
+
+// the document id and token of an upgrade request, the token from ?token= or the authorization header
+fn parse_request(request: &Request) -> Result<(ObjectId, AuthObject), Status> {
+    let id = request.uri().path().strip_prefix("/documents/").ok_or(Status::NotFound)?;
+    let id = ObjectId::parse_str(id.trim_end_matches('/')).map_err(|_| Status::NotFound)?;
+    let query_token = request.uri().query().and_then(|query| {
+        query.split('&').find_map(|pair| pair.strip_prefix("token=")).map(str::to_string)
+    });
+    let header_token = request.headers().get("authorization").and_then(|value| value.to_str().ok()).map(str::to_string);
+    let auth = query_token.or(header_token).map(|token| jwt_validate(&token)).ok_or(Status::Unauthorized)?;
+    if !auth.authorized {
+        return Err(Status::Unauthorized);
+    }
+    Ok((id, auth))
+}
+
+// the token's user has to still be active and able to read the document
+async fn access(repo: &MongoRepo, id: &ObjectId, auth: &AuthObject) -> Result<(Principal, Document), Status> {
+    check_token(repo, auth).await.map_err(|(status, _)| status)?;
+    let principal = get_principal(repo, auth).await?;
+    let document = authorize_document(repo, &principal, id, Permission::Viewer).await?;
+    Ok((principal, document))
+}
+
+fn can_edit(document: &Document, principal: &Principal) -> bool {
+    document.permission_for(principal).is_some_and(|permission| permission >= Permission::Editor)
+}
+
+// the room of the document for the token's user, with the repository of their organization
+async fn join(db: &MongoRepo, rooms: &Rooms, id: &ObjectId, auth: &AuthObject) -> Result<(Arc<Mutex<Room>>, Peer, MongoRepo), Status> {
+    let organization = match auth.org {
+        Some(org_id) => match db.find_organization(doc! {"_id": org_id}).await {
+            Ok(Some(organization)) => Some(organization),
+            Ok(None) => return Err(Status::NotFound),
+            Err(_) => return Err(Status::InternalServerError),
+        },
+        None => None,
+    };
+    let repo = db.for_organization(organization).await.map_err(|_| Status::InternalServerError)?;
+
+    let (principal, document) = access(&repo, id, auth).await?;
+    let peer = Peer {
+        session: NEXT_SESSION.fetch_add(1, Ordering::Relaxed),
+        user_id: principal.user.id,
+        name: principal.user.username.clone().or(principal.user.firstname.clone()),
+        can_edit: can_edit(&document, &principal),
+        cursor: None,
+    };
+    let room = rooms.open(&repo, &document).ok_or(Status::NotFound)?;
+    Ok((room, peer, repo))
+}
+
+// checks a session's access again, taking editing away or granting it; the error ends the session
+async fn recheck(repo: &MongoRepo, room: &Mutex<Room>, session: u64, id: &ObjectId, auth: &AuthObject) -> Result<(), Status> {
+    match access(repo, id, auth).await {
+        Ok((principal, document)) => {
+            room.lock().unwrap().set_can_edit(session, can_edit(&document, &principal));
+            Ok(())
+        },
+        // the database being unavailable for a moment does not end anyone's session
+        Err(status) if status == Status::InternalServerError => Ok(()),
+        Err(status) => Err(status),
+    }
+}
+
+async fn send(socket: &mut WebSocketStream<TcpStream>, message: &ServerMessage) -> bool {
+    let text = match serde_json::to_string(message) {
+        Ok(text) => text,
+        Err(_) => return false,
+    };
+    socket.send(Message::Text(text)).await.is_ok()
+}
+
+// what a client message changes in the room, with an error to send back when it can not be applied
+fn handle(room: &Mutex<Room>, session: u64, text: &str) -> Option<ServerMessage> {
+    let message = match serde_json::from_str::<ClientMessage>(text) {
+        Ok(message) => message,
+        Err(e) => return Some(ServerMessage::Error { message: format!("Invalid message: {}", e) }),
+    };
+    let mut room = room.lock().unwrap();
+    match message {
+        ClientMessage::Op { rev, operation } => room.submit(session, rev, operation).err().map(|message| ServerMessage::Error { message }),
+        ClientMessage::Cursor { cursor } => {
+            room.move_cursor(session, cursor);
+            None
+        },
+    }
+}
+
+async fn connect(db: MongoRepo, rooms: Rooms, stream: TcpStream) {
+    let mut target = None;
+    // the error response is what tungstenite expects, it can not be boxed
+    #[allow(clippy::result_large_err)]
+    let callback = |request: &Request, response: Response| match parse_request(request) {
+        Ok(parsed) => {
+            target = Some(parsed);
+            Ok(response)
+        },
+        Err(status) => Err(reject(status)),
+    };
+    let mut socket = match accept_hdr_async(stream, callback).await {
+        Ok(socket) => socket,
+        Err(_) => return,
+    };
+    let (id, auth) = match target {
+        Some(target) => target,
+        None => return,
+    };
+
+    let (room, peer, repo) = match join(&db, &rooms, &id, &auth).await {
+        Ok(joined) => joined,
+        Err(status) => {
+            let message = ServerMessage::Error { message: status.reason_lossy().to_string() };
+            send(&mut socket, &message).await;
+            let _ = socket.close(None).await;
+            return;
+        },
+    };
+    let session = peer.session;
+    let (init, mut events) = room.lock().unwrap().join(peer);
+    let mut rechecks = time::interval_at(Instant::now() + RECHECK_INTERVAL, RECHECK_INTERVAL);
+    if send(&mut socket, &init).await {
+        loop {
+            tokio::select! {
+                message = socket.next() => match message {
+                    Some(Ok(Message::Text(text))) => {
+                        let reply = handle(&room, session, &text);
+                        if let Some(reply) = reply {
+                            if !send(&mut socket, &reply).await {
+                                break;
+                            }
+                        }
+                    },
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    // pings are answered by the socket itself
+                    Some(Ok(_)) => {},
+                },
+                _ = rechecks.tick() => {
+                    if let Err(status) = recheck(&repo, &room, session, &id, &auth).await {
+                        send(&mut socket, &ServerMessage::Error { message: status.reason_lossy().to_string() }).await;
+                        break;
+                    }
+                },
+                event = events.recv() => {
+                    let message = match event {
+                        // the sender only needs to hear its operation went through
+                        Ok(RoomEvent::Message(ServerMessage::Op { rev, session: from, .. })) if from == session => ServerMessage::Ack { rev },
+                        Ok(RoomEvent::Message(message)) => message,
+                        Ok(RoomEvent::Recheck) => {
+                            if let Err(status) = recheck(&repo, &room, session, &id, &auth).await {
+                                send(&mut socket, &ServerMessage::Error { message: status.reason_lossy().to_string() }).await;
+                                break;
+                            }
+                            continue;
+                        },
+                        Ok(RoomEvent::Closed(message)) => {
+                            send(&mut socket, &ServerMessage::Error { message }).await;
+                            break;
+                        },
+                        Err(RecvError::Lagged(_)) => {
+                            let message = ServerMessage::Error { message: "Missed changes, rejoin to catch up".to_string() };
+                            send(&mut socket, &message).await;
+                            break;
+                        },
+                        Err(RecvError::Closed) => break,
+                    };
+                    if !send(&mut socket, &message).await {
+                        break;
+                    }
+                },
+            }
+        }
+    }
+    let _ = socket.close(None).await;
+    rooms.leave(&room, session).await;
+}
@@ -0,0 +1,111 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+use chrono::Utc;
+use mongodb::bson::oid::ObjectId;
+use rocket::tokio::sync::broadcast;
+
+use crate::models::{document::Document, event::{Event, EventKind}, user::User};
+
+// events kept for subscribers resuming with Last-Event-ID
+const HISTORY: usize = 1000;
+// events a slow subscriber can fall behind by before it misses some
+const CHANNEL_CAPACITY: usize = 1024;
+
+// an event with what subscribers need to decide whether it is theirs
+pub struct Published {
+    pub seq: u64,
+    pub org_id: Option<ObjectId>,
+    pub event: Event,
+    // the document as of the event, from the trash once deleted; None for user events
+    pub document: Option<Document>,
+}
+
+pub type Subscription = broadcast::Receiver<Arc<Published>>;
+
+struct History {
+    next_seq: u64,
+    recent: VecDeque<Arc<Published>>,
+}
+
+/**
+ * Carries every change to a document or user to whoever listens, across all
+ * organizations. Subscribers filter by organization and access themselves.
+ * Only the latest events are kept, and only in memory, so a subscriber that
+ * was away for long or across a restart is told it missed some instead.
+ */
+#[derive(Clone)]
+pub struct EventBus {
+    boot: i64,
+    history: Arc<Mutex<History>>,
+    sender: broadcast::Sender<Arc<Published>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        EventBus {
+            boot: Utc::now().timestamp_millis(),
+            history: Arc::new(Mutex::new(History { next_seq: 1, recent: VecDeque::new() })),
+            sender,
+        }
+    }
+
+    pub fn publish_document(&self, org_id: Option<ObjectId>, kind: EventKind, document: Document) {
+        self.publish(org_id, Event::for_document(kind, &document), Some(document));
+    }
+
+    pub fn publish_user(&self, org_id: Option<ObjectId>, kind: EventKind, user: &User) {
+        self.publish(org_id, Event::for_user(kind, user), None);
+    }
+
+    // numbers the event and hands it out
+    fn publish(&self, org_id: Option<ObjectId>, event: Event, document: Option<Document>) {
+        let mut history = self.history.lock().unwrap();
+        let seq = history.next_seq;
+        history.next_seq += 1;
+        let event = Event { id: format!("{}-{}", self.boot, seq), ..event };
+        let published = Arc::new(Published { seq, org_id, event, document });
+
+        history.recent.push_back(published.clone());
+        if history.recent.len() > HISTORY {
+            history.recent.pop_front();
+        }
+        // sent under the lock, so subscribers see events in order and none twice
+        let _ = self.sender.send(published);
+    }
+
+    /**
+     * Subscribes to new events. With the id of the last event a subscriber
+     * saw, the events since then come first; None instead of those when they
+     * are no longer known.
+     */
+    pub fn subscribe(&self, last_event_id: Option<&str>) -> (Option<Vec<Arc<Published>>>, Subscription) {
+        let history = self.history.lock().unwrap();
+        let subscription = self.sender.subscribe();
+        let last_seq = match last_event_id {
+            Some(id) => match self.parse_id(id) {
+                Some(seq) => seq,
+                None => return (None, subscription),
+            },
+            None => return (Some(Vec::new()), subscription),
+        };
+        // the event right after the last one seen has to still be here, unless nothing happened since
+        let oldest = history.recent.front().map(|published| published.seq).unwrap_or(history.next_seq);
+        if last_seq + 1 < oldest || last_seq >= history.next_seq {
+            return (None, subscription);
+        }
+        let missed = history.recent.iter().filter(|published| published.seq > last_seq).cloned().collect();
+        (Some(missed), subscription)
+    }
+
+    // the sequence number of an event id from this run
+    fn parse_id(&self, id: &str) -> Option<u64> {
+        let (boot, seq) = id.trim().split_once('-')?;
+        if boot.parse::<i64>().ok()? != self.boot {
+            return None;
+        }
+        seq.parse().ok()
+    }
+}
@@ -0,0 +1 @@
+pub mod bus;
\ No newline at end of file
@@ -0,0 +1,125 @@
+use similar::{ChangeTag, DiffTag, TextDiff};
+
+use crate::models::diff::{ChangeKind, DiffChange, DiffHunk, Granularity};
+
+fn text_diff<'a>(old: &'a str, new: &'a str, granularity: Granularity) -> TextDiff<'a, 'a, 'a, str> {
+    match granularity {
+        Granularity::Line => TextDiff::from_lines(old, new),
+        Granularity::Word => TextDiff::from_words(old, new),
+    }
+}
+
+fn change_kind(tag: ChangeTag) -> ChangeKind {
+    match tag {
+        ChangeTag::Equal => ChangeKind::Equal,
+        ChangeTag::Insert => ChangeKind::Insert,
+        ChangeTag::Delete => ChangeKind::Delete,
+    }
+}
+
+// groups changes into hunks with `context` unchanged lines (or words) around them
+pub fn diff_hunks(old: &str, new: &str, granularity: Granularity, context: usize) -> Vec<DiffHunk> {
+    let diff = text_diff(old, new, granularity);
+
+    diff.grouped_ops(context)
+        .iter()
+        .filter(|group| !group.is_empty())
+        .map(|group| {
+            let first = &group[0];
+            let last = &group[group.len() - 1];
+            let old_start = first.old_range().start;
+            let new_start = first.new_range().start;
+            let mut changes: Vec<DiffChange> = Vec::new();
+
+            for op in group {
+                for change in diff.iter_changes(op) {
+                    let kind = change_kind(change.tag());
+                    // merge runs of words so word hunks stay readable
+                    match changes.last_mut() {
+                        Some(previous) if granularity == Granularity::Word && previous.kind == kind => {
+                            previous.value.push_str(change.value());
+                        },
+                        _ => changes.push(DiffChange { kind, value: change.value().to_string() }),
+                    }
+                }
+            }
+
+            DiffHunk {
+                old_start: old_start + 1,
+                old_len: last.old_range().end - old_start,
+                new_start: new_start + 1,
+                new_len: last.new_range().end - new_start,
+                changes,
+            }
+        })
+        .collect()
+}
+
+/**
+ * Renders the diff as unified-diff text. Word granularity keeps the hunk
+ * layout but marks changes inline the way `git diff --word-diff` does,
+ * [-removed-]{+added+}.
+ */
+pub fn unified_diff(old: &str, new: &str, granularity: Granularity, context: usize, from: &str, to: &str) -> String {
+    if granularity == Granularity::Line {
+        return text_diff(old, new, granularity)
+            .unified_diff()
+            .context_radius(context)
+            .header(from, to)
+            .to_string();
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", from, to);
+    for hunk in diff_hunks(old, new, granularity, context) {
+        out.push_str(&format!(
+            "@@ -{},{} +{},{} @@\n",
+            hunk.old_start, hunk.old_len, hunk.new_start, hunk.new_len
+        ));
+        for change in hunk.changes {
+            match change.kind {
+                ChangeKind::Equal => out.push_str(&change.value),
+                ChangeKind::Delete => out.push_str(&format!("[-{}-]", change.value)),
+                ChangeKind::Insert => out.push_str(&format!("{{+{}+}}", change.value)),
+            }
+        }
+        if !out.ends_with('\n') {
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/**
+ * Carries the character range `start..end` of `old` over to `new`. Changes
+ * before the range shift it, changes after it leave it alone, and None means
+ * a change touched the range itself so it can not be carried over. Text
+ * inserted right at the start lands before the range, right at the end after it.
+ */
+pub fn rebase_range(old: &str, new: &str, start: usize, end: usize) -> Option<(usize, usize)> {
+    let diff = TextDiff::from_chars(old, new);
+    let (mut start_shift, mut end_shift) = (0_isize, 0_isize);
+
+    for op in diff.ops() {
+        if op.tag() == DiffTag::Equal {
+            continue;
+        }
+        let (old_range, new_range) = (op.old_range(), op.new_range());
+        let overlaps = if start == end {
+            old_range.start < start && old_range.end > start
+        } else {
+            old_range.start < end && old_range.end > start && !(old_range.is_empty() && old_range.start == start)
+        };
+        if overlaps {
+            return None;
+        }
+        let shift = new_range.len() as isize - old_range.len() as isize;
+        if old_range.end <= start {
+            start_shift += shift;
+        }
+        // text inserted at the end of a non-empty range stays outside of it
+        if old_range.end <= end && (start == end || old_range.start < end) {
+            end_shift += shift;
+        }
+    }
+    Some(((start as isize + start_shift) as usize, (end as isize + end_shift) as usize))
+}
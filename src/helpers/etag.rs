@@ -0,0 +1,68 @@
+use rocket::{
+    http::Status,
+    request::{FromRequest, Outcome},
+    response::{self, Responder},
+    Request, Response,
+};
+
+// wraps a response and adds an ETag header built from a document version
+pub struct Tagged<T>(pub T, pub i64);
+
+impl<'r, T: Responder<'r, 'static>> Responder<'r, 'static> for Tagged<T> {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        Response::build_from(self.0.respond_to(req)?)
+            .raw_header("ETag", format!("\"{}\"", self.1))
+            .ok()
+    }
+}
+
+/**
+ * Reads an If-Match header into the version it expects, `None` for `*`. A
+ * missing header matches any version too, unless the header is `required`.
+ */
+pub fn parse_if_match(header: Option<&str>, required: bool) -> Result<Option<i64>, (Status, &'static str)> {
+    let header = match header.map(str::trim) {
+        Some(h) => h,
+        None if required => return Err((Status::PreconditionRequired, "If-Match is required, send the ETag or `*`")),
+        None => return Ok(None),
+    };
+    if header == "*" {
+        return Ok(None);
+    }
+
+    let version = header.trim_start_matches("W/").trim_matches('"');
+    version.parse().map(Some).map_err(|_| (Status::BadRequest, "If-Match must hold an ETag returned by the API"))
+}
+
+/**
+ * The version a client expects to change, taken from the If-Match header.
+ * `None` when the header is missing or `*`, in which case any version matches.
+ */
+pub struct IfMatch(pub Option<i64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IfMatch {
+    type Error = &'r str;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match parse_if_match(req.headers().get_one("if-match"), false) {
+            Ok(version) => Outcome::Success(IfMatch(version)),
+            Err(failure) => Outcome::Failure(failure),
+        }
+    }
+}
+
+// like IfMatch, but a missing header answers 428 so writes are never blind by accident
+pub struct RequiredIfMatch(pub Option<i64>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for RequiredIfMatch {
+    type Error = &'r str;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match parse_if_match(req.headers().get_one("if-match"), true) {
+            Ok(version) => Outcome::Success(RequiredIfMatch(version)),
+            Err(failure) => Outcome::Failure(failure),
+        }
+    }
+}
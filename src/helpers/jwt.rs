@@ -1,26 +1,43 @@
 use chrono::{Duration, Local};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
+use mongodb::bson::{doc, oid::ObjectId, Document};
 use rocket::{http::Status, request::{FromRequest, Outcome}, Request};
 use serde::{Deserialize, Serialize};
 
+use crate::models::user::User;
+use crate::repository::mongodb_repo::MongoRepo;
+
 #[derive(Debug, Serialize, Deserialize)]
 struct Claims {
     exp: usize,
+    // the user's id as hex, ids never change while emails can
     user: String,
+    // the user's organization, tokens without one belong to the default organization
+    #[serde(default)]
+    org: Option<ObjectId>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthObject {
     pub authorized: bool,
     pub user: String,
+    pub org: Option<ObjectId>,
+}
+
+impl AuthObject {
+    // finds the token's user, tokens from before they carried ids find nobody
+    pub fn user_filter(&self) -> Document {
+        doc! {"_id": ObjectId::parse_str(&self.user).ok()}
+    }
 }
 
-pub fn jwt_sign(user: &str) -> String {
+pub fn jwt_sign(user: &str, org: Option<ObjectId>) -> String {
     let exp = Local::now() + Duration::days(10);
 
     let my_claims = Claims {
         user: user.to_string(),
         exp: exp.timestamp() as usize,
+        org,
     };
 
     let token = encode(
@@ -44,16 +61,37 @@ pub fn jwt_validate(token: &str) -> AuthObject {
             // get user with decoded token and store
             return AuthObject {
                 authorized: true,
-                user: t.claims.user
+                user: t.claims.user,
+                org: t.claims.org,
             };
         }, 
-        _ => return AuthObject { authorized: false, user: "".to_string() },
+        _ => return AuthObject { authorized: false, user: "".to_string(), org: None },
     };
 }
 
 // #[derive(Debug)]
 // pub struct Auth(AuthObject);
 
+// whether the user a token was issued to may still use it
+pub fn check_token_user(user: Option<&User>) -> Result<(), &'static str> {
+    match user {
+        Some(user) if user.is_active() => Ok(()),
+        Some(_) => Err("User is deactivated"),
+        None => Err("User no longer exists"),
+    }
+}
+
+/**
+ * Tokens stay valid until they expire, so deactivated, deleted and purged
+ * users are rejected here. `db` is the organization the token was issued for.
+ */
+pub async fn check_token(db: &MongoRepo, auth: &AuthObject) -> Result<(), (Status, &'static str)> {
+    match db.find_user(auth.user_filter()).await {
+        Ok(user) => check_token_user(user.as_ref()).map_err(|reason| (Status::Unauthorized, reason)),
+        Err(_) => Err((Status::InternalServerError, "User could not be checked")),
+    }
+}
+
 #[rocket::async_trait]
 impl<'r> FromRequest<'r> for AuthObject {
     type Error = &'r str;
@@ -69,6 +107,13 @@ impl<'r> FromRequest<'r> for AuthObject {
             return Outcome::Failure((Status::Unauthorized, "User is not authorized"));
         }
 
-        Outcome::Success(validate)
+        let db = match req.guard::<&MongoRepo>().await {
+            Outcome::Success(db) => db,
+            _ => return Outcome::Failure((Status::Unauthorized, "Organization not found")),
+        };
+        match check_token(db, &validate).await {
+            Ok(()) => Outcome::Success(validate),
+            Err(failure) => Outcome::Failure(failure),
+        }
     }
 }
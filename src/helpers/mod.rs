@@ -1,2 +1,10 @@
+pub mod diff;
+pub mod etag;
 pub mod jwt;
-pub mod mongo_id;
\ No newline at end of file
+pub mod mongo_id;
+pub mod password;
+pub mod password_policy;
+pub mod patch;
+pub mod scim;
+pub mod search;
+pub mod tenant;
\ No newline at end of file
@@ -0,0 +1,106 @@
+use std::{env, sync::OnceLock};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version
+};
+
+/**
+ * Argon2id cost parameters and optional pepper, read once from
+ * ARGON2_MEMORY_KIB, ARGON2_ITERATIONS, ARGON2_PARALLELISM and PASSWORD_PEPPER.
+ * Unset values fall back to the argon2 crate defaults.
+ */
+pub struct PasswordConfig {
+    params: Params,
+    pepper: Option<String>,
+}
+
+static PASSWORD_CONFIG: OnceLock<PasswordConfig> = OnceLock::new();
+
+fn env_u32(name: &str, default: u32) -> u32 {
+    match env::var(name) {
+        Ok(v) => v.parse().unwrap_or_else(|_| panic!("{} must be a positive number", name)),
+        Err(_) => default,
+    }
+}
+
+impl PasswordConfig {
+    pub fn from_env() -> Self {
+        let params = Params::new(
+            env_u32("ARGON2_MEMORY_KIB", Params::DEFAULT_M_COST),
+            env_u32("ARGON2_ITERATIONS", Params::DEFAULT_T_COST),
+            env_u32("ARGON2_PARALLELISM", Params::DEFAULT_P_COST),
+            None,
+        ).unwrap_or_else(|e| panic!("Invalid Argon2 parameters: {}", e));
+        let pepper = env::var("PASSWORD_PEPPER").ok().filter(|p| !p.is_empty());
+
+        PasswordConfig { params, pepper }
+    }
+
+    pub fn get() -> &'static PasswordConfig {
+        PASSWORD_CONFIG.get_or_init(PasswordConfig::from_env)
+    }
+
+    fn argon2(&self) -> Argon2<'_> {
+        match &self.pepper {
+            Some(pepper) => Argon2::new_with_secret(pepper.as_bytes(), Algorithm::Argon2id, Version::V0x13, self.params.clone()).unwrap(),
+            None => Argon2::new(Algorithm::Argon2id, Version::V0x13, self.params.clone()),
+        }
+    }
+
+    // hashes made with another algorithm, version or cost need upgrading
+    fn is_current(&self, hash: &PasswordHash) -> bool {
+        if hash.algorithm != Algorithm::Argon2id.ident() || hash.version != Some(Version::V0x13.into()) {
+            return false;
+        }
+        match Params::try_from(hash) {
+            Ok(p) => p.m_cost() == self.params.m_cost()
+                && p.t_cost() == self.params.t_cost()
+                && p.p_cost() == self.params.p_cost(),
+            Err(_) => false,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Verification {
+    Invalid,
+    Valid,
+    // the password matched but the stored hash should be replaced
+    ValidNeedsRehash,
+}
+
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    // Hash password to PHC string ($argon2id$v=19$...)
+    let password_hash = PasswordConfig::get().argon2().hash_password(password.as_bytes(), &salt).unwrap();
+
+    password_hash.to_string()
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> Verification {
+    let parsed_hash = match PasswordHash::new(password_hash) {
+        Ok(h) => h,
+        Err(_) => return Verification::Invalid,
+    };
+    let config = PasswordConfig::get();
+
+    if config.argon2().verify_password(password.as_bytes(), &parsed_hash).is_ok() {
+        return match config.is_current(&parsed_hash) {
+            true => Verification::Valid,
+            false => Verification::ValidNeedsRehash,
+        };
+    }
+
+    // hashes stored before a pepper was configured verify without it
+    if config.pepper.is_some() && Argon2::default().verify_password(password.as_bytes(), &parsed_hash).is_ok() {
+        return Verification::ValidNeedsRehash;
+    }
+
+    Verification::Invalid
+}
+
+// random, never disclosed secret for accounts that are provisioned without a password
+pub fn unusable_password() -> String {
+    let secret = SaltString::generate(&mut OsRng);
+    hash_password(secret.as_str())
+}
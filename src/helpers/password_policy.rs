@@ -0,0 +1,167 @@
+use std::{env, fs, path::PathBuf, sync::OnceLock};
+use chrono::{DateTime, Duration, Utc};
+use sha1::{Digest, Sha1};
+
+use crate::helpers::password::{verify_password, Verification};
+
+/**
+ * Rules every new password has to satisfy, read once from the environment:
+ * PASSWORD_MIN_LENGTH, PASSWORD_CHARACTER_CLASSES (comma separated list of
+ * lower, upper, digit and symbol), PASSWORD_HISTORY, PASSWORD_MAX_AGE_DAYS and
+ * BREACHED_PASSWORDS_DIR.
+ */
+pub struct PasswordPolicy {
+    min_length: usize,
+    character_classes: Vec<CharacterClass>,
+    history: usize,
+    max_age: Option<Duration>,
+    breached_dir: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CharacterClass {
+    Lower,
+    Upper,
+    Digit,
+    Symbol,
+}
+
+impl CharacterClass {
+    fn parse(name: &str) -> Option<CharacterClass> {
+        match name.trim().to_lowercase().as_str() {
+            "lower" => Some(CharacterClass::Lower),
+            "upper" => Some(CharacterClass::Upper),
+            "digit" => Some(CharacterClass::Digit),
+            "symbol" => Some(CharacterClass::Symbol),
+            _ => None,
+        }
+    }
+
+    fn matches(&self, c: char) -> bool {
+        match self {
+            CharacterClass::Lower => c.is_lowercase(),
+            CharacterClass::Upper => c.is_uppercase(),
+            CharacterClass::Digit => c.is_ascii_digit(),
+            CharacterClass::Symbol => !c.is_alphanumeric(),
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            CharacterClass::Lower => "a lowercase letter",
+            CharacterClass::Upper => "an uppercase letter",
+            CharacterClass::Digit => "a digit",
+            CharacterClass::Symbol => "a symbol",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PolicyViolation {
+    TooShort(usize),
+    MissingCharacterClass(&'static str),
+    Reused,
+    Breached,
+}
+
+static PASSWORD_POLICY: OnceLock<PasswordPolicy> = OnceLock::new();
+
+fn env_number(name: &str) -> Option<usize> {
+    env::var(name)
+        .ok()
+        .map(|v| v.parse().unwrap_or_else(|_| panic!("{} must be a positive number", name)))
+}
+
+impl PasswordPolicy {
+    pub fn from_env() -> Self {
+        let character_classes = match env::var("PASSWORD_CHARACTER_CLASSES") {
+            Ok(v) => v
+                .split(',')
+                .filter(|c| !c.trim().is_empty())
+                .map(|c| CharacterClass::parse(c).unwrap_or_else(|| panic!("Unknown password character class {}", c)))
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
+        PasswordPolicy {
+            min_length: env_number("PASSWORD_MIN_LENGTH").unwrap_or(8),
+            character_classes,
+            history: env_number("PASSWORD_HISTORY").unwrap_or(0),
+            max_age: env_number("PASSWORD_MAX_AGE_DAYS").filter(|d| *d > 0).map(|d| Duration::days(d as i64)),
+            breached_dir: env::var("BREACHED_PASSWORDS_DIR").ok().map(PathBuf::from),
+        }
+    }
+
+    pub fn get() -> &'static PasswordPolicy {
+        PASSWORD_POLICY.get_or_init(PasswordPolicy::from_env)
+    }
+
+    /**
+     * Checks a new password. `previous_hashes` holds the current hash followed
+     * by the remembered ones, newest first.
+     */
+    pub fn validate(&self, password: &str, previous_hashes: &[String]) -> Result<(), PolicyViolation> {
+        if password.chars().count() < self.min_length {
+            return Err(PolicyViolation::TooShort(self.min_length));
+        }
+
+        for class in &self.character_classes {
+            if !password.chars().any(|c| class.matches(c)) {
+                return Err(PolicyViolation::MissingCharacterClass(class.name()));
+            }
+        }
+
+        let reused = previous_hashes
+            .iter()
+            .take(self.history + 1)
+            .any(|hash| verify_password(password, hash) != Verification::Invalid);
+        if reused {
+            return Err(PolicyViolation::Reused);
+        }
+
+        if let Some(dir) = &self.breached_dir {
+            if is_breached(dir, password) {
+                return Err(PolicyViolation::Breached);
+            }
+        }
+
+        Ok(())
+    }
+
+    // the password history to store once `old_hash` has been replaced
+    pub fn remember(&self, old_hash: String, mut history: Vec<String>) -> Vec<String> {
+        history.insert(0, old_hash);
+        history.truncate(self.history);
+        history
+    }
+
+    // passwords without a change date predate the policy and never expire
+    pub fn is_expired(&self, changed_at: Option<DateTime<Utc>>) -> bool {
+        match (self.max_age, changed_at) {
+            (Some(max_age), Some(changed_at)) => changed_at + max_age < Utc::now(),
+            _ => false,
+        }
+    }
+}
+
+/**
+ * Looks the password up in an offline copy of a breached password corpus laid
+ * out like the k-anonymity range API: one file per 5 character upper case
+ * SHA-1 prefix, each line holding the remaining 35 characters and a count
+ * ("SUFFIX:COUNT"). Only the file for the password's prefix is read.
+ */
+pub fn is_breached(dir: &PathBuf, password: &str) -> bool {
+    let digest = Sha1::digest(password.as_bytes());
+    let hash: String = digest.iter().map(|b| format!("{:02X}", b)).collect();
+    let (prefix, suffix) = hash.split_at(5);
+
+    let range = match fs::read_to_string(dir.join(prefix)) {
+        Ok(r) => r,
+        Err(_) => return false,
+    };
+
+    range
+        .lines()
+        .filter_map(|line| line.split(':').next())
+        .any(|candidate| candidate.trim().eq_ignore_ascii_case(suffix))
+}
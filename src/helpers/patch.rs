@@ -0,0 +1,89 @@
+use mongodb::bson::{to_document, Document as BsonDocument};
+use rocket::http::Status;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+
+#[derive(Debug, PartialEq)]
+pub enum PatchError {
+    // the patch body can not be applied to a resource at all
+    Malformed(String),
+    // a `test` operation failed or a path does not exist in the resource
+    Conflict(String),
+    // the patched resource no longer fits the schema or touches a read-only field
+    Invalid(String),
+}
+
+impl From<PatchError> for Status {
+    fn from(error: PatchError) -> Self {
+        match error {
+            PatchError::Malformed(_) => Status::BadRequest,
+            PatchError::Conflict(_) => Status::Conflict,
+            PatchError::Invalid(_) => Status::UnprocessableEntity,
+        }
+    }
+}
+
+// the minimal update that turns the stored resource into the patched one
+#[derive(Debug, Default)]
+pub struct FieldChanges {
+    pub set: BsonDocument,
+    pub unset: BsonDocument,
+}
+
+impl FieldChanges {
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.unset.is_empty()
+    }
+}
+
+fn to_json<T: Serialize>(resource: &T) -> Result<Value, PatchError> {
+    serde_json::to_value(resource).map_err(|e| PatchError::Malformed(e.to_string()))
+}
+
+fn from_json<T: DeserializeOwned>(value: Value) -> Result<T, PatchError> {
+    serde_json::from_value(value).map_err(|e| PatchError::Invalid(e.to_string()))
+}
+
+// applies an RFC 7396 merge patch, `null` members remove the field
+pub fn apply_merge_patch<T: Serialize + DeserializeOwned>(resource: &T, patch: &Value) -> Result<T, PatchError> {
+    if !patch.is_object() {
+        return Err(PatchError::Malformed("a merge patch must be a JSON object".to_string()));
+    }
+    let mut value = to_json(resource)?;
+    json_patch::merge(&mut value, patch);
+    from_json(value)
+}
+
+// applies RFC 6902 operations, all or nothing
+pub fn apply_json_patch<T: Serialize + DeserializeOwned>(resource: &T, patch: &json_patch::Patch) -> Result<T, PatchError> {
+    let mut value = to_json(resource)?;
+    json_patch::patch(&mut value, &patch.0).map_err(|e| PatchError::Conflict(e.to_string()))?;
+    from_json(value)
+}
+
+/**
+ * Compares the stored and the patched resource field by field. Changed fields
+ * go to `$set`, removed ones to `$unset`; a change to any field outside
+ * `writable` rejects the whole patch.
+ */
+pub fn field_changes<T: Serialize>(before: &T, after: &T, writable: &[&str]) -> Result<FieldChanges, PatchError> {
+    let before = to_document(before).map_err(|e| PatchError::Invalid(e.to_string()))?;
+    let after = to_document(after).map_err(|e| PatchError::Invalid(e.to_string()))?;
+    let mut changes = FieldChanges::default();
+
+    let keys = before.keys().chain(after.keys().filter(|k| !before.contains_key(k.as_str())));
+    for key in keys {
+        if before.get(key) == after.get(key) {
+            continue;
+        }
+        if !writable.contains(&key.as_str()) {
+            return Err(PatchError::Invalid(format!("{} is read-only", key)));
+        }
+        match after.get(key) {
+            Some(value) => changes.set.insert(key, value.clone()),
+            None => changes.unset.insert(key, ""),
+        };
+    }
+
+    Ok(changes)
+}
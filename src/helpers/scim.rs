@@ -0,0 +1,566 @@
+use std::env;
+use mongodb::bson::{doc, oid::ObjectId, to_bson, Bson, Document, Regex};
+use rocket::{
+    http::{ContentType, Status},
+    request::{FromRequest, Outcome},
+    response::{self, Responder},
+    serde::json::Json,
+    Request, Response, State,
+};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::models::{scim::{role_from_scim, ScimError, ScimPatchOperation}, team::{Team, TeamMember, TeamRole}};
+use crate::repository::mongodb_repo::MongoRepo;
+
+/**
+ * Bearer token guard for the provisioning endpoints, deliberately separate
+ * from user JWTs. The token decides the organization: every organization has
+ * its own, stored hashed, and the default organization's comes from the
+ * SCIM_TOKEN environment var. Handlers get the repository of that
+ * organization; the X-Organization header plays no part.
+ */
+pub struct ScimAuth<'r> {
+    pub db: &'r MongoRepo,
+}
+
+// the organization a SCIM token belongs to, resolved once per request
+struct ScimTenant(Result<MongoRepo, (Status, &'static str)>);
+
+// SCIM tokens are random, a plain SHA-256 is enough to keep them out of the database
+pub fn scim_token_hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ScimAuth<'r> {
+    type Error = &'r str;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let db = match req.guard::<&State<MongoRepo>>().await {
+            Outcome::Success(db) => db,
+            _ => return Outcome::Failure((Status::InternalServerError, "Repository is not available")),
+        };
+        let token = match req.headers().get("authorization").next() {
+            Some(a) => a.trim_start_matches("Bearer ").trim(),
+            _ => return Outcome::Failure((Status::Unauthorized, "Authorization header not found")),
+        };
+
+        match req.local_cache_async(async { ScimTenant(scim_tenant(db, token).await) }).await {
+            ScimTenant(Ok(repo)) => Outcome::Success(ScimAuth { db: repo }),
+            ScimTenant(Err(failure)) => Outcome::Failure(*failure),
+        }
+    }
+}
+
+async fn scim_tenant(db: &MongoRepo, token: &str) -> Result<MongoRepo, (Status, &'static str)> {
+    // the default organization has no record to keep a token on
+    let default_token = env::var("SCIM_TOKEN").unwrap_or_default();
+    let organization = if !default_token.is_empty() && constant_time_eq(token.as_bytes(), default_token.as_bytes()) {
+        None
+    } else {
+        match db.find_organization(doc! {"scim_token_hash": scim_token_hash(token)}).await {
+            Ok(Some(organization)) => Some(organization),
+            Ok(None) => return Err((Status::Unauthorized, "SCIM token is not valid")),
+            Err(_) => return Err((Status::InternalServerError, "Organization lookup failed")),
+        }
+    };
+    match db.for_organization(organization).await {
+        Ok(repo) => Ok(repo),
+        Err(_) => Err((Status::InternalServerError, "Repository is not available")),
+    }
+}
+
+// failed SCIM requests answer with an error body instead of a bare status
+impl<'r> Responder<'r, 'static> for ScimError {
+    fn respond_to(self, req: &'r Request<'_>) -> response::Result<'static> {
+        let status = self.status.parse().ok().and_then(Status::from_code).unwrap_or(Status::InternalServerError);
+        Response::build_from(Json(self).respond_to(req)?)
+            .status(status)
+            .header(ContentType::new("application", "scim+json"))
+            .ok()
+    }
+}
+
+// lets handlers pass on the status of shared helpers with `?`
+impl From<Status> for ScimError {
+    fn from(status: Status) -> Self {
+        ScimError::new(status.code, None, status.reason_lossy())
+    }
+}
+
+// maps a SCIM attribute path onto the field stored on `User`
+fn user_field(path: &str) -> Option<&'static str> {
+    // drop value filters such as emails[type eq "work"].value
+    let path = match (path.find('['), path.find(']')) {
+        (Some(start), Some(end)) if end > start => format!("{}{}", &path[..start], &path[end + 1..]),
+        _ => path.to_string(),
+    };
+    let path = path.trim_start_matches("urn:ietf:params:scim:schemas:core:2.0:User:");
+
+    match path.to_lowercase().as_str() {
+        "id" => Some("_id"),
+        "username" => Some("username"),
+        "name.givenname" => Some("firstname"),
+        "name.familyname" => Some("lastname"),
+        "emails" | "emails.value" => Some("email"),
+        "active" => Some("active"),
+        "roles" | "roles.value" => Some("role"),
+        "password" => Some("password"),
+        _ => None,
+    }
+}
+
+// maps a SCIM group attribute path onto the field stored on `Team`
+fn group_field(path: &str) -> Option<&'static str> {
+    let path = path.trim_start_matches("urn:ietf:params:scim:schemas:core:2.0:Group:");
+
+    match path.to_lowercase().as_str() {
+        "id" => Some("_id"),
+        "displayname" => Some("name"),
+        "members" | "members.value" => Some("members.user_id"),
+        _ => None,
+    }
+}
+
+/**
+ * Filtering
+*/
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Word(String),
+    Str(String),
+}
+
+fn tokenize(filter: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut chars = filter.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' | '\t' | '\n' => { chars.next(); },
+            '(' => { chars.next(); tokens.push(Token::Open); },
+            ')' => { chars.next(); tokens.push(Token::Close); },
+            '"' => {
+                chars.next();
+                let mut s = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\\') => match chars.next() {
+                            Some(escaped) => s.push(escaped),
+                            None => return Err("Unterminated string in filter".to_string()),
+                        },
+                        Some('"') => break,
+                        Some(ch) => s.push(ch),
+                        None => return Err("Unterminated string in filter".to_string()),
+                    }
+                }
+                tokens.push(Token::Str(s));
+            },
+            _ => {
+                let mut w = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if ch.is_whitespace() || ch == '(' || ch == ')' {
+                        break;
+                    }
+                    w.push(ch);
+                    chars.next();
+                }
+                tokens.push(Token::Word(w));
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct FilterParser {
+    tokens: Vec<Token>,
+    pos: usize,
+    // maps attribute paths of the resource type being filtered onto stored fields
+    field: fn(&str) -> Option<&'static str>,
+}
+
+impl FilterParser {
+    fn peek_keyword(&self, keyword: &str) -> bool {
+        match self.tokens.get(self.pos) {
+            Some(Token::Word(w)) => w.eq_ignore_ascii_case(keyword),
+            _ => false,
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        t
+    }
+
+    fn expect_close(&mut self) -> Result<(), String> {
+        match self.next() {
+            Some(Token::Close) => Ok(()),
+            _ => Err("Expected ')' in filter".to_string()),
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Document, String> {
+        let mut terms = vec![self.parse_and()?];
+        while self.peek_keyword("or") {
+            self.pos += 1;
+            terms.push(self.parse_and()?);
+        }
+        Ok(if terms.len() == 1 { terms.remove(0) } else { doc! { "$or": terms } })
+    }
+
+    fn parse_and(&mut self) -> Result<Document, String> {
+        let mut terms = vec![self.parse_term()?];
+        while self.peek_keyword("and") {
+            self.pos += 1;
+            terms.push(self.parse_term()?);
+        }
+        Ok(if terms.len() == 1 { terms.remove(0) } else { doc! { "$and": terms } })
+    }
+
+    fn parse_term(&mut self) -> Result<Document, String> {
+        if self.peek_keyword("not") {
+            self.pos += 1;
+            if self.next() != Some(Token::Open) {
+                return Err("Expected '(' after not".to_string());
+            }
+            let inner = self.parse_or()?;
+            self.expect_close()?;
+            return Ok(doc! { "$nor": [inner] });
+        }
+
+        match self.next() {
+            Some(Token::Open) => {
+                let inner = self.parse_or()?;
+                self.expect_close()?;
+                Ok(inner)
+            },
+            Some(Token::Word(attribute)) => {
+                let op = match self.next() {
+                    Some(Token::Word(op)) => op.to_lowercase(),
+                    _ => return Err(format!("Missing operator after {}", attribute)),
+                };
+                if op == "pr" {
+                    return comparison(self.field, &attribute, &op, &Value::Null);
+                }
+                let value = match self.next() {
+                    Some(Token::Str(s)) => Value::String(s),
+                    Some(Token::Word(w)) => serde_json::from_str(&w).map_err(|_| format!("Invalid value {}", w))?,
+                    _ => return Err(format!("Missing value after {} {}", attribute, op)),
+                };
+                comparison(self.field, &attribute, &op, &value)
+            },
+            _ => Err("Invalid filter".to_string()),
+        }
+    }
+}
+
+fn comparison(field: fn(&str) -> Option<&'static str>, attribute: &str, op: &str, value: &Value) -> Result<Document, String> {
+    let field = match field(attribute) {
+        Some("password") | None => return Err(format!("Unsupported filter attribute {}", attribute)),
+        Some(f) => f,
+    };
+
+    if op == "pr" {
+        return Ok(match field {
+            "active" => doc! {},
+            _ => doc! { field: { "$exists": true, "$ne": Bson::Null } },
+        });
+    }
+
+    match field {
+        "_id" | "members.user_id" => {
+            let id = value.as_str().and_then(|v| ObjectId::parse_str(v).ok())
+                .ok_or_else(|| format!("{} must be a valid ObjectId", attribute))?;
+            match op {
+                "eq" => Ok(doc! { field: id }),
+                "ne" => Ok(doc! { field: { "$ne": id } }),
+                _ => Err(format!("Operator {} is not supported for {}", op, attribute)),
+            }
+        },
+        "active" => {
+            let active = value.as_bool().ok_or_else(|| "active must be a boolean".to_string())?;
+            let wanted = match op {
+                "eq" => active,
+                "ne" => !active,
+                _ => return Err(format!("Operator {} is not supported for active", op)),
+            };
+            // a missing flag means the user was never deactivated
+            Ok(if wanted { doc! { "active": { "$ne": false } } } else { doc! { "active": false } })
+        },
+        "role" => {
+            let role = value.as_str().and_then(role_from_scim)
+                .ok_or_else(|| "Unknown role".to_string())?;
+            let role = to_bson(&role).unwrap();
+            match op {
+                "eq" => Ok(doc! { "role": role }),
+                "ne" => Ok(doc! { "role": { "$ne": role } }),
+                _ => Err(format!("Operator {} is not supported for roles", op)),
+            }
+        },
+        _ => {
+            let v = value.as_str().ok_or_else(|| format!("{} must be a string", attribute))?;
+            let escaped = regex::escape(v);
+            // string attributes in the core schema are not case exact
+            let pattern = match op {
+                "eq" | "ne" => format!("^{}$", escaped),
+                "co" => escaped,
+                "sw" => format!("^{}", escaped),
+                "ew" => format!("{}$", escaped),
+                "gt" => return Ok(doc! { field: { "$gt": v } }),
+                "ge" => return Ok(doc! { field: { "$gte": v } }),
+                "lt" => return Ok(doc! { field: { "$lt": v } }),
+                "le" => return Ok(doc! { field: { "$lte": v } }),
+                _ => return Err(format!("Unknown operator {}", op)),
+            };
+            let re = Regex { pattern, options: "i".to_string() };
+            Ok(if op == "ne" { doc! { field: { "$not": re } } } else { doc! { field: re } })
+        }
+    }
+}
+
+fn parse_filter(filter: &str, field: fn(&str) -> Option<&'static str>) -> Result<Document, String> {
+    let mut parser = FilterParser { tokens: tokenize(filter)?, pos: 0, field };
+    let document = parser.parse_or()?;
+    if parser.pos != parser.tokens.len() {
+        return Err("Unexpected trailing input in filter".to_string());
+    }
+    Ok(document)
+}
+
+pub fn filter_to_document(filter: &str) -> Result<Document, String> {
+    parse_filter(filter, user_field)
+}
+
+pub fn group_filter_to_document(filter: &str) -> Result<Document, String> {
+    parse_filter(filter, group_field)
+}
+
+/**
+ * Patching
+*/
+
+#[derive(Debug, Default)]
+pub struct UserChanges {
+    pub set: Document,
+    pub unset: Document,
+    // kept in plain text so the password policy can be applied before hashing
+    pub password: Option<String>,
+}
+
+fn bool_value(value: &Value) -> Option<bool> {
+    match value {
+        Value::Bool(b) => Some(*b),
+        // some providers send booleans as strings
+        Value::String(s) => s.to_lowercase().parse().ok(),
+        _ => None,
+    }
+}
+
+// multi-valued attributes arrive either as a bare value, an object or a list of them
+fn multi_value(value: &Value) -> Option<String> {
+    match value {
+        Value::String(s) => Some(s.clone()),
+        Value::Object(o) => o.get("value").and_then(|v| v.as_str()).map(|v| v.to_string()),
+        Value::Array(items) => items
+            .iter()
+            .find(|i| i.get("primary").and_then(|p| p.as_bool()) == Some(true))
+            .or(items.first())
+            .and_then(multi_value),
+        _ => None,
+    }
+}
+
+impl UserChanges {
+    fn apply(&mut self, op: &str, path: &str, value: Option<&Value>) -> Result<(), String> {
+        if path.eq_ignore_ascii_case("name") {
+            if op == "remove" {
+                self.unset.insert("firstname", "");
+                self.unset.insert("lastname", "");
+                return Ok(());
+            }
+            let name = value.and_then(|v| v.as_object()).ok_or_else(|| "name must be an object".to_string())?;
+            for (k, v) in name {
+                self.apply(op, &format!("name.{}", k), Some(v))?;
+            }
+            return Ok(());
+        }
+
+        let field = user_field(path).ok_or_else(|| format!("Unsupported attribute {}", path))?;
+        if field == "_id" {
+            return Err("id is immutable".to_string());
+        }
+
+        if op == "remove" {
+            if field == "username" || field == "password" {
+                return Err(format!("{} can not be removed", path));
+            }
+            self.unset.insert(field, "");
+            return Ok(());
+        }
+
+        let value = value.ok_or_else(|| format!("Missing value for {}", path))?;
+        if field == "password" {
+            self.password = Some(value.as_str().ok_or_else(|| "password must be a string".to_string())?.to_string());
+            return Ok(());
+        }
+        let bson = match field {
+            "active" => Bson::Boolean(bool_value(value).ok_or_else(|| "active must be a boolean".to_string())?),
+            "role" => {
+                let role = multi_value(value).and_then(|r| role_from_scim(&r))
+                    .ok_or_else(|| "Unknown role".to_string())?;
+                to_bson(&role).unwrap()
+            },
+            "email" => Bson::String(multi_value(value).ok_or_else(|| "Invalid email".to_string())?),
+            _ => Bson::String(value.as_str().ok_or_else(|| format!("{} must be a string", path))?.to_string()),
+        };
+        self.set.insert(field, bson);
+        Ok(())
+    }
+}
+
+pub fn patch_to_changes(operations: &[ScimPatchOperation]) -> Result<UserChanges, String> {
+    let mut changes = UserChanges::default();
+
+    for operation in operations {
+        let op = operation.op.to_lowercase();
+        if op != "add" && op != "replace" && op != "remove" {
+            return Err(format!("Unknown patch operation {}", operation.op));
+        }
+
+        match &operation.path {
+            Some(path) => changes.apply(&op, path, operation.value.as_ref())?,
+            None => {
+                // without a path the value holds attribute/value pairs
+                let values = operation.value.as_ref().and_then(|v| v.as_object())
+                    .ok_or_else(|| "Patch operation without a path needs an object value".to_string())?;
+                for (path, value) in values {
+                    changes.apply(&op, path, Some(value))?;
+                }
+            }
+        }
+    }
+
+    Ok(changes)
+}
+
+/**
+ * Group patching
+*/
+
+// the user ids of a members value, a list of `{"value": "<id>"}` or a single one
+fn member_ids(value: &Value) -> Result<Vec<ObjectId>, String> {
+    let items: Vec<&Value> = match value {
+        Value::Array(items) => items.iter().collect(),
+        other => vec![other],
+    };
+    items.into_iter().map(|item| {
+        let id = match item {
+            Value::Object(o) => o.get("value").and_then(|v| v.as_str()),
+            Value::String(s) => Some(s.as_str()),
+            _ => None,
+        };
+        id.and_then(|id| ObjectId::parse_str(id).ok()).ok_or_else(|| "Members need a valid value".to_string())
+    }).collect()
+}
+
+// the member a path like members[value eq "<id>"] points at
+fn member_path(path: &str) -> Result<Option<ObjectId>, String> {
+    let lower = path.to_lowercase();
+    if lower == "members" {
+        return Ok(None);
+    }
+    let inner = lower.strip_prefix("members[").and_then(|rest| rest.strip_suffix(']'))
+        .ok_or_else(|| format!("Unsupported attribute {}", path))?;
+    match tokenize(inner)?.as_slice() {
+        [Token::Word(attribute), Token::Word(op), Token::Str(id)] if attribute == "value" && op == "eq" => {
+            ObjectId::parse_str(id).map(Some).map_err(|_| "Members need a valid value".to_string())
+        },
+        _ => Err(format!("Unsupported member filter {}", path)),
+    }
+}
+
+fn set_members(team: &mut Team, ids: Vec<ObjectId>) {
+    let previous = std::mem::take(&mut team.members);
+    for id in ids {
+        add_member(team, id, previous.iter().find(|m| m.user_id == id).map_or(TeamRole::Member, |m| m.role));
+    }
+}
+
+fn add_member(team: &mut Team, user_id: ObjectId, role: TeamRole) {
+    if team.role_of(&user_id).is_none() {
+        team.members.push(TeamMember { user_id, role });
+    }
+}
+
+fn apply_group_operation(team: &mut Team, op: &str, path: &str, value: Option<&Value>) -> Result<(), String> {
+    if path.eq_ignore_ascii_case("displayName") {
+        if op == "remove" {
+            return Err("displayName can not be removed".to_string());
+        }
+        let name = value.and_then(|v| v.as_str()).map(str::trim).filter(|n| !n.is_empty())
+            .ok_or_else(|| "displayName must be a non-empty string".to_string())?;
+        team.name = name.to_string();
+        return Ok(());
+    }
+
+    let member = member_path(path)?;
+    match (op, member) {
+        ("remove", Some(id)) => team.members.retain(|m| m.user_id != id),
+        ("remove", None) => match value {
+            Some(value) => {
+                let ids = member_ids(value)?;
+                team.members.retain(|m| !ids.contains(&m.user_id));
+            },
+            None => team.members.clear(),
+        },
+        ("add", None) => {
+            for id in member_ids(value.ok_or("Missing value for members")?)? {
+                add_member(team, id, TeamRole::Member);
+            }
+        },
+        ("replace", None) => set_members(team, member_ids(value.ok_or("Missing value for members")?)?),
+        _ => return Err(format!("Can not {} {}", op, path)),
+    }
+    Ok(())
+}
+
+/**
+ * Applies SCIM patch operations to a team. Members the provider adds join as
+ * plain members; members it keeps keep their team role.
+ */
+pub fn patch_group(team: &Team, operations: &[ScimPatchOperation]) -> Result<Team, String> {
+    let mut team = team.clone();
+
+    for operation in operations {
+        let op = operation.op.to_lowercase();
+        if op != "add" && op != "replace" && op != "remove" {
+            return Err(format!("Unknown patch operation {}", operation.op));
+        }
+
+        match &operation.path {
+            Some(path) => apply_group_operation(&mut team, &op, path, operation.value.as_ref())?,
+            None => {
+                let values = operation.value.as_ref().and_then(|v| v.as_object())
+                    .ok_or_else(|| "Patch operation without a path needs an object value".to_string())?;
+                for (path, value) in values {
+                    apply_group_operation(&mut team, &op, path, Some(value))?;
+                }
+            }
+        }
+    }
+
+    Ok(team)
+}
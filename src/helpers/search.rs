@@ -0,0 +1,150 @@
+use mongodb::bson::{doc, Bson, Document as BsonDocument};
+
+// a search box query split into plain words, "quoted phrases" and prefix* terms
+#[derive(Debug, Default, PartialEq)]
+pub struct SearchQuery {
+    pub words: Vec<String>,
+    pub phrases: Vec<String>,
+    pub prefixes: Vec<String>,
+}
+
+pub fn parse_query(q: &str) -> SearchQuery {
+    let mut query = SearchQuery::default();
+    let mut rest = q;
+
+    while let Some(open) = rest.find('"') {
+        push_terms(&mut query, &rest[..open]);
+        let after = &rest[open + 1..];
+        let close = after.find('"').unwrap_or(after.len());
+        let phrase = after[..close].split_whitespace().collect::<Vec<_>>().join(" ");
+        if !phrase.is_empty() {
+            query.phrases.push(phrase.to_lowercase());
+        }
+        rest = after.get(close + 1..).unwrap_or("");
+    }
+    push_terms(&mut query, rest);
+
+    query
+}
+
+fn push_terms(query: &mut SearchQuery, text: &str) {
+    for term in text.split_whitespace() {
+        let term = term.to_lowercase();
+        match term.strip_suffix('*') {
+            Some(prefix) if !prefix.is_empty() => query.prefixes.push(prefix.to_string()),
+            Some(_) => {},
+            None => query.words.push(term),
+        }
+    }
+}
+
+impl SearchQuery {
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty() && self.phrases.is_empty() && self.prefixes.is_empty()
+    }
+
+    // only words and phrases go through the text index, it has no prefix matching
+    pub fn uses_text_index(&self) -> bool {
+        !self.words.is_empty() || !self.phrases.is_empty()
+    }
+
+    /**
+     * Builds the Mongo filter: a `$text` search for words and phrases plus one
+     * case-insensitive word-start regex per prefix, matched against the title
+     * or the content.
+     */
+    pub fn to_filter(&self) -> BsonDocument {
+        let mut clauses: Vec<Bson> = Vec::new();
+
+        if self.uses_text_index() {
+            let mut search: Vec<String> = self.words.clone();
+            search.extend(self.phrases.iter().map(|p| format!("\"{}\"", p)));
+            clauses.push(Bson::Document(doc! {"$text": {"$search": search.join(" ")}}));
+        }
+        for prefix in &self.prefixes {
+            let pattern = format!(r"\b{}", regex::escape(prefix));
+            clauses.push(Bson::Document(doc! {"$or": [
+                {"title": {"$regex": &pattern, "$options": "i"}},
+                {"content": {"$regex": &pattern, "$options": "i"}},
+            ]}));
+        }
+
+        doc! {"$and": clauses}
+    }
+
+    // a word is highlighted when it starts with any searched term, which also covers simple stemming
+    fn matches(&self, word: &str) -> bool {
+        let word = word.to_lowercase();
+        self.words.iter()
+            .chain(self.prefixes.iter())
+            .map(String::as_str)
+            .chain(self.phrases.iter().flat_map(|p| p.split(' ')))
+            .any(|term| word.starts_with(term))
+    }
+}
+
+// snippets are HTML, the document text around the markers must not be
+pub fn escape_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/**
+ * Cuts `radius` words on each side of the first match out of `text` and wraps
+ * matching words in <mark></mark>. Falls back to the start of the text when
+ * only the title matched. The text is HTML escaped, only the markers are markup.
+ */
+pub fn snippet(text: &str, query: &SearchQuery, radius: usize) -> String {
+    let mut words: Vec<(usize, usize)> = Vec::new();
+    let mut start: Option<usize> = None;
+    for (i, c) in text.char_indices() {
+        match (c.is_alphanumeric(), start) {
+            (true, None) => start = Some(i),
+            (false, Some(s)) => {
+                words.push((s, i));
+                start = None;
+            },
+            _ => {},
+        }
+    }
+    if let Some(s) = start {
+        words.push((s, text.len()));
+    }
+    if words.is_empty() {
+        return String::new();
+    }
+
+    let matched: Vec<bool> = words.iter().map(|&(s, e)| query.matches(&text[s..e])).collect();
+    let first = matched.iter().position(|m| *m).unwrap_or(0);
+    let from = first.saturating_sub(radius);
+    let to = (first + radius + 1).min(words.len());
+
+    let mut out = String::new();
+    if from > 0 {
+        out.push('…');
+    }
+    let mut cursor = words[from].0;
+    for (&(s, e), &hit) in words[from..to].iter().zip(&matched[from..to]) {
+        out.push_str(&escape_html(&text[cursor..s]).replace(|c: char| c.is_whitespace(), " "));
+        if hit {
+            out.push_str(&format!("<mark>{}</mark>", escape_html(&text[s..e])));
+        } else {
+            out.push_str(&escape_html(&text[s..e]));
+        }
+        cursor = e;
+    }
+    if to < words.len() {
+        out.push('…');
+    }
+    out
+}
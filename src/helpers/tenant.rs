@@ -0,0 +1,62 @@
+use mongodb::bson::{doc, Document};
+use rocket::{http::Status, request::{FromRequest, Outcome}, Request, State};
+
+use crate::helpers::jwt::{jwt_validate, AuthObject};
+use crate::models::organization::Organization;
+use crate::repository::mongodb_repo::MongoRepo;
+
+// names the organization by slug on requests that carry no token yet, like signup and login
+pub const ORGANIZATION_HEADER: &str = "X-Organization";
+
+/**
+ * The organization a request acts for. A valid token decides on its own, so a
+ * signed-in user can not reach into another organization by sending the
+ * header; only requests without one fall back to X-Organization.
+ */
+async fn organization_of(db: &MongoRepo, req: &Request<'_>) -> Result<Option<Organization>, Status> {
+    let token = req.headers().get_one("authorization").map(jwt_validate);
+    let filter = match organization_filter(token, req.headers().get_one(ORGANIZATION_HEADER)) {
+        Some(filter) => filter,
+        None => return Ok(None),
+    };
+    match db.find_organization(filter).await {
+        Ok(Some(organization)) => Ok(Some(organization)),
+        Ok(None) => Err(Status::NotFound),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+// how to find the organization of a request, None for the default organization
+pub fn organization_filter(token: Option<AuthObject>, header: Option<&str>) -> Option<Document> {
+    match (token.filter(|auth| auth.authorized), header) {
+        (Some(auth), _) => auth.org.map(|org_id| doc! {"_id": org_id}),
+        (None, Some(slug)) => Some(doc! {"slug": slug.trim().to_lowercase()}),
+        (None, None) => None,
+    }
+}
+
+async fn scoped_repo(db: &MongoRepo, req: &Request<'_>) -> Result<MongoRepo, Status> {
+    let organization = organization_of(db, req).await?;
+    match db.for_organization(organization).await {
+        Ok(repo) => Ok(repo),
+        Err(_) => Err(Status::InternalServerError),
+    }
+}
+
+// hands handlers the repository of the caller's organization, resolved once per request
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for &'r MongoRepo {
+    type Error = &'r str;
+
+    async fn from_request(req: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        let db = match req.guard::<&State<MongoRepo>>().await {
+            Outcome::Success(db) => db,
+            _ => return Outcome::Failure((Status::InternalServerError, "Repository is not available")),
+        };
+
+        match req.local_cache_async(scoped_repo(db, req)).await {
+            Ok(repo) => Outcome::Success(repo),
+            Err(status) => Outcome::Failure((*status, "Organization not found")),
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::{env, time::Duration as StdDuration};
+use chrono::{Duration, Utc};
+use rocket::tokio::time;
+
+use crate::repository::mongodb_repo::MongoRepo;
+
+const PURGE_INTERVAL: StdDuration = StdDuration::from_secs(60 * 60);
+
+// how long a deleted user can still be reactivated, USER_DELETION_GRACE_DAYS (default 30)
+pub fn deletion_grace_period() -> Duration {
+    let days = match env::var("USER_DELETION_GRACE_DAYS") {
+        Ok(v) => v.parse().unwrap_or(30),
+        Err(_) => 30,
+    };
+    Duration::days(days)
+}
+
+pub async fn purge_deleted_users(db: MongoRepo) {
+    let mut interval = time::interval(PURGE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let cutoff = Utc::now() - deletion_grace_period();
+        let organizations = match db.all_organizations().await {
+            Ok(organizations) => organizations,
+            Err(e) => {
+                println!("Error listing organizations: {}", e);
+                continue;
+            },
+        };
+        for db in organizations {
+            match db.purge_deleted_users(cutoff).await {
+                Ok(purged) if !purged.is_empty() => println!("Purged {} deleted users", purged.len()),
+                Ok(_) => {},
+                Err(e) => println!("Error purging deleted users: {}", e),
+            }
+        }
+    }
+}
+
+// how long deleted documents stay in the trash, TRASH_RETENTION_DAYS (default 30)
+pub fn trash_retention_period() -> Duration {
+    let days = match env::var("TRASH_RETENTION_DAYS") {
+        Ok(v) => v.parse().unwrap_or(30),
+        Err(_) => 30,
+    };
+    Duration::days(days)
+}
+
+pub async fn purge_trash(db: MongoRepo) {
+    let mut interval = time::interval(PURGE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let cutoff = Utc::now() - trash_retention_period();
+        let organizations = match db.all_organizations().await {
+            Ok(organizations) => organizations,
+            Err(e) => {
+                println!("Error listing organizations: {}", e);
+                continue;
+            },
+        };
+        for db in organizations {
+            match db.purge_trash(cutoff).await {
+                Ok(purged) if purged > 0 => println!("Purged {} documents from the trash", purged),
+                Ok(_) => {},
+                Err(e) => println!("Error purging the trash: {}", e),
+            }
+        }
+    }
+}
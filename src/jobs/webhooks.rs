@@ -0,0 +1,376 @@
+use std::{collections::HashMap, env, net::{IpAddr, SocketAddr}, sync::{Arc, Mutex}, time::Duration as StdDuration};
+use chrono::{DateTime, Duration, Utc};
+use hmac::{Hmac, Mac};
+use mongodb::bson::{doc, oid::ObjectId, to_bson, Document as BsonDocument};
+use hyper::client::connect::dns::Name;
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use rocket::tokio::{self, select, sync::{broadcast::error::RecvError, Notify, Semaphore}, time};
+use sha2::Sha256;
+
+use crate::events::bus::Published;
+use crate::models::webhook::{DeliveryStatus, Webhook, WebhookDelivery};
+use crate::repository::mongodb_repo::{bson_date, MongoRepo};
+
+// due deliveries are also looked for this often, redeliveries and retries wait for it
+const POLL_INTERVAL: StdDuration = StdDuration::from_secs(5);
+const REQUEST_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+// retries never wait longer than this
+const MAX_RETRY_DELAY: i64 = 6 * 60 * 60;
+// deliveries sent at the same time, in all and to any one webhook
+const MAX_IN_FLIGHT: usize = 32;
+const MAX_IN_FLIGHT_PER_WEBHOOK: usize = 4;
+// seconds a claimed delivery is left to its sender before another one may take it
+const CLAIM_LEASE: i64 = 5 * 60;
+
+pub const SIGNATURE_HEADER: &str = "X-Webhook-Signature";
+pub const TIMESTAMP_HEADER: &str = "X-Webhook-Timestamp";
+pub const EVENT_HEADER: &str = "X-Webhook-Event";
+pub const DELIVERY_HEADER: &str = "X-Webhook-Delivery";
+
+// attempts before a delivery fails for good, WEBHOOK_MAX_ATTEMPTS (default 8)
+pub fn max_attempts() -> i64 {
+    match env::var("WEBHOOK_MAX_ATTEMPTS") {
+        Ok(v) => v.parse().unwrap_or(8),
+        Err(_) => 8,
+    }
+}
+
+// whether webhooks may point at this network, WEBHOOK_ALLOW_PRIVATE (default false)
+pub fn allow_private_targets() -> bool {
+    matches!(env::var("WEBHOOK_ALLOW_PRIVATE").as_deref(), Ok("true") | Ok("1"))
+}
+
+/**
+ * Addresses a webhook must not reach unless private targets are allowed:
+ * loopback, private, link-local, shared, multicast and unspecified ones,
+ * including IPv4 addresses written as IPv6 or behind NAT64.
+ */
+pub fn is_private_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+            ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_broadcast() || ip.is_multicast()
+                // 0.0.0.0/8, "this network"
+                || first == 0
+                // 100.64.0.0/10, carrier-grade NAT
+                || (first == 100 && (64..128).contains(&second))
+        },
+        IpAddr::V6(ip) => {
+            let segments = ip.segments();
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_private_address(IpAddr::V4(ip));
+            }
+            // 64:ff9b::/96, the NAT64 prefix carries an IPv4 address in its last 32 bits
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [.., high, low] = segments;
+                return is_private_address(IpAddr::V4(((high as u32) << 16 | low as u32).into()));
+            }
+            let first = segments[0];
+            // fc00::/7 unique local and fe80::/10 link-local
+            ip.is_loopback() || ip.is_unspecified() || ip.is_multicast() || (first & 0xfe00) == 0xfc00 || (first & 0xffc0) == 0xfe80
+        },
+    }
+}
+
+/**
+ * Resolves webhook hosts for the HTTP client and refuses names with a private
+ * address. The client connects to exactly the addresses checked here, so a
+ * name can not switch to a private address after it passed the check.
+ */
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if addresses.iter().any(|address| is_private_address(address.ip())) {
+                return Err(format!("{} resolves to a private address", name.as_str()).into());
+            }
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+// URLs naming an IP address are never resolved, so their address is checked on its own
+fn check_target(url: &str) -> Result<(), String> {
+    let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+    let host = url.host_str().unwrap_or_default().trim_start_matches('[').trim_end_matches(']');
+    match host.parse() {
+        Ok(ip) if is_private_address(ip) => Err(format!("{} is a private address", ip)),
+        _ => Ok(()),
+    }
+}
+
+/**
+ * How long to wait after the `attempts`th failed attempt. The wait starts at
+ * WEBHOOK_RETRY_SECONDS (default 30) and doubles with every attempt, up to
+ * six hours.
+ */
+pub fn retry_delay(attempts: i64) -> Duration {
+    let base: i64 = match env::var("WEBHOOK_RETRY_SECONDS") {
+        Ok(v) => v.parse().unwrap_or(30),
+        Err(_) => 30,
+    };
+    let doublings = attempts.clamp(1, 32) - 1;
+    Duration::seconds(base.max(1).saturating_mul(1 << doublings).min(MAX_RETRY_DELAY))
+}
+
+/**
+ * The signature sent with a payload: HMAC-SHA256 of `<timestamp>.<body>` with
+ * the webhook's secret, as hex. Receivers recompute it to check the payload
+ * came from us, and can reject old timestamps to stop replays.
+ */
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(format!("{}.{}", timestamp, body).as_bytes());
+    mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// redirects are not followed, they could lead a delivery to an address the URL check refused
+#[derive(Clone)]
+pub struct WebhookClient {
+    http: reqwest::Client,
+    allow_private: bool,
+}
+
+// the client deliveries are sent with, it only reaches private addresses when `allow_private`
+pub fn http_client(allow_private: bool) -> WebhookClient {
+    let mut builder = reqwest::Client::builder()
+        .timeout(REQUEST_TIMEOUT)
+        .redirect(reqwest::redirect::Policy::none());
+    if !allow_private {
+        builder = builder.dns_resolver(Arc::new(PublicResolver));
+    }
+    WebhookClient { http: builder.build().expect("HTTP client can be built"), allow_private }
+}
+
+/**
+ * POSTs the delivery's event, Ok with the status code of a 2xx answer. The
+ * target is checked again on every attempt, since the name may point
+ * somewhere else than when the webhook was registered.
+ */
+pub async fn send(client: &WebhookClient, webhook: &Webhook, delivery: &WebhookDelivery) -> Result<u16, (Option<u16>, String)> {
+    if !client.allow_private {
+        check_target(&webhook.url).map_err(|e| (None, e))?;
+    }
+    let body = serde_json::to_string(&delivery.event).map_err(|e| (None, e.to_string()))?;
+    let timestamp = Utc::now().timestamp();
+    let response = client
+        .http
+        .post(&webhook.url)
+        .header("Content-Type", "application/json")
+        .header(EVENT_HEADER, delivery.event.kind.name())
+        .header(DELIVERY_HEADER, delivery.id.map(|id| id.to_hex()).unwrap_or_default())
+        .header(TIMESTAMP_HEADER, timestamp.to_string())
+        .header(SIGNATURE_HEADER, format!("sha256={}", sign(&webhook.secret, timestamp, &body)))
+        .body(body)
+        .send()
+        .await
+        // the resolver's reason is the source of reqwest's connect error
+        .map_err(|e| (None, error_chain(&e)))?;
+    let status = response.status();
+    match status.is_success() {
+        true => Ok(status.as_u16()),
+        false => Err((Some(status.as_u16()), format!("Receiver answered {}", status))),
+    }
+}
+
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        message = format!("{}: {}", message, cause);
+        source = cause.source();
+    }
+    message
+}
+
+// the changes recording an attempt made at `now`
+fn attempt_update(delivery: &WebhookDelivery, result: Result<u16, (Option<u16>, String)>, now: DateTime<Utc>) -> Result<BsonDocument, mongodb::bson::ser::Error> {
+    let attempts = delivery.attempts + 1;
+    let mut set = doc! {"attempts": attempts, "last_attempt_at": to_bson(&now)?};
+    let mut unset = doc! {"next_attempt_at": ""};
+    match result {
+        Ok(code) => {
+            set.insert("status", to_bson(&DeliveryStatus::Delivered)?);
+            set.insert("response_status", code as i64);
+            unset.insert("error", "");
+        },
+        Err((code, error)) => {
+            set.insert("error", error);
+            match code {
+                Some(code) => set.insert("response_status", code as i64),
+                None => unset.insert("response_status", ""),
+            };
+            if attempts >= max_attempts() {
+                set.insert("status", to_bson(&DeliveryStatus::Failed)?);
+            } else {
+                set.insert("next_attempt_at", bson_date(now + retry_delay(attempts)));
+                unset.remove("next_attempt_at");
+            }
+        },
+    }
+    Ok(doc! {"$set": set, "$unset": unset})
+}
+
+async fn attempt(db: &MongoRepo, client: &WebhookClient, delivery: &WebhookDelivery) -> Result<(), Box<dyn std::error::Error>> {
+    let id = delivery.id.ok_or("Delivery has no id")?;
+    let webhook = db.find_webhook(doc! {"_id": delivery.webhook_id}).await?;
+    let result = match webhook {
+        Some(webhook) if webhook.active => send(client, &webhook, delivery).await,
+        Some(_) => Err((None, "Webhook is inactive".to_string())),
+        None => Err((None, "Webhook is gone".to_string())),
+    };
+    let update = attempt_update(delivery, result, Utc::now())?;
+    db.update_delivery(&id, update).await?;
+    Ok(())
+}
+
+// the repository of the organization an event happened in
+async fn repo_of(db: &MongoRepo, org_id: Option<ObjectId>) -> Result<Option<MongoRepo>, Box<dyn std::error::Error>> {
+    let organization = match org_id {
+        Some(org_id) => match db.find_organization(doc! {"_id": org_id}).await? {
+            Some(organization) => Some(organization),
+            None => return Ok(None),
+        },
+        None => None,
+    };
+    Ok(Some(db.for_organization(organization).await?))
+}
+
+// queues the event for every webhook of its organization that wants it, returns how many
+async fn enqueue(db: &MongoRepo, published: &Published) -> Result<usize, Box<dyn std::error::Error>> {
+    let repo = match repo_of(db, published.org_id).await? {
+        Some(repo) => repo,
+        None => return Ok(0),
+    };
+    let webhooks = repo.find_webhooks(doc! {"active": true}).await?;
+    let mut queued = 0;
+    for webhook in webhooks.iter().filter(|webhook| webhook.wants(published.event.kind)) {
+        let webhook_id = webhook.id.ok_or("Webhook has no id")?;
+        repo.create_delivery(WebhookDelivery::new(webhook_id, published.event.clone())).await?;
+        queued += 1;
+    }
+    Ok(queued)
+}
+
+/**
+ * Turns events from the bus into deliveries. The queue lives in the database,
+ * so deliveries survive restarts; only events published while the server was
+ * down or that this falls too far behind on are missed.
+ */
+async fn queue_events(db: MongoRepo, queued: Arc<Notify>) {
+    let (_, mut subscription) = db.events().subscribe(None);
+
+    loop {
+        let published = match subscription.recv().await {
+            Ok(published) => published,
+            Err(RecvError::Lagged(missed)) => {
+                println!("Webhooks missed {} events", missed);
+                continue;
+            },
+            Err(RecvError::Closed) => return,
+        };
+        match enqueue(&db, &published).await {
+            Ok(0) => {},
+            Ok(_) => queued.notify_one(),
+            Err(e) => println!("Error queueing webhook deliveries: {}", e),
+        }
+    }
+}
+
+// deliveries this server is sending, per webhook
+type InFlight = Arc<Mutex<HashMap<ObjectId, usize>>>;
+
+fn release(in_flight: &InFlight, webhook_id: &ObjectId) {
+    let mut in_flight = in_flight.lock().unwrap();
+    if let Some(count) = in_flight.get_mut(webhook_id) {
+        *count -= 1;
+        if *count == 0 {
+            in_flight.remove(webhook_id);
+        }
+    }
+}
+
+/**
+ * Claims due deliveries of one organization and sends each in a task of its
+ * own, up to MAX_IN_FLIGHT at once and MAX_IN_FLIGHT_PER_WEBHOOK to the same
+ * webhook, so a slow receiver does not hold up the others. Stops when nothing
+ * more is due or every slot is taken.
+ */
+async fn send_due(db: &MongoRepo, client: &WebhookClient, slots: &Arc<Semaphore>, in_flight: &InFlight) -> Result<(), Box<dyn std::error::Error>> {
+    loop {
+        let slot = match slots.clone().try_acquire_owned() {
+            Ok(slot) => slot,
+            Err(_) => return Ok(()),
+        };
+        let busy: Vec<ObjectId> = in_flight
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, count)| **count >= MAX_IN_FLIGHT_PER_WEBHOOK)
+            .map(|(webhook_id, _)| *webhook_id)
+            .collect();
+        let delivery = match db.claim_delivery(Utc::now(), Duration::seconds(CLAIM_LEASE), &busy).await? {
+            Some(delivery) => delivery,
+            None => return Ok(()),
+        };
+
+        *in_flight.lock().unwrap().entry(delivery.webhook_id).or_default() += 1;
+        let (db, client, in_flight) = (db.clone(), client.clone(), in_flight.clone());
+        tokio::spawn(async move {
+            if let Err(e) = attempt(&db, &client, &delivery).await {
+                println!("Error delivering webhook: {}", e);
+            }
+            release(&in_flight, &delivery.webhook_id);
+            drop(slot);
+        });
+    }
+}
+
+// sends what is due, right after new deliveries are queued and every few seconds
+async fn send_deliveries(db: MongoRepo, queued: Arc<Notify>) {
+    let client = http_client(allow_private_targets());
+    let slots = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+    let in_flight = InFlight::default();
+    let mut interval = time::interval(POLL_INTERVAL);
+
+    let organizations = match db.all_organizations().await {
+        Ok(organizations) => organizations,
+        Err(e) => {
+            println!("Error listing organizations: {}", e);
+            Vec::new()
+        },
+    };
+    for db in organizations {
+        if let Err(e) = db.backfill_delivery_dates().await {
+            println!("Error converting webhook delivery dates: {}", e);
+        }
+    }
+
+    loop {
+        select! {
+            _ = interval.tick() => {},
+            _ = queued.notified() => {},
+        }
+
+        let organizations = match db.all_organizations().await {
+            Ok(organizations) => organizations,
+            Err(e) => {
+                println!("Error listing organizations: {}", e);
+                continue;
+            },
+        };
+        for db in organizations {
+            if let Err(e) = send_due(&db, &client, &slots, &in_flight).await {
+                println!("Error claiming webhook deliveries: {}", e);
+            }
+        }
+    }
+}
+
+pub async fn deliver_webhooks(db: MongoRepo) {
+    let queued = Arc::new(Notify::new());
+    rocket::tokio::spawn(queue_events(db.clone(), queued.clone()));
+    send_deliveries(db, queued).await;
+}
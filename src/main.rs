@@ -1,8 +1,12 @@
 // add the modules
 mod api;
+mod collab;
+mod events;
 mod helpers;
+mod jobs;
 mod models;
 mod repository;
+mod search;
 
 #[cfg(test)]
 mod tests;
@@ -14,15 +18,108 @@ use api::auth::get_jwt;
 use api::user::{
     login,
     create_user,
+    get_me,
+    update_me,
+    delete_me,
     get_user,
     update_user,
+    merge_patch_user,
+    json_patch_user,
+    change_password,
+    renew_password,
+    reset_password,
+    deactivate_user,
+    reactivate_user,
     delete_user,
     get_all_users,
 };
 use api::document::{
+    list_documents,
     get_document,
-    create_document, update_document, delete_document
+    create_document, update_document, delete_document,
+    merge_patch_document, json_patch_document
 };
+use api::event::document_events;
+use api::revision::{get_revisions, get_revision, restore_revision, diff_revisions};
+use api::folder::{
+    get_root_folder,
+    get_folder,
+    create_folder,
+    rename_folder,
+    move_folder,
+    copy_folder,
+    delete_folder,
+    move_document,
+    copy_document,
+};
+use api::share::{
+    shared_with_me,
+    list_shares,
+    share_document,
+    unshare_document,
+    share_document_with_team,
+    unshare_document_with_team,
+    share_folder_with_team,
+    unshare_folder_with_team,
+};
+use api::comment::{
+    list_comments,
+    create_comment,
+    reply_to_comment,
+    edit_comment,
+    delete_comment,
+    resolve_comment,
+    reopen_comment,
+};
+use api::suggestion::{
+    list_suggestions,
+    create_suggestion,
+    accept_suggestion,
+    reject_suggestion,
+    withdraw_suggestion,
+};
+use api::link::{view_link, unlock_link, list_links, create_link, revoke_link};
+use api::organization::{list_organizations, get_organization, create_organization, rotate_scim_token};
+use api::team::{
+    list_teams,
+    create_team,
+    get_team,
+    rename_team,
+    delete_team,
+    set_team_member,
+    remove_team_member,
+};
+use api::trash::{list_trash, restore_document, delete_trashed_document, empty_trash};
+use api::tag::{add_tags, remove_tag, list_tags, rename_tag};
+use api::search::{search_documents, search_facets, rebuild_search_index};
+use api::scim::{
+    scim_list_users,
+    scim_get_user,
+    scim_create_user,
+    scim_replace_user,
+    scim_patch_user,
+    scim_delete_user,
+    scim_list_groups,
+    scim_get_group,
+    scim_create_group,
+    scim_replace_group,
+    scim_patch_group,
+    scim_delete_group,
+    scim_error,
+};
+use api::webhook::{
+    list_webhooks,
+    create_webhook,
+    get_webhook,
+    update_webhook,
+    delete_webhook,
+    ping_webhook,
+    list_deliveries,
+    get_delivery,
+    redeliver,
+};
+use helpers::password::PasswordConfig;
+use helpers::password_policy::PasswordPolicy;
 use repository::mongodb_repo::MongoRepo;
 
 use rocket::{get, http::Status, serde::json::Json, Build, Rocket};
@@ -35,11 +132,146 @@ fn hello() -> Result<Json<String>, Status> {
 #[launch]
 async fn rocket() -> Rocket<Build> {
     let db = MongoRepo::init().await;
+    // fail on startup rather than on the first signup when the hashing params are invalid
+    PasswordConfig::get();
+    PasswordPolicy::get();
+
+    rocket::tokio::spawn(jobs::purge::purge_deleted_users(db.clone()));
+    rocket::tokio::spawn(jobs::purge::purge_trash(db.clone()));
+    rocket::tokio::spawn(jobs::webhooks::deliver_webhooks(db.clone()));
+    rocket::tokio::spawn(collab::server::serve(db.clone()));
+    if db.search_index().is_some() {
+        // the embedded index lives in memory, fill it from the database on every start
+        let db = db.clone();
+        rocket::tokio::spawn(async move {
+            match db.rebuild_search_index().await {
+                Ok(count) => println!("Indexed {} documents for search", count),
+                Err(e) => println!("Error building the search index: {}", e),
+            }
+        });
+    }
 
     rocket::build()
         .manage(db)
         .mount("/", routes![hello])
-        .mount("/users", routes![create_user, get_user, update_user, delete_user, get_all_users, login])
-        .mount("/users/documents", routes![create_document, get_document, update_document, delete_document])
+        .mount("/users", routes![
+            create_user,
+            get_me,
+            update_me,
+            delete_me,
+            get_user,
+            update_user,
+            merge_patch_user,
+            json_patch_user,
+            change_password,
+            renew_password,
+            reset_password,
+            deactivate_user,
+            reactivate_user,
+            delete_user,
+            get_all_users,
+            login,
+        ])
+        .mount("/users/documents", routes![
+            list_documents,
+            create_document,
+            get_document,
+            update_document,
+            merge_patch_document,
+            json_patch_document,
+            delete_document,
+            get_revisions,
+            get_revision,
+            restore_revision,
+            diff_revisions,
+            search_documents,
+            search_facets,
+            rebuild_search_index,
+            add_tags,
+            remove_tag,
+            list_tags,
+            rename_tag,
+            move_document,
+            copy_document,
+            shared_with_me,
+            list_shares,
+            share_document,
+            unshare_document,
+            share_document_with_team,
+            unshare_document_with_team,
+            list_links,
+            create_link,
+            revoke_link,
+            list_trash,
+            restore_document,
+            delete_trashed_document,
+            empty_trash,
+            list_comments,
+            create_comment,
+            reply_to_comment,
+            edit_comment,
+            delete_comment,
+            resolve_comment,
+            reopen_comment,
+            list_suggestions,
+            create_suggestion,
+            accept_suggestion,
+            reject_suggestion,
+            withdraw_suggestion,
+            document_events,
+        ])
+        .mount("/users/folders", routes![
+            get_root_folder,
+            get_folder,
+            create_folder,
+            rename_folder,
+            move_folder,
+            copy_folder,
+            delete_folder,
+            share_folder_with_team,
+            unshare_folder_with_team,
+        ])
+        .mount("/s", routes![view_link, unlock_link])
+        .mount("/organizations", routes![
+            list_organizations,
+            get_organization,
+            create_organization,
+            rotate_scim_token,
+        ])
+        .mount("/teams", routes![
+            list_teams,
+            create_team,
+            get_team,
+            rename_team,
+            delete_team,
+            set_team_member,
+            remove_team_member,
+        ])
+        .mount("/webhooks", routes![
+            list_webhooks,
+            create_webhook,
+            get_webhook,
+            update_webhook,
+            delete_webhook,
+            ping_webhook,
+            list_deliveries,
+            get_delivery,
+            redeliver,
+        ])
         .mount("/auth", routes![get_jwt])
+        .mount("/scim/v2", routes![
+            scim_list_users,
+            scim_get_user,
+            scim_create_user,
+            scim_replace_user,
+            scim_patch_user,
+            scim_delete_user,
+            scim_list_groups,
+            scim_get_group,
+            scim_create_group,
+            scim_replace_group,
+            scim_patch_group,
+            scim_delete_group,
+        ])
+        .register("/scim/v2", catchers![scim_error])
 }
\ No newline at end of file
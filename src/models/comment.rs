@@ -0,0 +1,82 @@
+use chrono::{DateTime, Utc};
+use mongodb::bson::oid::ObjectId;
+use serde::{Serialize, Deserialize};
+use serde_with::skip_serializing_none;
+
+/**
+ * A range of a document's content, in characters from the start. The text
+ * it covered when the comment was made is kept as `quote`, so clients can
+ * find the passage again after the content changed.
+ */
+#[skip_serializing_none]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TextAnchor {
+    pub start: usize,
+    pub end: usize,
+    pub quote: Option<String>,
+}
+
+impl TextAnchor {
+    // the anchored text, None when the range is empty or runs past the content
+    pub fn quote_from(&self, content: &str) -> Option<String> {
+        if self.start >= self.end || self.end > content.chars().count() {
+            return None;
+        }
+        Some(content.chars().skip(self.start).take(self.end - self.start).collect())
+    }
+}
+
+/**
+ * A comment on a document. Comments without `parent_id` start a thread and
+ * can carry an anchor and be resolved; replies point at the thread's first
+ * comment.
+ */
+#[skip_serializing_none]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Comment {
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+
+    pub document_id: ObjectId,
+    pub author_id: Option<ObjectId>,
+    pub parent_id: Option<ObjectId>,
+    pub body: String,
+    pub anchor: Option<TextAnchor>,
+    pub resolved_at: Option<DateTime<Utc>>,
+    pub resolved_by: Option<ObjectId>,
+    pub date_created: Option<DateTime<Utc>>,
+    // set when the author edited the comment
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+impl Comment {
+    pub fn is_reply(&self) -> bool {
+        self.parent_id.is_some()
+    }
+
+    pub fn is_resolved(&self) -> bool {
+        self.resolved_at.is_some()
+    }
+}
+
+// a thread's first comment followed by its replies, oldest first
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CommentThread {
+    #[serde(flatten)]
+    pub comment: Comment,
+    pub replies: Vec<Comment>,
+}
+
+impl CommentThread {
+    // groups comments into threads, keeping the order they come in
+    pub fn group(comments: Vec<Comment>) -> Vec<CommentThread> {
+        let (roots, replies): (Vec<Comment>, Vec<Comment>) = comments.into_iter().partition(|c| !c.is_reply());
+        let mut threads: Vec<CommentThread> = roots.into_iter().map(|comment| CommentThread { comment, replies: Vec::new() }).collect();
+        for reply in replies {
+            if let Some(thread) = threads.iter_mut().find(|t| t.comment.id.is_some() && t.comment.id == reply.parent_id) {
+                thread.replies.push(reply);
+            }
+        }
+        threads
+    }
+}
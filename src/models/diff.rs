@@ -0,0 +1,42 @@
+use serde::{Serialize, Deserialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    Line,
+    Word,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Equal,
+    Insert,
+    Delete,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiffChange {
+    pub kind: ChangeKind,
+    pub value: String,
+}
+
+// ranges are 1-based and counted in lines or words depending on the granularity
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiffHunk {
+    pub old_start: usize,
+    pub old_len: usize,
+    pub new_start: usize,
+    pub new_len: usize,
+    pub changes: Vec<DiffChange>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DocumentDiff {
+    pub document_id: String,
+    pub from: i64,
+    // None when compared against the current document
+    pub to: Option<i64>,
+    pub granularity: Granularity,
+    pub hunks: Vec<DiffHunk>,
+}
@@ -4,6 +4,8 @@ use serde::{Serialize, Deserialize};
 use serde_with::skip_serializing_none;
 use struct_helpers::{to_lower_case_optional, Helpers};
 
+use crate::models::share::{Permission, Principal, Share, TeamShare};
+
 #[skip_serializing_none]
 #[derive(Debug, Default, Serialize, Deserialize, Helpers)]
 pub struct Document {
@@ -11,15 +13,45 @@ pub struct Document {
     pub id: Option<ObjectId>,
 
     pub owner_id: Option<ObjectId>,
+    // None for documents at the owner's root
+    pub folder_id: Option<ObjectId>,
     #[helper(to_lower_case)]
     pub title: Option<String>,
     pub content: Option<String>,
+    // ISO 639-1 code, picks the stemmer for search
+    pub language: Option<String>,
+    // lowercase and unique, see api::tag::normalize_tags
+    pub tags: Option<Vec<String>>,
+    // the users the owner shared the document with, see api::share
+    pub shares: Option<Vec<Share>>,
+    pub team_shares: Option<Vec<TeamShare>>,
     pub date_created: Option<DateTime<Utc>>,
     pub last_modified: Option<DateTime<Utc>>,
+    pub last_modified_by: Option<ObjectId>,
+    // bumped on every write, exposed as the ETag
+    pub version: Option<i64>,
+    // only set on documents in the trash, see api::trash
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub deleted_by: Option<ObjectId>,
 }
 
 impl Document {
-    pub fn remove_id(&mut self) {
-        self.id = None;
+    pub fn version(&self) -> i64 {
+        self.version.unwrap_or(0)
+    }
+
+    /**
+     * The most `principal` may do with the document, None when they can not
+     * even read it. Of the grants to the user, to their teams and to the
+     * folder the document is filed in, the highest one counts.
+     */
+    pub fn permission_for(&self, principal: &Principal) -> Option<Permission> {
+        let user_id = principal.user.id?;
+        if self.owner_id == Some(user_id) || principal.is_admin() {
+            return Some(Permission::Owner);
+        }
+        let shared = self.shares.iter().flatten().find(|share| share.user_id == user_id).map(|share| share.permission);
+        let folder = self.folder_id.and_then(|id| principal.folder_grants.get(&id).copied());
+        [shared, principal.team_permission(&self.team_shares), folder].into_iter().flatten().max()
     }
 }
@@ -0,0 +1,110 @@
+use chrono::{DateTime, Utc};
+use mongodb::bson::oid::ObjectId;
+use serde::{Serialize, Deserialize};
+use serde_with::skip_serializing_none;
+
+use crate::models::{document::Document, user::User};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum EventKind {
+    #[serde(rename = "document.created")]
+    DocumentCreated,
+    #[serde(rename = "document.updated")]
+    DocumentUpdated,
+    // moved to the trash
+    #[serde(rename = "document.deleted")]
+    DocumentDeleted,
+    #[serde(rename = "document.shared")]
+    DocumentShared,
+    #[serde(rename = "document.commented")]
+    DocumentCommented,
+    #[serde(rename = "user.created")]
+    UserCreated,
+    // profile changes, deactivation and reactivation
+    #[serde(rename = "user.updated")]
+    UserUpdated,
+    // scheduled for deletion, see the purge job
+    #[serde(rename = "user.deleted")]
+    UserDeleted,
+    // only sent to test a webhook
+    #[serde(rename = "ping")]
+    Ping,
+}
+
+impl EventKind {
+    // the name events of this kind are sent under, same as in JSON
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::DocumentCreated => "document.created",
+            EventKind::DocumentUpdated => "document.updated",
+            EventKind::DocumentDeleted => "document.deleted",
+            EventKind::DocumentShared => "document.shared",
+            EventKind::DocumentCommented => "document.commented",
+            EventKind::UserCreated => "user.created",
+            EventKind::UserUpdated => "user.updated",
+            EventKind::UserDeleted => "user.deleted",
+            EventKind::Ping => "ping",
+        }
+    }
+}
+
+/**
+ * Something that happened to a document or user, as subscribers see it. The
+ * bus numbers events `<boot>-<sequence>`: sequence numbers only count up
+ * within one run of the server, the boot part tells runs apart.
+ */
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    pub document_id: Option<ObjectId>,
+    pub title: Option<String>,
+    pub version: Option<i64>,
+    pub user_id: Option<ObjectId>,
+    pub username: Option<String>,
+    pub date: DateTime<Utc>,
+}
+
+impl Event {
+    // the id is left for the bus to fill in
+    pub fn for_document(kind: EventKind, document: &Document) -> Self {
+        Event {
+            id: String::new(),
+            kind,
+            document_id: document.id,
+            title: document.title.clone(),
+            version: Some(document.version()),
+            user_id: None,
+            username: None,
+            date: Utc::now(),
+        }
+    }
+
+    pub fn for_user(kind: EventKind, user: &User) -> Self {
+        Event {
+            id: String::new(),
+            kind,
+            document_id: None,
+            title: None,
+            version: None,
+            user_id: user.id,
+            username: user.username.clone(),
+            date: Utc::now(),
+        }
+    }
+
+    pub fn ping(id: String) -> Self {
+        Event {
+            id,
+            kind: EventKind::Ping,
+            document_id: None,
+            title: None,
+            version: None,
+            user_id: None,
+            username: None,
+            date: Utc::now(),
+        }
+    }
+}
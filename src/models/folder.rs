@@ -0,0 +1,39 @@
+use chrono::{DateTime, Utc};
+use mongodb::bson::oid::ObjectId;
+use serde::{Serialize, Deserialize};
+use serde_with::skip_serializing_none;
+
+use crate::models::{document::Document, share::TeamShare};
+
+// folders nest through parent_id, a folder without one sits at the owner's root
+#[skip_serializing_none]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Folder {
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+
+    pub owner_id: Option<ObjectId>,
+    pub name: String,
+    pub parent_id: Option<ObjectId>,
+    // team grants here cover the documents in this folder and its subfolders
+    pub team_shares: Option<Vec<TeamShare>>,
+    pub date_created: Option<DateTime<Utc>>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Breadcrumb {
+    pub id: String,
+    pub name: String,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FolderListing {
+    // None when listing the root
+    pub folder: Option<Folder>,
+    // from the top-level folder down to the listed one
+    pub breadcrumbs: Vec<Breadcrumb>,
+    pub folders: Vec<Folder>,
+    pub documents: Vec<Document>,
+}
@@ -0,0 +1,124 @@
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::oid::ObjectId;
+use serde::{Serialize, Deserialize};
+use serde_with::skip_serializing_none;
+
+use crate::models::document::Document;
+
+// wrong passwords a link takes before it locks
+pub const LINK_ATTEMPTS_BEFORE_LOCK: i64 = 5;
+const FIRST_LOCK_SECONDS: i64 = 60;
+const MAX_LOCK_SECONDS: i64 = 24 * 60 * 60;
+
+/**
+ * A public, read-only link to a document, opened without an account at
+ * `/s/<token>`. Links are kept outside the organizations' data so the token
+ * alone finds them, `org_id` says which organization the document is in.
+ */
+#[skip_serializing_none]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ShareLink {
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+
+    // random and unguessable, the only thing a visitor needs
+    pub token: String,
+    pub document_id: ObjectId,
+    pub org_id: Option<ObjectId>,
+    pub created_by: Option<ObjectId>,
+    // Argon2 hash, visitors have to send the password when set
+    pub password_hash: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_views: Option<i64>,
+    #[serde(default)]
+    pub views: i64,
+    // wrong passwords since the last successful visit
+    #[serde(default)]
+    pub failed_attempts: i64,
+    pub locked_until: Option<DateTime<Utc>>,
+    pub date_created: Option<DateTime<Utc>>,
+}
+
+/**
+ * How long a link stays locked after its `failed_attempts`th wrong password.
+ * The first few cost nothing, after that every failure doubles the lock, from
+ * a minute up to a day.
+ */
+pub fn lock_after(failed_attempts: i64) -> Option<Duration> {
+    let over = failed_attempts - LINK_ATTEMPTS_BEFORE_LOCK;
+    if over < 0 {
+        return None;
+    }
+    let seconds = FIRST_LOCK_SECONDS.saturating_mul(1_i64 << over.min(20));
+    Some(Duration::seconds(seconds.min(MAX_LOCK_SECONDS)))
+}
+
+impl ShareLink {
+    pub fn is_locked(&self, now: DateTime<Utc>) -> bool {
+        self.locked_until.is_some_and(|locked_until| locked_until > now)
+    }
+
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    pub fn is_used_up(&self) -> bool {
+        self.max_views.is_some_and(|max_views| self.views >= max_views)
+    }
+}
+
+// what owners see of a link, without the password hash
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShareLinkInfo {
+    pub id: Option<ObjectId>,
+    pub token: String,
+    pub document_id: ObjectId,
+    pub created_by: Option<ObjectId>,
+    pub has_password: bool,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub max_views: Option<i64>,
+    pub views: i64,
+    pub locked_until: Option<DateTime<Utc>>,
+    pub date_created: Option<DateTime<Utc>>,
+}
+
+impl From<ShareLink> for ShareLinkInfo {
+    fn from(link: ShareLink) -> Self {
+        ShareLinkInfo {
+            id: link.id,
+            token: link.token,
+            document_id: link.document_id,
+            created_by: link.created_by,
+            has_password: link.password_hash.is_some(),
+            expires_at: link.expires_at,
+            max_views: link.max_views,
+            views: link.views,
+            locked_until: link.locked_until,
+            date_created: link.date_created,
+        }
+    }
+}
+
+// what visitors of a link see of the document, no owners, shares or ids
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SharedDocument {
+    pub title: Option<String>,
+    pub content: Option<String>,
+    pub language: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+impl From<Document> for SharedDocument {
+    fn from(document: Document) -> Self {
+        SharedDocument {
+            title: document.title,
+            content: document.content,
+            language: document.language,
+            tags: document.tags,
+            last_modified: document.last_modified,
+        }
+    }
+}
@@ -1,2 +1,33 @@
+use chrono::{DateTime, Utc};
+use mongodb::bson;
+use serde::{Deserialize, Deserializer};
+
+pub mod comment;
+pub mod diff;
 pub mod document;
-pub mod user;
\ No newline at end of file
+pub mod event;
+pub mod folder;
+pub mod link;
+pub mod organization;
+pub mod revision;
+pub mod scim;
+pub mod search;
+pub mod share;
+pub mod suggestion;
+pub mod team;
+pub mod user;
+pub mod webhook;
+
+// a date stored as a BSON date, or as a string by records written before that
+pub fn stored_date<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<Utc>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Stored {
+        Date(bson::DateTime),
+        Text(DateTime<Utc>),
+    }
+    Ok(Option::<Stored>::deserialize(deserializer)?.map(|stored| match stored {
+        Stored::Date(date) => DateTime::from_timestamp_millis(date.timestamp_millis()).unwrap_or_default(),
+        Stored::Text(date) => date,
+    }))
+}
@@ -0,0 +1,32 @@
+use chrono::{DateTime, Utc};
+use mongodb::bson::oid::ObjectId;
+use serde::{Serialize, Deserialize};
+use serde_with::skip_serializing_none;
+
+/**
+ * A tenant of the deployment. Users, documents and everything attached to
+ * them belong to exactly one organization; data written before organizations
+ * existed belongs to the default one, which has no record of its own.
+ */
+#[skip_serializing_none]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Organization {
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+
+    pub name: String,
+    // lowercase letters, digits and dashes, sent in the X-Organization header
+    pub slug: String,
+    // set when the organization has a database of its own instead of sharing the default one
+    pub database: Option<String>,
+    // SHA-256 of the bearer token its identity provider uses for SCIM, the token is only shown once
+    pub scim_token_hash: Option<String>,
+    pub date_created: Option<DateTime<Utc>>,
+}
+
+impl Organization {
+    // what operators see, without the SCIM token hash
+    pub fn without_secrets(self) -> Self {
+        Organization { scim_token_hash: None, ..self }
+    }
+}
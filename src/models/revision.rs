@@ -0,0 +1,65 @@
+use chrono::{DateTime, Utc};
+use mongodb::bson::{doc, oid::ObjectId};
+use serde::{Serialize, Deserialize};
+use serde_with::skip_serializing_none;
+use sha2::{Digest, Sha256};
+
+use crate::models::document::Document;
+
+// an immutable snapshot of a document, written on every change
+#[skip_serializing_none]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Revision {
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+
+    pub document_id: ObjectId,
+    pub number: i64,
+    // the document version it captures, revisions are ordered by it since numbers are taken first come first served
+    pub version: Option<i64>,
+    pub author_id: Option<ObjectId>,
+    pub created_at: DateTime<Utc>,
+    pub content_hash: String,
+    pub title: Option<String>,
+    pub content: Option<String>,
+    // set when the revision was made by restoring an older one
+    pub restored_from: Option<i64>,
+}
+
+pub fn content_hash(content: Option<&str>) -> String {
+    let digest = Sha256::digest(content.unwrap_or("").as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Revision {
+    pub fn snapshot(document: &Document, number: i64, author_id: Option<ObjectId>) -> Option<Revision> {
+        Some(Revision {
+            id: None,
+            document_id: document.id?,
+            number,
+            version: Some(document.version()),
+            author_id,
+            created_at: Utc::now(),
+            content_hash: content_hash(document.content.as_deref()),
+            title: document.title.clone(),
+            content: document.content.clone(),
+            restored_from: None,
+        })
+    }
+
+    // the changes that bring a document back to this revision, everything else stays as it is
+    pub fn restore(&self, author_id: Option<ObjectId>) -> Document {
+        Document {
+            title: self.title.clone(),
+            content: self.content.clone(),
+            last_modified: Some(Utc::now()),
+            last_modified_by: author_id,
+            ..Default::default()
+        }
+    }
+}
+
+// lists go from the oldest version up, `direction` -1 turns them around
+pub fn revision_order(direction: i32) -> mongodb::bson::Document {
+    doc! {"version": direction, "number": direction}
+}
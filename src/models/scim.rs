@@ -0,0 +1,210 @@
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+use serde_with::skip_serializing_none;
+
+use crate::models::{team::Team, user::{RoleEnum, User}};
+
+pub const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+pub const GROUP_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+pub const LIST_RESPONSE_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+pub const PATCH_OP_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:PatchOp";
+pub const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+
+#[skip_serializing_none]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimName {
+    pub given_name: Option<String>,
+    pub family_name: Option<String>,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ScimMultiValue {
+    pub value: String,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub primary: Option<bool>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimMeta {
+    pub resource_type: String,
+    pub location: String,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimUser {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    pub id: Option<String>,
+    pub user_name: Option<String>,
+    pub name: Option<ScimName>,
+    #[serde(default)]
+    pub emails: Vec<ScimMultiValue>,
+    pub active: Option<bool>,
+    #[serde(default)]
+    pub roles: Vec<ScimMultiValue>,
+    pub password: Option<String>,
+    pub meta: Option<ScimMeta>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScimListResponse<T> {
+    pub schemas: Vec<String>,
+    #[serde(rename = "totalResults")]
+    pub total_results: u64,
+    #[serde(rename = "startIndex")]
+    pub start_index: u64,
+    #[serde(rename = "itemsPerPage")]
+    pub items_per_page: u64,
+    #[serde(rename = "Resources")]
+    pub resources: Vec<T>,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ScimMember {
+    pub value: String,
+    pub display: Option<String>,
+}
+
+// a team as the identity provider sees it, members are users of the organization
+#[skip_serializing_none]
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimGroup {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    pub id: Option<String>,
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub members: Vec<ScimMember>,
+    pub meta: Option<ScimMeta>,
+}
+
+/**
+ * The body of every failed SCIM request. `status` is the HTTP status code as
+ * a string, `scim_type` one of the error types of RFC 7644 section 3.12.
+ */
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScimError {
+    pub schemas: Vec<String>,
+    pub status: String,
+    pub scim_type: Option<String>,
+    pub detail: Option<String>,
+}
+
+impl ScimError {
+    pub fn new(status: u16, scim_type: Option<&str>, detail: impl Into<String>) -> Self {
+        ScimError {
+            schemas: vec![ERROR_SCHEMA.to_string()],
+            status: status.to_string(),
+            scim_type: scim_type.map(str::to_string),
+            detail: Some(detail.into()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScimPatchOperation {
+    pub op: String,
+    pub path: Option<String>,
+    pub value: Option<Value>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScimPatchRequest {
+    #[serde(default)]
+    pub schemas: Vec<String>,
+    #[serde(rename = "Operations")]
+    pub operations: Vec<ScimPatchOperation>,
+}
+
+pub fn role_from_scim(value: &str) -> Option<RoleEnum> {
+    match value.to_lowercase().as_str() {
+        "user" => Some(RoleEnum::User),
+        "administrator" | "admin" => Some(RoleEnum::Administrator),
+        _ => None,
+    }
+}
+
+pub fn role_to_scim(role: &RoleEnum) -> String {
+    match role {
+        RoleEnum::User => "User".to_string(),
+        RoleEnum::Administrator => "Administrator".to_string(),
+    }
+}
+
+impl ScimUser {
+    // the primary email wins, otherwise the first one listed
+    pub fn primary_email(&self) -> Option<String> {
+        self.emails
+            .iter()
+            .find(|e| e.primary == Some(true))
+            .or(self.emails.first())
+            .map(|e| e.value.clone())
+    }
+
+    pub fn role(&self) -> Option<RoleEnum> {
+        self.roles.iter().find_map(|r| role_from_scim(&r.value))
+    }
+}
+
+impl From<User> for ScimUser {
+    fn from(u: User) -> Self {
+        let id = u.id.map(|id| id.to_hex());
+        let active = u.is_active();
+
+        ScimUser {
+            schemas: vec![USER_SCHEMA.to_string()],
+            meta: id.as_ref().map(|id| ScimMeta {
+                resource_type: "User".to_string(),
+                location: format!("/scim/v2/Users/{}", id),
+            }),
+            id,
+            user_name: u.username,
+            name: Some(ScimName {
+                given_name: u.firstname,
+                family_name: u.lastname,
+            }),
+            emails: u.email.into_iter().map(|value| ScimMultiValue {
+                value,
+                kind: Some("work".to_string()),
+                primary: Some(true),
+            }).collect(),
+            active: Some(active),
+            roles: u.role.iter().map(|r| ScimMultiValue {
+                value: role_to_scim(r),
+                kind: None,
+                primary: Some(true),
+            }).collect(),
+            password: None,
+        }
+    }
+}
+
+impl From<Team> for ScimGroup {
+    fn from(team: Team) -> Self {
+        let id = team.id.map(|id| id.to_hex());
+
+        ScimGroup {
+            schemas: vec![GROUP_SCHEMA.to_string()],
+            meta: id.as_ref().map(|id| ScimMeta {
+                resource_type: "Group".to_string(),
+                location: format!("/scim/v2/Groups/{}", id),
+            }),
+            id,
+            display_name: Some(team.name),
+            members: team.members.into_iter().map(|member| ScimMember {
+                value: member.user_id.to_hex(),
+                display: None,
+            }).collect(),
+        }
+    }
+}
@@ -0,0 +1,28 @@
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+use serde_with::skip_serializing_none;
+
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SearchHit {
+    pub id: String,
+    pub title: Option<String>,
+    // content around the first match, matching words wrapped in <mark></mark>
+    pub snippet: String,
+    // relevance, missing when the results are not ranked
+    pub score: Option<f64>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FacetCount {
+    pub value: String,
+    pub count: u64,
+}
+
+// how the matching documents spread over owners and tags, most frequent first
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Facets {
+    pub owners: Vec<FacetCount>,
+    pub tags: Vec<FacetCount>,
+}
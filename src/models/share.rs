@@ -0,0 +1,56 @@
+use std::collections::HashMap;
+use mongodb::bson::oid::ObjectId;
+use serde::{Serialize, Deserialize};
+
+use crate::models::user::User;
+
+// what a user may do with a document, each level includes the ones below it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Viewer,
+    Commenter,
+    Editor,
+    // the owner and administrators, never granted through a share
+    Owner,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Share {
+    pub user_id: ObjectId,
+    pub permission: Permission,
+}
+
+// a grant to every member of a team, on a document or on a folder and everything below it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TeamShare {
+    pub team_id: ObjectId,
+    pub permission: Permission,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ShareGrant {
+    pub permission: Permission,
+}
+
+/**
+ * Who is asking for access: the user, the teams they belong to and the
+ * folders those teams were granted, with the grant inherited by subfolders.
+ */
+#[derive(Debug, Default)]
+pub struct Principal {
+    pub user: User,
+    pub team_ids: Vec<ObjectId>,
+    pub folder_grants: HashMap<ObjectId, Permission>,
+}
+
+impl Principal {
+    pub fn is_admin(&self) -> bool {
+        self.user.is_admin()
+    }
+
+    // the best of the grants that name one of the principal's teams
+    pub fn team_permission(&self, shares: &Option<Vec<TeamShare>>) -> Option<Permission> {
+        shares.iter().flatten().filter(|share| self.team_ids.contains(&share.team_id)).map(|share| share.permission).max()
+    }
+}
@@ -0,0 +1,79 @@
+use chrono::{DateTime, Utc};
+use crate::helpers::diff::rebase_range;
+use mongodb::bson::oid::ObjectId;
+use rocket::FromFormField;
+use serde::{Serialize, Deserialize};
+use serde_with::skip_serializing_none;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, FromFormField)]
+#[serde(rename_all = "lowercase")]
+pub enum SuggestionStatus {
+    Pending,
+    Accepted,
+    Rejected,
+    // another change rewrote the text the suggestion was about
+    Conflicted,
+}
+
+/**
+ * A proposed change to a document's content: the characters `start..end`
+ * of `base_content` replaced by `text`. An empty range inserts, an empty
+ * `text` deletes. Pending suggestions are rebased onto every revision an
+ * accepted suggestion makes.
+ */
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Suggestion {
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+
+    pub document_id: ObjectId,
+    pub author_id: Option<ObjectId>,
+    pub base_revision: i64,
+    // the document version and content the range refers to, missing on older suggestions
+    pub base_version: Option<i64>,
+    pub base_content: Option<String>,
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+    // the text the suggestion replaces, as it read in the base revision
+    pub quote: String,
+    pub status: SuggestionStatus,
+    pub date_created: Option<DateTime<Utc>>,
+    // who accepted or rejected the suggestion and when
+    pub decided_by: Option<ObjectId>,
+    pub decided_at: Option<DateTime<Utc>>,
+    // the revision an accepted suggestion made
+    pub revision: Option<i64>,
+}
+
+impl Suggestion {
+    // `content` with the suggestion applied, None when the range runs past its end
+    pub fn apply(&self, content: &str) -> Option<String> {
+        let chars: Vec<char> = content.chars().collect();
+        if self.start > self.end || self.end > chars.len() {
+            return None;
+        }
+        let mut applied: String = chars[..self.start].iter().collect();
+        applied.push_str(&self.text);
+        applied.extend(&chars[self.end..]);
+        Some(applied)
+    }
+
+    /**
+     * The suggestion moved from `base` onto `content`, the document at
+     * `version` recorded as `revision`. None when a change in between
+     * touched its range.
+     */
+    pub fn rebased(&self, base: &str, content: &str, version: i64, revision: i64) -> Option<Suggestion> {
+        let (start, end) = rebase_range(base, content, self.start, self.end)?;
+        Some(Suggestion {
+            base_revision: revision,
+            base_version: Some(version),
+            base_content: Some(content.to_string()),
+            start,
+            end,
+            ..self.clone()
+        })
+    }
+}
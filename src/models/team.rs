@@ -0,0 +1,40 @@
+use chrono::{DateTime, Utc};
+use mongodb::bson::oid::ObjectId;
+use serde::{Serialize, Deserialize};
+use serde_with::skip_serializing_none;
+
+// team admins manage the membership and can rename or delete the team
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TeamRole {
+    Member,
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TeamMember {
+    pub user_id: ObjectId,
+    pub role: TeamRole,
+}
+
+#[skip_serializing_none]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Team {
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+
+    pub name: String,
+    pub members: Vec<TeamMember>,
+    pub date_created: Option<DateTime<Utc>>,
+    pub last_modified: Option<DateTime<Utc>>,
+}
+
+impl Team {
+    pub fn role_of(&self, user_id: &ObjectId) -> Option<TeamRole> {
+        self.members.iter().find(|m| &m.user_id == user_id).map(|m| m.role)
+    }
+
+    pub fn admin_count(&self) -> usize {
+        self.members.iter().filter(|m| m.role == TeamRole::Admin).count()
+    }
+}
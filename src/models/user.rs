@@ -1,14 +1,26 @@
+use chrono::{DateTime, Utc};
 use mongodb::bson::oid::ObjectId;
 use serde::{Serialize, Deserialize};
 use serde_with::skip_serializing_none;
 use struct_helpers::{to_lower_case, to_lower_case_optional, Helpers};
 
-#[derive(Serialize, Deserialize, Debug)]
+use crate::models::stored_date;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub enum RoleEnum {
     User,
     Administrator,
 }
 
+// what happens to a user's documents once the user is purged
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum DeletionStrategy {
+    Delete,
+    Transfer { to: ObjectId },
+    Archive,
+}
+
 #[skip_serializing_none]
 #[derive(Debug, Default, Serialize, Deserialize, Helpers)]
 pub struct User {
@@ -21,6 +33,37 @@ pub struct User {
     pub email: Option<String>,
     pub password: String,
     pub role: Option<RoleEnum>,
+    pub active: Option<bool>,
+    pub password_history: Option<Vec<String>>,
+    pub password_changed_at: Option<DateTime<Utc>>,
+    // a BSON date so the purge can query it, older deletions stored it as a string
+    #[serde(default, deserialize_with = "stored_date")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub deletion_strategy: Option<DeletionStrategy>,
+}
+
+// what a user may change on their own profile, role and credentials are managed elsewhere
+#[skip_serializing_none]
+#[derive(Debug, Default, Serialize, Deserialize, Helpers)]
+pub struct ProfileUpdate {
+    #[helper(to_lower_case)]
+    pub firstname: Option<String>,
+    pub lastname: Option<String>,
+    pub username: Option<String>,
+}
+
+// a user as shown to clients, without credentials
+#[skip_serializing_none]
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UserProfile {
+    pub id: Option<String>,
+    pub firstname: Option<String>,
+    pub lastname: Option<String>,
+    pub username: Option<String>,
+    pub email: Option<String>,
+    pub role: Option<RoleEnum>,
+    pub active: bool,
+    pub deleted_at: Option<DateTime<Utc>>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Helpers)]
@@ -33,6 +76,35 @@ impl User {
     pub fn remove_id(&mut self) {
         self.id = None;
     }
+
+    // users without the flag predate deactivation and count as active
+    pub fn is_active(&self) -> bool {
+        self.active.unwrap_or(true) && self.deleted_at.is_none()
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.role == Some(RoleEnum::Administrator)
+    }
+
+    // users manage their own account, administrators every account
+    pub fn can_manage(&self, user_id: &ObjectId) -> bool {
+        self.id.as_ref() == Some(user_id) || self.is_admin()
+    }
+}
+
+impl From<User> for UserProfile {
+    fn from(u: User) -> Self {
+        UserProfile {
+            id: u.id.map(|id| id.to_hex()),
+            active: u.is_active(),
+            firstname: u.firstname,
+            lastname: u.lastname,
+            username: u.username,
+            email: u.email,
+            role: u.role,
+            deleted_at: u.deleted_at,
+        }
+    }
 }
 
 impl From<UserName> for User {
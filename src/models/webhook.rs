@@ -0,0 +1,117 @@
+use chrono::{DateTime, Utc};
+use mongodb::bson::oid::ObjectId;
+use serde::{Serialize, Deserialize};
+use serde_with::skip_serializing_none;
+
+use crate::models::event::{Event, EventKind};
+use crate::models::stored_date;
+
+/**
+ * A subscription of another system to events of the organization. Every
+ * matching event is POSTed to `url` as JSON, signed with `secret`, see
+ * jobs::webhooks.
+ */
+#[skip_serializing_none]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Webhook {
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+
+    pub url: String,
+    // the event types to send, all of them when empty
+    #[serde(default)]
+    pub events: Vec<EventKind>,
+    pub secret: String,
+    #[serde(default)]
+    pub active: bool,
+    pub created_by: Option<ObjectId>,
+    pub date_created: Option<DateTime<Utc>>,
+}
+
+impl Webhook {
+    pub fn wants(&self, kind: EventKind) -> bool {
+        self.active && (self.events.is_empty() || self.events.contains(&kind))
+    }
+}
+
+// a webhook as listed, the secret is only shown once when it is created
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookInfo {
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+
+    pub url: String,
+    pub events: Vec<EventKind>,
+    pub active: bool,
+    pub created_by: Option<ObjectId>,
+    pub date_created: Option<DateTime<Utc>>,
+}
+
+impl From<Webhook> for WebhookInfo {
+    fn from(webhook: Webhook) -> Self {
+        WebhookInfo {
+            id: webhook.id,
+            url: webhook.url,
+            events: webhook.events,
+            active: webhook.active,
+            created_by: webhook.created_by,
+            date_created: webhook.date_created,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    // waiting for its next attempt
+    Pending,
+    Delivered,
+    // gave up after the last attempt
+    Failed,
+}
+
+/**
+ * One event on its way to one webhook, kept as the queue and as the log of
+ * attempts. A redelivery is a new delivery of the same event.
+ */
+#[skip_serializing_none]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WebhookDelivery {
+    #[serde(rename = "_id")]
+    pub id: Option<ObjectId>,
+
+    pub webhook_id: ObjectId,
+    pub event: Event,
+    pub status: DeliveryStatus,
+    #[serde(default)]
+    pub attempts: i64,
+    // stored as a BSON date so due deliveries can be queried, see delivery_entry
+    #[serde(default, deserialize_with = "stored_date")]
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    pub last_attempt_at: Option<DateTime<Utc>>,
+    // what the last attempt got back, a status code or why there was none
+    pub response_status: Option<i64>,
+    pub error: Option<String>,
+    pub redelivery_of: Option<ObjectId>,
+    pub date_created: Option<DateTime<Utc>>,
+}
+
+impl WebhookDelivery {
+    pub fn new(webhook_id: ObjectId, event: Event) -> Self {
+        let now = Utc::now();
+        WebhookDelivery {
+            id: None,
+            webhook_id,
+            event,
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            next_attempt_at: Some(now),
+            last_attempt_at: None,
+            response_status: None,
+            error: None,
+            redelivery_of: None,
+            date_created: Some(now),
+        }
+    }
+}
@@ -1 +1,2 @@
-pub mod mongodb_repo;
\ No newline at end of file
+pub mod mongodb_repo;
+pub mod scoped;
\ No newline at end of file
@@ -1,26 +1,95 @@
-use std::{env, error::Error};
+use std::{collections::HashMap, env, error::Error, sync::{Arc, Mutex}};
 extern crate dotenv;
 use dotenv::dotenv;
 use rocket::{futures::StreamExt};
+use tokio::sync::OnceCell;
+use chrono::{DateTime, Duration, Utc};
 use serde::{Serialize, Deserialize};
-use argon2::{
-    password_hash::{PasswordHash, PasswordVerifier},
-    Argon2
-};
 use mongodb::{
-    bson::{doc, oid::ObjectId, to_document},
+    bson::{self, doc, oid::ObjectId, Bson, to_bson, to_document, Document as BsonDocument},
+    error::{ErrorKind, WriteFailure},
+    options::{FindOneAndUpdateOptions, FindOneOptions, FindOptions, IndexOptions, ReturnDocument, UpdateModifications},
     results::{DeleteResult, InsertOneResult, UpdateResult},
-    Client, Collection,
+    Client, ClientSession, Collection, Database, IndexModel,
 };
-use crate::{models::{user::User, document::Document}, helpers::jwt};
+use crate::{models::{user::{DeletionStrategy, User}, comment::Comment, document::Document, event::EventKind, folder::Folder, link::{lock_after, ShareLink}, organization::Organization, revision::{revision_order, Revision}, search::{FacetCount, Facets}, share::{Permission, Share, TeamShare}, suggestion::Suggestion, team::{Team, TeamMember, TeamRole}, webhook::{DeliveryStatus, Webhook, WebhookDelivery}}, helpers::{jwt, password::{self, Verification}, password_policy::PasswordPolicy}};
+use crate::events::bus::EventBus;
+use crate::repository::scoped::ScopedCollection;
+use crate::search::{analyzer::Language, index::{SearchConfig, SearchEngine, SearchIndex}};
+
+// documents written before versioning have no version field and count as version 0
+fn version_filter(version: i64) -> Bson {
+    match version {
+        0 => Bson::Document(doc! {"$in": [Bson::Null, 0_i64]}),
+        v => Bson::Int64(v),
+    }
+}
+
+// matches the document `id`, and only at `expected_version` when the client sent one
+pub fn expected_version_filter(id: &ObjectId, expected_version: Option<i64>) -> BsonDocument {
+    let mut filter = doc! {"_id": id};
+    if let Some(version) = expected_version {
+        filter.insert("version", version_filter(version));
+    }
+    filter
+}
 
+/**
+ * A pipeline update that puts `grant` in the array `field`, replacing the
+ * element whose `key` equals `principal`. Grants, shares and memberships all
+ * keep one element per principal this way.
+ */
+fn grant_pipeline(field: &str, key: &str, principal: &ObjectId, grant: Bson) -> Vec<BsonDocument> {
+    vec![doc! {"$set": {field: {"$concatArrays": [
+        {"$filter": {
+            "input": {"$ifNull": [format!("${}", field), []]},
+            "cond": {"$ne": [format!("$$this.{}", key), principal]},
+        }},
+        [grant],
+    ]}}}]
+}
+
+// folders nested deeper than this are not followed
+pub const MAX_FOLDER_DEPTH: usize = 64;
+
+// the database of the default organization and of every organization without one of its own
+const DEFAULT_DATABASE: &str = "rustDB";
+
+/**
+ * The repository of one organization, see repository::scoped. The managed
+ * instance belongs to the default organization; requests get one scoped to
+ * the caller's organization through helpers::tenant.
+ */
+#[derive(Clone)]
 pub struct MongoRepo {
-    user_col: Collection<User>,
-    document_col: Collection<Document>,
+    client: Client,
+    // None for the default organization
+    organization: Option<Organization>,
+    organization_col: Collection<Organization>,
+    // share links of every organization, looked up by token alone
+    link_col: Collection<ShareLink>,
+    user_col: ScopedCollection<User>,
+    document_col: ScopedCollection<Document>,
+    archive_col: ScopedCollection<BsonDocument>,
+    trash_col: ScopedCollection<Document>,
+    revision_col: ScopedCollection<Revision>,
+    folder_col: ScopedCollection<Folder>,
+    team_col: ScopedCollection<Team>,
+    comment_col: ScopedCollection<Comment>,
+    suggestion_col: ScopedCollection<Suggestion>,
+    webhook_col: ScopedCollection<Webhook>,
+    delivery_col: ScopedCollection<WebhookDelivery>,
+    // only set when SEARCH_ENGINE is embedded
+    search_index: Option<Arc<SearchIndex>>,
+    // the embedded index of every organization, shared by all repositories
+    search_indexes: SearchIndexes,
+    // changes to documents of every organization, see events::bus
+    events: EventBus,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct UserResponse {
+    id: String,
     username: String,
     firstname: String,
     lastname: String,
@@ -39,6 +108,45 @@ pub struct AuthResponse {
     token: String,
 }
 
+// users created over SCIM may lack names or an email, those come out empty
+impl From<&User> for UserResponse {
+    fn from(user: &User) -> Self {
+        UserResponse {
+            id: user.id.map(|id| id.to_hex()).unwrap_or_default(),
+            username: user.username.clone().unwrap_or_default(),
+            firstname: user.firstname.clone().unwrap_or_default(),
+            lastname: user.lastname.clone().unwrap_or_default(),
+            email: user.email.clone().unwrap_or_default(),
+        }
+    }
+}
+
+// why a login was refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoginError {
+    // unknown username or wrong password, callers can not tell which
+    Invalid,
+    Deactivated,
+    // the password is right but too old, see renew_password
+    Expired,
+    Database,
+}
+
+/**
+ * Checks a password against the user found for the username, `is_expired`
+ * tells from when it was set whether it is too old. The password is checked
+ * first, so the account status is only revealed to whoever knows it.
+ */
+pub fn check_login(user: Option<&User>, password: &str, is_expired: impl Fn(Option<DateTime<Utc>>) -> bool) -> Result<Verification, LoginError> {
+    let user = user.ok_or(LoginError::Invalid)?;
+    match password::verify_password(password, &user.password) {
+        Verification::Invalid => Err(LoginError::Invalid),
+        _ if !user.is_active() => Err(LoginError::Deactivated),
+        _ if is_expired(user.password_changed_at) => Err(LoginError::Expired),
+        verification => Ok(verification),
+    }
+}
+
 impl MongoRepo {
     pub async fn init() -> Self {
         dotenv().ok();
@@ -49,12 +157,214 @@ impl MongoRepo {
         };
 
         let client = Client::with_uri_str(client_uri).await.unwrap();
-        let db = client.database("rustDB");
+        let db = client.database(DEFAULT_DATABASE);
+        create_indexes(&db).await;
+
+        let link_index = IndexModel::builder()
+            .keys(doc! {"token": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        if let Err(e) = db.collection::<ShareLink>("ShareLink").create_index(link_index, None).await {
+            println!("Error creating share link index: {}", e);
+        }
+
+        // slugs name organizations in the X-Organization header, SCIM tokens in provisioning requests
+        let organization_col: Collection<Organization> = db.collection("Organization");
+        let organization_indexes = [
+            IndexModel::builder().keys(doc! {"slug": 1}).options(IndexOptions::builder().unique(true).build()).build(),
+            IndexModel::builder().keys(doc! {"scim_token_hash": 1}).options(IndexOptions::builder().unique(true).sparse(true).build()).build(),
+        ];
+        if let Err(e) = organization_col.create_indexes(organization_indexes, None).await {
+            println!("Error creating organization indexes: {}", e);
+        }
+
+        let default = MongoRepo::scoped(client, None, organization_col, Arc::new(Mutex::new(HashMap::new())), EventBus::new());
+        default.with_search_index().await.expect("Error building the search index")
+    }
+
+    fn scoped(
+        client: Client,
+        organization: Option<Organization>,
+        organization_col: Collection<Organization>,
+        search_indexes: SearchIndexes,
+        events: EventBus,
+    ) -> Self {
+        let org_id = organization.as_ref().and_then(|o| o.id);
+        let db = client.database(organization.as_ref().and_then(|o| o.database.as_deref()).unwrap_or(DEFAULT_DATABASE));
+
+        MongoRepo {
+            user_col: ScopedCollection::new(db.collection("User"), org_id),
+            document_col: ScopedCollection::new(db.collection("Document"), org_id),
+            archive_col: ScopedCollection::new(db.collection("DocumentArchive"), org_id),
+            trash_col: ScopedCollection::new(db.collection("DocumentTrash"), org_id),
+            revision_col: ScopedCollection::new(db.collection("Revision"), org_id),
+            folder_col: ScopedCollection::new(db.collection("Folder"), org_id),
+            team_col: ScopedCollection::new(db.collection("Team"), org_id),
+            comment_col: ScopedCollection::new(db.collection("Comment"), org_id),
+            suggestion_col: ScopedCollection::new(db.collection("Suggestion"), org_id),
+            webhook_col: ScopedCollection::new(db.collection("Webhook"), org_id),
+            delivery_col: ScopedCollection::new(db.collection("WebhookDelivery"), org_id),
+            link_col: client.database(DEFAULT_DATABASE).collection("ShareLink"),
+            client,
+            organization,
+            organization_col,
+            search_index: None,
+            search_indexes,
+            events,
+        }
+    }
+
+    /**
+     * Picks up the organization's embedded index. The first request of an
+     * organization builds it from the database, concurrent ones wait for that
+     * build, and a failed build leaves nothing behind so the next one retries.
+     */
+    async fn with_search_index(mut self) -> Result<Self, Box<dyn Error>> {
+        let search_config = SearchConfig::get();
+        if search_config.engine == SearchEngine::Mongo {
+            return Ok(self);
+        }
+        let cell = self.search_indexes.lock().unwrap().entry(self.org_id()).or_default().clone();
+        let index = cell.get_or_try_init(|| self.build_search_index(search_config.default_language)).await?.clone();
+        self.search_index = Some(index);
+        Ok(self)
+    }
+
+    async fn build_search_index(&self, default_language: Language) -> Result<Arc<SearchIndex>, Box<dyn Error>> {
+        let index = SearchIndex::new(default_language);
+        let mut cursor = self.document_col.find(None, None).await?;
+        let mut documents = Vec::new();
+        while let Some(document) = cursor.next().await {
+            documents.push(document?);
+        }
+        index.rebuild(documents);
+        Ok(Arc::new(index))
+    }
+}
+
+// the embedded search index of every organization, filled in on first use
+type SearchIndexes = Arc<Mutex<HashMap<Option<ObjectId>, Arc<OnceCell<Arc<SearchIndex>>>>>>;
+
+const MAX_REVISION_ATTEMPTS: usize = 5;
 
-        let document_col: Collection<Document> = db.collection("Document");
-        let user_col: Collection<User> = db.collection("User");
+pub fn bson_date(date: DateTime<Utc>) -> bson::DateTime {
+    bson::DateTime::from_millis(date.timestamp_millis())
+}
+
+/**
+ * A document as it is kept in the trash. Next to `deleted_at`, which clients
+ * see, it carries `trashed_at` as a BSON date so the purge can find expired
+ * entries with an indexed query.
+ */
+pub fn trash_entry(document: Document, deleted_by: Option<ObjectId>, now: DateTime<Utc>) -> Result<BsonDocument, bson::ser::Error> {
+    let mut entry = to_document(&Document { deleted_at: Some(now), deleted_by, ..document })?;
+    entry.insert("trashed_at", bson_date(now));
+    Ok(entry)
+}
+
+pub fn expired_trash_filter(cutoff: DateTime<Utc>) -> BsonDocument {
+    doc! {"trashed_at": {"$lt": bson_date(cutoff)}}
+}
+
+pub fn expired_users_filter(cutoff: DateTime<Utc>) -> BsonDocument {
+    doc! {"deleted_at": {"$lt": bson_date(cutoff)}}
+}
 
-        MongoRepo { document_col, user_col }
+// a delivery as it is queued, with `next_attempt_at` as a BSON date so the senders find due ones with an indexed query
+pub fn delivery_entry(delivery: &WebhookDelivery) -> Result<BsonDocument, bson::ser::Error> {
+    let mut entry = to_document(delivery)?;
+    if let Some(next_attempt_at) = delivery.next_attempt_at {
+        entry.insert("next_attempt_at", bson_date(next_attempt_at));
+    }
+    Ok(entry)
+}
+
+// pending deliveries due at `now`, except those of the webhooks in `busy`
+pub fn due_delivery_filter(now: DateTime<Utc>, busy: &[ObjectId]) -> Result<BsonDocument, bson::ser::Error> {
+    Ok(doc! {
+        "status": to_bson(&DeliveryStatus::Pending)?,
+        "next_attempt_at": {"$lte": bson_date(now)},
+        "webhook_id": {"$nin": busy},
+    })
+}
+
+// a folder holding subfolders or documents is only deleted recursively
+pub fn folder_blocks_delete(folders_in_tree: usize, has_documents: bool) -> bool {
+    folders_in_tree > 1 || has_documents
+}
+
+// the fields of `document` an update may `$set`, creation metadata and ownership never change after insert
+pub fn writable_fields(document: &Document) -> Result<BsonDocument, bson::ser::Error> {
+    let mut fields = to_document(document)?;
+    for field in ["_id", "owner_id", "date_created", "version"] {
+        fields.remove(field);
+    }
+    Ok(fields)
+}
+
+// whether a write failed on a unique index
+pub fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    matches!(e.kind.as_ref(), ErrorKind::Write(WriteFailure::WriteError(w)) if w.code == 11000)
+}
+
+// ids of the documents in `col` matching `filter`
+async fn document_ids_in_session(
+    col: &ScopedCollection<Document>,
+    filter: BsonDocument,
+    session: &mut ClientSession,
+) -> mongodb::error::Result<Vec<ObjectId>> {
+    let mut cursor = col.find_with_session(filter, None, session).await?;
+    let mut ids = Vec::new();
+    while let Some(document) = cursor.next(session).await {
+        ids.extend(document?.id);
+    }
+    Ok(ids)
+}
+
+// indexes every organization database needs
+async fn create_indexes(db: &Database) {
+    // revision numbers are allocated optimistically, the index rejects duplicates
+    let revision_index = IndexModel::builder()
+        .keys(doc! {"document_id": 1, "number": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    if let Err(e) = db.collection::<Revision>("Revision").create_index(revision_index, None).await {
+        println!("Error creating revision index: {}", e);
+    }
+    // concurrent writers may take numbers out of order, lists and the latest revision go by version
+    let revision_version_index = IndexModel::builder().keys(doc! {"document_id": 1, "version": 1, "number": 1}).build();
+    if let Err(e) = db.collection::<Revision>("Revision").create_index(revision_version_index, None).await {
+        println!("Error creating revision version index: {}", e);
+    }
+
+    // the trash purge looks for entries older than the retention period
+    let trash_index = IndexModel::builder().keys(doc! {"trashed_at": 1}).build();
+    if let Err(e) = db.collection::<Document>("DocumentTrash").create_index(trash_index, None).await {
+        println!("Error creating trash index: {}", e);
+    }
+
+    // the user purge looks for users deleted before the grace period, few users have the field
+    let deleted_index = IndexModel::builder()
+        .keys(doc! {"deleted_at": 1})
+        .options(IndexOptions::builder().sparse(true).build())
+        .build();
+    if let Err(e) = db.collection::<User>("User").create_index(deleted_index, None).await {
+        println!("Error creating deleted user index: {}", e);
+    }
+
+    // webhook senders claim the pending deliveries that are due
+    let delivery_index = IndexModel::builder().keys(doc! {"status": 1, "next_attempt_at": 1}).build();
+    if let Err(e) = db.collection::<WebhookDelivery>("WebhookDelivery").create_index(delivery_index, None).await {
+        println!("Error creating webhook delivery index: {}", e);
+    }
+
+    // backs document search, title matches weigh more than content matches
+    let text_index = IndexModel::builder()
+        .keys(doc! {"title": "text", "content": "text"})
+        .options(IndexOptions::builder().name("document_text".to_string()).weights(doc! {"title": 10, "content": 1}).build())
+        .build();
+    if let Err(e) = db.collection::<Document>("Document").create_index(text_index, None).await {
+        println!("Error creating search index: {}", e);
     }
 }
 
@@ -69,43 +379,29 @@ impl MongoRepo {
                     panic!("Error creating user: {}", e)
                 }
             };
+        if let Some(id) = user.inserted_id.as_object_id() {
+            self.publish_user_event(&id, EventKind::UserCreated).await;
+        }
         Ok(user)
     }
 
-    pub async fn login(&self, credentials: LoginObject) -> Result<AuthResponse, Box<dyn Error>> {
-        // get user by username: if not found return 404
-        let filter = doc! {"username": credentials.username};
-        let resp = match self
-            .user_col
-            .find_one(filter, None).await {
-                Ok(u) => {
-                    let user = u.as_ref().unwrap();
-                    let password_hash = user.password.to_string();
-                    let parsed_hash = PasswordHash::new(&password_hash).unwrap();
-
-                    match Argon2::default().verify_password(credentials.password.as_bytes(), &parsed_hash) {
-                        Ok(_data) => {
-                            let derived_user = user;
-                            let user_response = UserResponse {
-                                firstname: derived_user.firstname.as_ref().unwrap().to_string(),
-                                lastname: derived_user.lastname.as_ref().unwrap().to_string(),
-                                username: derived_user.username.as_ref().unwrap().to_string(),
-                                email: derived_user.email.as_ref().unwrap().to_string(),
-                            };
-                            let user_email = user_response.email.as_str();
-                            let signed_string = jwt::jwt_sign(user_email);
-
-                            Ok(AuthResponse {
-                                user: user_response,
-                                token: signed_string
-                            })
-                        },
-                        Err(_) => Err("Login Error: Passwords do not match".to_string())
-                    }
-                },
-                Err(_) => Err("User does not exist".to_string()),
-            };
-        Ok(resp.unwrap())
+    pub async fn login(&self, credentials: LoginObject) -> Result<AuthResponse, LoginError> {
+        let filter = doc! {"username": &credentials.username};
+        let user = self.user_col.find_one(filter, None).await.map_err(|_| LoginError::Database)?;
+        let verification = check_login(user.as_ref(), &credentials.password, |changed_at| PasswordPolicy::get().is_expired(changed_at))?;
+        let user = user.ok_or(LoginError::Invalid)?;
+
+        if verification == Verification::ValidNeedsRehash {
+            // upgrade the stored hash to the configured parameters while we have the plaintext
+            let rehashed = password::hash_password(&credentials.password);
+            if let Err(e) = self.user_col.update_one(doc! {"_id": user.id}, doc! {"$set": {"password": rehashed}}, None).await {
+                println!("Error rehashing password: {}", e);
+            }
+        }
+
+        let user_response = UserResponse::from(&user);
+        let token = jwt::jwt_sign(&user_response.id, self.org_id());
+        Ok(AuthResponse { user: user_response, token })
     }
 
     pub async fn get_user(&self, id: &String) -> Result<User, Box<dyn Error>> {
@@ -120,17 +416,6 @@ impl MongoRepo {
         Ok(user_detail.unwrap())
     }
 
-    pub async fn get_user_by_email(&self, email: String) -> Result<User, Box<dyn Error>> {
-        let filter = doc! {"email": email};
-        let user_detail = self
-            .user_col
-            .find_one(filter, None)
-            .await 
-            .ok()
-            .expect("Error getting user's detail");
-        Ok(user_detail.unwrap())
-    }
-
     pub async fn update_user(&self, id: &String, new_user: User) -> Result<UpdateResult, Box<dyn Error>> {
         let mut doc = to_document(&new_user).unwrap();
         doc.remove("_id");
@@ -144,23 +429,220 @@ impl MongoRepo {
             .await
             .ok()
             .expect("Error updating user");
+        if updated_doc.matched_count == 1 {
+            self.publish_user_event(&obj_id, EventKind::UserUpdated).await;
+        }
         Ok(updated_doc)
     }
 
-    pub async fn delete_user(&self, id: &String) -> Result<DeleteResult, Box<dyn Error>> {
-        let obj_id = ObjectId::parse_str(id).unwrap();
+    /**
+     * Removes the user for good and applies `strategy` to their documents.
+     * Both run in one transaction (which needs a replica set), so documents are
+     * never left pointing at a missing owner.
+     */
+    pub async fn delete_user(&self, id: &ObjectId, strategy: &DeletionStrategy) -> Result<DeleteResult, Box<dyn Error>> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+
+        match self.delete_user_in_session(id, strategy, &mut session).await {
+            Ok(user_detail) => {
+                session.commit_transaction().await?;
+                if let Some(index) = &self.search_index {
+                    match strategy {
+                        DeletionStrategy::Transfer { to } => index.transfer_owner(id, to),
+                        _ => index.remove_owner(id),
+                    }
+                }
+                Ok(user_detail)
+            },
+            Err(e) => {
+                session.abort_transaction().await?;
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    async fn delete_user_in_session(
+        &self,
+        id: &ObjectId,
+        strategy: &DeletionStrategy,
+        session: &mut ClientSession,
+    ) -> mongodb::error::Result<DeleteResult> {
+        let owned = doc! {"owner_id": id};
+
+        match strategy {
+            DeletionStrategy::Delete => {
+                let mut ids = document_ids_in_session(&self.document_col, owned.clone(), session).await?;
+                ids.extend(document_ids_in_session(&self.trash_col, owned.clone(), session).await?);
+                self.folder_col.delete_many_with_session(owned.clone(), None, session).await?;
+                self.trash_col.delete_many_with_session(owned.clone(), None, session).await?;
+                self.document_col.delete_many_with_session(owned, None, session).await?;
+                self.delete_dependents_in_session(&ids, session).await?;
+            },
+            DeletionStrategy::Transfer { to } => {
+                let update = doc! {"$set": {"owner_id": to}};
+                self.folder_col.update_many_with_session(owned.clone(), update.clone(), None, session).await?;
+                self.trash_col.update_many_with_session(owned.clone(), update.clone(), None, session).await?;
+                self.document_col.update_many_with_session(owned, update, None, session).await?;
+            },
+            DeletionStrategy::Archive => {
+                // archived copies keep the original owner_id for reference
+                let raw_col = self.document_col.clone_with_type::<BsonDocument>();
+                let mut cursor = raw_col.find_with_session(owned.clone(), None, session).await?;
+                let mut archived = Vec::new();
+                while let Some(document) = cursor.next(session).await {
+                    let mut document = document?;
+                    document.insert("archived_at", bson::DateTime::now());
+                    archived.push(document);
+                }
+                if !archived.is_empty() {
+                    self.archive_col.insert_many_with_session(archived, None, session).await?;
+                }
+                // archived documents keep their folder_id, the folders themselves go
+                self.folder_col.delete_many_with_session(owned.clone(), None, session).await?;
+                // the trash was meant to go anyway
+                let trashed = document_ids_in_session(&self.trash_col, owned.clone(), session).await?;
+                self.trash_col.delete_many_with_session(owned.clone(), None, session).await?;
+                self.delete_dependents_in_session(&trashed, session).await?;
+                self.document_col.delete_many_with_session(owned, None, session).await?;
+            },
+        }
+
+        // documents of other owners stop being shared with the user
+        self.document_col.update_many_with_session(
+            doc! {"shares.user_id": id},
+            doc! {"$pull": {"shares": {"user_id": id}}},
+            None,
+            session,
+        ).await?;
+        self.team_col.update_many_with_session(
+            doc! {"members.user_id": id},
+            doc! {"$pull": {"members": {"user_id": id}}},
+            None,
+            session,
+        ).await?;
+
+        self.user_col.delete_one_with_session(doc! {"_id": id}, None, session).await
+    }
+
+    pub async fn find_user(&self, filter: BsonDocument) -> Result<Option<User>, Box<dyn Error>> {
+        let user = self.user_col.find_one(filter, None).await?;
+        Ok(user)
+    }
+
+    pub async fn find_users(&self, filter: BsonDocument, skip: u64, limit: i64) -> Result<Vec<User>, Box<dyn Error>> {
+        let options = FindOptions::builder().skip(skip).limit(limit).build();
+        let mut cursor = self.user_col.find(filter, options).await?;
+        let mut users = Vec::new();
+        while let Some(user) = cursor.next().await {
+            users.push(user?);
+        }
+        Ok(users)
+    }
+
+    pub async fn count_users(&self, filter: BsonDocument) -> Result<u64, Box<dyn Error>> {
+        let count = self.user_col.count_documents(filter, None).await?;
+        Ok(count)
+    }
+
+    // applies field level changes instead of replacing the whole user, returns the matched count
+    pub async fn update_user_fields(&self, id: &String, set: BsonDocument, unset: BsonDocument) -> Result<u64, Box<dyn Error>> {
+        let obj_id = ObjectId::parse_str(id)?;
         let filter = doc! {"_id": obj_id};
-        let user_detail = self
-            .user_col
-            .delete_one(filter, None)
-            .await
-            .ok()
-            .expect("Error deleting user");
-        Ok(user_detail)
+        let mut update = BsonDocument::new();
+        if !set.is_empty() {
+            update.insert("$set", set);
+        }
+        if !unset.is_empty() {
+            update.insert("$unset", unset);
+        }
+        if update.is_empty() {
+            // nothing to change, only report whether the user exists
+            let matched_count = self.user_col.count_documents(filter, None).await?;
+            return Ok(matched_count);
+        }
+        let updated_doc = self.user_col.update_one(filter, update, None).await?;
+        if updated_doc.matched_count == 1 {
+            self.publish_user_event(&obj_id, EventKind::UserUpdated).await;
+        }
+        Ok(updated_doc.matched_count)
+    }
+
+    // `fields` are the password, its history and change date, see api::user::password_fields
+    pub async fn set_password(&self, id: &ObjectId, fields: BsonDocument) -> Result<UpdateResult, Box<dyn Error>> {
+        let filter = doc! {"_id": id};
+        let update = doc! {"$set": fields};
+        let updated_doc = self.user_col.update_one(filter, update, None).await?;
+        Ok(updated_doc)
+    }
+
+    // marks the user as deleted, the purge job removes them once the grace period is over
+    pub async fn soft_delete_user(&self, id: &String, strategy: &DeletionStrategy) -> Result<UpdateResult, Box<dyn Error>> {
+        let obj_id = ObjectId::parse_str(id)?;
+        let filter = doc! {"_id": obj_id, "deleted_at": {"$exists": false}};
+        let update = doc! {"$set": {
+            "active": false,
+            "deleted_at": bson_date(Utc::now()),
+            "deletion_strategy": to_bson(strategy)?,
+        }};
+        let updated_doc = self.user_col.update_one(filter, update, None).await?;
+        if updated_doc.modified_count == 1 {
+            self.publish_user_event(&obj_id, EventKind::UserDeleted).await;
+        }
+        Ok(updated_doc)
+    }
+
+    pub async fn set_user_active(&self, id: &String, active: bool) -> Result<UpdateResult, Box<dyn Error>> {
+        let obj_id = ObjectId::parse_str(id)?;
+        let filter = doc! {"_id": obj_id};
+        let update = match active {
+            // reactivating also cancels a pending deletion
+            true => doc! {"$set": {"active": true}, "$unset": {"deleted_at": "", "deletion_strategy": ""}},
+            false => doc! {"$set": {"active": false}},
+        };
+        let updated_doc = self.user_col.update_one(filter, update, None).await?;
+        if updated_doc.matched_count == 1 {
+            self.publish_user_event(&obj_id, EventKind::UserUpdated).await;
+        }
+        Ok(updated_doc)
+    }
+
+    // hard deletes users soft deleted before `cutoff` and returns their ids
+    pub async fn purge_deleted_users(&self, cutoff: DateTime<Utc>) -> Result<Vec<ObjectId>, Box<dyn Error>> {
+        // users deleted before deleted_at became a BSON date still have it as a string
+        let legacy = doc! {"deleted_at": {"$type": "string"}};
+        let backfill = vec![doc! {"$set": {"deleted_at": {"$toDate": "$deleted_at"}}}];
+        self.user_col.update_many(legacy, backfill, None).await?;
+
+        let mut cursor = self.user_col.find(expired_users_filter(cutoff), None).await?;
+        let mut expired = Vec::new();
+        while let Some(user) = cursor.next().await {
+            expired.push(user?);
+        }
+
+        let mut purged = Vec::new();
+        for user in expired {
+            let id = match user.id {
+                Some(id) => id,
+                None => continue,
+            };
+            // users deleted before strategies existed keep their documents in the archive
+            let mut strategy = user.deletion_strategy.unwrap_or(DeletionStrategy::Archive);
+            if let DeletionStrategy::Transfer { to } = &strategy {
+                let recipient = self.user_col.find_one(doc! {"_id": to}, None).await?;
+                if !recipient.map_or(false, |r| r.is_active()) {
+                    strategy = DeletionStrategy::Archive;
+                }
+            }
+            self.delete_user(&id, &strategy).await?;
+            purged.push(id);
+        }
+        Ok(purged)
     }
 
     pub async fn get_all_users(&self) -> Result<Vec<User>, Box<dyn Error>> {
-        let users = match self.user_col.find(None, None).await {
+        let filter = doc! {"deleted_at": {"$exists": false}};
+        let users = match self.user_col.find(filter, None).await {
                 Ok(cursors) => cursors.map(|doc| doc.unwrap()).collect().await,
                 Err(_e) => {
                     println!("Error getting list of users");
@@ -186,6 +668,10 @@ impl MongoRepo {
                     panic!("Error creating document: {}", e)
                 }
             };
+        if let Some(id) = document.inserted_id.as_object_id() {
+            self.sync_search_index(&id).await?;
+            self.publish_document_event(&id, EventKind::DocumentCreated).await;
+        }
         Ok(document)
     }
 
@@ -201,31 +687,1101 @@ impl MongoRepo {
         Ok(doc_detail.unwrap())
     }
 
-    pub async fn update_document(&self, id: &String, new_document: Document) -> Result<UpdateResult, Box<dyn Error>> {
-        let mut doc = to_document(&new_document).unwrap();
-        doc.remove("_id");
+    /**
+     * Sets the given fields and bumps the version, returning the document as
+     * written. With `expected_version` the update only applies while the stored
+     * version still matches, so a stale edit matches nothing and returns None.
+     */
+    pub async fn update_document(&self, id: &String, new_document: Document, expected_version: Option<i64>) -> Result<Option<Document>, Box<dyn Error>> {
+        let doc = writable_fields(&new_document)?;
+        self.update_document_fields(id, doc, BsonDocument::new(), expected_version).await
+    }
 
-        let obj_id = ObjectId::parse_str(id).unwrap();
-        let filter = doc! {"_id": obj_id};
-        let new_doc = doc! { "$set": doc };
-        let updated_doc = self
-            .document_col
-            .update_one(filter, new_doc, None)
-            .await
-            .ok()
-            .expect("Error updating document");
+    // like update_document, but with explicit `$set` and `$unset` fields
+    pub async fn update_document_fields(&self, id: &String, set: BsonDocument, unset: BsonDocument, expected_version: Option<i64>) -> Result<Option<Document>, Box<dyn Error>> {
+        let obj_id = ObjectId::parse_str(id)?;
+        let filter = expected_version_filter(&obj_id, expected_version);
+        let mut new_doc = doc! { "$inc": {"version": 1_i64} };
+        if !set.is_empty() {
+            new_doc.insert("$set", set);
+        }
+        if !unset.is_empty() {
+            new_doc.insert("$unset", unset);
+        }
+        // the version in the ETag is the one this update wrote, not whatever a later read sees
+        let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+        let updated_doc = self.document_col.find_one_and_update(filter, new_doc, options).await?;
+        if updated_doc.is_some() {
+            self.sync_search_index(&obj_id).await?;
+            self.publish_document_event(&obj_id, EventKind::DocumentUpdated).await;
+        }
         Ok(updated_doc)
     }
 
-    pub async fn delete_document(&self, id: &String) -> Result<DeleteResult, Box<dyn Error>> {
-        let obj_id = ObjectId::parse_str(id).unwrap();
-        let filter = doc! {"_id": obj_id};
-        let doc_detail = self
-            .document_col
-            .delete_one(filter, None)
-            .await
-            .ok()
-            .expect("Error deleting document");
-        Ok(doc_detail)
+    /**
+     * Moves a document to the trash, see the Trash section. With
+     * `expected_version` it only moves while the stored version still
+     * matches. Returns the number of documents moved.
+     */
+    pub async fn trash_document(&self, id: &ObjectId, expected_version: Option<i64>, deleted_by: Option<ObjectId>) -> Result<u64, Box<dyn Error>> {
+        let mut filter = doc! {"_id": id};
+        if let Some(version) = expected_version {
+            filter.insert("version", version_filter(version));
+        }
+
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+        let trashed = match self.trash_documents_in_session(filter, deleted_by, &mut session).await {
+            Ok(trashed) => {
+                session.commit_transaction().await?;
+                trashed.len() as u64
+            },
+            Err(e) => {
+                session.abort_transaction().await?;
+                return Err(Box::new(e));
+            }
+        };
+        if trashed == 1 {
+            self.sync_search_index(id).await?;
+            self.publish_document_event(id, EventKind::DocumentDeleted).await;
+        }
+        Ok(trashed)
+    }
+
+    // most recently modified first
+    pub async fn find_documents(&self, filter: BsonDocument, skip: u64, limit: i64) -> Result<Vec<Document>, Box<dyn Error>> {
+        let options = FindOptions::builder().sort(doc! {"last_modified": -1}).skip(skip).limit(limit).build();
+        let mut cursor = self.document_col.find(filter, options).await?;
+        let mut documents = Vec::new();
+        while let Some(document) = cursor.next().await {
+            documents.push(document?);
+        }
+        Ok(documents)
+    }
+
+    pub async fn find_document(&self, filter: BsonDocument) -> Result<Option<Document>, Box<dyn Error>> {
+        let document = self.document_col.find_one(filter, None).await?;
+        Ok(document)
+    }
+
+
+    /**
+     * Folders
+    */
+
+    pub async fn create_folder(&self, folder: Folder) -> Result<InsertOneResult, Box<dyn Error>> {
+        let inserted = self.folder_col.insert_one(folder, None).await?;
+        Ok(inserted)
+    }
+
+    pub async fn find_folder(&self, filter: BsonDocument) -> Result<Option<Folder>, Box<dyn Error>> {
+        let folder = self.folder_col.find_one(filter, None).await?;
+        Ok(folder)
+    }
+
+    // sorted by name
+    pub async fn find_folders(&self, filter: BsonDocument) -> Result<Vec<Folder>, Box<dyn Error>> {
+        let options = FindOptions::builder().sort(doc! {"name": 1}).build();
+        let mut cursor = self.folder_col.find(filter, options).await?;
+        let mut folders = Vec::new();
+        while let Some(folder) = cursor.next().await {
+            folders.push(folder?);
+        }
+        Ok(folders)
+    }
+
+    pub async fn update_folder_fields(&self, id: &ObjectId, set: BsonDocument, unset: BsonDocument) -> Result<u64, Box<dyn Error>> {
+        let mut update = doc! {"$set": set};
+        if !unset.is_empty() {
+            update.insert("$unset", unset);
+        }
+        let result = self.folder_col.update_one(doc! {"_id": id}, update, None).await?;
+        Ok(result.matched_count)
     }
-}
\ No newline at end of file
+
+    pub async fn count_documents(&self, filter: BsonDocument) -> Result<u64, Box<dyn Error>> {
+        let count = self.document_col.count_documents(filter, None).await?;
+        Ok(count)
+    }
+
+    // the folder and every folder below it, parents before their children
+    pub async fn folder_subtree(&self, root: Folder) -> Result<Vec<Folder>, Box<dyn Error>> {
+        let mut subtree = vec![root];
+        let mut next = 0;
+        while next < subtree.len() {
+            let level: Vec<ObjectId> = subtree[next..].iter().filter_map(|f| f.id).collect();
+            next = subtree.len();
+            subtree.extend(self.find_folders(doc! {"parent_id": {"$in": level}}).await?);
+        }
+        Ok(subtree)
+    }
+
+    // the folder and its parents, top-level folder first
+    pub async fn folder_path(&self, folder: Folder) -> Result<Vec<Folder>, Box<dyn Error>> {
+        let mut path = vec![folder];
+        // the depth bound guards against a corrupted parent chain looping forever
+        while path.len() < MAX_FOLDER_DEPTH {
+            let parent_id = match path[path.len() - 1].parent_id {
+                Some(parent_id) => parent_id,
+                None => break,
+            };
+            match self.find_folder(doc! {"_id": parent_id}).await? {
+                Some(parent) => path.push(parent),
+                None => break,
+            }
+        }
+        path.reverse();
+        Ok(path)
+    }
+
+    /**
+     * Writes a copied folder tree, its documents and their first revisions in
+     * one transaction, so a failed copy leaves nothing half written behind.
+     * Every item comes with its id already set.
+     */
+    pub async fn insert_folder_copies(&self, folders: Vec<Folder>, documents: Vec<Document>, revisions: Vec<Revision>) -> Result<(), Box<dyn Error>> {
+        let ids: Vec<ObjectId> = documents.iter().filter_map(|d| d.id).collect();
+
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+        match self.insert_folder_copies_in_session(folders, documents, revisions, &mut session).await {
+            Ok(()) => session.commit_transaction().await?,
+            Err(e) => {
+                session.abort_transaction().await?;
+                return Err(Box::new(e));
+            }
+        }
+
+        for id in &ids {
+            self.sync_search_index(id).await?;
+            self.publish_document_event(id, EventKind::DocumentCreated).await;
+        }
+        Ok(())
+    }
+
+    async fn insert_folder_copies_in_session(
+        &self,
+        folders: Vec<Folder>,
+        documents: Vec<Document>,
+        revisions: Vec<Revision>,
+        session: &mut ClientSession,
+    ) -> mongodb::error::Result<()> {
+        // an empty batch is an error to the server
+        self.folder_col.insert_many_with_session(folders, None, session).await?;
+        if !documents.is_empty() {
+            self.document_col.insert_many_with_session(documents, None, session).await?;
+            self.revision_col.insert_many_with_session(revisions, None, session).await?;
+        }
+        Ok(())
+    }
+
+    /**
+     * Deletes a folder with its subfolders and moves the documents filed in
+     * them to the trash. The tree is read inside the transaction, so documents
+     * filed meanwhile are trashed with it rather than left in a deleted folder.
+     * Unless `recursive`, only an empty folder is deleted and None is returned
+     * for one that is not. Returns the number of documents trashed.
+     */
+    pub async fn delete_folder_tree(&self, root: Folder, recursive: bool, deleted_by: Option<ObjectId>) -> Result<Option<u64>, Box<dyn Error>> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+        let trashed = match self.delete_folder_tree_in_session(root, recursive, deleted_by, &mut session).await {
+            Ok(Some(trashed)) => {
+                session.commit_transaction().await?;
+                trashed
+            },
+            Ok(None) => {
+                session.abort_transaction().await?;
+                return Ok(None);
+            },
+            Err(e) => {
+                session.abort_transaction().await?;
+                return Err(Box::new(e));
+            }
+        };
+
+        for id in &trashed {
+            self.sync_search_index(id).await?;
+            self.publish_document_event(id, EventKind::DocumentDeleted).await;
+        }
+        Ok(Some(trashed.len() as u64))
+    }
+
+    async fn delete_folder_tree_in_session(
+        &self,
+        root: Folder,
+        recursive: bool,
+        deleted_by: Option<ObjectId>,
+        session: &mut ClientSession,
+    ) -> mongodb::error::Result<Option<Vec<ObjectId>>> {
+        let root_id = root.id;
+        let mut ids: Vec<ObjectId> = root_id.into_iter().collect();
+        let mut next = 0;
+        while next < ids.len() {
+            let level = ids[next..].to_vec();
+            next = ids.len();
+            let mut cursor = self.folder_col.find_with_session(doc! {"parent_id": {"$in": level}}, None, session).await?;
+            while let Some(folder) = cursor.next(session).await {
+                ids.extend(folder?.id);
+            }
+        }
+
+        if !recursive {
+            let mut filed = self.document_col.find_with_session(doc! {"folder_id": root_id}, None, session).await?;
+            let has_documents = filed.next(session).await.transpose()?.is_some();
+            if folder_blocks_delete(ids.len(), has_documents) {
+                return Ok(None);
+            }
+        }
+
+        let trashed = self.trash_documents_in_session(doc! {"folder_id": {"$in": &ids}}, deleted_by, session).await?;
+        self.folder_col.delete_many_with_session(doc! {"_id": {"$in": &ids}}, None, session).await?;
+        Ok(Some(trashed))
+    }
+
+
+    /**
+     * Trash
+    */
+
+    // stamps the documents matching `filter` as deleted and moves them to the trash
+    async fn trash_documents_in_session(
+        &self,
+        filter: BsonDocument,
+        deleted_by: Option<ObjectId>,
+        session: &mut ClientSession,
+    ) -> mongodb::error::Result<Vec<ObjectId>> {
+        let now = Utc::now();
+        let mut cursor = self.document_col.find_with_session(filter, None, session).await?;
+        let mut ids = Vec::new();
+        let mut trashed = Vec::new();
+        while let Some(document) = cursor.next(session).await {
+            let document = document?;
+            ids.extend(document.id);
+            trashed.push(trash_entry(document, deleted_by, now)?);
+        }
+        if trashed.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        self.trash_col.clone_with_type::<BsonDocument>().insert_many_with_session(trashed, None, session).await?;
+        self.document_col.delete_many_with_session(doc! {"_id": {"$in": &ids}}, None, session).await?;
+        Ok(ids)
+    }
+
+    // most recently deleted first
+    pub async fn find_trashed_documents(&self, filter: BsonDocument, skip: u64, limit: i64) -> Result<Vec<Document>, Box<dyn Error>> {
+        let options = FindOptions::builder().sort(doc! {"deleted_at": -1}).skip(skip).limit(limit).build();
+        let mut cursor = self.trash_col.find(filter, options).await?;
+        let mut documents = Vec::new();
+        while let Some(document) = cursor.next().await {
+            documents.push(document?);
+        }
+        Ok(documents)
+    }
+
+    pub async fn find_trashed_document(&self, filter: BsonDocument) -> Result<Option<Document>, Box<dyn Error>> {
+        let document = self.trash_col.find_one(filter, None).await?;
+        Ok(document)
+    }
+
+    /**
+     * Moves a document out of the trash. It goes back to its folder, or to
+     * the owner's root when the folder is gone by now.
+     */
+    pub async fn restore_document(&self, id: &ObjectId) -> Result<Option<Document>, Box<dyn Error>> {
+        let document = match self.trash_col.find_one(doc! {"_id": id}, None).await? {
+            Some(document) => document,
+            None => return Ok(None),
+        };
+        let folder_id = match document.folder_id {
+            Some(folder_id) => self.folder_col.find_one(doc! {"_id": folder_id}, None).await?.and(Some(folder_id)),
+            None => None,
+        };
+        let restored = Document { folder_id, deleted_at: None, deleted_by: None, ..document };
+
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+        match self.restore_document_in_session(&restored, &mut session).await {
+            Ok(_) => session.commit_transaction().await?,
+            Err(e) => {
+                session.abort_transaction().await?;
+                return Err(Box::new(e));
+            }
+        }
+
+        self.sync_search_index(id).await?;
+        self.publish_document_event(id, EventKind::DocumentCreated).await;
+        Ok(Some(restored))
+    }
+
+    async fn restore_document_in_session(&self, restored: &Document, session: &mut ClientSession) -> mongodb::error::Result<DeleteResult> {
+        self.document_col.insert_many_with_session([restored], None, session).await?;
+        self.trash_col.delete_one_with_session(doc! {"_id": restored.id}, None, session).await
+    }
+
+    /**
+     * Deletes trashed documents for good along with their revisions, share
+     * links, comments and suggestions, all in one transaction so nothing is
+     * left pointing at a document that is gone. Returns how many were deleted.
+     */
+    pub async fn delete_trashed_documents(&self, filter: BsonDocument) -> Result<u64, Box<dyn Error>> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+        match self.delete_trashed_documents_in_session(filter, &mut session).await {
+            Ok(deleted) => {
+                session.commit_transaction().await?;
+                Ok(deleted)
+            },
+            Err(e) => {
+                session.abort_transaction().await?;
+                Err(Box::new(e))
+            }
+        }
+    }
+
+    async fn delete_trashed_documents_in_session(&self, filter: BsonDocument, session: &mut ClientSession) -> mongodb::error::Result<u64> {
+        let ids = document_ids_in_session(&self.trash_col, filter, session).await?;
+        if ids.is_empty() {
+            return Ok(0);
+        }
+
+        let deleted = self.trash_col.delete_many_with_session(doc! {"_id": {"$in": &ids}}, None, session).await?;
+        self.delete_dependents_in_session(&ids, session).await?;
+        Ok(deleted.deleted_count)
+    }
+
+    // revisions, comments, suggestions and share links of documents that are deleted for good
+    async fn delete_dependents_in_session(&self, ids: &[ObjectId], session: &mut ClientSession) -> mongodb::error::Result<()> {
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let of_documents = doc! {"document_id": {"$in": ids}};
+        self.revision_col.delete_many_with_session(of_documents.clone(), None, session).await?;
+        self.comment_col.delete_many_with_session(of_documents.clone(), None, session).await?;
+        self.suggestion_col.delete_many_with_session(of_documents, None, session).await?;
+        self.link_col.delete_many_with_session(doc! {"document_id": {"$in": ids}, "org_id": self.org_id()}, None, session).await?;
+        Ok(())
+    }
+
+    pub async fn purge_trash(&self, cutoff: DateTime<Utc>) -> Result<u64, Box<dyn Error>> {
+        // entries trashed before trashed_at existed get it from their deleted_at string
+        let legacy = doc! {"trashed_at": {"$exists": false}};
+        let backfill = vec![doc! {"$set": {"trashed_at": {"$toDate": {"$ifNull": ["$deleted_at", "$$NOW"]}}}}];
+        self.trash_col.update_many(legacy, backfill, None).await?;
+
+        self.delete_trashed_documents(expired_trash_filter(cutoff)).await
+    }
+
+
+    /**
+     * Tags
+    */
+
+    // tag changes bump the version like any other write but add no revision
+    async fn update_document_tags(&self, id: &ObjectId, update: BsonDocument, expected_version: Option<i64>) -> Result<UpdateResult, Box<dyn Error>> {
+        let mut update = update;
+        update.insert("$inc", doc! {"version": 1_i64});
+        let result = self.document_col.update_one(expected_version_filter(id, expected_version), update, None).await?;
+        if result.matched_count == 1 {
+            self.sync_search_index(id).await?;
+            self.publish_document_event(id, EventKind::DocumentUpdated).await;
+        }
+        Ok(result)
+    }
+
+    pub async fn add_document_tags(&self, id: &ObjectId, tags: Vec<String>, expected_version: Option<i64>) -> Result<UpdateResult, Box<dyn Error>> {
+        self.update_document_tags(id, doc! {"$addToSet": {"tags": {"$each": tags}}}, expected_version).await
+    }
+
+    pub async fn remove_document_tag(&self, id: &ObjectId, tag: &str, expected_version: Option<i64>) -> Result<UpdateResult, Box<dyn Error>> {
+        self.update_document_tags(id, doc! {"$pull": {"tags": tag}}, expected_version).await
+    }
+
+    /**
+     * Replaces tag `from` with `to` on every document matching `scope`. When a
+     * document already has `to` the two tags merge into one. Returns the
+     * number of documents changed.
+     */
+    pub async fn rename_document_tag(&self, scope: BsonDocument, from: &str, to: &str) -> Result<u64, Box<dyn Error>> {
+        let filter = doc! {"$and": [scope, {"tags": from}]};
+        let ids: Vec<ObjectId> = self.find_documents(filter.clone(), 0, 0).await?.into_iter().filter_map(|d| d.id).collect();
+
+        // a pipeline update swaps the tag and bumps the version in one step
+        let pipeline = vec![doc! {"$set": {
+            "tags": {"$setUnion": [{"$setDifference": ["$tags", [from]]}, [to]]},
+            "version": {"$add": [{"$ifNull": ["$version", 0_i64]}, 1_i64]},
+        }}];
+        let result = self.document_col.update_many(filter, pipeline, None).await?;
+        for id in &ids {
+            self.sync_search_index(id).await?;
+            self.publish_document_event(id, EventKind::DocumentUpdated).await;
+        }
+        Ok(result.modified_count)
+    }
+
+
+    /**
+     * Sharing
+    */
+
+    // shares decide who may see a document rather than change it, so they leave the version alone
+    async fn update_document_shares(&self, id: &ObjectId, update: impl Into<UpdateModifications>) -> Result<UpdateResult, Box<dyn Error>> {
+        let result = self.document_col.update_one(doc! {"_id": id}, update, None).await?;
+        if result.matched_count == 1 {
+            self.sync_search_index(id).await?;
+            self.publish_document_event(id, EventKind::DocumentShared).await;
+        }
+        Ok(result)
+    }
+
+    // grants `permission` to `user_id`, replacing what they were granted before
+    pub async fn share_document(&self, id: &ObjectId, user_id: &ObjectId, permission: Permission) -> Result<UpdateResult, Box<dyn Error>> {
+        let share = to_bson(&Share { user_id: *user_id, permission })?;
+        self.update_document_shares(id, grant_pipeline("shares", "user_id", user_id, share)).await
+    }
+
+    pub async fn unshare_document(&self, id: &ObjectId, user_id: &ObjectId) -> Result<UpdateResult, Box<dyn Error>> {
+        self.update_document_shares(id, doc! {"$pull": {"shares": {"user_id": user_id}}}).await
+    }
+
+    pub async fn share_document_with_team(&self, id: &ObjectId, team_id: &ObjectId, permission: Permission) -> Result<UpdateResult, Box<dyn Error>> {
+        let share = to_bson(&TeamShare { team_id: *team_id, permission })?;
+        self.update_document_shares(id, grant_pipeline("team_shares", "team_id", team_id, share)).await
+    }
+
+    pub async fn unshare_document_with_team(&self, id: &ObjectId, team_id: &ObjectId) -> Result<UpdateResult, Box<dyn Error>> {
+        self.update_document_shares(id, doc! {"$pull": {"team_shares": {"team_id": team_id}}}).await
+    }
+
+    pub async fn share_folder_with_team(&self, id: &ObjectId, team_id: &ObjectId, permission: Permission) -> Result<UpdateResult, Box<dyn Error>> {
+        let share = to_bson(&TeamShare { team_id: *team_id, permission })?;
+        let result = self.folder_col.update_one(doc! {"_id": id}, grant_pipeline("team_shares", "team_id", team_id, share), None).await?;
+        Ok(result)
+    }
+
+    pub async fn unshare_folder_with_team(&self, id: &ObjectId, team_id: &ObjectId) -> Result<UpdateResult, Box<dyn Error>> {
+        let result = self.folder_col.update_one(doc! {"_id": id}, doc! {"$pull": {"team_shares": {"team_id": team_id}}}, None).await?;
+        Ok(result)
+    }
+
+    /**
+     * The folders granted to any of `team_ids` and everything below them, with
+     * the best permission that reaches each folder.
+     */
+    pub async fn team_folder_grants(&self, team_ids: &[ObjectId]) -> Result<HashMap<ObjectId, Permission>, Box<dyn Error>> {
+        let mut grants: HashMap<ObjectId, Permission> = HashMap::new();
+        if team_ids.is_empty() {
+            return Ok(grants);
+        }
+        let granted = self.find_folders(doc! {"team_shares.team_id": {"$in": team_ids}}).await?;
+        for folder in granted {
+            let permission = match folder.team_shares.iter().flatten().filter(|s| team_ids.contains(&s.team_id)).map(|s| s.permission).max() {
+                Some(permission) => permission,
+                None => continue,
+            };
+            let subtree = self.folder_subtree(folder).await?;
+            for id in subtree.into_iter().filter_map(|f| f.id) {
+                let best = grants.entry(id).or_insert(permission);
+                *best = (*best).max(permission);
+            }
+        }
+        Ok(grants)
+    }
+
+
+    /**
+     * Share links
+    */
+
+    // links are stamped with the repository's organization like every other record
+    pub async fn create_share_link(&self, link: ShareLink) -> Result<ShareLink, Box<dyn Error>> {
+        let mut link = ShareLink { org_id: self.org_id(), ..link };
+        let inserted = self.link_col.insert_one(&link, None).await?;
+        link.id = inserted.inserted_id.as_object_id();
+        Ok(link)
+    }
+
+    // newest first
+    pub async fn find_share_links(&self, document_id: &ObjectId) -> Result<Vec<ShareLink>, Box<dyn Error>> {
+        let options = FindOptions::builder().sort(doc! {"date_created": -1}).build();
+        let mut cursor = self.link_col.find(doc! {"document_id": document_id, "org_id": self.org_id()}, options).await?;
+        let mut links = Vec::new();
+        while let Some(link) = cursor.next().await {
+            links.push(link?);
+        }
+        Ok(links)
+    }
+
+    // the only lookup that crosses organizations, the token is all a visitor has
+    pub async fn find_share_link_by_token(&self, token: &str) -> Result<Option<ShareLink>, Box<dyn Error>> {
+        let link = self.link_col.find_one(doc! {"token": token}, None).await?;
+        Ok(link)
+    }
+
+    pub async fn delete_share_link(&self, document_id: &ObjectId, id: &ObjectId) -> Result<DeleteResult, Box<dyn Error>> {
+        let deleted = self.link_col.delete_one(doc! {"_id": id, "document_id": document_id, "org_id": self.org_id()}, None).await?;
+        Ok(deleted)
+    }
+
+    /**
+     * Counts a view of the link. Returns false when the link has no views
+     * left, the check and the increment are one update so concurrent visitors
+     * can not go over the limit. A successful visit clears the failed passwords.
+     */
+    pub async fn record_link_view(&self, id: &ObjectId) -> Result<bool, Box<dyn Error>> {
+        let filter = doc! {"_id": id, "$or": [
+            {"max_views": null},
+            {"$expr": {"$lt": [{"$ifNull": ["$views", 0]}, "$max_views"]}},
+        ]};
+        let update = doc! {"$inc": {"views": 1_i64}, "$set": {"failed_attempts": 0_i64}, "$unset": {"locked_until": ""}};
+        let updated = self.link_col.update_one(filter, update, None).await?;
+        Ok(updated.modified_count == 1)
+    }
+
+    // counts a wrong password, the counter is incremented atomically so concurrent guesses all count
+    pub async fn record_link_failure(&self, id: &ObjectId, now: DateTime<Utc>) -> Result<(), Box<dyn Error>> {
+        let options = FindOneAndUpdateOptions::builder().return_document(ReturnDocument::After).build();
+        let link = self.link_col.find_one_and_update(doc! {"_id": id}, doc! {"$inc": {"failed_attempts": 1_i64}}, options).await?;
+        if let Some(lock) = link.and_then(|link| lock_after(link.failed_attempts)) {
+            let locked_until = to_bson(&(now + lock))?;
+            self.link_col.update_one(doc! {"_id": id}, doc! {"$set": {"locked_until": locked_until}}, None).await?;
+        }
+        Ok(())
+    }
+
+
+    /**
+     * Comments
+    */
+
+    pub async fn create_comment(&self, comment: Comment) -> Result<Comment, Box<dyn Error>> {
+        let mut comment = comment;
+        let inserted = self.comment_col.insert_one(&comment, None).await?;
+        comment.id = inserted.inserted_id.as_object_id();
+        self.publish_document_event(&comment.document_id, EventKind::DocumentCommented).await;
+        Ok(comment)
+    }
+
+    pub async fn find_comment(&self, filter: BsonDocument) -> Result<Option<Comment>, Box<dyn Error>> {
+        let comment = self.comment_col.find_one(filter, None).await?;
+        Ok(comment)
+    }
+
+    // oldest first, the order threads read in
+    pub async fn find_comments(&self, filter: BsonDocument) -> Result<Vec<Comment>, Box<dyn Error>> {
+        let options = FindOptions::builder().sort(doc! {"date_created": 1}).build();
+        let mut cursor = self.comment_col.find(filter, options).await?;
+        let mut comments = Vec::new();
+        while let Some(comment) = cursor.next().await {
+            comments.push(comment?);
+        }
+        Ok(comments)
+    }
+
+    pub async fn update_comment(&self, id: &ObjectId, update: BsonDocument) -> Result<UpdateResult, Box<dyn Error>> {
+        let updated = self.comment_col.update_one(doc! {"_id": id}, update, None).await?;
+        Ok(updated)
+    }
+
+    // deleting the first comment of a thread deletes its replies too
+    pub async fn delete_comment(&self, id: &ObjectId) -> Result<u64, Box<dyn Error>> {
+        let deleted = self.comment_col.delete_many(doc! {"$or": [{"_id": id}, {"parent_id": id}]}, None).await?;
+        Ok(deleted.deleted_count)
+    }
+
+
+    /**
+     * Suggestions
+    */
+
+    pub async fn create_suggestion(&self, suggestion: Suggestion) -> Result<Suggestion, Box<dyn Error>> {
+        let mut suggestion = suggestion;
+        let inserted = self.suggestion_col.insert_one(&suggestion, None).await?;
+        suggestion.id = inserted.inserted_id.as_object_id();
+        Ok(suggestion)
+    }
+
+    pub async fn find_suggestion(&self, filter: BsonDocument) -> Result<Option<Suggestion>, Box<dyn Error>> {
+        let suggestion = self.suggestion_col.find_one(filter, None).await?;
+        Ok(suggestion)
+    }
+
+    // oldest first
+    pub async fn find_suggestions(&self, filter: BsonDocument) -> Result<Vec<Suggestion>, Box<dyn Error>> {
+        let options = FindOptions::builder().sort(doc! {"date_created": 1}).build();
+        let mut cursor = self.suggestion_col.find(filter, options).await?;
+        let mut suggestions = Vec::new();
+        while let Some(suggestion) = cursor.next().await {
+            suggestions.push(suggestion?);
+        }
+        Ok(suggestions)
+    }
+
+    pub async fn update_suggestion(&self, id: &ObjectId, update: BsonDocument) -> Result<UpdateResult, Box<dyn Error>> {
+        let updated = self.suggestion_col.update_one(doc! {"_id": id}, update, None).await?;
+        Ok(updated)
+    }
+
+    pub async fn delete_suggestion(&self, id: &ObjectId) -> Result<DeleteResult, Box<dyn Error>> {
+        let deleted = self.suggestion_col.delete_one(doc! {"_id": id}, None).await?;
+        Ok(deleted)
+    }
+
+
+    /**
+     * Webhooks
+    */
+
+    pub async fn create_webhook(&self, webhook: Webhook) -> Result<Webhook, Box<dyn Error>> {
+        let mut webhook = webhook;
+        let inserted = self.webhook_col.insert_one(&webhook, None).await?;
+        webhook.id = inserted.inserted_id.as_object_id();
+        Ok(webhook)
+    }
+
+    pub async fn find_webhook(&self, filter: BsonDocument) -> Result<Option<Webhook>, Box<dyn Error>> {
+        let webhook = self.webhook_col.find_one(filter, None).await?;
+        Ok(webhook)
+    }
+
+    // oldest first
+    pub async fn find_webhooks(&self, filter: BsonDocument) -> Result<Vec<Webhook>, Box<dyn Error>> {
+        let options = FindOptions::builder().sort(doc! {"_id": 1}).build();
+        let mut cursor = self.webhook_col.find(filter, options).await?;
+        let mut webhooks = Vec::new();
+        while let Some(webhook) = cursor.next().await {
+            webhooks.push(webhook?);
+        }
+        Ok(webhooks)
+    }
+
+    pub async fn update_webhook(&self, id: &ObjectId, update: BsonDocument) -> Result<UpdateResult, Box<dyn Error>> {
+        let result = self.webhook_col.update_one(doc! {"_id": id}, update, None).await?;
+        Ok(result)
+    }
+
+    // the webhook goes with its deliveries, the ones still queued are dropped
+    pub async fn delete_webhook(&self, id: &ObjectId) -> Result<DeleteResult, Box<dyn Error>> {
+        let deleted = self.webhook_col.delete_one(doc! {"_id": id}, None).await?;
+        self.delivery_col.delete_many(doc! {"webhook_id": id}, None).await?;
+        Ok(deleted)
+    }
+
+    pub async fn create_delivery(&self, delivery: WebhookDelivery) -> Result<WebhookDelivery, Box<dyn Error>> {
+        let mut delivery = delivery;
+        let inserted = self.delivery_col.clone_with_type::<BsonDocument>().insert_one(delivery_entry(&delivery)?, None).await?;
+        delivery.id = inserted.inserted_id.as_object_id();
+        Ok(delivery)
+    }
+
+    pub async fn find_delivery(&self, filter: BsonDocument) -> Result<Option<WebhookDelivery>, Box<dyn Error>> {
+        let delivery = self.delivery_col.find_one(filter, None).await?;
+        Ok(delivery)
+    }
+
+    // newest first
+    pub async fn find_deliveries(&self, filter: BsonDocument, skip: u64, limit: i64) -> Result<Vec<WebhookDelivery>, Box<dyn Error>> {
+        let options = FindOptions::builder().sort(doc! {"_id": -1}).skip(skip).limit(limit).build();
+        let mut cursor = self.delivery_col.find(filter, options).await?;
+        let mut deliveries = Vec::new();
+        while let Some(delivery) = cursor.next().await {
+            deliveries.push(delivery?);
+        }
+        Ok(deliveries)
+    }
+
+    /**
+     * Claims the delivery that has been due the longest by moving its next
+     * attempt `lease` ahead, so no other sender takes it meanwhile. Should the
+     * sender die, the delivery is due again once the lease runs out.
+     */
+    pub async fn claim_delivery(&self, now: DateTime<Utc>, lease: Duration, busy: &[ObjectId]) -> Result<Option<WebhookDelivery>, Box<dyn Error>> {
+        let update = doc! {"$set": {"next_attempt_at": bson_date(now + lease)}};
+        let options = FindOneAndUpdateOptions::builder().sort(doc! {"next_attempt_at": 1}).build();
+        let delivery = self.delivery_col.find_one_and_update(due_delivery_filter(now, busy)?, update, options).await?;
+        Ok(delivery)
+    }
+
+    // deliveries queued while next_attempt_at was a string get it as a BSON date
+    pub async fn backfill_delivery_dates(&self) -> Result<u64, Box<dyn Error>> {
+        let legacy = doc! {"status": to_bson(&DeliveryStatus::Pending)?, "next_attempt_at": {"$not": {"$type": "date"}}};
+        let backfill = vec![doc! {"$set": {"next_attempt_at": {"$toDate": {"$ifNull": ["$next_attempt_at", "$$NOW"]}}}}];
+        let result = self.delivery_col.update_many(legacy, backfill, None).await?;
+        Ok(result.modified_count)
+    }
+
+    pub async fn update_delivery(&self, id: &ObjectId, update: BsonDocument) -> Result<UpdateResult, Box<dyn Error>> {
+        let result = self.delivery_col.update_one(doc! {"_id": id}, update, None).await?;
+        Ok(result)
+    }
+
+
+    /**
+     * Teams
+    */
+
+    pub async fn create_team(&self, team: Team) -> Result<InsertOneResult, Box<dyn Error>> {
+        let inserted = self.team_col.insert_one(team, None).await?;
+        Ok(inserted)
+    }
+
+    pub async fn find_team(&self, filter: BsonDocument) -> Result<Option<Team>, Box<dyn Error>> {
+        let team = self.team_col.find_one(filter, None).await?;
+        Ok(team)
+    }
+
+    // sorted by name
+    pub async fn find_teams(&self, filter: BsonDocument) -> Result<Vec<Team>, Box<dyn Error>> {
+        let options = FindOptions::builder().sort(doc! {"name": 1}).build();
+        let mut cursor = self.team_col.find(filter, options).await?;
+        let mut teams = Vec::new();
+        while let Some(team) = cursor.next().await {
+            teams.push(team?);
+        }
+        Ok(teams)
+    }
+
+    // one page of teams by name, for listings that page in the query
+    pub async fn find_teams_page(&self, filter: BsonDocument, skip: u64, limit: i64) -> Result<Vec<Team>, Box<dyn Error>> {
+        let options = FindOptions::builder().sort(doc! {"name": 1}).skip(skip).limit(limit).build();
+        let mut cursor = self.team_col.find(filter, options).await?;
+        let mut teams = Vec::new();
+        while let Some(team) = cursor.next().await {
+            teams.push(team?);
+        }
+        Ok(teams)
+    }
+
+    pub async fn count_teams(&self, filter: BsonDocument) -> Result<u64, Box<dyn Error>> {
+        let count = self.team_col.count_documents(filter, None).await?;
+        Ok(count)
+    }
+
+    pub async fn team_ids_of(&self, user_id: &ObjectId) -> Result<Vec<ObjectId>, Box<dyn Error>> {
+        let teams = self.find_teams(doc! {"members.user_id": user_id}).await?;
+        Ok(teams.into_iter().filter_map(|t| t.id).collect())
+    }
+
+    pub async fn rename_team(&self, id: &ObjectId, name: &str) -> Result<UpdateResult, Box<dyn Error>> {
+        let update = doc! {"$set": {"name": name, "last_modified": to_bson(&Utc::now())?}};
+        let result = self.team_col.update_one(doc! {"_id": id}, update, None).await?;
+        Ok(result)
+    }
+
+    // adds the user to the team or changes their role
+    pub async fn set_team_member(&self, id: &ObjectId, user_id: &ObjectId, role: TeamRole) -> Result<UpdateResult, Box<dyn Error>> {
+        let member = to_bson(&TeamMember { user_id: *user_id, role })?;
+        let result = self.team_col.update_one(doc! {"_id": id}, grant_pipeline("members", "user_id", user_id, member), None).await?;
+        Ok(result)
+    }
+
+    pub async fn update_team(&self, id: &ObjectId, update: BsonDocument) -> Result<UpdateResult, Box<dyn Error>> {
+        let result = self.team_col.update_one(doc! {"_id": id}, update, None).await?;
+        Ok(result)
+    }
+
+    pub async fn remove_team_member(&self, id: &ObjectId, user_id: &ObjectId) -> Result<UpdateResult, Box<dyn Error>> {
+        let result = self.team_col.update_one(doc! {"_id": id}, doc! {"$pull": {"members": {"user_id": user_id}}}, None).await?;
+        Ok(result)
+    }
+
+    // deletes the team and every grant made to it
+    pub async fn delete_team(&self, id: &ObjectId) -> Result<DeleteResult, Box<dyn Error>> {
+        let granted = doc! {"team_shares.team_id": id};
+        let pull = doc! {"$pull": {"team_shares": {"team_id": id}}};
+        let documents: Vec<ObjectId> = self.find_documents(granted.clone(), 0, 0).await?.into_iter().filter_map(|d| d.id).collect();
+
+        self.document_col.update_many(granted.clone(), pull.clone(), None).await?;
+        self.folder_col.update_many(granted, pull, None).await?;
+        let deleted = self.team_col.delete_one(doc! {"_id": id}, None).await?;
+        for id in &documents {
+            self.sync_search_index(id).await?;
+            self.publish_document_event(id, EventKind::DocumentShared).await;
+        }
+        Ok(deleted)
+    }
+
+
+    /**
+     * Search
+    */
+
+    /**
+     * Runs a search filter over documents. With `ranked` the filter has to use
+     * the text index and results come with their text score, best first;
+     * otherwise the most recently modified come first.
+     */
+    pub async fn search_documents(&self, filter: BsonDocument, ranked: bool, skip: u64, limit: i64) -> Result<Vec<(Document, Option<f64>)>, Box<dyn Error>> {
+        let options = if ranked {
+            FindOptions::builder()
+                .projection(doc! {"score": {"$meta": "textScore"}})
+                .sort(doc! {"score": {"$meta": "textScore"}})
+                .skip(skip)
+                .limit(limit)
+                .build()
+        } else {
+            FindOptions::builder().sort(doc! {"last_modified": -1}).skip(skip).limit(limit).build()
+        };
+
+        let mut cursor = self.document_col.clone_with_type::<BsonDocument>().find(filter, options).await?;
+        let mut hits = Vec::new();
+        while let Some(found) = cursor.next().await {
+            let mut found = found?;
+            let score = found.get_f64("score").ok();
+            found.remove("score");
+            hits.push((bson::from_document::<Document>(found)?, score));
+        }
+        Ok(hits)
+    }
+
+    // owner and tag counts over every document matching `filter`
+    pub async fn document_facets(&self, filter: BsonDocument) -> Result<Facets, Box<dyn Error>> {
+        let pipeline = vec![
+            doc! {"$match": filter},
+            doc! {"$facet": {
+                "owners": [
+                    {"$match": {"owner_id": {"$ne": null}}},
+                    {"$group": {"_id": "$owner_id", "count": {"$sum": 1}}},
+                    {"$sort": {"count": -1, "_id": 1}},
+                ],
+                "tags": [
+                    {"$unwind": "$tags"},
+                    {"$group": {"_id": "$tags", "count": {"$sum": 1}}},
+                    {"$sort": {"count": -1, "_id": 1}},
+                ],
+            }},
+        ];
+        let mut cursor = self.document_col.aggregate(pipeline, None).await?;
+        let result = match cursor.next().await {
+            Some(result) => result?,
+            None => return Ok(Facets::default()),
+        };
+
+        let counts = |name: &str| -> Vec<FacetCount> {
+            result.get_array(name).map(|groups| {
+                groups.iter().filter_map(|group| {
+                    let group = group.as_document()?;
+                    let value = match group.get("_id")? {
+                        Bson::ObjectId(id) => id.to_hex(),
+                        Bson::String(value) => value.clone(),
+                        _ => return None,
+                    };
+                    let count = group.get_i32("count").map(|c| c as u64).or_else(|_| group.get_i64("count").map(|c| c as u64)).ok()?;
+                    Some(FacetCount { value, count })
+                }).collect()
+            }).unwrap_or_default()
+        };
+        Ok(Facets { owners: counts("owners"), tags: counts("tags") })
+    }
+
+    pub fn search_index(&self) -> Option<&SearchIndex> {
+        self.search_index.as_deref()
+    }
+
+    // brings the embedded index entry of a document in line with the database
+    async fn sync_search_index(&self, id: &ObjectId) -> Result<(), Box<dyn Error>> {
+        let index = match &self.search_index {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        match self.document_col.find_one(doc! {"_id": id}, None).await? {
+            Some(document) => index.upsert(&document),
+            None => index.remove(id),
+        }
+        Ok(())
+    }
+
+    // refills the embedded index from every stored document, returns how many were indexed
+    pub async fn rebuild_search_index(&self) -> Result<usize, Box<dyn Error>> {
+        let index = match &self.search_index {
+            Some(index) => index,
+            None => return Ok(0),
+        };
+        let mut cursor = self.document_col.find(None, None).await?;
+        let mut documents = Vec::new();
+        while let Some(document) = cursor.next().await {
+            documents.push(document?);
+        }
+        Ok(index.rebuild(documents))
+    }
+
+
+    /**
+     * Events
+    */
+
+    pub fn events(&self) -> &EventBus {
+        &self.events
+    }
+
+    /**
+     * Tells subscribers about a change to a document, deleted ones are looked
+     * up in the trash. The change is already written, so a failed lookup only
+     * costs the event and is logged instead of failing the request.
+     */
+    async fn publish_document_event(&self, id: &ObjectId, kind: EventKind) {
+        let found = match kind {
+            EventKind::DocumentDeleted => self.trash_col.find_one(doc! {"_id": id}, None).await,
+            _ => self.document_col.find_one(doc! {"_id": id}, None).await,
+        };
+        match found {
+            Ok(Some(document)) => self.events.publish_document(self.org_id(), kind, document),
+            Ok(None) => {},
+            Err(e) => println!("Error publishing {} for document {}: {}", kind.name(), id, e),
+        }
+    }
+
+    async fn publish_user_event(&self, id: &ObjectId, kind: EventKind) {
+        match self.user_col.find_one(doc! {"_id": id}, None).await {
+            Ok(Some(user)) => self.events.publish_user(self.org_id(), kind, &user),
+            Ok(None) => {},
+            Err(e) => println!("Error publishing {} for user {}: {}", kind.name(), id, e),
+        }
+    }
+
+
+    /**
+     * Organizations
+    */
+
+    pub fn organization(&self) -> Option<&Organization> {
+        self.organization.as_ref()
+    }
+
+    pub fn org_id(&self) -> Option<ObjectId> {
+        self.organization.as_ref().and_then(|o| o.id)
+    }
+
+    /**
+     * The repository of `organization` (None for the default one). The first
+     * time an organization is used with the embedded search engine its index
+     * is filled from the database.
+     */
+    pub async fn for_organization(&self, organization: Option<Organization>) -> Result<MongoRepo, Box<dyn Error>> {
+        let repo = MongoRepo::scoped(self.client.clone(), organization, self.organization_col.clone(), self.search_indexes.clone(), self.events.clone());
+        repo.with_search_index().await
+    }
+
+    // the default organization's repository followed by one for every other organization
+    pub async fn all_organizations(&self) -> Result<Vec<MongoRepo>, Box<dyn Error>> {
+        let mut repos = vec![self.for_organization(None).await?];
+        let organizations = self.find_organizations(doc! {}).await?;
+        for organization in organizations {
+            repos.push(self.for_organization(Some(organization)).await?);
+        }
+        Ok(repos)
+    }
+
+    /**
+     * Registers an organization. With `dedicated_database` its data goes to a
+     * database of its own, named after the slug, which gets the same indexes
+     * as the default one.
+     */
+    pub async fn create_organization(&self, organization: Organization, dedicated_database: bool) -> Result<Organization, Box<dyn Error>> {
+        let mut organization = organization;
+        if dedicated_database {
+            let database = format!("{}_{}", DEFAULT_DATABASE, organization.slug.replace('-', "_"));
+            create_indexes(&self.client.database(&database)).await;
+            organization.database = Some(database);
+        }
+        let inserted = self.organization_col.insert_one(&organization, None).await?;
+        organization.id = inserted.inserted_id.as_object_id();
+        Ok(organization)
+    }
+
+    // replaces the organization's SCIM token, returns whether the organization exists
+    pub async fn set_scim_token_hash(&self, id: &ObjectId, scim_token_hash: String) -> Result<bool, Box<dyn Error>> {
+        let result = self.organization_col.update_one(doc! {"_id": id}, doc! {"$set": {"scim_token_hash": scim_token_hash}}, None).await?;
+        Ok(result.matched_count == 1)
+    }
+
+    pub async fn find_organization(&self, filter: BsonDocument) -> Result<Option<Organization>, Box<dyn Error>> {
+        let organization = self.organization_col.find_one(filter, None).await?;
+        Ok(organization)
+    }
+
+    // sorted by slug
+    pub async fn find_organizations(&self, filter: BsonDocument) -> Result<Vec<Organization>, Box<dyn Error>> {
+        let options = FindOptions::builder().sort(doc! {"slug": 1}).build();
+        let mut cursor = self.organization_col.find(filter, options).await?;
+        let mut organizations = Vec::new();
+        while let Some(organization) = cursor.next().await {
+            organizations.push(organization?);
+        }
+        Ok(organizations)
+    }
+
+
+    /**
+     * Revisions
+    */
+
+    // the revision of the newest version, revisions from before versions were stored count as older
+    pub async fn latest_revision(&self, document_id: &ObjectId) -> Result<Option<Revision>, Box<dyn Error>> {
+        let options = FindOneOptions::builder().sort(revision_order(-1)).build();
+        let revision = self.revision_col.find_one(doc! {"document_id": document_id}, options).await?;
+        Ok(revision)
+    }
+
+    async fn next_revision_number(&self, document_id: &ObjectId) -> Result<i64, Box<dyn Error>> {
+        let options = FindOneOptions::builder().sort(doc! {"number": -1}).projection(doc! {"content": 0}).build();
+        let revision = self.revision_col.find_one(doc! {"document_id": document_id}, options).await?;
+        Ok(revision.map_or(1, |latest| latest.number + 1))
+    }
+
+    // stores the current state of `document` as its next revision
+    pub async fn record_revision(
+        &self,
+        document: &Document,
+        author_id: Option<ObjectId>,
+        restored_from: Option<i64>,
+    ) -> Result<Revision, Box<dyn Error>> {
+        let document_id = document.id.ok_or("Document has no id")?;
+        // a concurrent writer may take the same number, the unique index rejects it and we try the next one
+        for _ in 0..MAX_REVISION_ATTEMPTS {
+            let number = self.next_revision_number(&document_id).await?;
+
+            let mut revision = Revision::snapshot(document, number, author_id).ok_or("Document has no id")?;
+            revision.restored_from = restored_from;
+            match self.revision_col.insert_one(&revision, None).await {
+                Ok(inserted) => {
+                    revision.id = inserted.inserted_id.as_object_id();
+                    return Ok(revision);
+                },
+                Err(e) if is_duplicate_key(&e) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err("Could not allocate a revision number".into())
+    }
+
+    // documents written before revisions existed get their current state recorded first
+    pub async fn ensure_initial_revision(&self, document: &Document) -> Result<(), Box<dyn Error>> {
+        let document_id = document.id.ok_or("Document has no id")?;
+        if self.latest_revision(&document_id).await?.is_none() {
+            self.record_revision(document, document.owner_id, None).await?;
+        }
+        Ok(())
+    }
+
+    // revisions oldest version first, without their content
+    pub async fn get_revisions(&self, document_id: &ObjectId) -> Result<Vec<Revision>, Box<dyn Error>> {
+        let options = FindOptions::builder()
+            .sort(revision_order(1))
+            .projection(doc! {"content": 0})
+            .build();
+        let mut cursor = self.revision_col.find(doc! {"document_id": document_id}, options).await?;
+        let mut revisions = Vec::new();
+        while let Some(revision) = cursor.next().await {
+            revisions.push(revision?);
+        }
+        Ok(revisions)
+    }
+
+    pub async fn get_revision(&self, document_id: &ObjectId, number: i64) -> Result<Option<Revision>, Box<dyn Error>> {
+        let filter = doc! {"document_id": document_id, "number": number};
+        let revision = self.revision_col.find_one(filter, None).await?;
+        Ok(revision)
+    }
+}
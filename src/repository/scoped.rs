@@ -0,0 +1,180 @@
+use std::borrow::Borrow;
+use mongodb::{
+    bson::{doc, oid::ObjectId, to_document, Document},
+    error::Result,
+    options::{AggregateOptions, CountOptions, DeleteOptions, FindOneAndUpdateOptions, FindOneOptions, FindOptions, InsertManyOptions, InsertOneOptions, UpdateModifications, UpdateOptions},
+    results::{DeleteResult, InsertManyResult, InsertOneResult, UpdateResult},
+    ClientSession, Collection, Cursor, SessionCursor,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+/**
+ * A collection that only sees the documents of one organization. Every
+ * filter is narrowed to the organization's `org_id` and every insert is
+ * stamped with it, so no repository method can reach another tenant's data.
+ * The default organization owns the documents that have no `org_id`.
+ */
+#[derive(Debug)]
+pub struct ScopedCollection<T> {
+    inner: Collection<T>,
+    org_id: Option<ObjectId>,
+}
+
+// narrows `filter` to the organization, `org_id: null` also matches documents without the field
+pub fn tenant_filter(org_id: Option<ObjectId>, filter: Option<Document>) -> Document {
+    let tenant = doc! {"org_id": org_id};
+    match filter {
+        Some(filter) if !filter.is_empty() => doc! {"$and": [tenant, filter]},
+        _ => tenant,
+    }
+}
+
+// marks `document` as the organization's, the default organization's documents have no org_id
+pub fn stamp_tenant(org_id: Option<ObjectId>, mut document: Document) -> Document {
+    match org_id {
+        Some(org_id) => document.insert("org_id", org_id),
+        None => document.remove("org_id"),
+    };
+    document
+}
+
+// a derive would ask for T: Clone, the collection handle does not need it
+impl<T> Clone for ScopedCollection<T> {
+    fn clone(&self) -> Self {
+        ScopedCollection { inner: self.inner.clone(), org_id: self.org_id }
+    }
+}
+
+impl<T> ScopedCollection<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync,
+{
+    pub fn new(inner: Collection<T>, org_id: Option<ObjectId>) -> Self {
+        ScopedCollection { inner, org_id }
+    }
+
+    fn scope(&self, filter: impl Into<Option<Document>>) -> Document {
+        tenant_filter(self.org_id, filter.into())
+    }
+
+    fn stamp(&self, item: &T) -> Result<Document> {
+        Ok(stamp_tenant(self.org_id, to_document(item)?))
+    }
+
+    fn raw(&self) -> Collection<Document> {
+        self.inner.clone_with_type::<Document>()
+    }
+
+    pub fn clone_with_type<U>(&self) -> ScopedCollection<U> {
+        ScopedCollection { inner: self.inner.clone_with_type::<U>(), org_id: self.org_id }
+    }
+
+    pub async fn find(&self, filter: impl Into<Option<Document>>, options: impl Into<Option<FindOptions>>) -> Result<Cursor<T>> {
+        self.inner.find(self.scope(filter), options).await
+    }
+
+    pub async fn find_with_session(
+        &self,
+        filter: impl Into<Option<Document>>,
+        options: impl Into<Option<FindOptions>>,
+        session: &mut ClientSession,
+    ) -> Result<SessionCursor<T>> {
+        self.inner.find_with_session(self.scope(filter), options, session).await
+    }
+
+    pub async fn find_one(&self, filter: impl Into<Option<Document>>, options: impl Into<Option<FindOneOptions>>) -> Result<Option<T>> {
+        self.inner.find_one(self.scope(filter), options).await
+    }
+
+    pub async fn count_documents(&self, filter: impl Into<Option<Document>>, options: impl Into<Option<CountOptions>>) -> Result<u64> {
+        self.inner.count_documents(self.scope(filter), options).await
+    }
+
+    // the tenant filter joins a leading $match, a $text match has to stay the first stage
+    pub async fn aggregate(&self, pipeline: Vec<Document>, options: impl Into<Option<AggregateOptions>>) -> Result<Cursor<Document>> {
+        let mut pipeline = pipeline;
+        match pipeline.first_mut().and_then(|stage| stage.get_document_mut("$match").ok()) {
+            Some(filter) => *filter = self.scope(filter.clone()),
+            None => pipeline.insert(0, doc! {"$match": self.scope(None)}),
+        }
+        self.inner.aggregate(pipeline, options).await
+    }
+
+    pub async fn insert_one(&self, item: impl Borrow<T>, options: impl Into<Option<InsertOneOptions>>) -> Result<InsertOneResult> {
+        let document = self.stamp(item.borrow())?;
+        self.raw().insert_one(document, options).await
+    }
+
+    pub async fn insert_many_with_session(
+        &self,
+        items: impl IntoIterator<Item = impl Borrow<T>>,
+        options: impl Into<Option<InsertManyOptions>>,
+        session: &mut ClientSession,
+    ) -> Result<InsertManyResult> {
+        let documents = items.into_iter().map(|item| self.stamp(item.borrow())).collect::<Result<Vec<Document>>>()?;
+        self.raw().insert_many_with_session(documents, options, session).await
+    }
+
+    pub async fn update_one(
+        &self,
+        query: Document,
+        update: impl Into<UpdateModifications>,
+        options: impl Into<Option<UpdateOptions>>,
+    ) -> Result<UpdateResult> {
+        self.inner.update_one(self.scope(query), update, options).await
+    }
+
+    pub async fn find_one_and_update(
+        &self,
+        filter: Document,
+        update: impl Into<UpdateModifications>,
+        options: impl Into<Option<FindOneAndUpdateOptions>>,
+    ) -> Result<Option<T>> {
+        self.inner.find_one_and_update(self.scope(filter), update, options).await
+    }
+
+    pub async fn update_many(
+        &self,
+        query: Document,
+        update: impl Into<UpdateModifications>,
+        options: impl Into<Option<UpdateOptions>>,
+    ) -> Result<UpdateResult> {
+        self.inner.update_many(self.scope(query), update, options).await
+    }
+
+    pub async fn update_many_with_session(
+        &self,
+        query: Document,
+        update: impl Into<UpdateModifications>,
+        options: impl Into<Option<UpdateOptions>>,
+        session: &mut ClientSession,
+    ) -> Result<UpdateResult> {
+        self.inner.update_many_with_session(self.scope(query), update, options, session).await
+    }
+
+    pub async fn delete_one(&self, query: Document, options: impl Into<Option<DeleteOptions>>) -> Result<DeleteResult> {
+        self.inner.delete_one(self.scope(query), options).await
+    }
+
+    pub async fn delete_many(&self, query: Document, options: impl Into<Option<DeleteOptions>>) -> Result<DeleteResult> {
+        self.inner.delete_many(self.scope(query), options).await
+    }
+
+    pub async fn delete_one_with_session(
+        &self,
+        query: Document,
+        options: impl Into<Option<DeleteOptions>>,
+        session: &mut ClientSession,
+    ) -> Result<DeleteResult> {
+        self.inner.delete_one_with_session(self.scope(query), options, session).await
+    }
+
+    pub async fn delete_many_with_session(
+        &self,
+        query: Document,
+        options: impl Into<Option<DeleteOptions>>,
+        session: &mut ClientSession,
+    ) -> Result<DeleteResult> {
+        self.inner.delete_many_with_session(self.scope(query), options, session).await
+    }
+}
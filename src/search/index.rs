@@ -0,0 +1,334 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    sync::{OnceLock, RwLock},
+};
+use mongodb::bson::oid::ObjectId;
+
+use crate::models::{document::Document, search::{FacetCount, Facets}};
+use crate::search::analyzer::{analyze, stem, tokenize, Language};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SearchEngine {
+    Mongo,
+    Embedded,
+}
+
+/**
+ * The engine behind document search, SEARCH_ENGINE `mongo` (the text index,
+ * default) or `embedded` (the in-memory index below), and the language of
+ * documents that do not name one, SEARCH_DEFAULT_LANGUAGE (default `en`).
+ */
+pub struct SearchConfig {
+    pub engine: SearchEngine,
+    pub default_language: Language,
+}
+
+static SEARCH_CONFIG: OnceLock<SearchConfig> = OnceLock::new();
+
+impl SearchConfig {
+    pub fn from_env() -> Self {
+        let engine = match env::var("SEARCH_ENGINE").as_deref() {
+            Ok("mongo") | Err(_) => SearchEngine::Mongo,
+            Ok("embedded") => SearchEngine::Embedded,
+            Ok(other) => panic!("SEARCH_ENGINE must be mongo or embedded, not {}", other),
+        };
+        let default_language = match env::var("SEARCH_DEFAULT_LANGUAGE") {
+            Ok(code) => Language::from_code(&code).unwrap_or_else(|| panic!("SEARCH_DEFAULT_LANGUAGE {} is not supported", code)),
+            Err(_) => Language::English,
+        };
+
+        SearchConfig { engine, default_language }
+    }
+
+    pub fn get() -> &'static SearchConfig {
+        SEARCH_CONFIG.get_or_init(SearchConfig::from_env)
+    }
+}
+
+// title words count this many times a content word
+const TITLE_BOOST: f64 = 3.0;
+// BM25 term frequency saturation and length normalisation
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+const PREFIX_WEIGHT: f64 = 0.8;
+const FUZZY_WEIGHT: f64 = 0.5;
+
+// edits allowed for a fuzzy match, short words have to match exactly
+fn max_edits(word: &str) -> usize {
+    match word.chars().count() {
+        0..=3 => 0,
+        4..=7 => 1,
+        _ => 2,
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Posting {
+    title: u32,
+    content: u32,
+}
+
+struct Entry {
+    owner_id: Option<ObjectId>,
+    folder_id: Option<ObjectId>,
+    // users and teams the document is shared with
+    readers: Vec<ObjectId>,
+    teams: Vec<ObjectId>,
+    tags: Vec<String>,
+    language: Language,
+    terms: HashSet<String>,
+    length: usize,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<ObjectId, Entry>,
+    postings: HashMap<String, HashMap<ObjectId, Posting>>,
+    total_length: usize,
+}
+
+impl Inner {
+    fn insert(&mut self, id: ObjectId, document: &Document, language: Language) {
+        let title = analyze(document.title.as_deref().unwrap_or(""), language);
+        let content = analyze(document.content.as_deref().unwrap_or(""), language);
+
+        for term in &title {
+            self.postings.entry(term.clone()).or_default().entry(id).or_default().title += 1;
+        }
+        for term in &content {
+            self.postings.entry(term.clone()).or_default().entry(id).or_default().content += 1;
+        }
+
+        let length = title.len() + content.len();
+        self.total_length += length;
+        self.entries.insert(id, Entry {
+            owner_id: document.owner_id,
+            folder_id: document.folder_id,
+            readers: document.shares.iter().flatten().map(|share| share.user_id).collect(),
+            teams: document.team_shares.iter().flatten().map(|share| share.team_id).collect(),
+            tags: document.tags.clone().unwrap_or_default(),
+            language,
+            terms: title.into_iter().chain(content).collect(),
+            length,
+        });
+    }
+
+    fn remove(&mut self, id: &ObjectId) {
+        let entry = match self.entries.remove(id) {
+            Some(entry) => entry,
+            None => return,
+        };
+        self.total_length -= entry.length;
+        for term in entry.terms {
+            if let Some(postings) = self.postings.get_mut(&term) {
+                postings.remove(id);
+                if postings.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+
+    fn average_length(&self) -> f64 {
+        if self.entries.is_empty() {
+            return 1.0;
+        }
+        (self.total_length as f64 / self.entries.len() as f64).max(1.0)
+    }
+
+    /**
+     * The indexed terms a query word stands for, with a weight. The word is
+     * stemmed for every language in the index since the query does not say
+     * which one it is in; prefix and fuzzy matches count for less than exact ones.
+     */
+    fn expand(&self, word: &str, prefix: bool, fuzzy: bool) -> Vec<(String, f64)> {
+        let languages: HashSet<Language> = self.entries.values().map(|e| e.language).collect();
+        let mut variants: HashSet<String> = languages.iter().map(|l| stem(word, *l)).collect();
+        variants.insert(word.to_string());
+
+        let mut terms: HashMap<String, f64> = HashMap::new();
+        let mut keep = |term: &str, weight: f64| {
+            let best = terms.entry(term.to_string()).or_insert(0.0);
+            *best = best.max(weight);
+        };
+
+        for variant in &variants {
+            if self.postings.contains_key(variant) {
+                keep(variant, 1.0);
+            }
+        }
+        if prefix || fuzzy {
+            let edits = max_edits(word);
+            for term in self.postings.keys() {
+                if prefix && term.starts_with(word) {
+                    keep(term, PREFIX_WEIGHT);
+                }
+                if fuzzy && edits > 0 {
+                    let distance = variants.iter().map(|v| strsim::levenshtein(v, term)).min().unwrap_or(usize::MAX);
+                    if distance > 0 && distance <= edits {
+                        keep(term, FUZZY_WEIGHT / distance as f64);
+                    }
+                }
+            }
+        }
+
+        terms.into_iter().collect()
+    }
+}
+
+pub struct IndexQuery<'a> {
+    pub text: &'a str,
+    pub fuzzy: bool,
+    // only documents this user owns or has been shared with, None searches all of them
+    pub readable_by: Option<ObjectId>,
+    // with readable_by, also documents shared with these teams or filed in these folders
+    pub readable_teams: Vec<ObjectId>,
+    pub readable_folders: Vec<ObjectId>,
+    pub owner: Option<ObjectId>,
+    // documents have to carry all of these
+    pub tags: Vec<String>,
+}
+
+pub struct IndexResults {
+    // best match first
+    pub hits: Vec<(ObjectId, f64)>,
+    pub facets: Facets,
+}
+
+/**
+ * An in-memory inverted index over document titles and content, an
+ * alternative to the MongoDB text index. It is filled from the database on
+ * start and kept up to date by the repository on every document write.
+ */
+pub struct SearchIndex {
+    default_language: Language,
+    inner: RwLock<Inner>,
+}
+
+impl SearchIndex {
+    pub fn new(default_language: Language) -> Self {
+        SearchIndex { default_language, inner: RwLock::new(Inner::default()) }
+    }
+
+    fn language(&self, document: &Document) -> Language {
+        document.language.as_deref().and_then(Language::from_code).unwrap_or(self.default_language)
+    }
+
+    pub fn upsert(&self, document: &Document) {
+        let id = match document.id {
+            Some(id) => id,
+            None => return,
+        };
+        let language = self.language(document);
+        let mut inner = self.inner.write().unwrap();
+        inner.remove(&id);
+        inner.insert(id, document, language);
+    }
+
+    pub fn remove(&self, id: &ObjectId) {
+        self.inner.write().unwrap().remove(id);
+    }
+
+    pub fn remove_owner(&self, owner_id: &ObjectId) {
+        let mut inner = self.inner.write().unwrap();
+        let owned: Vec<ObjectId> = inner.entries.iter()
+            .filter(|(_, e)| e.owner_id.as_ref() == Some(owner_id))
+            .map(|(id, _)| *id)
+            .collect();
+        for id in owned {
+            inner.remove(&id);
+        }
+    }
+
+    pub fn transfer_owner(&self, from: &ObjectId, to: &ObjectId) {
+        let mut inner = self.inner.write().unwrap();
+        for entry in inner.entries.values_mut() {
+            if entry.owner_id.as_ref() == Some(from) {
+                entry.owner_id = Some(*to);
+            }
+        }
+    }
+
+    // swaps in an index built from `documents`, searches keep using the old one meanwhile
+    pub fn rebuild(&self, documents: Vec<Document>) -> usize {
+        let mut fresh = Inner::default();
+        for document in &documents {
+            if let Some(id) = document.id {
+                fresh.insert(id, document, self.language(document));
+            }
+        }
+        let count = fresh.entries.len();
+        *self.inner.write().unwrap() = fresh;
+        count
+    }
+
+    pub fn search(&self, query: &IndexQuery) -> IndexResults {
+        let inner = self.inner.read().unwrap();
+        let total = inner.entries.len() as f64;
+        let average_length = inner.average_length();
+        let mut scores: HashMap<ObjectId, f64> = HashMap::new();
+
+        for piece in query.text.split_whitespace() {
+            let words = tokenize(piece);
+            let last = words.len().saturating_sub(1);
+            for (i, word) in words.iter().enumerate() {
+                let prefix = i == last && piece.ends_with('*');
+                for (term, weight) in inner.expand(word, prefix, query.fuzzy) {
+                    let postings = &inner.postings[&term];
+                    let found = postings.len() as f64;
+                    let idf = (1.0 + (total - found + 0.5) / (found + 0.5)).ln();
+                    for (id, posting) in postings {
+                        let length = inner.entries[id].length as f64;
+                        let tf = posting.title as f64 * TITLE_BOOST + posting.content as f64;
+                        let norm = K1 * (1.0 - B + B * length / average_length);
+                        *scores.entry(*id).or_default() += weight * idf * tf * (K1 + 1.0) / (tf + norm);
+                    }
+                }
+            }
+        }
+
+        let mut hits: Vec<(ObjectId, f64)> = scores
+            .into_iter()
+            .filter(|(id, _)| {
+                let entry = &inner.entries[id];
+                let readable = match &query.readable_by {
+                    Some(user_id) => {
+                        entry.owner_id.as_ref() == Some(user_id)
+                            || entry.readers.contains(user_id)
+                            || entry.teams.iter().any(|team| query.readable_teams.contains(team))
+                            || entry.folder_id.is_some_and(|folder| query.readable_folders.contains(&folder))
+                    },
+                    None => true,
+                };
+                readable
+                    && (query.owner.is_none() || entry.owner_id == query.owner)
+                    && query.tags.iter().all(|tag| entry.tags.contains(tag))
+            })
+            .collect();
+        hits.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        let mut owners: HashMap<String, u64> = HashMap::new();
+        let mut tags: HashMap<String, u64> = HashMap::new();
+        for (id, _) in &hits {
+            let entry = &inner.entries[id];
+            if let Some(owner_id) = entry.owner_id {
+                *owners.entry(owner_id.to_hex()).or_default() += 1;
+            }
+            for tag in &entry.tags {
+                *tags.entry(tag.clone()).or_default() += 1;
+            }
+        }
+
+        IndexResults {
+            hits,
+            facets: Facets { owners: facet_counts(owners), tags: facet_counts(tags) },
+        }
+    }
+}
+
+fn facet_counts(counts: HashMap<String, u64>) -> Vec<FacetCount> {
+    let mut counts: Vec<FacetCount> = counts.into_iter().map(|(value, count)| FacetCount { value, count }).collect();
+    counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.value.cmp(&b.value)));
+    counts
+}
@@ -122,7 +122,7 @@ mod tests {
 
         // delete client (using jwt token)
         let deleted: String = client
-            .delete(format!("http://localhost:8000/users/{}", &user_id))
+            .delete(format!("http://localhost:8000/users/{}?strategy=delete", &user_id))
             .header("Authorization", token)
             .send()
             .await
@@ -133,4 +133,1061 @@ mod tests {
 
         assert_eq!(deleted, "\"User successfully deleted!\"".to_string());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn scim_filter_translates_to_mongo() {
+        use crate::helpers::scim::filter_to_document;
+        use mongodb::bson::doc;
+
+        let filter = filter_to_document(r#"userName eq "Bjensen" and active eq false"#).unwrap();
+        let expected_username = mongodb::bson::Regex { pattern: "^Bjensen$".into(), options: "i".into() };
+        assert_eq!(filter, doc! { "$and": [ { "username": expected_username }, { "active": false } ] });
+
+        let filter = filter_to_document(r#"name.familyName sw "J" or not (emails pr)"#).unwrap();
+        assert!(filter.get_array("$or").is_ok());
+
+        assert!(filter_to_document(r#"password eq "secret""#).is_err());
+        assert!(filter_to_document(r#"userName eq "unterminated"#).is_err());
+    }
+
+    #[test]
+    fn scim_patch_builds_set_and_unset() {
+        use crate::helpers::scim::patch_to_changes;
+        use crate::models::scim::ScimPatchRequest;
+
+        let patch: ScimPatchRequest = serde_json::from_value(json!({
+            "schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"],
+            "Operations": [
+                { "op": "replace", "value": { "active": "False", "name.givenName": "Barbara" } },
+                { "op": "remove", "path": "emails" },
+                { "op": "add", "path": "roles", "value": [{ "value": "Administrator" }] }
+            ]
+        })).unwrap();
+
+        let changes = patch_to_changes(&patch.operations).unwrap();
+        assert_eq!(changes.set.get_bool("active").unwrap(), false);
+        assert_eq!(changes.set.get_str("firstname").unwrap(), "Barbara");
+        assert_eq!(changes.set.get_str("role").unwrap(), "Administrator");
+        assert!(changes.unset.contains_key("email"));
+    }
+
+    #[test]
+    fn scim_groups_patch_members() {
+        use crate::helpers::scim::{group_filter_to_document, patch_group};
+        use crate::models::scim::{ScimError, ScimPatchRequest};
+        use crate::models::team::{Team, TeamMember, TeamRole};
+        use mongodb::bson::{doc, oid::ObjectId};
+
+        let (admin, member, newcomer) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+        let team = Team {
+            name: "Engineering".to_string(),
+            members: vec![TeamMember { user_id: admin, role: TeamRole::Admin }, TeamMember { user_id: member, role: TeamRole::Member }],
+            ..Default::default()
+        };
+        let patch: ScimPatchRequest = serde_json::from_value(json!({
+            "schemas": ["urn:ietf:params:scim:api:messages:2.0:PatchOp"],
+            "Operations": [
+                { "op": "add", "path": "members", "value": [{ "value": newcomer.to_hex() }] },
+                { "op": "remove", "path": format!("members[value eq \"{}\"]", member.to_hex()) },
+                { "op": "replace", "value": { "displayName": "Platform" } }
+            ]
+        })).unwrap();
+
+        let patched = patch_group(&team, &patch.operations).unwrap();
+        assert_eq!(patched.name, "Platform");
+        assert_eq!(patched.role_of(&admin), Some(TeamRole::Admin));
+        assert_eq!(patched.role_of(&newcomer), Some(TeamRole::Member));
+        assert_eq!(patched.role_of(&member), None);
+
+        let replace: ScimPatchRequest = serde_json::from_value(json!({
+            "Operations": [{ "op": "replace", "path": "members", "value": [{ "value": admin.to_hex() }] }]
+        })).unwrap();
+        assert_eq!(patch_group(&team, &replace.operations).unwrap().members, vec![TeamMember { user_id: admin, role: TeamRole::Admin }]);
+        let invalid: ScimPatchRequest = serde_json::from_value(json!({
+            "Operations": [{ "op": "remove", "path": "displayName" }]
+        })).unwrap();
+        assert!(patch_group(&team, &invalid.operations).is_err());
+
+        assert!(group_filter_to_document(r#"displayName eq "Platform""#).unwrap().contains_key("name"));
+        assert_eq!(group_filter_to_document(&format!(r#"members eq "{}""#, admin.to_hex())).unwrap(), doc! {"members.user_id": admin});
+
+        // failures carry the error schema and the status as a string
+        let error = serde_json::to_value(ScimError::new(409, Some("uniqueness"), "userName is already taken")).unwrap();
+        assert_eq!(error, json!({
+            "schemas": ["urn:ietf:params:scim:api:messages:2.0:Error"],
+            "status": "409",
+            "scimType": "uniqueness",
+            "detail": "userName is already taken"
+        }));
+    }
+
+    #[test]
+    fn scim_tokens_are_stored_hashed() {
+        use crate::helpers::scim::scim_token_hash;
+        use crate::models::organization::Organization;
+
+        let hash = scim_token_hash("token-of-acme");
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, scim_token_hash("token-of-acme"));
+        assert_ne!(hash, scim_token_hash("token-of-globex"));
+        assert!(!hash.contains("token"));
+
+        let organization = Organization { name: "Acme".to_string(), slug: "acme".to_string(), scim_token_hash: Some(hash), ..Default::default() };
+        let shown = serde_json::to_value(organization.without_secrets()).unwrap();
+        assert_eq!(shown["slug"], "acme");
+        assert!(shown.get("scim_token_hash").is_none());
+    }
+
+    #[test]
+    fn outdated_password_hashes_need_rehash() {
+        use crate::helpers::password::{hash_password, verify_password, Verification};
+        use argon2::{
+            password_hash::{rand_core::OsRng, PasswordHasher, SaltString},
+            Algorithm, Argon2, Params, Version,
+        };
+
+        let current = hash_password("correct horse");
+        assert_eq!(verify_password("correct horse", &current), Verification::Valid);
+        assert_eq!(verify_password("wrong horse", &current), Verification::Invalid);
+
+        let cheap = Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(1024, 1, 1, None).unwrap());
+        let salt = SaltString::generate(&mut OsRng);
+        let legacy = cheap.hash_password(b"correct horse", &salt).unwrap().to_string();
+        assert_eq!(verify_password("correct horse", &legacy), Verification::ValidNeedsRehash);
+    }
+
+    #[test]
+    fn logins_are_refused_with_a_reason() {
+        use crate::api::user::login_failure;
+        use crate::helpers::password::{hash_password, Verification};
+        use crate::models::user::{User, UserProfile};
+        use crate::repository::mongodb_repo::{check_login, LoginError};
+        use rocket::http::Status;
+
+        // a user provisioned over SCIM, without names or an email
+        let user = User { username: Some("scim".to_string()), password: hash_password("correct horse"), ..Default::default() };
+        let never = |_| false;
+        assert_eq!(check_login(Some(&user), "correct horse", never), Ok(Verification::Valid));
+        assert_eq!(check_login(None, "correct horse", never), Err(LoginError::Invalid));
+        assert_eq!(check_login(Some(&user), "wrong horse", never), Err(LoginError::Invalid));
+        assert_eq!(check_login(Some(&user), "correct horse", |_| true), Err(LoginError::Expired));
+        let deactivated = User { active: Some(false), ..user };
+        assert_eq!(check_login(Some(&deactivated), "correct horse", never), Err(LoginError::Deactivated));
+        // the status of an account stays hidden from wrong passwords
+        assert_eq!(check_login(Some(&deactivated), "wrong horse", never), Err(LoginError::Invalid));
+
+        assert_eq!(login_failure(LoginError::Invalid).0, Status::Unauthorized);
+        assert_eq!(login_failure(LoginError::Deactivated).0, Status::Forbidden);
+        assert_eq!(login_failure(LoginError::Expired), (Status::Forbidden, "Password expired"));
+
+        // what users look like to others carries no password hashes
+        let shown = serde_json::to_value(UserProfile::from(User { password_history: Some(vec!["old".to_string()]), ..deactivated })).unwrap();
+        assert!(shown.get("password").is_none() && shown.get("password_history").is_none());
+    }
+
+    #[test]
+    fn signups_get_no_role_and_tokens_name_user_ids() {
+        use crate::api::user::new_signup;
+        use crate::helpers::jwt::{jwt_sign, jwt_validate};
+        use crate::models::user::{RoleEnum, User};
+        use mongodb::bson::{doc, oid::ObjectId};
+
+        let signup = User { username: Some("mallory".to_string()), password: "correct horse".to_string(), role: Some(RoleEnum::Administrator), ..Default::default() };
+        let user = new_signup(signup);
+        assert_eq!(user.role, None);
+        assert_ne!(user.password, "correct horse");
+
+        // tokens find their user by id, an email in a token finds nobody
+        let id = ObjectId::new();
+        assert_eq!(jwt_validate(&jwt_sign(&id.to_hex(), None)).user_filter(), doc! {"_id": id});
+        assert_eq!(jwt_validate(&jwt_sign("admin@example.com", None)).user_filter(), doc! {"_id": null});
+    }
+
+    #[test]
+    fn profiles_and_roles_are_restricted() {
+        use crate::api::user::permitted_role;
+        use crate::models::user::{ProfileUpdate, RoleEnum, User, UserProfile};
+        use mongodb::bson::{oid::ObjectId, to_document};
+
+        // PUT /users/me only takes profile fields, role and password are left out
+        let update: ProfileUpdate = serde_json::from_value(json!({
+            "firstname": "ada",
+            "role": "Administrator",
+            "password": "hunter22",
+        })).unwrap();
+        let set = to_document(&update).unwrap();
+        assert_eq!(set.keys().collect::<Vec<_>>(), vec!["firstname"]);
+
+        // GET /users/me never shows credentials
+        let user = User { id: Some(ObjectId::new()), password: "hash".to_string(), password_history: Some(vec!["old".to_string()]), ..Default::default() };
+        let profile = serde_json::to_value(UserProfile::from(user)).unwrap();
+        assert!(profile.get("password").is_none() && profile.get("password_history").is_none());
+
+        let (me, other) = (ObjectId::new(), ObjectId::new());
+        let caller = User { id: Some(me), role: Some(RoleEnum::User), ..Default::default() };
+        let admin = User { id: Some(ObjectId::new()), role: Some(RoleEnum::Administrator), ..Default::default() };
+        assert!(caller.can_manage(&me) && !caller.can_manage(&other));
+        assert!(admin.can_manage(&other));
+
+        // only administrators hand out roles
+        assert_eq!(permitted_role(&caller, Some(RoleEnum::Administrator)), None);
+        assert_eq!(permitted_role(&admin, Some(RoleEnum::Administrator)), Some(RoleEnum::Administrator));
+    }
+
+    #[test]
+    fn tokens_of_inactive_users_are_refused() {
+        use crate::helpers::jwt::check_token_user;
+        use crate::models::user::{DeletionStrategy, User};
+        use chrono::Utc;
+
+        let user = User { active: None, ..Default::default() };
+        assert_eq!(check_token_user(Some(&user)), Ok(()));
+
+        let deactivated = User { active: Some(false), ..user };
+        assert_eq!(check_token_user(Some(&deactivated)), Err("User is deactivated"));
+        let reactivated = User { active: Some(true), ..deactivated };
+        assert_eq!(check_token_user(Some(&reactivated)), Ok(()));
+
+        // soft deleted users wait for the purge deactivated, purged ones are gone
+        let deleted = User { active: Some(false), deleted_at: Some(Utc::now()), deletion_strategy: Some(DeletionStrategy::Archive), ..reactivated };
+        assert!(check_token_user(Some(&deleted)).is_err());
+        assert_eq!(check_token_user(None), Err("User no longer exists"));
+    }
+
+    #[test]
+    fn users_are_deleted_by_themselves_or_admins() {
+        use crate::api::user::parse_deletion_strategy;
+        use crate::models::user::{DeletionStrategy, RoleEnum, User};
+        use mongodb::bson::oid::ObjectId;
+        use rocket::http::Status;
+
+        let (victim, attacker) = (ObjectId::new(), ObjectId::new());
+        let caller = User { id: Some(attacker), role: Some(RoleEnum::User), ..Default::default() };
+        // anyone else's account answers 403, whatever the strategy
+        assert!(!caller.can_manage(&victim));
+        assert!(caller.can_manage(&attacker));
+        assert!(User { role: Some(RoleEnum::Administrator), ..caller }.can_manage(&victim));
+
+        let to = attacker.to_hex();
+        assert_eq!(parse_deletion_strategy(&victim, Some("transfer"), Some(&to)), Ok(DeletionStrategy::Transfer { to: attacker }));
+        assert_eq!(parse_deletion_strategy(&attacker, Some("transfer"), Some(&to)), Err(Status::BadRequest));
+        assert_eq!(parse_deletion_strategy(&victim, Some("transfer"), None), Err(Status::BadRequest));
+        assert_eq!(parse_deletion_strategy(&victim, Some("transfer"), Some("nobody")), Err(Status::BadRequest));
+        assert_eq!(parse_deletion_strategy(&victim, Some("archive"), Some(&to)), Err(Status::BadRequest));
+        assert_eq!(parse_deletion_strategy(&victim, Some("delete"), None), Ok(DeletionStrategy::Delete));
+    }
+
+    #[test]
+    fn breached_passwords_are_found_by_prefix() {
+        use crate::helpers::password_policy::is_breached;
+
+        let dir = std::env::temp_dir().join("docs_api_breached_passwords");
+        std::fs::create_dir_all(&dir).unwrap();
+        // SHA-1 of "password" is 5BAA61E4C9B93F3F0682250B6CF8331B7EE68FD8
+        std::fs::write(dir.join("5BAA6"), "003D68EB55068C33ACE09247EE4C639306B:3\n1E4C9B93F3F0682250B6CF8331B7EE68FD8:9545824\n").unwrap();
+
+        assert!(is_breached(&dir, "password"));
+        assert!(!is_breached(&dir, "correct horse battery staple"));
+    }
+
+    #[test]
+    fn revision_diffs_by_line_and_word() {
+        use crate::helpers::diff::{diff_hunks, unified_diff};
+        use crate::models::diff::{ChangeKind, Granularity};
+
+        let old = "first line\nsecond line\nthird line\n";
+        let new = "first line\nsecond row\nthird line\n";
+
+        let hunks = diff_hunks(old, new, Granularity::Line, 0);
+        assert_eq!(hunks.len(), 1);
+        assert_eq!((hunks[0].old_start, hunks[0].old_len, hunks[0].new_start, hunks[0].new_len), (2, 1, 2, 1));
+        assert_eq!(hunks[0].changes[0].kind, ChangeKind::Delete);
+        assert_eq!(hunks[0].changes[1].value, "second row\n");
+
+        let words = diff_hunks(old, new, Granularity::Word, 0);
+        assert_eq!(words[0].changes.len(), 2);
+        assert_eq!(words[0].changes[0].value, "line");
+
+        let text = unified_diff(old, new, Granularity::Line, 1, "revision 1", "revision 2");
+        assert!(text.starts_with("--- revision 1\n+++ revision 2\n@@ -1,3 +1,3 @@"));
+        assert!(text.contains("-second line\n+second row\n"));
+
+        let text = unified_diff(old, new, Granularity::Word, 2, "revision 1", "revision 2");
+        assert!(text.contains("second [-line-]{+row+}"));
+    }
+
+    #[test]
+    fn revisions_list_by_version_and_restore_their_text() {
+        use crate::models::document::Document;
+        use crate::models::revision::{revision_order, Revision};
+        use mongodb::bson::oid::ObjectId;
+
+        let author = Some(ObjectId::new());
+        let document = Document {
+            id: Some(ObjectId::new()),
+            title: Some("Plan".to_string()),
+            content: Some("first draft".to_string()),
+            tags: Some(vec!["work".to_string()]),
+            version: Some(6),
+            ..Default::default()
+        };
+        let revision = Revision::snapshot(&document, 7, author).unwrap();
+        assert_eq!((revision.number, revision.version), (7, Some(6)));
+
+        // numbers are taken first come first served, the version decides the order
+        let keys: Vec<_> = revision_order(1).keys().cloned().collect();
+        assert_eq!(keys, vec!["version", "number"]);
+        assert_eq!(revision_order(-1).get_i32("version").unwrap(), -1);
+
+        let restored = revision.restore(author);
+        assert_eq!(restored.title.as_deref(), Some("Plan"));
+        assert_eq!(restored.content.as_deref(), Some("first draft"));
+        assert_eq!(restored.last_modified_by, author);
+        // tags, owner and version are not part of the history
+        assert!(restored.tags.is_none() && restored.owner_id.is_none() && restored.version.is_none());
+    }
+
+    #[test]
+    fn revision_diffs_render_as_asked() {
+        use crate::api::revision::{revision_diff, DiffResponse};
+        use crate::models::diff::Granularity;
+        use rocket::http::Status;
+
+        let (old, new) = ("one\ntwo\n", "one\nthree\n");
+        match revision_diff("doc".to_string(), 1, None, old, new, None, None, None) {
+            Ok(DiffResponse::Json(diff)) => {
+                assert_eq!((diff.from, diff.to, diff.granularity), (1, None, Granularity::Line));
+                assert_eq!(diff.hunks.len(), 1);
+            },
+            _ => panic!("expected a JSON diff"),
+        }
+        match revision_diff("doc".to_string(), 1, Some(2), old, new, Some("word"), Some("unified"), Some(0)) {
+            Ok(DiffResponse::Text(text)) => assert!(text.starts_with("--- revision 1\n+++ revision 2\n")),
+            _ => panic!("expected a unified diff"),
+        }
+        match revision_diff("doc".to_string(), 1, None, old, new, None, Some("unified"), None) {
+            Ok(DiffResponse::Text(text)) => assert!(text.contains("+++ current\n")),
+            _ => panic!("expected a unified diff"),
+        }
+        assert_eq!(revision_diff("doc".to_string(), 1, None, old, new, Some("char"), None, None).err(), Some(Status::BadRequest));
+        assert_eq!(revision_diff("doc".to_string(), 1, None, old, new, None, Some("html"), None).err(), Some(Status::BadRequest));
+    }
+
+    #[test]
+    fn patches_become_field_changes() {
+        use crate::helpers::patch::{apply_json_patch, apply_merge_patch, field_changes, PatchError};
+        use crate::models::document::Document;
+        use mongodb::bson::oid::ObjectId;
+
+        let document = Document {
+            id: Some(ObjectId::new()),
+            title: Some("notes".to_string()),
+            content: Some("draft".to_string()),
+            version: Some(3),
+            ..Default::default()
+        };
+        let writable = ["title", "content"];
+
+        let patched = apply_merge_patch(&document, &serde_json::json!({"title": "plans", "content": null})).unwrap();
+        let changes = field_changes(&document, &patched, &writable).unwrap();
+        assert_eq!(changes.set.get_str("title").unwrap(), "plans");
+        assert!(changes.unset.contains_key("content"));
+        assert_eq!(changes.set.len() + changes.unset.len(), 2);
+
+        let patch: json_patch::Patch = serde_json::from_value(serde_json::json!([
+            {"op": "test", "path": "/content", "value": "draft"},
+            {"op": "replace", "path": "/content", "value": "final"}
+        ])).unwrap();
+        let patched = apply_json_patch(&document, &patch).unwrap();
+        let changes = field_changes(&document, &patched, &writable).unwrap();
+        assert_eq!(changes.set.get_str("content").unwrap(), "final");
+        assert!(changes.unset.is_empty());
+
+        let failing: json_patch::Patch = serde_json::from_value(serde_json::json!([
+            {"op": "test", "path": "/content", "value": "final"}
+        ])).unwrap();
+        assert!(matches!(apply_json_patch(&document, &failing), Err(PatchError::Conflict(_))));
+
+        let patched = apply_merge_patch(&document, &serde_json::json!({"version": 9})).unwrap();
+        assert!(matches!(field_changes(&document, &patched, &writable), Err(PatchError::Invalid(_))));
+        assert!(matches!(apply_merge_patch(&document, &serde_json::json!({"version": "nine"})), Err(PatchError::Invalid(_))));
+    }
+
+    #[rocket::put("/<version>")]
+    #[allow(clippy::result_large_err)]
+    fn stale_unless(version: i64, if_match: crate::helpers::etag::RequiredIfMatch) -> Result<&'static str, crate::api::document::DocumentError> {
+        use crate::api::document::DocumentError;
+        use crate::helpers::etag::Tagged;
+        use crate::models::document::Document;
+        use rocket::serde::json::Json;
+
+        match if_match.0 {
+            Some(expected) if expected != version => {
+                let current = Document { version: Some(version), ..Default::default() };
+                Err(DocumentError::Stale(Tagged(Json(current), version)))
+            },
+            _ => Ok("written"),
+        }
+    }
+
+    #[test]
+    fn writes_need_a_current_if_match() {
+        use crate::helpers::etag::parse_if_match;
+        use rocket::http::{Header, Status};
+        use rocket::local::blocking::Client;
+
+        assert_eq!(parse_if_match(None, false), Ok(None));
+        assert_eq!(parse_if_match(None, true).unwrap_err().0, Status::PreconditionRequired);
+        assert_eq!(parse_if_match(Some("*"), true), Ok(None));
+        assert_eq!(parse_if_match(Some("W/\"7\""), true), Ok(Some(7)));
+        assert_eq!(parse_if_match(Some("latest"), false).unwrap_err().0, Status::BadRequest);
+
+        let client = Client::tracked(rocket::build().mount("/", rocket::routes![stale_unless])).unwrap();
+        assert_eq!(client.put("/3").dispatch().status(), Status::PreconditionRequired);
+        assert_eq!(client.put("/3").header(Header::new("If-Match", "\"3\"")).dispatch().status(), Status::Ok);
+        assert_eq!(client.put("/3").header(Header::new("If-Match", "*")).dispatch().status(), Status::Ok);
+
+        // a stale write answers 412 with the current document and its ETag
+        let stale = client.put("/3").header(Header::new("If-Match", "\"2\"")).dispatch();
+        assert_eq!(stale.status(), Status::PreconditionFailed);
+        assert_eq!(stale.headers().get_one("ETag"), Some("\"3\""));
+        let body: serde_json::Value = stale.into_json().unwrap();
+        assert_eq!(body["version"], 3);
+    }
+
+    #[test]
+    fn creation_metadata_is_server_managed() {
+        use crate::api::document::NewDocument;
+        use crate::models::document::Document;
+        use crate::repository::mongodb_repo::writable_fields;
+        use chrono::Utc;
+        use mongodb::bson::oid::ObjectId;
+
+        // clients can not send ownership, timestamps or the version
+        let forged: NewDocument = serde_json::from_value(json!({
+            "title": "notes",
+            "owner_id": ObjectId::new().to_hex(),
+            "date_created": "2001-01-01T00:00:00Z",
+            "last_modified_by": ObjectId::new().to_hex(),
+            "version": 99,
+        })).unwrap();
+        let written = serde_json::to_value(forged).unwrap();
+        assert_eq!(written["title"], "notes");
+        for field in ["owner_id", "date_created", "last_modified_by", "version"] {
+            assert!(written.get(field).is_none(), "{} was accepted", field);
+        }
+
+        let now = Utc::now();
+        let changes = Document {
+            id: Some(ObjectId::new()),
+            owner_id: Some(ObjectId::new()),
+            date_created: Some(now),
+            version: Some(4),
+            content: Some("text".to_string()),
+            last_modified: Some(now),
+            ..Default::default()
+        };
+        let fields = writable_fields(&changes).unwrap();
+        assert!(fields.contains_key("content") && fields.contains_key("last_modified"));
+        for field in ["_id", "owner_id", "date_created", "version"] {
+            assert!(!fields.contains_key(field), "{} would be overwritten", field);
+        }
+    }
+
+    #[test]
+    fn search_queries_and_snippets() {
+        use crate::helpers::search::{parse_query, snippet};
+
+        let query = parse_query(r#"Budget "quarterly report" fore*"#);
+        assert_eq!(query.words, vec!["budget"]);
+        assert_eq!(query.phrases, vec!["quarterly report"]);
+        assert_eq!(query.prefixes, vec!["fore"]);
+        assert!(query.uses_text_index());
+
+        let filter = query.to_filter();
+        let clauses = filter.get_array("$and").unwrap();
+        assert_eq!(clauses[0].as_document().unwrap().get_document("$text").unwrap().get_str("$search").unwrap(), r#"budget "quarterly report""#);
+        assert_eq!(clauses.len(), 2);
+
+        let prefix_only = parse_query("fore*");
+        assert!(!prefix_only.uses_text_index());
+
+        let text = "The team met on Monday.\nThe forecast for the budget looks fine, nothing else changed.";
+        let excerpt = snippet(text, &query, 3);
+        assert_eq!(excerpt, "…on Monday. The <mark>forecast</mark> for the <mark>budget</mark>…");
+
+        // markup in the content comes out escaped, only the markers are HTML
+        let text = "if a < b <script>alert(1)</script> & the budget";
+        let excerpt = snippet(text, &parse_query("budget script"), 10);
+        assert_eq!(excerpt, "if a &lt; b &lt;<mark>script</mark>&gt;alert(1)&lt;/<mark>script</mark>&gt; &amp; the <mark>budget</mark>");
+    }
+
+    #[test]
+    fn embedded_index_stems_fuzzes_and_facets() {
+        use crate::models::document::Document;
+        use crate::search::analyzer::Language;
+        use crate::search::index::{IndexQuery, SearchIndex};
+        use mongodb::bson::oid::ObjectId;
+
+        let alice = ObjectId::new();
+        let bob = ObjectId::new();
+        let document = |owner: ObjectId, title: &str, content: &str, language: Option<&str>| Document {
+            id: Some(ObjectId::new()),
+            owner_id: Some(owner),
+            title: Some(title.to_string()),
+            content: Some(content.to_string()),
+            language: language.map(String::from),
+            ..Default::default()
+        };
+        let runs = document(alice, "weekly runs", "she runs the database migrations", None);
+        let notes = document(bob, "notes", "the database was running slowly", None);
+        let french = document(bob, "chansons", "elles chantaient des chansons", Some("fr"));
+
+        let index = SearchIndex::new(Language::English);
+        assert_eq!(index.rebuild(vec![runs, notes, french]), 3);
+        let query = |text| IndexQuery {
+            text,
+            fuzzy: false,
+            readable_by: None,
+            readable_teams: Vec::new(),
+            readable_folders: Vec::new(),
+            owner: None,
+            tags: Vec::new(),
+        };
+
+        // both forms of "run" share a stem, the title match ranks first
+        let results = index.search(&query("running"));
+        assert_eq!(results.hits.len(), 2);
+        assert_eq!(results.facets.owners.len(), 2);
+        let first = results.hits[0].0;
+
+        assert_eq!(index.search(&query("chanson")).hits.len(), 1);
+        assert_eq!(index.search(&query("datab*")).hits.len(), 2);
+        assert!(index.search(&query("databse")).hits.is_empty());
+        assert_eq!(index.search(&IndexQuery { fuzzy: true, ..query("databse") }).hits.len(), 2);
+        assert_eq!(index.search(&IndexQuery { readable_by: Some(bob), ..query("database") }).hits.len(), 1);
+
+        index.remove(&first);
+        assert_eq!(index.search(&query("running")).hits.len(), 1);
+        index.remove_owner(&bob);
+        assert!(index.search(&query("database")).hits.is_empty());
+    }
+
+    #[test]
+    fn tags_are_renamed_on_owned_documents() {
+        use crate::api::document::owned_filter;
+        use crate::models::user::{RoleEnum, User};
+        use mongodb::bson::{doc, oid::ObjectId};
+
+        let id = ObjectId::new();
+        let user = User { id: Some(id), role: Some(RoleEnum::User), ..Default::default() };
+        assert_eq!(owned_filter(&user), doc! {"owner_id": id});
+        let admin = User { role: Some(RoleEnum::Administrator), ..user };
+        assert_eq!(owned_filter(&admin), doc! {});
+    }
+
+    #[test]
+    fn tags_are_normalized() {
+        use crate::api::tag::normalize_tags;
+        use rocket::http::Status;
+
+        let tags = vec![" Work ".to_string(), "urgent".to_string(), "work".to_string()];
+        assert_eq!(normalize_tags(tags).unwrap(), vec!["work", "urgent"]);
+        assert_eq!(normalize_tags(vec!["  ".to_string()]), Err(Status::UnprocessableEntity));
+        assert_eq!(normalize_tags(vec!["x".repeat(65)]), Err(Status::UnprocessableEntity));
+    }
+
+    #[test]
+    fn tag_changes_honour_if_match() {
+        use crate::repository::mongodb_repo::expected_version_filter;
+        use mongodb::bson::{doc, oid::ObjectId, Bson};
+
+        let id = ObjectId::new();
+        assert_eq!(expected_version_filter(&id, None), doc! {"_id": id});
+        assert_eq!(expected_version_filter(&id, Some(3)), doc! {"_id": id, "version": 3_i64});
+        // documents from before versioning match version 0
+        assert_eq!(expected_version_filter(&id, Some(0)), doc! {"_id": id, "version": {"$in": [Bson::Null, 0_i64]}});
+    }
+
+    #[test]
+    fn folders_move_copy_and_delete() {
+        use crate::api::folder::{check_depth, check_move, copy_of, plan_folder_copy, subtree_height};
+        use crate::models::{document::Document, folder::Folder};
+        use crate::repository::mongodb_repo::{folder_blocks_delete, MAX_FOLDER_DEPTH};
+        use chrono::Utc;
+        use mongodb::bson::oid::ObjectId;
+        use rocket::http::Status;
+
+        let folder = |parent_id: Option<ObjectId>, name: &str| Folder { id: Some(ObjectId::new()), parent_id, name: name.to_string(), ..Default::default() };
+        let root = folder(None, "projects");
+        let child = folder(root.id, "drafts");
+        let grandchild = folder(child.id, "old");
+        let sibling = folder(root.id, "notes");
+        let subtree = vec![root.clone(), child.clone(), sibling.clone(), grandchild.clone()];
+        assert_eq!(subtree_height(&subtree), 3);
+        assert_eq!(subtree_height(&subtree[..1]), 1);
+
+        // the depth limit counts the destination path and the levels moved or copied below it
+        assert_eq!(check_depth(MAX_FOLDER_DEPTH - 3, 3), Ok(()));
+        assert_eq!(check_depth(MAX_FOLDER_DEPTH - 2, 3), Err(Status::UnprocessableEntity));
+        assert_eq!(check_depth(MAX_FOLDER_DEPTH, 1), Err(Status::UnprocessableEntity));
+
+        let elsewhere = folder(None, "archive");
+        let root_id = root.id.unwrap();
+        assert_eq!(check_move(&root_id, std::slice::from_ref(&elsewhere), 3), Ok(()));
+        assert_eq!(check_move(&root_id, &[root.clone(), child.clone()], 3), Err(Status::Conflict));
+
+        let owner = Some(ObjectId::new());
+        let now = Utc::now();
+        let (copies, ids) = plan_folder_copy(&subtree, elsewhere.id, owner, now);
+        assert_eq!(copies.len(), 4);
+        assert_eq!(copies[0].parent_id, elsewhere.id);
+        assert_eq!(copies[1].parent_id, copies[0].id);
+        assert_eq!(copies[3].parent_id, copies[1].id);
+        assert_eq!(copies[3].name, "old");
+        assert!(copies.iter().all(|c| c.owner_id == owner && c.team_shares.is_none()));
+        assert!(subtree.iter().all(|f| ids.get(&f.id.unwrap()) != f.id.as_ref()));
+
+        let document = Document { id: Some(ObjectId::new()), folder_id: child.id, title: Some("plan".to_string()), version: Some(7), ..Default::default() };
+        let copy = copy_of(&document, owner, ids.get(&child.id.unwrap()).copied(), now);
+        assert_ne!(copy.id, document.id);
+        assert_eq!((copy.folder_id, copy.version, copy.title.as_deref()), (copies[1].id, Some(1), Some("plan")));
+
+        // only an empty folder goes without recursive=true
+        assert!(!folder_blocks_delete(1, false));
+        assert!(folder_blocks_delete(1, true));
+        assert!(folder_blocks_delete(2, false));
+    }
+
+    #[test]
+    fn shares_grant_permissions() {
+        use crate::models::{document::Document, share::{Permission, Principal, Share, TeamShare}, user::{RoleEnum, User}};
+        use mongodb::bson::oid::ObjectId;
+        use std::collections::HashMap;
+
+        let (owner, editor, stranger) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+        let (team, folder) = (ObjectId::new(), ObjectId::new());
+        let document = Document {
+            owner_id: Some(owner),
+            folder_id: Some(folder),
+            shares: Some(vec![Share { user_id: editor, permission: Permission::Editor }]),
+            team_shares: Some(vec![TeamShare { team_id: team, permission: Permission::Viewer }]),
+            ..Default::default()
+        };
+        let user = |id: ObjectId, role: RoleEnum| Principal {
+            user: User { id: Some(id), role: Some(role), ..Default::default() },
+            ..Default::default()
+        };
+
+        assert_eq!(document.permission_for(&user(owner, RoleEnum::User)), Some(Permission::Owner));
+        assert_eq!(document.permission_for(&user(editor, RoleEnum::User)), Some(Permission::Editor));
+        assert_eq!(document.permission_for(&user(stranger, RoleEnum::User)), None);
+        assert_eq!(document.permission_for(&user(stranger, RoleEnum::Administrator)), Some(Permission::Owner));
+        assert!(Permission::Editor > Permission::Commenter && Permission::Commenter > Permission::Viewer);
+
+        // the best of the team and folder grants counts
+        let member = Principal { team_ids: vec![team], ..user(stranger, RoleEnum::User) };
+        assert_eq!(document.permission_for(&member), Some(Permission::Viewer));
+        let member = Principal { folder_grants: HashMap::from([(folder, Permission::Commenter)]), ..member };
+        assert_eq!(document.permission_for(&member), Some(Permission::Commenter));
+    }
+
+    #[test]
+    fn share_links_expire_and_run_out() {
+        use crate::models::link::{ShareLink, ShareLinkInfo};
+        use chrono::{Duration, Utc};
+        use mongodb::bson::oid::ObjectId;
+
+        let now = Utc::now();
+        let link = ShareLink { document_id: ObjectId::new(), ..Default::default() };
+        assert!(!link.is_expired(now) && !link.is_used_up());
+
+        let link = ShareLink { expires_at: Some(now - Duration::minutes(1)), max_views: Some(2), views: 1, ..link };
+        assert!(link.is_expired(now) && !link.is_expired(now - Duration::hours(1)));
+        assert!(!link.is_used_up());
+        assert!(ShareLink { views: 2, ..link.clone() }.is_used_up());
+
+        // owners learn whether a password is set, never the hash
+        let info = ShareLinkInfo::from(ShareLink { password_hash: Some("hash".to_string()), ..link });
+        assert!(info.has_password);
+        let shown = serde_json::to_value(&info).unwrap();
+        assert!(shown.get("password_hash").is_none());
+        assert!(shown.as_object().unwrap().values().all(|value| value != "hash"));
+    }
+
+    #[test]
+    fn share_link_passwords_lock_out_guessing() {
+        use crate::models::link::{lock_after, ShareLink, LINK_ATTEMPTS_BEFORE_LOCK};
+        use chrono::{Duration, Utc};
+
+        assert_eq!(lock_after(1), None);
+        assert_eq!(lock_after(LINK_ATTEMPTS_BEFORE_LOCK - 1), None);
+        assert_eq!(lock_after(LINK_ATTEMPTS_BEFORE_LOCK), Some(Duration::minutes(1)));
+        assert_eq!(lock_after(LINK_ATTEMPTS_BEFORE_LOCK + 1), Some(Duration::minutes(2)));
+        assert_eq!(lock_after(LINK_ATTEMPTS_BEFORE_LOCK + 3), Some(Duration::minutes(8)));
+        assert_eq!(lock_after(LINK_ATTEMPTS_BEFORE_LOCK + 60), Some(Duration::days(1)));
+
+        let now = Utc::now();
+        let link = ShareLink { locked_until: Some(now + Duration::minutes(1)), ..Default::default() };
+        assert!(link.is_locked(now));
+        assert!(!link.is_locked(now + Duration::minutes(2)));
+        assert!(!ShareLink::default().is_locked(now));
+    }
+
+    #[test]
+    fn trash_entries_expire_by_bson_date() {
+        use crate::models::document::Document;
+        use crate::repository::mongodb_repo::{expired_trash_filter, trash_entry};
+        use chrono::{Duration, Utc};
+        use mongodb::bson::{oid::ObjectId, Bson};
+
+        let now = Utc::now();
+        let deleted_by = Some(ObjectId::new());
+        let document = Document { id: Some(ObjectId::new()), title: Some("notes".to_string()), ..Default::default() };
+        let entry = trash_entry(document, deleted_by, now).unwrap();
+        assert_eq!(entry.get_str("title").unwrap(), "notes");
+        assert_eq!(entry.get_object_id("deleted_by").ok(), deleted_by);
+        assert!(entry.contains_key("deleted_at"));
+        let trashed_at = entry.get_datetime("trashed_at").unwrap();
+        assert_eq!(trashed_at.timestamp_millis(), now.timestamp_millis());
+
+        // the purge compares dates in the query, not strings in code
+        let cutoff = now - Duration::days(30);
+        let filter = expired_trash_filter(cutoff);
+        match filter.get_document("trashed_at").unwrap().get("$lt") {
+            Some(Bson::DateTime(date)) => assert!(date.timestamp_millis() < trashed_at.timestamp_millis()),
+            other => panic!("expected a date, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn deleted_users_expire_by_bson_date() {
+        use crate::models::user::User;
+        use crate::repository::mongodb_repo::{bson_date, expired_users_filter};
+        use chrono::{Duration, Utc};
+        use mongodb::bson::{doc, from_document, to_bson, Bson};
+
+        let now = Utc::now();
+        let deleted: User = from_document(doc! {"password": "", "deleted_at": bson_date(now)}).unwrap();
+        assert_eq!(deleted.deleted_at.unwrap().timestamp_millis(), now.timestamp_millis());
+        assert!(!deleted.is_active());
+        // users deleted before the purge queried dates still read
+        let legacy: User = from_document(doc! {"password": "", "deleted_at": to_bson(&now).unwrap()}).unwrap();
+        assert_eq!(legacy.deleted_at, Some(now));
+
+        let cutoff = now - Duration::days(30);
+        match expired_users_filter(cutoff).get_document("deleted_at").unwrap().get("$lt") {
+            Some(Bson::DateTime(date)) => assert_eq!(date, &bson_date(cutoff)),
+            other => panic!("expected a date, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn comments_anchor_and_thread() {
+        use crate::models::comment::{Comment, CommentThread, TextAnchor};
+        use mongodb::bson::oid::ObjectId;
+
+        let anchor = |start, end| TextAnchor { start, end, quote: None };
+        assert_eq!(anchor(4, 9).quote_from("Die Größe zählt"), Some("Größe".to_string()));
+        assert_eq!(anchor(4, 4).quote_from("text"), None);
+        assert_eq!(anchor(2, 5).quote_from("text"), None);
+
+        let document_id = ObjectId::new();
+        let comment = |id: ObjectId, parent_id: Option<ObjectId>| Comment { id: Some(id), document_id, parent_id, ..Default::default() };
+        let (first, second, reply) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+        let threads = CommentThread::group(vec![comment(first, None), comment(reply, Some(second)), comment(second, None)]);
+        assert_eq!(threads.len(), 2);
+        assert!(threads[0].replies.is_empty());
+        assert_eq!(threads[1].replies[0].id, Some(reply));
+    }
+
+    #[test]
+    fn suggestions_apply_and_rebase() {
+        use crate::helpers::diff::rebase_range;
+        use crate::models::suggestion::{Suggestion, SuggestionStatus};
+        use mongodb::bson::oid::ObjectId;
+
+        let suggestion = Suggestion {
+            id: None,
+            document_id: ObjectId::new(),
+            author_id: None,
+            base_revision: 1,
+            base_version: Some(1),
+            base_content: Some("the quick fox".to_string()),
+            start: 4,
+            end: 9,
+            text: "slow".to_string(),
+            quote: "quick".to_string(),
+            status: SuggestionStatus::Pending,
+            date_created: None,
+            decided_by: None,
+            decided_at: None,
+            revision: None,
+        };
+        assert_eq!(suggestion.apply("the quick fox").unwrap(), "the slow fox");
+        assert_eq!(Suggestion { end: 20, ..suggestion.clone() }.apply("the quick fox"), None);
+
+        // edits before the range shift it, edits after it or at its edges leave it alone
+        assert_eq!(rebase_range("the quick fox", "oh the quick fox", 4, 9), Some((7, 12)));
+        assert_eq!(rebase_range("the quick fox", "the quick red fox", 4, 9), Some((4, 9)));
+        assert_eq!(rebase_range("the quick fox", "the quickest fox", 4, 9), Some((4, 9)));
+        assert_eq!(rebase_range("the quick fox", "the very quick fox", 4, 4), Some((9, 9)));
+        // an edit inside the range conflicts
+        assert_eq!(rebase_range("the quick fox", "the quack fox", 4, 9), None);
+        assert_eq!(rebase_range("the quick fox", "the fox", 5, 5), None);
+
+        // a rebase starts from the content the suggestion was made on and moves its base along
+        let rebased = suggestion.rebased("the quick fox", "oh the quick fox", 3, 2).unwrap();
+        assert_eq!((rebased.start, rebased.end, rebased.base_revision, rebased.base_version), (7, 12, 2, Some(3)));
+        assert_eq!(rebased.base_content.as_deref(), Some("oh the quick fox"));
+        assert_eq!(rebased.apply("oh the quick fox").unwrap(), "oh the slow fox");
+        assert!(suggestion.rebased("the quick fox", "the quack fox", 3, 2).is_none());
+    }
+
+    #[test]
+    fn concurrent_edits_converge() {
+        use crate::collab::ot::{transform, transform_cursor, TextOperation};
+
+        // "the fox": one session inserts "quick " and the other deletes "the "
+        let a = TextOperation::new().retain(4).insert("quick ").retain(3);
+        let b = TextOperation::new().delete(4).retain(3);
+        let (a_prime, b_prime) = transform(&a, &b).unwrap();
+        let ab = b_prime.apply(&a.apply("the fox").unwrap()).unwrap();
+        let ba = a_prime.apply(&b.apply("the fox").unwrap()).unwrap();
+        assert_eq!(ab, "quick fox");
+        assert_eq!(ab, ba);
+
+        // inserts at the same spot keep the first operation's text first
+        let c = TextOperation::new().retain(3).insert("x");
+        let d = TextOperation::new().retain(3).insert("y");
+        let (c_prime, d_prime) = transform(&c, &d).unwrap();
+        assert_eq!(d_prime.apply(&c.apply("abc").unwrap()).unwrap(), "abcxy");
+        assert_eq!(c_prime.apply(&d.apply("abc").unwrap()).unwrap(), "abcxy");
+
+        // operations only fit text of their base length
+        assert_eq!(a.apply("the quick fox"), None);
+        assert!(transform(&a, &TextOperation::new().retain(2)).is_none());
+
+        assert_eq!(transform_cursor(5, &a), 11);
+        assert_eq!(transform_cursor(2, &b), 0);
+        assert_eq!(transform_cursor(6, &b), 2);
+
+        // a change saved elsewhere is merged into the room's unsaved edits the same way
+        let base = "the fox";
+        let unsaved = TextOperation::between(base, "the quick fox");
+        let stored = TextOperation::between(base, "the fox jumps");
+        assert_eq!(unsaved.apply(base).unwrap(), "the quick fox");
+        let (_, external) = transform(&unsaved, &stored).unwrap();
+        assert_eq!(external.apply("the quick fox").unwrap(), "the quick fox jumps");
+        assert_eq!(TextOperation::between("café", "cafés").apply("café").unwrap(), "cafés");
+    }
+
+    #[test]
+    fn organizations_only_see_their_own_data() {
+        use crate::helpers::jwt::AuthObject;
+        use crate::helpers::tenant::organization_filter;
+        use crate::repository::scoped::{stamp_tenant, tenant_filter};
+        use mongodb::bson::{doc, oid::ObjectId, Bson, Document};
+
+        // enough of MongoDB's matching for the filters the scoped collections build
+        fn matches(filter: &Document, item: &Document) -> bool {
+            filter.iter().all(|(key, value)| match (key.as_str(), value) {
+                ("$and", Bson::Array(clauses)) => clauses.iter().all(|c| c.as_document().is_some_and(|c| matches(c, item))),
+                (key, Bson::Null) => matches!(item.get(key), None | Some(Bson::Null)),
+                (key, value) => item.get(key) == Some(value),
+            })
+        }
+
+        let (acme, globex) = (ObjectId::new(), ObjectId::new());
+        let id = ObjectId::new();
+        let stored = stamp_tenant(Some(globex), doc! {"_id": id, "title": "Plans", "org_id": acme});
+        assert_eq!(stored.get_object_id("org_id").unwrap(), globex);
+        let legacy = stamp_tenant(None, doc! {"_id": id, "org_id": globex});
+        assert!(!legacy.contains_key("org_id"));
+
+        // find, update and delete all go through the same scope, by id or with no filter at all
+        for filter in [Some(doc! {"_id": id}), Some(doc! {}), None] {
+            assert!(matches(&tenant_filter(Some(globex), filter.clone()), &stored));
+            assert!(!matches(&tenant_filter(Some(acme), filter.clone()), &stored));
+            assert!(!matches(&tenant_filter(None, filter.clone()), &stored));
+            assert!(matches(&tenant_filter(None, filter), &legacy));
+        }
+
+        // a token decides the organization, the header only counts without one
+        let token = |org| Some(AuthObject { authorized: true, user: ObjectId::new().to_hex(), org });
+        assert_eq!(organization_filter(token(Some(acme)), Some("globex")), Some(doc! {"_id": acme}));
+        assert_eq!(organization_filter(token(None), Some("globex")), None);
+        assert_eq!(organization_filter(None, Some(" Globex ")), Some(doc! {"slug": "globex"}));
+        let expired = Some(AuthObject { authorized: false, user: String::new(), org: Some(acme) });
+        assert_eq!(organization_filter(expired, Some("globex")), Some(doc! {"slug": "globex"}));
+        assert_eq!(organization_filter(None, None), None);
+    }
+
+    #[test]
+    fn event_bus_resumes_from_last_event_id() {
+        use crate::api::event::changes_access;
+        use crate::events::bus::EventBus;
+        use crate::models::{document::Document, event::EventKind, share::Principal, user::User};
+        use mongodb::bson::oid::ObjectId;
+
+        let bus = EventBus::new();
+        let document = || Document { id: Some(ObjectId::new()), ..Default::default() };
+        bus.publish_document(None, EventKind::DocumentCreated, document());
+        let (missed, mut subscription) = bus.subscribe(None);
+        assert!(missed.unwrap().is_empty());
+
+        bus.publish_document(None, EventKind::DocumentUpdated, document());
+        bus.publish_document(None, EventKind::DocumentShared, document());
+        let first = subscription.try_recv().unwrap().event.id.clone();
+        assert_eq!(subscription.try_recv().unwrap().event.kind, EventKind::DocumentShared);
+
+        // resuming after the first event replays the second
+        let (missed, _) = bus.subscribe(Some(&first));
+        let missed = missed.unwrap();
+        assert_eq!(missed.len(), 1);
+        assert_eq!(missed[0].event.kind, EventKind::DocumentShared);
+
+        // ids from another run or from the future are unknown
+        assert!(bus.subscribe(Some("1-1")).0.is_none());
+        let (boot, _) = first.split_once('-').unwrap();
+        assert!(bus.subscribe(Some(&format!("{}-99", boot))).0.is_none());
+
+        // streams look their caller up again after shares change or the caller themselves does
+        let me = User { id: Some(ObjectId::new()), ..Default::default() };
+        let principal = Principal { user: User { id: me.id, ..Default::default() }, ..Default::default() };
+        let someone = User { id: Some(ObjectId::new()), ..Default::default() };
+        bus.publish_user(None, EventKind::UserUpdated, &someone);
+        bus.publish_user(None, EventKind::UserUpdated, &me);
+        bus.publish_user(Some(ObjectId::new()), EventKind::UserDeleted, &me);
+        let mut changed = vec![changes_access(&missed[0], None, &principal)];
+        while let Ok(published) = subscription.try_recv() {
+            changed.push(changes_access(&published, None, &principal));
+        }
+        assert_eq!(changed, vec![true, false, true, false]);
+    }
+
+    #[tokio::test]
+    async fn webhooks_are_signed_and_back_off() {
+        use crate::jobs::webhooks::{http_client, retry_delay, send, sign, SIGNATURE_HEADER, TIMESTAMP_HEADER};
+        use crate::models::{event::Event, webhook::{Webhook, WebhookDelivery}};
+        use mongodb::bson::oid::ObjectId;
+        use rocket::tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+        // a local receiver that answers `status` to one request and hands the request back
+        async fn receiver(status: &'static str) -> (String, tokio::task::JoinHandle<String>) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let url = format!("http://{}/hook", listener.local_addr().unwrap());
+            let handle = tokio::spawn(async move {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0u8; 4096];
+                loop {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some((head, body)) = text.split_once("\r\n\r\n") {
+                        let length = head
+                            .lines()
+                            .find_map(|line| line.to_lowercase().strip_prefix("content-length:").map(|v| v.trim().parse::<usize>().unwrap()))
+                            .unwrap_or(0);
+                        if body.len() >= length || read == 0 {
+                            break;
+                        }
+                    }
+                }
+                let answer = format!("HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+                stream.write_all(answer.as_bytes()).await.unwrap();
+                String::from_utf8(request).unwrap()
+            });
+            (url, handle)
+        }
+
+        let (url, request) = receiver("200 OK").await;
+        let webhook = Webhook { url, secret: "s3cret".to_string(), active: true, ..Default::default() };
+        let delivery = WebhookDelivery { id: Some(ObjectId::new()), ..WebhookDelivery::new(ObjectId::new(), Event::ping("ping-1".to_string())) };
+        assert_eq!(send(&http_client(true), &webhook, &delivery).await, Ok(200));
+
+        // the receiver can recompute the signature from the timestamp and the raw body
+        let request = request.await.unwrap();
+        let (head, body) = request.split_once("\r\n\r\n").unwrap();
+        let header = |name: &str| head.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then(|| value.trim().to_string())
+        }).unwrap();
+        let timestamp: i64 = header(TIMESTAMP_HEADER).parse().unwrap();
+        assert_eq!(header(SIGNATURE_HEADER), format!("sha256={}", sign("s3cret", timestamp, body)));
+        assert!(body.contains("\"type\":\"ping\""));
+
+        let (url, _) = receiver("500 Internal Server Error").await;
+        let failing = Webhook { url, ..webhook };
+        assert_eq!(send(&http_client(true), &failing, &delivery).await.unwrap_err().0, Some(500));
+
+        assert_eq!(retry_delay(1).num_seconds() * 2, retry_delay(2).num_seconds());
+        assert_eq!(retry_delay(40).num_seconds(), 6 * 60 * 60);
+    }
+
+    #[test]
+    fn webhook_deliveries_queue_by_bson_date() {
+        use crate::models::{event::Event, webhook::WebhookDelivery};
+        use crate::repository::mongodb_repo::{bson_date, delivery_entry, due_delivery_filter};
+        use chrono::Utc;
+        use mongodb::bson::{self, oid::ObjectId, Bson};
+
+        let delivery = WebhookDelivery::new(ObjectId::new(), Event::ping("ping-1".to_string()));
+        let due = delivery.next_attempt_at.unwrap();
+        let entry = delivery_entry(&delivery).unwrap();
+        assert_eq!(entry.get("next_attempt_at"), Some(&Bson::DateTime(bson_date(due))));
+
+        // read back from the driver's raw documents and from older string dates alike
+        let raw = bson::to_vec(&entry).unwrap();
+        let stored: WebhookDelivery = bson::from_slice(&raw).unwrap();
+        assert_eq!(stored.next_attempt_at.unwrap().timestamp_millis(), due.timestamp_millis());
+        let legacy: WebhookDelivery = bson::from_document(bson::to_document(&delivery).unwrap()).unwrap();
+        assert_eq!(legacy.next_attempt_at, Some(due));
+        // the API still shows it as a date string
+        assert!(serde_json::to_value(&stored).unwrap()["next_attempt_at"].is_string());
+
+        let now = Utc::now();
+        let busy = ObjectId::new();
+        let filter = due_delivery_filter(now, &[busy]).unwrap();
+        assert_eq!(filter.get_str("status").unwrap(), "pending");
+        assert_eq!(filter.get_document("next_attempt_at").unwrap().get_datetime("$lte").unwrap(), &bson_date(now));
+        assert_eq!(filter.get_document("webhook_id").unwrap().get_array("$nin").unwrap(), &vec![Bson::ObjectId(busy)]);
+    }
+
+    #[tokio::test]
+    async fn webhook_urls_stay_off_private_networks() {
+        use crate::api::webhook::check_url;
+        use crate::jobs::webhooks::{http_client, is_private_address, send};
+        use crate::models::{event::Event, webhook::{Webhook, WebhookDelivery}};
+        use mongodb::bson::oid::ObjectId;
+        use rocket::http::Status;
+
+        let private_addresses = [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0", "0.1.2.3",
+            "224.0.0.1", "239.255.255.250", "::1", "fd00::1", "fe80::1", "ff02::1", "::ffff:10.0.0.1", "64:ff9b::a00:1", "64:ff9b::7f00:1",
+        ];
+        for private in private_addresses {
+            assert!(is_private_address(private.parse().unwrap()), "{}", private);
+        }
+        for public in ["93.184.216.34", "100.128.0.1", "2606:2800:220:1::", "64:ff9b::5db8:d822"] {
+            assert!(!is_private_address(public.parse().unwrap()), "{}", public);
+        }
+
+        // WEBHOOK_ALLOW_PRIVATE is not set in tests
+        assert_eq!(check_url("http://127.0.0.1:9000/hook").await, Err(Status::UnprocessableEntity));
+        assert_eq!(check_url("http://[::1]/hook").await, Err(Status::UnprocessableEntity));
+        assert_eq!(check_url("http://localhost/hook").await, Err(Status::UnprocessableEntity));
+        assert_eq!(check_url("ftp://93.184.216.34/hook").await, Err(Status::UnprocessableEntity));
+        assert_eq!(check_url(" https://93.184.216.34/hook ").await, Ok("https://93.184.216.34/hook".to_string()));
+
+        // names are checked again when sending, against the addresses the request then goes to
+        let delivery = WebhookDelivery::new(ObjectId::new(), Event::ping("ping-1".to_string()));
+        for url in ["http://127.0.0.1:9/hook", "http://[::1]:9/hook", "http://localhost:9/hook"] {
+            let webhook = Webhook { url: url.to_string(), secret: "s3cret".to_string(), active: true, ..Default::default() };
+            let (status, error) = send(&http_client(false), &webhook, &delivery).await.unwrap_err();
+            assert_eq!(status, None);
+            assert!(error.contains("private address"), "{}: {}", url, error);
+        }
+    }
+}